@@ -1,3 +1,4 @@
 pub mod receive;
+pub mod resume;
 pub mod send;
 pub mod transfer;
@@ -1,27 +1,61 @@
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use tauri::{AppHandle, Emitter, Manager};
+use tauri_plugin_opener::OpenerExt;
 use tokio::sync::{mpsc, oneshot};
-use tracing::{error, info, warn};
+use tracing::{error, info};
 
+use crate::crypto::keyschedule::{self, KeySchedule};
 use crate::crypto::spake::KeyExchange;
-use crate::network::quic::QuicEndpoint;
-use crate::network::relay::RelayStream;
-use crate::network::signaling::{PeerInfo, SignalingClient};
-use crate::network::transport::Transport;
-use crate::transfer::code::TransferCode;
+use crate::error::{AppError, AppResult};
+use crate::network::connect::{self, ConnectionMode};
+use crate::network::quic::{CertStore, NetworkOptions, QuicEndpoint};
+use crate::network::signaling::{ReconnectPolicy, SignalingClient, DEFAULT_PEER_TIMEOUT};
+use crate::network::tls;
+use crate::protocol::reassembler::FlushPolicy;
+use crate::protocol::sink::{SinkFactory, VerifyOnly};
+use crate::transfer::code::{redacted, TransferCode};
+use crate::transfer::collision::CollisionPolicy;
+use crate::transfer::history::HistoryLog;
+use crate::transfer::journal::{JournalDir, PartialFileReport, ReceiveJournal};
+use crate::transfer::opener::{OnCompleteAction, Opener};
+use crate::transfer::portable::ResumeTarget;
 use crate::transfer::progress::ProgressEvent;
-use crate::transfer::receiver;
-use crate::transfer::session::{TransferRole, TransferSession};
+use crate::transfer::receiver::{self, ReceiveOptions};
+use crate::transfer::session::{TransferRole, TransferSession, TransferState};
+use crate::transfer::space::StatvfsProbe;
+use crate::transfer::staging::ManualFinalize;
+use crate::transfer::usage::UsageStore;
 
-use super::transfer::{AcceptChannelStore, SessionStore};
+use super::transfer::{AcceptChannelStore, FinalizeChannelStore, SessionStore};
 
 const DEFAULT_SIGNAL_URL: &str = "ws://localhost:8080";
 
-/// Timeout for the receiver trying to connect to sender via QUIC.
-const RECEIVER_QUIC_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/// What the frontend can ask of [`start_receive`] besides the code and save
+/// directory. Anything left out keeps its default.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct StartReceiveOptions {
+    pub signal_server_url: Option<String>,
+    /// Fallbacks tried in order after `signal_server_url`, as for the sender.
+    pub signal_server_urls: Vec<String>,
+    pub flush_interval_ms: Option<u64>,
+    pub inline_text: bool,
+    pub network: NetworkOptions,
+    pub inspect_before_finalize: bool,
+    pub on_complete: OnCompleteAction,
+    pub resume: bool,
+    pub peer_timeout_secs: Option<u64>,
+    pub connection_mode: ConnectionMode,
+    pub skip_existing: bool,
+    pub on_collision: CollisionPolicy,
+    pub max_total_bytes: Option<u64>,
+    pub max_file_count: Option<usize>,
+    /// Check the files and throw them away instead of saving them.
+    pub verify_only: bool,
+}
 
 /// Start receiving: parse code, connect to signaling server, discover sender, transfer.
 #[tauri::command]
@@ -29,63 +63,186 @@ pub async fn start_receive(
     app: AppHandle,
     code: String,
     save_dir: String,
-    signal_server_url: Option<String>,
+    options: Option<StartReceiveOptions>,
 ) -> Result<String, String> {
-    let _parsed_code = TransferCode::parse(&code).map_err(|e| e.to_string())?;
-    let save_path = PathBuf::from(&save_dir);
+    let request = options.unwrap_or_default();
+    let parsed_code = TransferCode::parse(&code).map_err(|e| e.to_string())?;
+    let save_path = prepare_save_dir(PathBuf::from(&save_dir)).await?;
+
+    let session = TransferSession::new(TransferRole::Receiver, parsed_code).with_resume_target(
+        ResumeTarget::Receive {
+            save_dir: save_path.clone(),
+        },
+    );
+    let mut receive_options = ReceiveOptions {
+        inline_text: request.inline_text,
+        on_complete: request.on_complete,
+        resume: request.resume,
+        skip_existing: request.skip_existing,
+        on_collision: request.on_collision,
+        max_total_bytes: request.max_total_bytes,
+        max_file_count: request.max_file_count,
+        sink_factory: request
+            .verify_only
+            .then(|| Arc::new(VerifyOnly) as Arc<dyn SinkFactory>),
+        ..Default::default()
+    };
+    if let Some(ms) = request.flush_interval_ms {
+        receive_options.flush_policy = FlushPolicy {
+            every_bytes: None,
+            every: Some(std::time::Duration::from_millis(ms)),
+        };
+    }
+    let connection = ReceiveConnection {
+        signal_server_urls: request
+            .signal_server_url
+            .into_iter()
+            .chain(request.signal_server_urls)
+            .collect(),
+        network: request.network,
+        mode: request.connection_mode,
+        peer_timeout: request
+            .peer_timeout_secs
+            .map(std::time::Duration::from_secs),
+    };
+    begin_receive(
+        app,
+        session,
+        save_path,
+        receive_options,
+        request.inspect_before_finalize,
+        connection,
+    )
+    .await
+}
 
+/// Create the save directory if needed and check it can be written to.
+pub(super) async fn prepare_save_dir(save_path: PathBuf) -> Result<PathBuf, String> {
     if !save_path.is_dir() {
         tokio::fs::create_dir_all(&save_path)
             .await
             .map_err(|e| format!("Cannot create save directory: {e}"))?;
     }
+    receiver::check_save_dir(&save_path)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(save_path)
+}
 
-    info!("receive: starting with code '{code}'");
+/// How a receive finds and connects to its sender.
+#[derive(Debug, Default)]
+pub(super) struct ReceiveConnection {
+    /// Tried in order; empty means [`DEFAULT_SIGNAL_URL`].
+    pub signal_server_urls: Vec<String>,
+    pub network: NetworkOptions,
+    /// Decides between a direct connection and the relay.
+    pub mode: ConnectionMode,
+    /// How long the sender gets to show up; by default
+    /// [`DEFAULT_PEER_TIMEOUT`].
+    pub peer_timeout: Option<std::time::Duration>,
+}
+
+/// Register `session` and spawn the receive pipeline into `save_path`,
+/// unless the monthly usage quota is used up. The session's controls, the
+/// history log, usage store, journal and opener are attached to `options`.
+pub(super) async fn begin_receive(
+    app: AppHandle,
+    session: TransferSession,
+    save_path: PathBuf,
+    options: ReceiveOptions,
+    inspect_before_finalize: bool,
+    mut connection: ReceiveConnection,
+) -> Result<String, String> {
+    let code = session.code.to_code_string();
+    info!("receive: starting with code '{}'", redacted(&code));
+    #[cfg(debug_assertions)]
+    tracing::trace!("receive: full code '{code}'");
+
+    let usage = app.state::<UsageStore>().inner().clone();
+    usage.check_quota().await.map_err(|e| e.to_string())?;
+
+    // Endpoints use this device's persisted certificate.
+    connection.network.identity = app.state::<CertStore>().inner().clone();
 
-    let session = TransferSession::new(
-        TransferRole::Receiver,
-        TransferCode::parse(&code).map_err(|e| e.to_string())?,
-    );
     let session_id = session.id.clone();
-    let cancel_token = session.cancel_token.clone();
+    let pause_token = session.pause_token.clone();
+    let partials = session.partial_files.clone();
+    let destinations = session.destinations.clone();
+    let selection = session.selection.clone();
+    let journal = app
+        .state::<JournalDir>()
+        .path_for(&session_id)
+        .map_err(|e| e.to_string())?;
 
     // Store session
     let store = app.state::<SessionStore>().inner().clone();
-    store.lock().await.insert(session_id.clone(), Arc::new(session));
+    let session = Arc::new(session);
+    store
+        .lock()
+        .await
+        .insert(session_id.clone(), session.clone());
 
     // Create accept/decline channel
     let (accept_tx, accept_rx) = oneshot::channel::<bool>();
     let accept_store = app.state::<AcceptChannelStore>().inner().clone();
     accept_store.lock().await.insert(session_id.clone(), accept_tx);
 
-    let server_url = signal_server_url.unwrap_or_else(|| DEFAULT_SIGNAL_URL.into());
+    if connection.signal_server_urls.is_empty() {
+        connection.signal_server_urls = vec![DEFAULT_SIGNAL_URL.into()];
+    }
+
+    let mut options = ReceiveOptions {
+        pause: pause_token,
+        partials,
+        destinations,
+        selection,
+        history: Some(app.state::<HistoryLog>().inner().clone()),
+        code: Some(code.clone()),
+        usage: Some(usage),
+        space_probe: Some(Arc::new(StatvfsProbe)),
+        journal: Some(journal),
+        opener: Some(Arc::new(DesktopOpener(app.clone()))),
+        #[cfg(feature = "metrics")]
+        metrics: Some(crate::transfer::metrics::Metrics::global()),
+        ..options
+    };
+    if inspect_before_finalize {
+        let (finalize_tx, finalize_rx) = oneshot::channel::<bool>();
+        app.state::<FinalizeChannelStore>()
+            .0
+            .lock()
+            .await
+            .insert(session_id.clone(), finalize_tx);
+        options.inspector = Some(Arc::new(ManualFinalize::new(finalize_rx)));
+    }
 
     let (progress_tx, mut progress_rx) = mpsc::unbounded_channel::<ProgressEvent>();
     let app_handle = app.clone();
 
     // Forward progress events
+    let observed = session.clone();
     tokio::spawn(async move {
         while let Some(event) = progress_rx.recv().await {
+            observed.observe(&event).await;
             if let Err(e) = app_handle.emit("transfer:progress", &event) {
                 error!("failed to emit progress event: {e}");
             }
         }
     });
 
-    let code_clone = code.clone();
-
     // Run receive pipeline
     let app_handle2 = app.clone();
     tokio::spawn(async move {
         let result = run_receive_with_signaling(
             save_path,
-            &code_clone,
-            &server_url,
+            connection,
             progress_tx.clone(),
             accept_rx,
-            cancel_token,
+            &session,
+            options,
         )
         .await;
+        session.finish(&result).await;
 
         match result {
             Ok(()) => {
@@ -109,146 +266,195 @@ pub async fn start_receive(
 }
 
 /// Full receive flow with signaling server, SPAKE2 key exchange,
-/// and fallback to relay if QUIC connection fails.
+/// and fallback to relay if QUIC connection fails (as the connection's mode
+/// allows).
 async fn run_receive_with_signaling(
     save_dir: PathBuf,
-    code: &str,
-    server_url: &str,
+    connection: ReceiveConnection,
     progress_tx: mpsc::UnboundedSender<ProgressEvent>,
     accept_rx: oneshot::Receiver<bool>,
-    cancel: tokio_util::sync::CancellationToken,
+    session: &TransferSession,
+    options: ReceiveOptions,
 ) -> Result<(), crate::error::AppError> {
+    let code = &session.code.to_code_string();
+    let server_urls = &connection.signal_server_urls;
+    let mode = connection.mode;
+    let cancel = session.cancel_token.clone();
+    let discovery = session.discovery_token.clone();
+    let session_key = &session.key;
     progress_tx
         .send(ProgressEvent::StateChanged {
             state: "connecting".into(),
         })
         .ok();
 
-    // 1. Connect to signaling server
-    let mut signaling = SignalingClient::connect(server_url, code).await?;
-
-    // 2. Register as receiver
-    signaling.register("receiver", None).await?;
+    // 1. Connect to signaling server. Until it's handed off, every wait on
+    // the server also ends when discovery is cancelled.
+    let signaling = tokio::select! {
+        result = SignalingClient::connect_any(server_urls, code, tls::configured()) => result?,
+        _ = discovery.cancelled() => return Err(crate::error::AppError::Cancelled),
+    };
+    let mut signaling = signaling
+        .with_cancel(discovery)
+        .with_reconnect(ReconnectPolicy::default());
+
+    // 2. Register as receiver, with our QUIC address so the sender can dial
+    // us too. Relay-only needs no QUIC endpoint at all. Only local
+    // addresses are advertised, so don't wait on STUN here.
+    let network = NetworkOptions {
+        stun_server: None,
+        ..connection.network
+    };
+    let quic = if mode.tries_direct() {
+        Some(QuicEndpoint::with_options(0, &network).await?)
+    } else {
+        None
+    };
+    let listen_addr = quic.as_ref().map(QuicEndpoint::local_addr).transpose()?;
+    signaling.register("receiver", listen_addr).await?;
 
     // 3. Wait for sender to join
-    let peer_info = signaling.wait_for_peer().await?;
-    info!("receive: sender discovered via signaling");
-
-    // 4. SPAKE2 key exchange
-    let key_exchange = KeyExchange::new(code);
-    let outbound = key_exchange.outbound_message().to_vec();
-    let peer_spake2 = signaling.exchange_spake2(&outbound).await?;
-    let encryption_key = key_exchange.finish(&peer_spake2)?;
-    info!("receive: SPAKE2 key exchange complete");
-
-    // 5. Exchange cert fingerprints
-    let quic = QuicEndpoint::new(0).await?;
-    let _peer_fingerprint = signaling
-        .exchange_cert_fingerprint(&quic.cert_fingerprint(), &encryption_key)
+    let peer_info = signaling
+        .wait_for_peer(connection.peer_timeout.unwrap_or(DEFAULT_PEER_TIMEOUT))
         .await?;
-    info!("receive: cert fingerprint exchange complete");
-
-    // 6. Try QUIC connection to sender, fall back to relay on timeout/failure.
-    let peer_addr = resolve_peer_addr(&peer_info);
-
-    let mut transport = match peer_addr {
-        Ok(addr) => {
-            info!("receive: attempting QUIC connect to {addr} (timeout {}s)", RECEIVER_QUIC_TIMEOUT.as_secs());
-            match tokio::time::timeout(RECEIVER_QUIC_TIMEOUT, quic.connect(addr)).await {
-                Ok(Ok(conn)) => {
-                    info!("receive: direct QUIC connection established");
-                    signaling.disconnect().await.ok();
-
-                    progress_tx
-                        .send(ProgressEvent::ConnectionTypeChanged {
-                            connection_type: "direct".into(),
-                        })
-                        .ok();
-
-                    let (send, recv) = conn.accept_bi().await.map_err(|e| {
-                        crate::error::AppError::Network(format!("failed to accept stream: {e}"))
-                    })?;
-                    Transport::Direct { send, recv }
-                }
-                Ok(Err(e)) => {
-                    warn!("receive: QUIC connect failed: {e}, falling back to relay");
-                    activate_relay(signaling, &progress_tx).await?
-                }
-                Err(_) => {
-                    warn!("receive: QUIC connect timed out, falling back to relay");
-                    activate_relay(signaling, &progress_tx).await?
-                }
-            }
+    info!("receive: sender discovered via signaling");
+    session.set_state(TransferState::Exchanging).await;
+
+    // 4. SPAKE2 key exchange, unless resuming with an exported key, which
+    // is instead salted with fresh nonces from both sides. Peers that
+    // confirm the key also expand it into per-purpose keys.
+    let keys = match session_key.get() {
+        Some(key) => {
+            let nonce = keyschedule::resume_nonce()?;
+            let peer_nonce = signaling.exchange_resume_nonce(&nonce).await?;
+            info!("receive: resuming with a key derived from the imported one");
+            KeySchedule::resumed(&key, &peer_nonce, &nonce)?
         }
-        Err(e) => {
-            warn!("receive: no usable peer address ({e}), going direct to relay");
-            activate_relay(signaling, &progress_tx).await?
+        None => {
+            let key_exchange = KeyExchange::new(code);
+            let outbound = key_exchange.outbound_message().to_vec();
+            let peer_spake2 = signaling.exchange_spake2(&outbound).await?;
+            let key = key_exchange.finish(&peer_spake2)?;
+            let confirmed = signaling.confirm_key(&key).await?;
+            session_key.set(key);
+            info!("receive: SPAKE2 key exchange complete");
+            if confirmed {
+                KeySchedule::derive(&key)?
+            } else {
+                KeySchedule::legacy(key)
+            }
         }
     };
 
+    // 5-6. Exchange cert fingerprints, then connect directly or via the
+    // relay.
+    let mut transport = connect::receiver_transport(
+        signaling,
+        quic.as_ref(),
+        peer_info,
+        &keys.signaling,
+        mode,
+        session,
+        &progress_tx,
+    )
+    .await?;
+
     // 7. Run transfer over the established transport
     receiver::run_receive(
         save_dir,
         &mut transport,
-        encryption_key,
+        keys.sender_to_receiver,
         progress_tx,
         accept_rx,
         cancel,
+        options,
     )
     .await
 }
 
-/// Request relay mode from the signaling server, then convert the WebSocket
-/// into a relay transport.
-async fn activate_relay(
-    mut signaling: SignalingClient,
-    progress_tx: &mpsc::UnboundedSender<ProgressEvent>,
-) -> Result<Transport, crate::error::AppError> {
-    signaling.request_relay().await?;
-
-    progress_tx
-        .send(ProgressEvent::ConnectionTypeChanged {
-            connection_type: "relay".into(),
-        })
-        .ok();
+/// Reveals and opens received files through the opener plugin.
+struct DesktopOpener(AppHandle);
 
-    let ws = signaling.into_ws();
-    Ok(Transport::Relayed {
-        ws: RelayStream::new(ws),
-    })
+impl std::fmt::Debug for DesktopOpener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DesktopOpener")
+    }
 }
 
-/// Determine the best address to connect to the sender.
-/// Prefer local IP (LAN), fall back to public IP.
-fn resolve_peer_addr(peer_info: &PeerInfo) -> Result<SocketAddr, crate::error::AppError> {
-    use crate::error::AppError;
-
-    // Try local address first (same LAN)
-    if !peer_info.local_ip.is_empty() && peer_info.local_port > 0 {
-        if let Ok(addr) = format!("{}:{}", peer_info.local_ip, peer_info.local_port).parse() {
-            return Ok(addr);
-        }
+impl Opener for DesktopOpener {
+    fn reveal(&self, path: &Path) -> AppResult<()> {
+        self.0
+            .opener()
+            .reveal_item_in_dir(path)
+            .map_err(|e| AppError::Transfer(format!("cannot reveal {}: {e}", path.display())))
     }
 
-    // Fall back to public address
-    if !peer_info.public_ip.is_empty() && peer_info.public_port > 0 {
-        if let Ok(addr) = format!("{}:{}", peer_info.public_ip, peer_info.public_port).parse() {
-            return Ok(addr);
-        }
+    fn open(&self, path: &Path) -> AppResult<()> {
+        self.0
+            .opener()
+            .open_path(path.to_string_lossy(), None::<&str>)
+            .map_err(|e| AppError::Transfer(format!("cannot open {}: {e}", path.display())))
     }
+}
+
+/// Check that a save directory is usable before starting a receive.
+#[tauri::command]
+pub async fn check_save_dir(path: String) -> Result<(), String> {
+    receiver::check_save_dir(&PathBuf::from(path))
+        .await
+        .map_err(|e| e.to_string())
+}
 
-    Err(AppError::Network(
-        "no usable address for sender".into(),
-    ))
+/// Re-check the files an interrupted receive left on disk, using the
+/// session's journal. Tells the user which files are complete, partial, or
+/// corrupt, i.e. what needs receiving again.
+#[tauri::command]
+pub async fn verify_partial(
+    app: AppHandle,
+    session_token: String,
+) -> Result<Vec<PartialFileReport>, String> {
+    let path = app
+        .state::<JournalDir>()
+        .path_for(&session_token)
+        .map_err(|e| e.to_string())?;
+    let journal = ReceiveJournal::load(&path).await.map_err(|e| match e {
+        crate::error::AppError::Io(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            format!("no interrupted receive for session {session_token}")
+        }
+        e => e.to_string(),
+    })?;
+    journal.verify().await.map_err(|e| e.to_string())
 }
 
-/// Accept or decline an incoming file offer.
+/// Accept or decline an incoming file offer. `destinations` maps top-level
+/// offered items (a file's or folder's name) to the directory to save each
+/// in; the rest go to the save directory. `accepted_indices` accepts only
+/// the offered files at those indices.
 #[tauri::command]
 pub async fn accept_transfer(
     app: AppHandle,
     session_id: String,
     accept: bool,
+    destinations: Option<HashMap<String, String>>,
+    accepted_indices: Option<Vec<u16>>,
 ) -> Result<(), String> {
+    if accept && (destinations.is_some() || accepted_indices.is_some()) {
+        let store = app.state::<SessionStore>().inner().clone();
+        let sessions = store.lock().await;
+        let session = sessions
+            .get(&session_id)
+            .ok_or_else(|| format!("session not found: {session_id}"))?;
+        for (item, dir) in destinations.unwrap_or_default() {
+            session
+                .destinations
+                .set(item, dir)
+                .map_err(|e| e.to_string())?;
+        }
+        if let Some(indices) = accepted_indices {
+            session.selection.set(indices);
+        }
+    }
+
     let accept_store = app.state::<AcceptChannelStore>().inner().clone();
     let mut channels = accept_store.lock().await;
 
@@ -258,3 +464,21 @@ pub async fn accept_transfer(
         Err(format!("no pending accept for session {session_id}"))
     }
 }
+
+/// Move a receive staged for inspection into the save directory, or
+/// discard it. Valid once the `stagingReady` event has been emitted.
+#[tauri::command]
+pub async fn finalize_transfer(
+    app: AppHandle,
+    session_id: String,
+    accept: bool,
+) -> Result<(), String> {
+    let finalize_store = app.state::<FinalizeChannelStore>();
+    let mut channels = finalize_store.0.lock().await;
+
+    if let Some(tx) = channels.remove(&session_id) {
+        tx.send(accept).map_err(|_| "channel closed".to_string())
+    } else {
+        Err(format!("no pending finalize for session {session_id}"))
+    }
+}
@@ -0,0 +1,114 @@
+use std::path::PathBuf;
+
+use tauri::{AppHandle, Manager};
+use tracing::info;
+
+use crate::transfer::code::TransferCode;
+use crate::transfer::portable::{self, ResumeStateDir, ResumeTarget};
+use crate::transfer::receiver::ReceiveOptions;
+use crate::transfer::sender::SendOptions;
+use crate::transfer::session::{TransferRole, TransferSession};
+
+use super::receive::{begin_receive, prepare_save_dir, ReceiveConnection};
+use super::send::{
+    begin_send, HiddenFilter, SendConnection, SendInput, SymlinkPolicy, DEFAULT_MAX_DEPTH,
+};
+use super::transfer::SessionStore;
+
+/// Export what it takes to resume a transfer from another network, sealed
+/// under `passphrase`. Returns the path of the exported file.
+#[tauri::command]
+pub async fn export_resume_state(
+    app: AppHandle,
+    session_id: String,
+    passphrase: String,
+) -> Result<String, String> {
+    let store = app.state::<SessionStore>().inner().clone();
+    let session = store
+        .lock()
+        .await
+        .get(&session_id)
+        .cloned()
+        .ok_or_else(|| format!("session not found: {session_id}"))?;
+    let state = session.portable_state().ok_or_else(|| {
+        "this transfer can't be resumed: it has no key yet or nothing to pick up".to_string()
+    })?;
+
+    let path = app
+        .state::<ResumeStateDir>()
+        .export(&session_id, &state, &passphrase)
+        .await
+        .map_err(|e| e.to_string())?;
+    info!("exported resume state for transfer {session_id}");
+    Ok(path.display().to_string())
+}
+
+/// Resume a transfer exported with `export_resume_state`, meeting the peer
+/// (which must import its own state) on the signaling server again.
+/// Returns the new session's id.
+#[tauri::command]
+pub async fn import_resume_state(
+    app: AppHandle,
+    file: String,
+    passphrase: String,
+    signal_server_url: Option<String>,
+) -> Result<String, String> {
+    let state = portable::import(&PathBuf::from(file), &passphrase)
+        .await
+        .map_err(|e| e.to_string())?;
+    let code = TransferCode::parse(&state.code).map_err(|e| e.to_string())?;
+
+    match state.target {
+        ResumeTarget::Send { paths } => {
+            if let Some(missing) = paths.iter().find(|p| !p.exists()) {
+                return Err(format!("Path not found: {}", missing.display()));
+            }
+            let session = TransferSession::new(TransferRole::Sender, code)
+                .with_key(state.key)
+                .with_resume_target(ResumeTarget::Send {
+                    paths: paths.clone(),
+                });
+            let started = begin_send(
+                app,
+                session,
+                SendInput::Paths {
+                    paths,
+                    max_depth: DEFAULT_MAX_DEPTH,
+                    symlinks: SymlinkPolicy::default(),
+                    hidden: HiddenFilter::default(),
+                },
+                SendOptions::default(),
+                SendConnection {
+                    signal_server_urls: signal_server_url.into_iter().collect(),
+                    ..SendConnection::default()
+                },
+            )
+            .await?;
+            Ok(started.session_id)
+        }
+        ResumeTarget::Receive { save_dir } => {
+            let save_path = prepare_save_dir(save_dir).await?;
+            let session = TransferSession::new(TransferRole::Receiver, code)
+                .with_key(state.key)
+                .with_resume_target(ResumeTarget::Receive {
+                    save_dir: save_path.clone(),
+                });
+            let options = ReceiveOptions {
+                resume: true,
+                ..Default::default()
+            };
+            begin_receive(
+                app,
+                session,
+                save_path,
+                options,
+                false,
+                ReceiveConnection {
+                    signal_server_urls: signal_server_url.into_iter().collect(),
+                    ..ReceiveConnection::default()
+                },
+            )
+            .await
+        }
+    }
+}
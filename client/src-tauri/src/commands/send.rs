@@ -1,30 +1,86 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
 
 use tauri::{AppHandle, Emitter, Manager};
 use tokio::sync::mpsc;
 use tracing::{error, info, warn};
 
+use crate::crypto::keyschedule::{self, KeySchedule};
 use crate::crypto::spake::KeyExchange;
-use crate::network::quic::QuicEndpoint;
-use crate::network::relay::RelayStream;
-use crate::network::signaling::SignalingClient;
-use crate::network::transport::Transport;
+use crate::network::connect::{self, ConnectionMode};
+use crate::network::quic::{parse_fingerprint, CertStore, NetworkOptions, QuicEndpoint};
+use crate::network::signaling::{ReconnectPolicy, SignalingClient, DEFAULT_PEER_TIMEOUT};
+use crate::network::tls;
+use crate::protocol::chunker::FileSource;
 use crate::protocol::messages::FileInfo;
-use crate::transfer::code::TransferCode;
+use crate::protocol::pieces::PieceHashConfig;
+use crate::transfer::code::{redacted, TransferCode};
+use crate::transfer::history::HistoryLog;
+use crate::transfer::portable::ResumeTarget;
 use crate::transfer::progress::ProgressEvent;
-use crate::transfer::sender;
-use crate::transfer::session::{TransferRole, TransferSession};
+use crate::transfer::receiver::sanitize_link_target;
+use crate::transfer::sender::{self, SendOptions};
+use crate::transfer::session::{TransferRole, TransferSession, TransferState};
+use crate::transfer::usage::UsageStore;
 
 use super::transfer::SessionStore;
 
 const DEFAULT_SIGNAL_URL: &str = "ws://localhost:8080";
 
-/// Timeout for the sender waiting for a QUIC connection from the receiver.
-const SENDER_QUIC_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// How many directory levels below a selected folder are walked by default.
+/// Deeper directories are skipped, which also bounds relative path length.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
 
-/// Hidden files/directories to skip during folder expansion.
-const HIDDEN_ENTRIES: &[&str] = &[".DS_Store", ".git", "Thumbs.db", ".gitignore", "__MACOSX"];
+/// Junk files/directories folder expansion skips by default, even when
+/// sending hidden entries.
+const HIDDEN_ENTRIES: &[&str] = &[".DS_Store", ".git", "Thumbs.db", "__MACOSX"];
+
+/// Which entries folder expansion leaves out. A selected path is always
+/// sent, whatever its name; this only applies to what's inside folders.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HiddenFilter {
+    /// Send entries whose names start with a dot, like `.env` or
+    /// `.gitignore`. Names on the `ignore` list are left out regardless.
+    pub include_hidden: bool,
+    /// Names always left out. Defaults to [`HIDDEN_ENTRIES`].
+    pub ignore: Vec<String>,
+}
+
+impl Default for HiddenFilter {
+    fn default() -> Self {
+        Self {
+            include_hidden: false,
+            ignore: HIDDEN_ENTRIES.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+}
+
+impl HiddenFilter {
+    /// Whether an entry called `name` is left out.
+    fn skips(&self, name: &str) -> bool {
+        (!self.include_hidden && name.starts_with('.')) || self.ignore.iter().any(|n| n == name)
+    }
+}
+
+/// What folder expansion does with the symbolic links it finds. A selected
+/// path that is itself a link is always followed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SymlinkPolicy {
+    /// Leave them out.
+    #[default]
+    Skip,
+    /// Send what they point at in their place: a file's content, or a
+    /// folder's files. Links that lead back into a folder already walked
+    /// are left out.
+    FollowFiles,
+    /// Send them as links, for the receiver to recreate. Links that would
+    /// point outside the receiver's save directory are left out.
+    PreserveAsLink,
+}
 
 #[derive(serde::Serialize)]
 pub struct SendStarted {
@@ -33,72 +89,304 @@ pub struct SendStarted {
     pub port: u16,
 }
 
+/// What the frontend can ask of [`start_send`] besides the paths. Anything
+/// left out keeps its default.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct StartSendOptions {
+    pub signal_server_url: Option<String>,
+    /// Fallbacks tried in order after `signal_server_url`; the receiver
+    /// should be given the same servers in the same order.
+    pub signal_server_urls: Vec<String>,
+    pub dedupe: bool,
+    pub piece_hashes: Option<PieceHashConfig>,
+    pub challenge: bool,
+    pub network: NetworkOptions,
+    /// Defaults to [`DEFAULT_MAX_DEPTH`].
+    pub max_depth: Option<usize>,
+    pub code_ttl_secs: Option<u64>,
+    pub max_bytes_per_sec: Option<u64>,
+    /// Hex certificate fingerprints of the only receivers served.
+    pub peer_allowlist: Option<Vec<String>>,
+    pub pre_hash: bool,
+    pub batch_small_files: bool,
+    pub peer_timeout_secs: Option<u64>,
+    pub local_copy: bool,
+    pub connection_mode: ConnectionMode,
+    pub symlinks: SymlinkPolicy,
+    pub accept_timeout_secs: Option<u64>,
+    pub parallel_streams: usize,
+    pub hidden: HiddenFilter,
+    pub archive: bool,
+}
+
 /// Start a send operation: generate code, connect to signaling, exchange keys, transfer.
 #[tauri::command]
 pub async fn start_send(
     app: AppHandle,
     file_paths: Vec<String>,
-    signal_server_url: Option<String>,
+    options: Option<StartSendOptions>,
 ) -> Result<SendStarted, String> {
+    let request = options.unwrap_or_default();
+    let peer_allowlist = request
+        .peer_allowlist
+        .map(|fingerprints| {
+            fingerprints
+                .iter()
+                .map(|fp| parse_fingerprint(fp))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()
+        .map_err(|e| e.to_string())?;
     let input_paths: Vec<PathBuf> = file_paths.into_iter().map(PathBuf::from).collect();
+    check_paths_exist(&input_paths)?;
 
-    // Validate paths exist
-    for f in &input_paths {
-        if !f.exists() {
-            return Err(format!("Path not found: {}", f.display()));
-        }
+    let session = TransferSession::new(TransferRole::Sender, TransferCode::generate())
+        .with_resume_target(ResumeTarget::Send {
+            paths: input_paths.clone(),
+        });
+    let send_options = SendOptions {
+        dedupe: request.dedupe,
+        piece_hashes: request.piece_hashes,
+        challenge: request.challenge,
+        max_bytes_per_sec: request.max_bytes_per_sec,
+        pre_hash: request.pre_hash,
+        batch_small_files: request.batch_small_files,
+        local_copy: request.local_copy,
+        accept_timeout: request.accept_timeout_secs.map(Duration::from_secs),
+        parallel_streams: request.parallel_streams,
+        archive: request.archive,
+        ..Default::default()
+    };
+    let connection = SendConnection {
+        signal_server_urls: request
+            .signal_server_url
+            .into_iter()
+            .chain(request.signal_server_urls)
+            .collect(),
+        network: request.network,
+        mode: request.connection_mode,
+        code_ttl: request.code_ttl_secs.map(Duration::from_secs),
+        peer_timeout: request.peer_timeout_secs.map(Duration::from_secs),
+        peer_allowlist,
+    };
+    let input = SendInput::Paths {
+        paths: input_paths,
+        max_depth: request.max_depth.unwrap_or(DEFAULT_MAX_DEPTH),
+        symlinks: request.symlinks,
+        hidden: request.hidden,
+    };
+    begin_send(app, session, input, send_options, connection).await
+}
+
+/// What a send of some paths would transfer.
+#[derive(Debug, serde::Serialize)]
+pub struct SendPreview {
+    pub files: Vec<FileInfo>,
+    pub total_bytes: u64,
+    pub file_count: usize,
+    /// Folders that would be left out for being nested too deep.
+    pub skipped: Vec<String>,
+    /// Folders that would be recreated empty.
+    pub empty_dirs: Vec<String>,
+}
+
+/// List what [`start_send`] would transfer for `file_paths`, expanded the
+/// same way, without connecting anywhere. Fails naming the first path that
+/// is missing or can't be read.
+#[tauri::command]
+pub async fn preview_send(
+    file_paths: Vec<String>,
+    max_depth: Option<usize>,
+    symlinks: Option<SymlinkPolicy>,
+    hidden: Option<HiddenFilter>,
+) -> Result<SendPreview, String> {
+    let input_paths: Vec<PathBuf> = file_paths.into_iter().map(PathBuf::from).collect();
+    check_paths_exist(&input_paths)?;
+
+    let (_, files, skipped, empty_dirs) = expand_paths(
+        &input_paths,
+        max_depth.unwrap_or(DEFAULT_MAX_DEPTH),
+        symlinks.unwrap_or_default(),
+        &hidden.unwrap_or_default(),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    Ok(SendPreview {
+        total_bytes: files.iter().map(|f| f.size).sum(),
+        file_count: files.len(),
+        files,
+        skipped,
+        empty_dirs,
+    })
+}
+
+fn check_paths_exist(paths: &[PathBuf]) -> Result<(), String> {
+    match paths.iter().find(|path| !path.exists()) {
+        Some(missing) => Err(format!("Path not found: {}", missing.display())),
+        None => Ok(()),
+    }
+}
+
+/// Send a snippet of text (e.g. from the clipboard) as a single in-memory file.
+#[tauri::command]
+pub async fn send_text(
+    app: AppHandle,
+    text: String,
+    signal_server_url: Option<String>,
+) -> Result<SendStarted, String> {
+    if text.is_empty() {
+        return Err("Nothing to send: text is empty".into());
+    }
+    begin_send(
+        app,
+        TransferSession::new(TransferRole::Sender, TransferCode::generate()),
+        SendInput::Text(text),
+        SendOptions::default(),
+        SendConnection {
+            signal_server_urls: signal_server_url.into_iter().collect(),
+            ..SendConnection::default()
+        },
+    )
+    .await
+}
+
+/// What the user asked to send.
+pub(super) enum SendInput {
+    /// Files and folders on disk; folders are expanded once connected,
+    /// down to `max_depth` levels, treating links in them per `symlinks`
+    /// and leaving out what `hidden` says.
+    Paths {
+        paths: Vec<PathBuf>,
+        max_depth: usize,
+        symlinks: SymlinkPolicy,
+        hidden: HiddenFilter,
+    },
+    /// A text snippet sent as an in-memory file.
+    Text(String),
+}
+
+/// How a send finds and connects to its receiver.
+#[derive(Debug, Default)]
+pub(super) struct SendConnection {
+    /// Tried in order; empty means [`DEFAULT_SIGNAL_URL`].
+    pub signal_server_urls: Vec<String>,
+    pub network: NetworkOptions,
+    /// A relay-only mode sets up no QUIC endpoint.
+    pub mode: ConnectionMode,
+    /// Abandon the send if no peer joins within this time.
+    pub code_ttl: Option<Duration>,
+    /// Without a `code_ttl`, how long to wait for a peer; by default
+    /// [`DEFAULT_PEER_TIMEOUT`].
+    pub peer_timeout: Option<Duration>,
+    /// Serve only receivers with one of these certificate fingerprints.
+    pub peer_allowlist: Option<Vec<[u8; 32]>>,
+}
+
+/// Register `session` and spawn the send pipeline for `input`, unless the
+/// monthly usage quota is used up. The session's pause token and the usage
+/// store are attached to `options`. A relay-only connection reports port 0.
+pub(super) async fn begin_send(
+    app: AppHandle,
+    session: TransferSession,
+    input: SendInput,
+    options: SendOptions,
+    mut connection: SendConnection,
+) -> Result<SendStarted, String> {
+    let mode = connection.mode;
+    if connection.peer_allowlist.is_some() && !mode.tries_direct() {
+        return Err(
+            "A peer allowlist needs the certificate exchange, which relay-only mode skips".into(),
+        );
     }
+    let code_str = session.code.to_code_string();
+    info!("send: using code '{}'", redacted(&code_str));
+    #[cfg(debug_assertions)]
+    tracing::trace!("send: full code '{code_str}'");
 
-    let code = TransferCode::generate();
-    let code_str = code.to_code_string();
-    info!("send: generated code '{code_str}'");
+    let usage = app.state::<UsageStore>().inner().clone();
+    usage.check_quota().await.map_err(|e| e.to_string())?;
 
-    let session = TransferSession::new(TransferRole::Sender, code);
     let session_id = session.id.clone();
-    let cancel_token = session.cancel_token.clone();
+    let options = SendOptions {
+        pause: session.pause_token.clone(),
+        usage: Some(usage),
+        history: Some(app.state::<HistoryLog>().inner().clone()),
+        code: Some(code_str.clone()),
+        #[cfg(feature = "metrics")]
+        metrics: Some(crate::transfer::metrics::Metrics::global()),
+        ..options
+    };
 
     // Store session
     let store = app.state::<SessionStore>().inner().clone();
-    store.lock().await.insert(session_id.clone(), Arc::new(session));
+    let session = Arc::new(session);
+    store
+        .lock()
+        .await
+        .insert(session_id.clone(), session.clone());
 
-    // Set up QUIC endpoint (OS-assigned port)
-    let quic = QuicEndpoint::new(0).await.map_err(|e| e.to_string())?;
-    let port = quic.local_addr().map_err(|e| e.to_string())?.port();
-    let local_addr = quic.local_addr().map_err(|e| e.to_string())?;
+    // Set up QUIC endpoint (OS-assigned port) with this device's persisted
+    // certificate, unless only relaying
+    connection.network.identity = app.state::<CertStore>().inner().clone();
+    let quic = if mode.tries_direct() {
+        let mut quic = QuicEndpoint::with_options(0, &connection.network)
+            .await
+            .map_err(|e| e.to_string())?;
+        if let Some(allowlist) = connection.peer_allowlist.take() {
+            quic = quic.with_peer_allowlist(allowlist);
+        }
+        Some(quic)
+    } else {
+        None
+    };
+    let port = match &quic {
+        Some(quic) => quic.local_addr().map_err(|e| e.to_string())?.port(),
+        None => 0,
+    };
 
     let (progress_tx, mut progress_rx) = mpsc::unbounded_channel::<ProgressEvent>();
     let app_handle = app.clone();
 
     // Forward progress events to frontend
+    let observed = session.clone();
     tokio::spawn(async move {
         while let Some(event) = progress_rx.recv().await {
+            observed.observe(&event).await;
             if let Err(e) = app_handle.emit("transfer:progress", &event) {
                 error!("failed to emit progress event: {e}");
             }
         }
     });
 
-    let server_url = signal_server_url.unwrap_or_else(|| DEFAULT_SIGNAL_URL.into());
-    let code_clone = code_str.clone();
+    if connection.signal_server_urls.is_empty() {
+        connection.signal_server_urls = vec![DEFAULT_SIGNAL_URL.into()];
+    }
 
     // Run the send pipeline in background
     let app_handle2 = app.clone();
     tokio::spawn(async move {
         let result = run_send_with_signaling(
-            input_paths,
+            input,
             quic,
-            local_addr,
-            &code_clone,
-            &server_url,
+            connection,
             progress_tx.clone(),
-            cancel_token,
+            &session,
+            options,
         )
         .await;
+        session.finish(&result).await;
 
         match result {
             Ok(()) => {
                 info!("send pipeline completed successfully");
             }
+            Err(crate::error::AppError::SessionExpired) => {
+                info!("send: code expired before a peer joined");
+            }
+            Err(crate::error::AppError::CodeInUse) => {
+                info!("send: code already in use, a fresh one is needed");
+            }
             Err(e) => {
                 error!("send pipeline failed: {e}");
                 app_handle2
@@ -120,159 +408,199 @@ pub async fn start_send(
     })
 }
 
-/// What happened during the QUIC/relay race.
-enum RaceOutcome {
-    /// Direct QUIC connection succeeded.
-    QuicConnected(quinn::Connection),
-    /// QUIC failed or peer requested relay — need to fall back.
-    FallbackToRelay,
-}
-
 /// Full send flow with signaling server for peer discovery, SPAKE2 key exchange,
-/// and fallback to relay if QUIC fails.
+/// and fallback to relay if QUIC fails (as the connection's mode allows).
 async fn run_send_with_signaling(
-    input_paths: Vec<PathBuf>,
-    quic: QuicEndpoint,
-    local_addr: std::net::SocketAddr,
-    code: &str,
-    server_url: &str,
+    input: SendInput,
+    quic: Option<QuicEndpoint>,
+    connection: SendConnection,
     progress_tx: mpsc::UnboundedSender<ProgressEvent>,
-    cancel: tokio_util::sync::CancellationToken,
+    session: &TransferSession,
+    options: SendOptions,
 ) -> Result<(), crate::error::AppError> {
+    let code = &session.code.to_code_string();
+    let server_urls = &connection.signal_server_urls;
+    let mode = connection.mode;
+    let cancel = session.cancel_token.clone();
+    let discovery = session.discovery_token.clone();
+    let session_key = &session.key;
+    let expires_at = connection
+        .code_ttl
+        .map(|ttl| tokio::time::Instant::now() + ttl);
     progress_tx
         .send(ProgressEvent::StateChanged {
             state: "connecting".into(),
         })
         .ok();
 
-    // 1. Connect to signaling server
-    let mut signaling = SignalingClient::connect(server_url, code).await?;
+    // 1. Connect to signaling server. Until it's handed off, every wait on
+    // the server also ends when discovery is cancelled.
+    let signaling = tokio::select! {
+        result = SignalingClient::connect_any(server_urls, code, tls::configured()) => result?,
+        _ = discovery.cancelled() => return Err(crate::error::AppError::Cancelled),
+    };
+    let mut signaling = signaling
+        .with_cancel(discovery)
+        .with_reconnect(ReconnectPolicy::default())
+        .with_public_addr(quic.as_ref().and_then(QuicEndpoint::public_addr));
 
-    // 2. Register as sender with our QUIC listen address
-    signaling.register("sender", Some(local_addr)).await?;
+    // 2. Register as sender with our QUIC listen address, if any
+    let listen_addr = quic.as_ref().map(QuicEndpoint::local_addr).transpose()?;
+    signaling.register("sender", listen_addr).await?;
 
-    // 3. Wait for receiver to join
-    let _peer_info = signaling.wait_for_peer().await?;
-    info!("send: peer discovered via signaling server");
-
-    // 4. SPAKE2 key exchange
-    let key_exchange = KeyExchange::new(code);
-    let outbound = key_exchange.outbound_message().to_vec();
-    let peer_spake2 = signaling.exchange_spake2(&outbound).await?;
-    let encryption_key = key_exchange.finish(&peer_spake2)?;
-    info!("send: SPAKE2 key exchange complete");
-
-    // 5. Exchange cert fingerprints (encrypted with SPAKE2 key)
-    let _peer_fingerprint = signaling
-        .exchange_cert_fingerprint(&quic.cert_fingerprint(), &encryption_key)
-        .await?;
-    info!("send: cert fingerprint exchange complete");
-
-    // 6. Race: wait for QUIC connection from receiver OR a relay request.
-    info!(
-        "send: waiting for QUIC connection (timeout {}s) or relay request",
-        SENDER_QUIC_TIMEOUT.as_secs()
-    );
-
-    let race_outcome: RaceOutcome = tokio::select! {
-        result = async {
-            tokio::time::timeout(SENDER_QUIC_TIMEOUT, quic.accept_any()).await
-        } => {
-            match result {
-                Ok(Ok(conn)) => {
-                    info!("send: direct QUIC connection established");
-                    RaceOutcome::QuicConnected(conn)
-                }
-                Ok(Err(e)) => {
-                    warn!("send: QUIC accept failed: {e}, falling back to relay");
-                    RaceOutcome::FallbackToRelay
-                }
-                Err(_) => {
-                    warn!("send: QUIC accept timed out, falling back to relay");
-                    RaceOutcome::FallbackToRelay
-                }
-            }
+    // 3. Wait for receiver to join, until the code expires
+    let peer = match expires_at {
+        Some(deadline) => signaling.wait_for_peer_until(deadline).await,
+        None => {
+            signaling
+                .wait_for_peer(connection.peer_timeout.unwrap_or(DEFAULT_PEER_TIMEOUT))
+                .await
         }
-
-        result = signaling.check_for_relay_request() => {
-            match result {
-                Ok(true) => {
-                    info!("send: peer requested relay");
-                    RaceOutcome::FallbackToRelay
-                }
-                Ok(false) | Err(_) => {
-                    warn!("send: signaling message during QUIC wait, falling back to relay");
-                    RaceOutcome::FallbackToRelay
-                }
-            }
+    };
+    match peer {
+        Err(crate::error::AppError::SessionExpired) => {
+            progress_tx.send(ProgressEvent::Expired).ok();
         }
-
-        _ = cancel.cancelled() => {
-            signaling.disconnect().await.ok();
-            return Err(crate::error::AppError::Cancelled);
+        Err(crate::error::AppError::CodeInUse) => {
+            progress_tx.send(ProgressEvent::CodeInUse).ok();
         }
-    };
+        _ => {}
+    }
+    let _peer_info = peer?;
+    info!("send: peer discovered via signaling server");
+    session.set_state(TransferState::Exchanging).await;
 
-    // 7. Build transport based on race outcome.
-    let mut transport = match race_outcome {
-        RaceOutcome::QuicConnected(conn) => {
-            // Direct connection — disconnect signaling, we don't need it anymore.
-            signaling.disconnect().await.ok();
-
-            progress_tx
-                .send(ProgressEvent::ConnectionTypeChanged {
-                    connection_type: "direct".into(),
-                })
-                .ok();
-
-            let (send, recv) = conn.open_bi().await.map_err(|e| {
-                crate::error::AppError::Network(format!("failed to open stream: {e}"))
-            })?;
-            Transport::Direct { send, recv }
+    // 4. SPAKE2 key exchange, unless resuming with an exported key, which
+    // is instead salted with fresh nonces from both sides. Peers that
+    // confirm the key also expand it into per-purpose keys.
+    let keys = match session_key.get() {
+        Some(key) => {
+            let nonce = keyschedule::resume_nonce()?;
+            let peer_nonce = signaling.exchange_resume_nonce(&nonce).await?;
+            info!("send: resuming with a key derived from the imported one");
+            KeySchedule::resumed(&key, &nonce, &peer_nonce)?
         }
-        RaceOutcome::FallbackToRelay => {
-            // Request relay, then hand off the WebSocket for data transfer.
-            signaling.request_relay().await?;
-
-            progress_tx
-                .send(ProgressEvent::ConnectionTypeChanged {
-                    connection_type: "relay".into(),
-                })
-                .ok();
-
-            let ws = signaling.into_ws();
-            Transport::Relayed {
-                ws: RelayStream::new(ws),
+        None => {
+            let key_exchange = KeyExchange::new(code);
+            let outbound = key_exchange.outbound_message().to_vec();
+            let peer_spake2 = signaling.exchange_spake2(&outbound).await?;
+            let key = key_exchange.finish(&peer_spake2)?;
+            let confirmed = signaling.confirm_key(&key).await?;
+            session_key.set(key);
+            info!("send: SPAKE2 key exchange complete");
+            if confirmed {
+                KeySchedule::derive(&key)?
+            } else {
+                KeySchedule::legacy(key)
             }
         }
     };
 
+    // 5-7. Exchange cert fingerprints and settle on direct QUIC or the relay
+    let mut transport = connect::sender_transport(
+        signaling,
+        quic.as_ref(),
+        &keys.signaling,
+        mode,
+        session,
+        &progress_tx,
+    )
+    .await?;
+
     // Expand directories into individual files
-    let (files, file_infos) = expand_paths(&input_paths).await?;
+    let (files, file_infos, empty_dirs) = match input {
+        SendInput::Paths {
+            paths,
+            max_depth,
+            symlinks,
+            hidden,
+        } => {
+            let (files, infos, skipped, empty_dirs) =
+                expand_paths(&paths, max_depth, symlinks, &hidden).await?;
+            for path in skipped {
+                warn!("send: skipping '{path}': nested deeper than {max_depth} levels");
+                progress_tx
+                    .send(ProgressEvent::FileSkipped {
+                        path,
+                        reason: format!("nested deeper than {max_depth} levels"),
+                    })
+                    .ok();
+            }
+            (files, infos, empty_dirs)
+        }
+        SendInput::Text(text) => {
+            let (source, info) = sender::text_file(text);
+            (vec![source], vec![info], Vec::new())
+        }
+    };
 
     // 8. Run transfer over the established transport
-    sender::run_send(files, file_infos, &mut transport, encryption_key, progress_tx, cancel).await
+    sender::run_send(
+        files,
+        file_infos,
+        &mut transport,
+        keys.sender_to_receiver,
+        progress_tx,
+        cancel,
+        SendOptions {
+            empty_dirs,
+            ..options
+        },
+    )
+    .await
 }
 
 /// Expand input paths: directories become their recursive file listing,
-/// plain files pass through as-is.
+/// plain files pass through as-is, dotfiles included. Also returns the
+/// relative paths of directories skipped for exceeding `max_depth`, and of
+/// empty ones.
 async fn expand_paths(
     input_paths: &[PathBuf],
-) -> Result<(Vec<PathBuf>, Vec<FileInfo>), crate::error::AppError> {
+    max_depth: usize,
+    symlinks: SymlinkPolicy,
+    hidden: &HiddenFilter,
+) -> Result<(Vec<FileSource>, Vec<FileInfo>, Vec<String>, Vec<String>), crate::error::AppError> {
     let mut files = Vec::new();
     let mut infos = Vec::new();
+    let mut skipped = Vec::new();
+    let mut empty_dirs = Vec::new();
 
     for path in input_paths {
-        let meta = tokio::fs::metadata(path).await?;
+        let meta = tokio::fs::metadata(path).await.map_err(at_path(path))?;
         if meta.is_dir() {
             let dir_name = path
                 .file_name()
                 .map(|n| n.to_string_lossy().to_string())
                 .unwrap_or_else(|| "folder".into());
 
-            let expanded = expand_directory(path, &dir_name).await?;
+            let (expanded, too_deep, empty, links) =
+                expand_directory(path, &dir_name, max_depth, symlinks, hidden).await?;
+            skipped.extend(too_deep);
+            empty_dirs.extend(empty);
+            for (relative_path, target) in links {
+                let name = relative_path
+                    .rsplit('/')
+                    .next()
+                    .unwrap_or(&relative_path)
+                    .to_string();
+                infos.push(FileInfo {
+                    name,
+                    size: 0,
+                    relative_path: Some(relative_path),
+                    duplicates: Vec::new(),
+                    mime_hint: None,
+                    streaming: false,
+                    modified: None,
+                    mode: None,
+                    checksum: None,
+                    link_target: Some(target),
+                });
+                files.push(FileSource::Memory(Vec::new()));
+            }
             for (file_path, relative_path) in expanded {
-                let file_meta = tokio::fs::metadata(&file_path).await?;
+                let file_meta = tokio::fs::metadata(&file_path)
+                    .await
+                    .map_err(at_path(&file_path))?;
                 let name = file_path
                     .file_name()
                     .map(|n| n.to_string_lossy().to_string())
@@ -282,8 +610,15 @@ async fn expand_paths(
                     name,
                     size: file_meta.len(),
                     relative_path: Some(relative_path),
+                    duplicates: Vec::new(),
+                    mime_hint: None,
+                    streaming: false,
+                    modified: modified_secs(&file_meta),
+                    mode: unix_mode(&file_meta),
+                    checksum: None,
+                    link_target: None,
                 });
-                files.push(file_path);
+                files.push(FileSource::Path(file_path));
             }
         } else {
             let name = path
@@ -294,48 +629,170 @@ async fn expand_paths(
                 name,
                 size: meta.len(),
                 relative_path: None,
+                duplicates: Vec::new(),
+                mime_hint: None,
+                streaming: false,
+                modified: modified_secs(&meta),
+                mode: unix_mode(&meta),
+                checksum: None,
+                link_target: None,
             });
-            files.push(path.clone());
+            files.push(FileSource::Path(path.clone()));
         }
     }
 
-    Ok((files, infos))
+    Ok((files, infos, skipped, empty_dirs))
+}
+
+/// Name `path` in an I/O error, so a failed expansion says which entry it
+/// was about.
+fn at_path(path: &Path) -> impl FnOnce(std::io::Error) -> std::io::Error + '_ {
+    move |e| std::io::Error::new(e.kind(), format!("{}: {e}", path.display()))
+}
+
+/// A file's modification time in unix seconds, if the platform reports one.
+fn modified_secs(meta: &std::fs::Metadata) -> Option<u64> {
+    let modified = meta.modified().ok()?;
+    modified
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs())
+}
+
+/// Permission bits on Unix, for the receiver to reapply; `None` elsewhere.
+fn unix_mode(meta: &std::fs::Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Some(meta.permissions().mode() & 0o777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = meta;
+        None
+    }
 }
 
 /// Recursively walk a directory, returning (absolute_path, relative_path) pairs.
-/// Skips hidden files and common junk files.
+/// Skips the hidden and junk entries `hidden` says to.
+///
+/// Directories more than `max_depth` levels below `dir` are not entered;
+/// their relative paths are returned separately so the caller can report them.
+/// So are directories with nothing left in them once hidden entries are
+/// skipped, which the receiver recreates as empty folders. Symbolic links
+/// are treated per `symlinks`; those kept as links come last, as
+/// (relative_path, target) pairs.
 pub async fn expand_directory(
-    dir: &PathBuf,
+    dir: &Path,
     prefix: &str,
-) -> Result<Vec<(PathBuf, String)>, crate::error::AppError> {
+    max_depth: usize,
+    symlinks: SymlinkPolicy,
+    hidden: &HiddenFilter,
+) -> Result<ExpandedDirectory, crate::error::AppError> {
     let mut result = Vec::new();
-    let mut stack: Vec<(PathBuf, String)> = vec![(dir.clone(), prefix.to_string())];
+    let mut skipped = Vec::new();
+    let mut empty = Vec::new();
+    let mut links = Vec::new();
+    let mut stack: Vec<(PathBuf, String, usize)> = vec![(dir.to_path_buf(), prefix.to_string(), 0)];
+    // Every folder walked, by its real path, so following a link back into
+    // one can't go round forever.
+    let mut walked = HashSet::new();
+    if symlinks == SymlinkPolicy::FollowFiles {
+        walked.insert(tokio::fs::canonicalize(dir).await.map_err(at_path(dir))?);
+    }
 
-    while let Some((current_dir, current_prefix)) = stack.pop() {
-        let mut entries = tokio::fs::read_dir(&current_dir).await?;
-        while let Some(entry) = entries.next_entry().await? {
+    while let Some((current_dir, current_prefix, depth)) = stack.pop() {
+        let mut entries = tokio::fs::read_dir(&current_dir)
+            .await
+            .map_err(at_path(&current_dir))?;
+        let mut has_entries = false;
+        while let Some(entry) = entries.next_entry().await.map_err(at_path(&current_dir))? {
             let name = entry.file_name().to_string_lossy().to_string();
 
             // Skip hidden files and known junk
-            if name.starts_with('.') || HIDDEN_ENTRIES.contains(&name.as_str()) {
+            if hidden.skips(&name) {
                 continue;
             }
 
             let path = entry.path();
             let relative = format!("{current_prefix}/{name}");
 
-            let file_type = entry.file_type().await?;
+            let mut file_type = entry.file_type().await.map_err(at_path(&path))?;
+            if file_type.is_symlink() {
+                match symlinks {
+                    SymlinkPolicy::Skip => continue,
+                    SymlinkPolicy::FollowFiles => {
+                        // Dangling and circular links have nothing to follow.
+                        let Ok(meta) = tokio::fs::metadata(&path).await else {
+                            warn!("send: skipping '{relative}': link leads nowhere");
+                            continue;
+                        };
+                        if meta.is_dir() {
+                            let real = tokio::fs::canonicalize(&path)
+                                .await
+                                .map_err(at_path(&path))?;
+                            if !walked.insert(real) {
+                                warn!(
+                                    "send: skipping '{relative}': links to a folder already sent"
+                                );
+                                continue;
+                            }
+                        }
+                        file_type = meta.file_type();
+                    }
+                    SymlinkPolicy::PreserveAsLink => {
+                        let target = tokio::fs::read_link(&path)
+                            .await
+                            .map_err(at_path(&path))?
+                            .to_string_lossy()
+                            .into_owned();
+                        if let Err(e) = sanitize_link_target(&relative, &target) {
+                            warn!("send: skipping '{relative}': {e}");
+                            continue;
+                        }
+                        has_entries = true;
+                        links.push((relative, target));
+                        continue;
+                    }
+                }
+            } else if file_type.is_dir() && symlinks == SymlinkPolicy::FollowFiles {
+                walked.insert(
+                    tokio::fs::canonicalize(&path)
+                        .await
+                        .map_err(at_path(&path))?,
+                );
+            }
+
             if file_type.is_dir() {
-                stack.push((path, relative));
+                has_entries = true;
+                if depth < max_depth {
+                    stack.push((path, relative, depth + 1));
+                } else {
+                    skipped.push(relative);
+                }
             } else if file_type.is_file() {
+                has_entries = true;
                 result.push((path, relative));
             }
         }
+        if !has_entries {
+            empty.push(current_prefix);
+        }
     }
 
-    Ok(result)
+    Ok((result, skipped, empty, links))
 }
 
+/// What [`expand_directory`] finds: files as (absolute_path, relative_path)
+/// pairs, folders too deep to enter, empty folders, and links kept as
+/// (relative_path, target) pairs.
+pub type ExpandedDirectory = (
+    Vec<(PathBuf, String)>,
+    Vec<String>,
+    Vec<String>,
+    Vec<(String, String)>,
+);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -353,9 +810,11 @@ mod tests {
         std::fs::create_dir_all(root.join(".git")).unwrap();
         std::fs::write(root.join(".git/config"), "git config").unwrap();
 
-        let result = expand_directory(&root.to_path_buf(), "test-folder")
+        let (result, skipped, empty, _) = expand_directory(root, "test-folder", DEFAULT_MAX_DEPTH, SymlinkPolicy::Skip, &HiddenFilter::default())
             .await
             .unwrap();
+        assert!(skipped.is_empty());
+        assert!(empty.is_empty());
 
         // Should have readme.txt and docs/guide.md, NOT .DS_Store or .git/*
         assert_eq!(result.len(), 2);
@@ -364,4 +823,224 @@ mod tests {
         assert!(rel_paths.contains(&"test-folder/readme.txt"));
         assert!(rel_paths.contains(&"test-folder/docs/guide.md"));
     }
+
+    #[tokio::test]
+    async fn test_expand_directory_includes_hidden_on_request() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        std::fs::create_dir_all(root.join(".config")).unwrap();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::create_dir_all(root.join("node_modules")).unwrap();
+        std::fs::write(root.join("app.py"), "print()").unwrap();
+        std::fs::write(root.join(".env"), "KEY=1").unwrap();
+        std::fs::write(root.join(".gitignore"), "target/").unwrap();
+        std::fs::write(root.join(".config/settings.toml"), "x = 1").unwrap();
+        std::fs::write(root.join(".git/HEAD"), "ref: main").unwrap();
+        std::fs::write(root.join(".DS_Store"), "junk").unwrap();
+        std::fs::write(root.join("node_modules/dep.js"), "dep").unwrap();
+
+        let expand = |hidden: HiddenFilter| async move {
+            let (result, _, _, _) =
+                expand_directory(root, "app", DEFAULT_MAX_DEPTH, SymlinkPolicy::Skip, &hidden)
+                    .await
+                    .unwrap();
+            let mut rel_paths: Vec<String> = result.into_iter().map(|(_, r)| r).collect();
+            rel_paths.sort();
+            rel_paths
+        };
+
+        assert_eq!(
+            expand(HiddenFilter::default()).await,
+            ["app/app.py", "app/node_modules/dep.js"]
+        );
+        // Dotfiles come along, the junk still doesn't.
+        let with_hidden = HiddenFilter {
+            include_hidden: true,
+            ..HiddenFilter::default()
+        };
+        assert_eq!(
+            expand(with_hidden).await,
+            [
+                "app/.config/settings.toml",
+                "app/.env",
+                "app/.gitignore",
+                "app/app.py",
+                "app/node_modules/dep.js"
+            ]
+        );
+        // An ignore list of its own replaces the junk defaults.
+        let custom = HiddenFilter {
+            include_hidden: true,
+            ignore: vec!["node_modules".into(), ".env".into()],
+        };
+        assert_eq!(
+            expand(custom).await,
+            [
+                "app/.DS_Store",
+                "app/.config/settings.toml",
+                "app/.git/HEAD",
+                "app/.gitignore",
+                "app/app.py"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_selected_dotfiles_always_sent() {
+        let temp = tempfile::tempdir().unwrap();
+        let env = temp.path().join(".env");
+        std::fs::write(&env, "KEY=1").unwrap();
+        let store = temp.path().join(".DS_Store");
+        std::fs::write(&store, "junk").unwrap();
+        let dotfiles = temp.path().join(".dotfiles");
+        std::fs::create_dir_all(&dotfiles).unwrap();
+        std::fs::write(dotfiles.join("vimrc"), "set nu").unwrap();
+        std::fs::write(dotfiles.join(".bashrc"), "alias l=ls").unwrap();
+
+        let (files, infos, _, _) = expand_paths(
+            &[env, store, dotfiles],
+            DEFAULT_MAX_DEPTH,
+            SymlinkPolicy::Skip,
+            &HiddenFilter::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(files.len(), 3);
+        let sent: Vec<_> = infos
+            .iter()
+            .map(|info| info.relative_path.as_deref().unwrap_or(&info.name))
+            .collect();
+        // Chosen by name, so sent; inside a folder, the filter still applies.
+        assert_eq!(sent, [".env", ".DS_Store", ".dotfiles/vimrc"]);
+    }
+
+    #[tokio::test]
+    async fn test_expand_directory_enforces_max_depth() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+
+        // root/top.txt, root/a/1.txt, ..., root/a/b/c/d/e/5.txt
+        std::fs::write(root.join("top.txt"), "0").unwrap();
+        let mut dir = root.to_path_buf();
+        for (level, name) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            dir = dir.join(name);
+            std::fs::create_dir(&dir).unwrap();
+            std::fs::write(dir.join(format!("{}.txt", level + 1)), "x").unwrap();
+        }
+
+        let (result, skipped, _, _) = expand_directory(root, "deep", 2, SymlinkPolicy::Skip, &HiddenFilter::default())
+            .await
+            .unwrap();
+
+        let mut rel_paths: Vec<&str> = result.iter().map(|(_, r)| r.as_str()).collect();
+        rel_paths.sort();
+        assert_eq!(
+            rel_paths,
+            ["deep/a/1.txt", "deep/a/b/2.txt", "deep/top.txt"]
+        );
+        assert_eq!(skipped, ["deep/a/b/c"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_expand_directory_symlink_policies() {
+        use std::os::unix::fs::symlink;
+
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("real.txt"), "real").unwrap();
+        std::fs::write(root.join("sub/inner.txt"), "inner").unwrap();
+        let elsewhere = temp.path().join("elsewhere");
+        std::fs::create_dir(&elsewhere).unwrap();
+        std::fs::write(elsewhere.join("far.txt"), "far").unwrap();
+
+        symlink("real.txt", root.join("to-file")).unwrap();
+        symlink(&elsewhere, root.join("to-dir")).unwrap();
+        symlink("..", root.join("sub/up")).unwrap();
+        symlink("missing", root.join("dangling")).unwrap();
+        symlink("ping", root.join("pong")).unwrap();
+        symlink("pong", root.join("ping")).unwrap();
+        symlink("../../outside", root.join("escape")).unwrap();
+
+        let expand = |policy| {
+            let root = root.clone();
+            async move {
+                let expanded = tokio::time::timeout(
+                    Duration::from_secs(10),
+                    expand_directory(
+                        &root,
+                        "r",
+                        DEFAULT_MAX_DEPTH,
+                        policy,
+                        &HiddenFilter::default(),
+                    ),
+                )
+                .await
+                .expect("expansion went round in circles")
+                .unwrap();
+                let mut files: Vec<String> = expanded.0.into_iter().map(|(_, rel)| rel).collect();
+                files.sort();
+                let mut links = expanded.3;
+                links.sort();
+                (files, links)
+            }
+        };
+
+        let (files, links) = expand(SymlinkPolicy::Skip).await;
+        assert_eq!(files, ["r/real.txt", "r/sub/inner.txt"]);
+        assert!(links.is_empty());
+
+        // Followed: the linked file and folder, but not the way back up,
+        // and not links that lead nowhere.
+        let (files, links) = expand(SymlinkPolicy::FollowFiles).await;
+        assert_eq!(
+            files,
+            [
+                "r/real.txt",
+                "r/sub/inner.txt",
+                "r/to-dir/far.txt",
+                "r/to-file"
+            ]
+        );
+        assert!(links.is_empty());
+
+        // Kept as links, except those that would leave the save directory.
+        let (files, links) = expand(SymlinkPolicy::PreserveAsLink).await;
+        assert_eq!(files, ["r/real.txt", "r/sub/inner.txt"]);
+        let link = |rel: &str, target: &str| (rel.to_string(), target.to_string());
+        assert_eq!(
+            links,
+            [
+                link("r/dangling", "missing"),
+                link("r/ping", "pong"),
+                link("r/pong", "ping"),
+                link("r/sub/up", ".."),
+                link("r/to-file", "real.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_start_send_options_from_frontend() {
+        let options: StartSendOptions = serde_json::from_value(serde_json::json!({
+            "signalServerUrl": "wss://relay.example",
+            "maxBytesPerSec": 1_000_000,
+            "connectionMode": "relay_only",
+            "hidden": { "includeHidden": true },
+        }))
+        .unwrap();
+        assert_eq!(
+            options.signal_server_url.as_deref(),
+            Some("wss://relay.example")
+        );
+        assert_eq!(options.max_bytes_per_sec, Some(1_000_000));
+        assert_eq!(options.connection_mode, ConnectionMode::RelayOnly);
+        assert!(options.hidden.include_hidden);
+        // Left out: defaults, as if no options were passed.
+        assert!(!options.dedupe);
+        assert_eq!(options.max_depth, None);
+        assert_eq!(options.symlinks, SymlinkPolicy::Skip);
+        assert_eq!(options.hidden.ignore, HiddenFilter::default().ignore);
+    }
 }
@@ -1,11 +1,17 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use tauri::{AppHandle, Manager};
+use tauri::{AppHandle, Emitter, Manager};
 use tokio::sync::{oneshot, Mutex};
 use tracing::info;
 
-use crate::transfer::session::TransferSession;
+use crate::network::interfaces::{self, InterfaceInfo};
+use crate::network::quic;
+use crate::network::tls::{self, SignalingTls};
+use crate::transfer::history::{HistoryEntry, HistoryLog};
+use crate::transfer::progress::ProgressEvent;
+use crate::transfer::session::{PauseReason, TransferSession, TransferState};
+use crate::transfer::usage::{UsageStats, UsageStore};
 
 /// Type alias for the shared session store.
 pub type SessionStore = Arc<Mutex<HashMap<String, Arc<TransferSession>>>>;
@@ -13,6 +19,10 @@ pub type SessionStore = Arc<Mutex<HashMap<String, Arc<TransferSession>>>>;
 /// Type alias for pending accept/decline channels.
 pub type AcceptChannelStore = Arc<Mutex<HashMap<String, oneshot::Sender<bool>>>>;
 
+/// Pending finalize/discard channels for receives staged for inspection.
+#[derive(Default)]
+pub struct FinalizeChannelStore(pub Mutex<HashMap<String, oneshot::Sender<bool>>>);
+
 /// Create the default stores to be managed by Tauri.
 pub fn create_stores() -> (SessionStore, AcceptChannelStore) {
     (
@@ -35,3 +45,187 @@ pub async fn cancel_transfer(app: AppHandle, session_id: String) -> Result<(), S
         Err(format!("session not found: {session_id}"))
     }
 }
+
+/// Cancel a transfer that is still waiting for or handshaking with its peer.
+/// Once the peers are connected this does nothing; use `cancel_transfer`.
+#[tauri::command]
+pub async fn cancel_discovery(app: AppHandle, session_id: String) -> Result<(), String> {
+    let store = app.state::<SessionStore>().inner().clone();
+    let sessions = store.lock().await;
+
+    if let Some(session) = sessions.get(&session_id) {
+        info!("cancelling discovery for transfer {session_id}");
+        session.cancel_discovery();
+        Ok(())
+    } else {
+        Err(format!("session not found: {session_id}"))
+    }
+}
+
+/// Cancel a receive and, once it has stopped, remove every partial or
+/// staged file it left, whichever phase it was in. Returns how many files
+/// were removed.
+#[tauri::command]
+pub async fn cancel_and_cleanup(app: AppHandle, session_id: String) -> Result<usize, String> {
+    let store = app.state::<SessionStore>().inner().clone();
+    let session = store
+        .lock()
+        .await
+        .get(&session_id)
+        .cloned()
+        .ok_or_else(|| format!("session not found: {session_id}"))?;
+
+    info!("cancelling transfer {session_id} and removing partial files");
+    session.cancel();
+    let removed = session.partial_files.clean_up().await;
+    info!("transfer {session_id}: removed {removed} partial file(s)");
+    Ok(removed)
+}
+
+/// Where a session is up to, for a UI picking things up again after a reload.
+#[tauri::command]
+pub async fn get_session_state(
+    app: AppHandle,
+    session_id: String,
+) -> Result<TransferState, String> {
+    let store = app.state::<SessionStore>().inner().clone();
+    let session = store
+        .lock()
+        .await
+        .get(&session_id)
+        .cloned()
+        .ok_or_else(|| format!("session not found: {session_id}"))?;
+    Ok(session.get_state().await)
+}
+
+/// Most recent sends and receives from the history log, newest first.
+#[tauri::command]
+pub async fn transfer_history(
+    app: AppHandle,
+    limit: Option<usize>,
+) -> Result<Vec<HistoryEntry>, String> {
+    let log = app.state::<HistoryLog>().inner().clone();
+    log.recent(limit.unwrap_or(50))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Forget every transfer in the history log.
+#[tauri::command]
+pub async fn clear_transfer_history(app: AppHandle) -> Result<(), String> {
+    let log = app.state::<HistoryLog>().inner().clone();
+    log.clear().await.map_err(|e| e.to_string())
+}
+
+/// Bytes sent and received this month, direct and relayed, with the quota.
+#[tauri::command]
+pub async fn usage_stats(app: AppHandle) -> Result<UsageStats, String> {
+    let usage = app.state::<UsageStore>().inner().clone();
+    usage.stats().await.map_err(|e| e.to_string())
+}
+
+/// Set the monthly quota in bytes, or clear it with `None`. Once this
+/// month's usage reaches it, new sends and receives are refused.
+#[tauri::command]
+pub async fn set_usage_quota(app: AppHandle, quota_bytes: Option<u64>) -> Result<(), String> {
+    let usage = app.state::<UsageStore>().inner().clone();
+    usage
+        .set_quota(quota_bytes)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Pause an active transfer until `resume_transfer`. The connection stays up
+/// and nothing received so far is lost.
+#[tauri::command]
+pub async fn pause_transfer(app: AppHandle, session_id: String) -> Result<(), String> {
+    let store = app.state::<SessionStore>().inner().clone();
+    let sessions = store.lock().await;
+    let session = sessions
+        .get(&session_id)
+        .ok_or_else(|| format!("session not found: {session_id}"))?;
+
+    if session.pause() {
+        info!("transfer {session_id}: paused");
+        app.emit(
+            "transfer:progress",
+            &ProgressEvent::Paused {
+                reason: PauseReason::User,
+            },
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Resume a transfer paused with `pause_transfer`.
+#[tauri::command]
+pub async fn resume_transfer(app: AppHandle, session_id: String) -> Result<(), String> {
+    let store = app.state::<SessionStore>().inner().clone();
+    let sessions = store.lock().await;
+    let session = sessions
+        .get(&session_id)
+        .ok_or_else(|| format!("session not found: {session_id}"))?;
+
+    if session.resume() {
+        info!("transfer {session_id}: resumed");
+        app.emit("transfer:progress", &ProgressEvent::Resumed)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Tell an active transfer whether the device is on a metered network.
+/// Metered connections pause the transfer until the network is un-metered.
+#[tauri::command]
+pub async fn set_network_metered(
+    app: AppHandle,
+    session_id: String,
+    metered: bool,
+) -> Result<(), String> {
+    let store = app.state::<SessionStore>().inner().clone();
+    let sessions = store.lock().await;
+    let session = sessions
+        .get(&session_id)
+        .ok_or_else(|| format!("session not found: {session_id}"))?;
+
+    if session.set_network_metered(metered) {
+        info!("transfer {session_id}: network metered={metered}");
+        let event = if metered {
+            ProgressEvent::Paused {
+                reason: PauseReason::MeteredNetwork,
+            }
+        } else {
+            ProgressEvent::Resumed
+        };
+        app.emit("transfer:progress", &event)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// This device's certificate fingerprint as hex, for a sender to put on its
+/// peer allowlist.
+#[tauri::command]
+pub async fn local_fingerprint(app: AppHandle) -> Result<String, String> {
+    app.state::<quic::CertStore>()
+        .fingerprint()
+        .map(|fp| quic::fingerprint_hex(&fp))
+        .map_err(|e| e.to_string())
+}
+
+/// This device's network interfaces and their addresses, for diagnosing
+/// direct connections. Link-local addresses only if `include_link_local`.
+#[tauri::command]
+pub async fn list_network_interfaces(
+    include_link_local: Option<bool>,
+) -> Result<Vec<InterfaceInfo>, String> {
+    interfaces::list(include_link_local.unwrap_or(false)).map_err(|e| e.to_string())
+}
+
+/// How to check a `wss://` signaling server's certificate from now on:
+/// extra trusted roots, or a pinned certificate fingerprint.
+#[tauri::command]
+pub async fn set_signaling_tls(config: SignalingTls) -> Result<(), String> {
+    tls::set_configured(config).map_err(|e| e.to_string())
+}
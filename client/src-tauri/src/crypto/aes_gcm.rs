@@ -1,95 +1,272 @@
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use ring::hkdf;
 use ring::rand::SecureRandom;
 
 use crate::error::{AppError, AppResult};
 
+/// HKDF info string for deriving the next key in a ratchet.
+const RATCHET_INFO: &[u8] = b"relay-chunk-key-ratchet";
+
+/// The key that follows `key` in a ratchet: HKDF-SHA256 with a fixed info
+/// string, so both sides step through the same sequence without talking.
+pub fn ratchet_key(key: &[u8; 32]) -> [u8; 32] {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(key);
+    let mut next = [0u8; 32];
+    prk.expand(&[RATCHET_INFO], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut next))
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    next
+}
+
+/// Additional authenticated data for one file chunk: the file and chunk
+/// index, big-endian, then 1 if the chunk is compressed. Binding them into
+/// the tag means a chunk moved to another position or file, or with its
+/// compression flag flipped, fails to decrypt.
+pub fn chunk_aad(file_index: u16, chunk_index: u32, compressed: bool) -> [u8; 7] {
+    let mut aad = [0u8; 7];
+    aad[..2].copy_from_slice(&file_index.to_be_bytes());
+    aad[2..6].copy_from_slice(&chunk_index.to_be_bytes());
+    aad[6] = u8::from(compressed);
+    aad
+}
+
+fn aes_key(key_bytes: &[u8; 32]) -> AppResult<LessSafeKey> {
+    let unbound = UnboundKey::new(&AES_256_GCM, key_bytes)
+        .map_err(|_| AppError::Crypto("failed to create AES-256-GCM key".into()))?;
+    Ok(LessSafeKey::new(unbound))
+}
+
 /// Encrypts file chunks with AES-256-GCM.
 /// Uses a counter-based nonce: [4-byte random prefix][8-byte counter].
+///
+/// Every file gets a fresh encryptor, so a fresh random prefix; one is
+/// never reused across files. Should its counter ever run out, sealing
+/// fails rather than repeat a nonce.
 pub struct ChunkEncryptor {
     key: LessSafeKey,
+    /// Raw bytes of `key`, kept to derive the next one on a ratchet.
+    secret: [u8; 32],
     nonce_prefix: [u8; 4],
     counter: u64,
 }
 
 impl ChunkEncryptor {
     pub fn new(key_bytes: &[u8; 32]) -> AppResult<Self> {
-        let unbound = UnboundKey::new(&AES_256_GCM, key_bytes)
-            .map_err(|_| AppError::Crypto("failed to create AES-256-GCM key".into()))?;
-
         let mut nonce_prefix = [0u8; 4];
         ring::rand::SystemRandom::new()
             .fill(&mut nonce_prefix)
             .map_err(|_| AppError::Crypto("failed to generate nonce prefix".into()))?;
 
         Ok(Self {
-            key: LessSafeKey::new(unbound),
+            key: aes_key(key_bytes)?,
+            secret: *key_bytes,
             nonce_prefix,
             counter: 0,
         })
     }
 
+    /// Switch to the next key in the ratchet. The nonce counter carries on,
+    /// so nonces stay unique across the whole file.
+    pub fn ratchet(&mut self) -> AppResult<()> {
+        self.secret = ratchet_key(&self.secret);
+        self.key = aes_key(&self.secret)?;
+        Ok(())
+    }
+
     /// Returns the nonce prefix so the receiver can be told (not secret, just unique).
     pub fn nonce_prefix(&self) -> [u8; 4] {
         self.nonce_prefix
     }
 
-    /// Encrypt a chunk of plaintext. Returns (ciphertext_with_tag, nonce).
-    /// The ciphertext includes the 16-byte authentication tag appended by AES-GCM.
-    pub fn encrypt_chunk(&mut self, plaintext: &[u8]) -> AppResult<(Vec<u8>, [u8; 12])> {
-        let nonce_bytes = self.make_nonce();
+    /// Whether nothing has been sealed yet.
+    pub fn is_fresh(&self) -> bool {
+        self.counter == 0
+    }
+
+    /// Encrypt chunk `chunk_index` of file `file_index`, `compressed` or
+    /// not. Returns (ciphertext_with_tag, nonce). The ciphertext includes
+    /// the 16-byte authentication tag appended by AES-GCM, which also
+    /// covers both indices and the compression flag.
+    pub fn encrypt_chunk(
+        &mut self,
+        plaintext: &[u8],
+        file_index: u16,
+        chunk_index: u32,
+        compressed: bool,
+    ) -> AppResult<(Vec<u8>, [u8; 12])> {
+        self.seal(plaintext, &chunk_aad(file_index, chunk_index, compressed))
+    }
+
+    /// Encrypt a single small payload (convenience for non-streaming use).
+    /// Returns (ciphertext_with_tag, nonce).
+    pub fn encrypt_one(mut self, plaintext: &[u8]) -> AppResult<(Vec<u8>, [u8; 12])> {
+        self.seal(plaintext, &[])
+    }
+
+    fn seal(&mut self, plaintext: &[u8], aad: &[u8]) -> AppResult<(Vec<u8>, [u8; 12])> {
+        let nonce_bytes = self.make_nonce()?;
         let nonce = Nonce::assume_unique_for_key(nonce_bytes);
 
         let mut in_out = plaintext.to_vec();
         self.key
-            .seal_in_place_append_tag(nonce, Aad::empty(), &mut in_out)
+            .seal_in_place_append_tag(nonce, Aad::from(aad), &mut in_out)
             .map_err(|_| AppError::Crypto("AES-GCM encryption failed".into()))?;
 
         self.counter += 1;
         Ok((in_out, nonce_bytes))
     }
 
-    /// Encrypt a single small payload (convenience for non-streaming use).
-    /// Returns (ciphertext_with_tag, nonce).
-    pub fn encrypt_one(mut self, plaintext: &[u8]) -> AppResult<(Vec<u8>, [u8; 12])> {
-        self.encrypt_chunk(plaintext)
-    }
-
-    fn make_nonce(&self) -> [u8; 12] {
+    /// The nonce for the next seal. The last counter value is never
+    /// used, so incrementing past a used one can't wrap to 0.
+    fn make_nonce(&self) -> AppResult<[u8; 12]> {
+        if self.counter == u64::MAX {
+            return Err(AppError::Crypto("nonce counter exhausted".into()));
+        }
         let mut nonce = [0u8; 12];
         nonce[..4].copy_from_slice(&self.nonce_prefix);
         nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
-        nonce
+        Ok(nonce)
     }
 }
 
 /// Decrypts file chunks with AES-256-GCM.
 pub struct ChunkDecryptor {
     key: LessSafeKey,
+    /// Raw bytes of `key`, kept to derive the next one on a ratchet.
+    secret: [u8; 32],
 }
 
 impl ChunkDecryptor {
     pub fn new(key_bytes: &[u8; 32]) -> AppResult<Self> {
-        let unbound = UnboundKey::new(&AES_256_GCM, key_bytes)
-            .map_err(|_| AppError::Crypto("failed to create AES-256-GCM key".into()))?;
         Ok(Self {
-            key: LessSafeKey::new(unbound),
+            key: aes_key(key_bytes)?,
+            secret: *key_bytes,
         })
     }
 
+    /// Switch to the next key in the ratchet, in step with the sender.
+    pub fn ratchet(&mut self) -> AppResult<()> {
+        self.secret = ratchet_key(&self.secret);
+        self.key = aes_key(&self.secret)?;
+        Ok(())
+    }
+
     /// Decrypt a single small payload (convenience for non-streaming use).
     pub fn decrypt_one(self, ciphertext: &[u8], nonce: &[u8; 12]) -> AppResult<Vec<u8>> {
-        self.decrypt_chunk(ciphertext, nonce)
+        self.open(ciphertext, nonce, &[])
     }
 
-    /// Decrypt a chunk. `ciphertext` includes the 16-byte auth tag at the end.
-    pub fn decrypt_chunk(&self, ciphertext: &[u8], nonce: &[u8; 12]) -> AppResult<Vec<u8>> {
-        let nonce = Nonce::assume_unique_for_key(*nonce);
+    /// Decrypt chunk `chunk_index` of file `file_index`. `ciphertext`
+    /// includes the 16-byte auth tag at the end; wrong indices or a wrong
+    /// `compressed` flag fail it.
+    pub fn decrypt_chunk(
+        &self,
+        ciphertext: &[u8],
+        nonce: &[u8; 12],
+        file_index: u16,
+        chunk_index: u32,
+        compressed: bool,
+    ) -> AppResult<Vec<u8>> {
+        let aad = chunk_aad(file_index, chunk_index, compressed);
+        self.open(ciphertext, nonce, &aad)
+    }
+
+    /// Decrypt chunk `chunk_index` of file `file_index` where it lies:
+    /// `in_out` holds the ciphertext and tag, and the returned plaintext is
+    /// its leading part. Unlike [`decrypt_chunk`](Self::decrypt_chunk) this
+    /// allocates nothing, so a caller can reuse one buffer for every chunk.
+    pub fn decrypt_chunk_in_place<'a>(
+        &self,
+        in_out: &'a mut [u8],
+        nonce: &[u8; 12],
+        file_index: u16,
+        chunk_index: u32,
+        compressed: bool,
+    ) -> AppResult<&'a [u8]> {
+        let aad = chunk_aad(file_index, chunk_index, compressed);
+        self.open_in_place(in_out, nonce, &aad)
+    }
+
+    fn open(&self, ciphertext: &[u8], nonce: &[u8; 12], aad: &[u8]) -> AppResult<Vec<u8>> {
         let mut in_out = ciphertext.to_vec();
+        let len = self.open_in_place(&mut in_out, nonce, aad)?.len();
+        in_out.truncate(len);
+        Ok(in_out)
+    }
+
+    fn open_in_place<'a>(
+        &self,
+        in_out: &'a mut [u8],
+        nonce: &[u8; 12],
+        aad: &[u8],
+    ) -> AppResult<&'a [u8]> {
+        let nonce = Nonce::assume_unique_for_key(*nonce);
         let plaintext = self
             .key
-            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .open_in_place(nonce, Aad::from(aad), in_out)
             .map_err(|_| AppError::Crypto("AES-GCM decryption failed (tampered or wrong key)".into()))?;
-        Ok(plaintext.to_vec())
+        Ok(plaintext)
+    }
+}
+
+/// How many counters below the highest one seen are still tracked individually.
+const NONCE_WINDOW: u64 = 64;
+
+/// Receive-side guard against nonce reuse for one file.
+///
+/// Every file is encrypted under a single nonce prefix with an increasing
+/// counter, so this only keeps the highest counter seen plus a bitmap of the
+/// ones just below it (as in IPsec anti-replay). A repeated nonce, one older
+/// than the window, or a prefix change mid-file is rejected before decrypting.
+#[derive(Debug, Default)]
+pub struct NonceWindow {
+    prefix: Option<[u8; 4]>,
+    highest: u64,
+    /// Bit `n` set means counter `highest - n` has been seen.
+    seen: u64,
+}
+
+impl NonceWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record `nonce`, failing if it may have been used before.
+    pub fn observe(&mut self, nonce: &[u8; 12]) -> AppResult<()> {
+        let prefix: [u8; 4] = nonce[..4].try_into().expect("4-byte prefix");
+        let counter = u64::from_be_bytes(nonce[4..].try_into().expect("8-byte counter"));
+
+        match self.prefix {
+            None => {
+                self.prefix = Some(prefix);
+                self.highest = counter;
+                self.seen = 1;
+                return Ok(());
+            }
+            Some(known) if known != prefix => {
+                return Err(AppError::Crypto("nonce prefix changed mid-file".into()));
+            }
+            Some(_) => {}
+        }
+
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= NONCE_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = counter;
+            return Ok(());
+        }
+
+        let offset = self.highest - counter;
+        if offset >= NONCE_WINDOW || self.seen & (1 << offset) != 0 {
+            return Err(AppError::Crypto("nonce reuse detected".into()));
+        }
+        self.seen |= 1 << offset;
+        Ok(())
     }
 }
 
@@ -104,12 +281,34 @@ mod tests {
         let decryptor = ChunkDecryptor::new(&key).unwrap();
 
         let plaintext = b"Hello, Relay! This is a test chunk of data.";
-        let (ciphertext, nonce) = encryptor.encrypt_chunk(plaintext).unwrap();
+        let (ciphertext, nonce) = encryptor.encrypt_chunk(plaintext, 0, 0, false).unwrap();
 
-        let decrypted = decryptor.decrypt_chunk(&ciphertext, &nonce).unwrap();
+        let decrypted = decryptor
+            .decrypt_chunk(&ciphertext, &nonce, 0, 0, false)
+            .unwrap();
         assert_eq!(&decrypted, plaintext);
     }
 
+    #[test]
+    fn test_decrypt_chunk_in_place() {
+        let key = [42u8; 32];
+        let mut encryptor = ChunkEncryptor::new(&key).unwrap();
+        let decryptor = ChunkDecryptor::new(&key).unwrap();
+
+        let plaintext = b"decrypted where it lies";
+        let (mut ciphertext, nonce) = encryptor.encrypt_chunk(plaintext, 1, 3, false).unwrap();
+        let mut tampered = ciphertext.clone();
+        let decrypted = decryptor
+            .decrypt_chunk_in_place(&mut ciphertext, &nonce, 1, 3, false)
+            .unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        tampered[0] ^= 1;
+        assert!(decryptor
+            .decrypt_chunk_in_place(&mut tampered, &nonce, 1, 3, false)
+            .is_err());
+    }
+
     #[test]
     fn test_encrypt_decrypt_multiple_chunks() {
         let key = [99u8; 32];
@@ -118,23 +317,99 @@ mod tests {
 
         for i in 0..100 {
             let plaintext = format!("chunk number {i}");
-            let (ciphertext, nonce) = encryptor.encrypt_chunk(plaintext.as_bytes()).unwrap();
-            let decrypted = decryptor.decrypt_chunk(&ciphertext, &nonce).unwrap();
+            let (ciphertext, nonce) = encryptor
+                .encrypt_chunk(plaintext.as_bytes(), 0, i, false)
+                .unwrap();
+            let decrypted = decryptor
+                .decrypt_chunk(&ciphertext, &nonce, 0, i, false)
+                .unwrap();
             assert_eq!(decrypted, plaintext.as_bytes());
         }
     }
 
+    #[test]
+    fn test_ratchet_keeps_both_sides_in_step() {
+        let key = [7u8; 32];
+        let mut encryptor = ChunkEncryptor::new(&key).unwrap();
+        let mut decryptor = ChunkDecryptor::new(&key).unwrap();
+        let stale = ChunkDecryptor::new(&key).unwrap();
+
+        for _ in 0..3 {
+            encryptor.ratchet().unwrap();
+            decryptor.ratchet().unwrap();
+            let (ciphertext, nonce) = encryptor
+                .encrypt_chunk(b"after ratchet", 0, 0, false)
+                .unwrap();
+            assert_eq!(
+                decryptor
+                    .decrypt_chunk(&ciphertext, &nonce, 0, 0, false)
+                    .unwrap(),
+                b"after ratchet"
+            );
+            assert!(stale
+                .decrypt_chunk(&ciphertext, &nonce, 0, 0, false)
+                .is_err());
+        }
+        assert_ne!(ratchet_key(&key), key);
+        assert_ne!(ratchet_key(&ratchet_key(&key)), ratchet_key(&key));
+    }
+
+    #[test]
+    fn test_chunk_indices_are_authenticated() {
+        let key = [42u8; 32];
+        let mut encryptor = ChunkEncryptor::new(&key).unwrap();
+        let decryptor = ChunkDecryptor::new(&key).unwrap();
+
+        let (ciphertext, nonce) = encryptor.encrypt_chunk(b"chunk five", 0, 5, false).unwrap();
+        assert!(decryptor
+            .decrypt_chunk(&ciphertext, &nonce, 0, 6, false)
+            .is_err());
+        assert!(decryptor
+            .decrypt_chunk(&ciphertext, &nonce, 1, 5, false)
+            .is_err());
+        assert_eq!(
+            decryptor
+                .decrypt_chunk(&ciphertext, &nonce, 0, 5, false)
+                .unwrap(),
+            b"chunk five"
+        );
+    }
+
+    #[test]
+    fn test_compression_flag_is_authenticated() {
+        let key = [42u8; 32];
+        let mut encryptor = ChunkEncryptor::new(&key).unwrap();
+        let decryptor = ChunkDecryptor::new(&key).unwrap();
+
+        let (plain, plain_nonce) = encryptor.encrypt_chunk(b"raw bytes", 0, 0, false).unwrap();
+        assert!(decryptor
+            .decrypt_chunk(&plain, &plain_nonce, 0, 0, true)
+            .is_err());
+        let (packed, packed_nonce) = encryptor.encrypt_chunk(b"zstd frame", 0, 1, true).unwrap();
+        assert!(decryptor
+            .decrypt_chunk(&packed, &packed_nonce, 0, 1, false)
+            .is_err());
+        assert_eq!(
+            decryptor
+                .decrypt_chunk(&packed, &packed_nonce, 0, 1, true)
+                .unwrap(),
+            b"zstd frame"
+        );
+    }
+
     #[test]
     fn test_tampered_ciphertext_fails() {
         let key = [42u8; 32];
         let mut encryptor = ChunkEncryptor::new(&key).unwrap();
         let decryptor = ChunkDecryptor::new(&key).unwrap();
 
-        let (mut ciphertext, nonce) = encryptor.encrypt_chunk(b"secret data").unwrap();
+        let (mut ciphertext, nonce) = encryptor
+            .encrypt_chunk(b"secret data", 0, 0, false)
+            .unwrap();
         // Flip a byte
         ciphertext[0] ^= 0xff;
 
-        let result = decryptor.decrypt_chunk(&ciphertext, &nonce);
+        let result = decryptor.decrypt_chunk(&ciphertext, &nonce, 0, 0, false);
         assert!(result.is_err(), "tampered ciphertext must fail decryption");
     }
 
@@ -145,19 +420,70 @@ mod tests {
         let mut encryptor = ChunkEncryptor::new(&key1).unwrap();
         let decryptor = ChunkDecryptor::new(&key2).unwrap();
 
-        let (ciphertext, nonce) = encryptor.encrypt_chunk(b"secret data").unwrap();
-        let result = decryptor.decrypt_chunk(&ciphertext, &nonce);
+        let (ciphertext, nonce) = encryptor
+            .encrypt_chunk(b"secret data", 0, 0, false)
+            .unwrap();
+        let result = decryptor.decrypt_chunk(&ciphertext, &nonce, 0, 0, false);
         assert!(result.is_err(), "wrong key must fail decryption");
     }
 
+    #[test]
+    fn test_exhausted_nonce_counter_refuses_to_seal() {
+        let key = [42u8; 32];
+        let mut enc = ChunkEncryptor::new(&key).unwrap();
+        assert!(enc.is_fresh());
+        enc.counter = u64::MAX - 1;
+
+        let (ct, nonce) = enc.encrypt_chunk(b"last one", 0, 0, false).unwrap();
+        assert_eq!(nonce[4..], (u64::MAX - 1).to_be_bytes());
+        let dec = ChunkDecryptor::new(&key).unwrap();
+        assert_eq!(
+            dec.decrypt_chunk(&ct, &nonce, 0, 0, false).unwrap(),
+            b"last one"
+        );
+
+        let result = enc.encrypt_chunk(b"one too many", 0, 1, false);
+        assert!(matches!(result, Err(AppError::Crypto(_))));
+        assert_eq!(enc.counter, u64::MAX, "counter must not wrap");
+    }
+
     #[test]
     fn test_empty_plaintext() {
         let key = [42u8; 32];
         let mut encryptor = ChunkEncryptor::new(&key).unwrap();
         let decryptor = ChunkDecryptor::new(&key).unwrap();
 
-        let (ciphertext, nonce) = encryptor.encrypt_chunk(b"").unwrap();
-        let decrypted = decryptor.decrypt_chunk(&ciphertext, &nonce).unwrap();
+        let (ciphertext, nonce) = encryptor.encrypt_chunk(b"", 0, 0, false).unwrap();
+        let decrypted = decryptor
+            .decrypt_chunk(&ciphertext, &nonce, 0, 0, false)
+            .unwrap();
         assert!(decrypted.is_empty());
     }
+
+    fn nonce(prefix: u8, counter: u64) -> [u8; 12] {
+        let mut nonce = [prefix; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    #[test]
+    fn test_nonce_window_rejects_repeats() {
+        let mut window = NonceWindow::new();
+        for counter in 0..10 {
+            window.observe(&nonce(1, counter)).unwrap();
+        }
+        assert!(window.observe(&nonce(1, 4)).is_err());
+        assert!(window.observe(&nonce(1, 9)).is_err());
+    }
+
+    #[test]
+    fn test_nonce_window_bounds() {
+        let mut window = NonceWindow::new();
+        window.observe(&nonce(1, 100)).unwrap();
+        // Slightly out of order but unseen is fine; older than the window is not.
+        window.observe(&nonce(1, 98)).unwrap();
+        assert!(window.observe(&nonce(1, 100 - NONCE_WINDOW)).is_err());
+        // One file never switches prefix.
+        assert!(window.observe(&nonce(2, 101)).is_err());
+    }
 }
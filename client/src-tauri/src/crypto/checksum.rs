@@ -1,27 +1,55 @@
+use ring::hmac;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-/// Streaming SHA-256 checksum calculator.
+/// Hash used for whole-file checksums. Both produce 32 bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Sha256,
+    /// Several times faster than SHA-256; worth it on fast links.
+    Blake3,
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+/// Streaming file checksum calculator, SHA-256 unless told otherwise.
 /// Feed it data incrementally, finalize when done.
 pub struct StreamingChecksum {
-    hasher: Sha256,
+    hasher: Hasher,
 }
 
 impl StreamingChecksum {
     pub fn new() -> Self {
-        Self {
-            hasher: Sha256::new(),
-        }
+        Self::with_algorithm(ChecksumAlgorithm::Sha256)
+    }
+
+    pub fn with_algorithm(algorithm: ChecksumAlgorithm) -> Self {
+        let hasher = match algorithm {
+            ChecksumAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+        };
+        Self { hasher }
     }
 
     pub fn update(&mut self, data: &[u8]) {
-        self.hasher.update(data);
+        match &mut self.hasher {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
     }
 
     pub fn finalize(self) -> [u8; 32] {
-        let result = self.hasher.finalize();
-        let mut hash = [0u8; 32];
-        hash.copy_from_slice(&result);
-        hash
+        match self.hasher {
+            Hasher::Sha256(hasher) => hasher.finalize().into(),
+            Hasher::Blake3(hasher) => hasher.finalize().into(),
+        }
     }
 }
 
@@ -31,6 +59,38 @@ impl Default for StreamingChecksum {
     }
 }
 
+/// Streaming HMAC-SHA256 keyed by a sender-chosen challenge nonce.
+///
+/// Both sides feed it the plaintext as they read or write it; a receiver can
+/// only produce the sender's value if it saw exactly the same bytes.
+pub struct ChallengeMac {
+    context: hmac::Context,
+}
+
+impl ChallengeMac {
+    pub fn new(nonce: &[u8; 32]) -> Self {
+        Self {
+            context: hmac::Context::with_key(&hmac::Key::new(hmac::HMAC_SHA256, nonce)),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.context.update(data);
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        let mut mac = [0u8; 32];
+        mac.copy_from_slice(self.context.sign().as_ref());
+        mac
+    }
+
+    /// Compare against a peer's MAC in constant time.
+    pub fn verify(self, mac: &[u8; 32]) -> bool {
+        let ours = self.finalize();
+        ours.iter().zip(mac).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -62,6 +122,37 @@ mod tests {
         assert_eq!(oneshot, streaming);
     }
 
+    #[test]
+    fn test_blake3_streaming_matches_oneshot() {
+        let data = b"Hello, Relay!";
+        let mut cs = StreamingChecksum::with_algorithm(ChecksumAlgorithm::Blake3);
+        cs.update(&data[..5]);
+        cs.update(&data[5..]);
+        let streaming = cs.finalize();
+
+        assert_eq!(streaming, *blake3::hash(data).as_bytes());
+        let sha256: [u8; 32] = Sha256::digest(data).into();
+        assert_ne!(streaming, sha256);
+    }
+
+    #[test]
+    fn test_challenge_mac_depends_on_bytes_and_nonce() {
+        let mac = |nonce: [u8; 32], data: &[u8]| {
+            let mut m = ChallengeMac::new(&nonce);
+            m.update(data);
+            m.finalize()
+        };
+        let reference = mac([1; 32], b"Hello, Relay!");
+
+        let mut streamed = ChallengeMac::new(&[1; 32]);
+        streamed.update(b"Hello, ");
+        streamed.update(b"Relay!");
+        assert!(streamed.verify(&reference));
+
+        assert_ne!(mac([2; 32], b"Hello, Relay!"), reference);
+        assert_ne!(mac([1; 32], b"Hello, Relay?"), reference);
+    }
+
     fn hex(bytes: &[u8]) -> String {
         bytes.iter().map(|b| format!("{b:02x}")).collect()
     }
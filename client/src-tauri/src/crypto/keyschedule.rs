@@ -0,0 +1,125 @@
+// Key schedule — expands the SPAKE2 shared secret into one key per purpose,
+// so neither direction of the transfer nor the signaling exchange shares a
+// key with another.
+
+use ring::hkdf;
+use ring::rand::SecureRandom;
+
+use crate::error::{AppError, AppResult};
+
+const SENDER_TO_RECEIVER_INFO: &[u8] = b"relay sender to receiver";
+const RECEIVER_TO_SENDER_INFO: &[u8] = b"relay receiver to sender";
+const SIGNALING_INFO: &[u8] = b"relay signaling";
+const RESUMED_INFO: &[u8] = b"relay resumed session";
+
+/// A random nonce to swap with the peer when resuming; see
+/// [`KeySchedule::resumed`].
+pub fn resume_nonce() -> AppResult<[u8; 32]> {
+    let mut nonce = [0u8; 32];
+    ring::rand::SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| AppError::Crypto("failed to generate resume nonce".into()))?;
+    Ok(nonce)
+}
+
+/// The keys a transfer uses, each derived from the shared secret with HKDF
+/// under its own label.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct KeySchedule {
+    /// Encrypts what the sender sends: file chunks.
+    pub sender_to_receiver: [u8; 32],
+    /// Encrypts what the receiver sends.
+    pub receiver_to_sender: [u8; 32],
+    /// Encrypts the certificate fingerprints exchanged over signaling.
+    pub signaling: [u8; 32],
+}
+
+impl KeySchedule {
+    pub fn derive(shared_key: &[u8; 32]) -> AppResult<Self> {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(shared_key);
+        let expand = |info: &[u8]| -> AppResult<[u8; 32]> {
+            let mut key = [0u8; 32];
+            prk.expand(&[info], hkdf::HKDF_SHA256)
+                .and_then(|okm| okm.fill(&mut key))
+                .map_err(|_| AppError::Crypto("failed to derive subkey".into()))?;
+            Ok(key)
+        };
+        Ok(Self {
+            sender_to_receiver: expand(SENDER_TO_RECEIVER_INFO)?,
+            receiver_to_sender: expand(RECEIVER_TO_SENDER_INFO)?,
+            signaling: expand(SIGNALING_INFO)?,
+        })
+    }
+
+    /// The keys for a resumed session, which skips SPAKE2: a fresh secret
+    /// is first derived from the exported one, salted with the nonces both
+    /// peers chose for this session, so no two sessions share keys.
+    pub fn resumed(
+        exported_key: &[u8; 32],
+        sender_nonce: &[u8; 32],
+        receiver_nonce: &[u8; 32],
+    ) -> AppResult<Self> {
+        let salt = [sender_nonce.as_slice(), receiver_nonce.as_slice()].concat();
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(exported_key);
+        let mut session_key = [0u8; 32];
+        prk.expand(&[RESUMED_INFO], hkdf::HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut session_key))
+            .map_err(|_| AppError::Crypto("failed to derive resumed session key".into()))?;
+        Self::derive(&session_key)
+    }
+
+    /// The shared secret for every purpose, as peers that predate the key
+    /// schedule use it.
+    pub fn legacy(shared_key: [u8; 32]) -> Self {
+        Self {
+            sender_to_receiver: shared_key,
+            receiver_to_sender: shared_key,
+            signaling: shared_key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::spake::KeyExchange;
+
+    #[test]
+    fn test_both_sides_derive_the_same_distinct_subkeys() {
+        let code = "7-guitar-palace";
+        let (sender, receiver) = (KeyExchange::new(code), KeyExchange::new(code));
+        let sender_msg = sender.outbound_message().to_vec();
+        let receiver_msg = receiver.outbound_message().to_vec();
+        let shared = sender.finish(&receiver_msg).unwrap();
+
+        let sender_keys = KeySchedule::derive(&shared).unwrap();
+        let receiver_keys = KeySchedule::derive(&receiver.finish(&sender_msg).unwrap()).unwrap();
+        assert!(sender_keys == receiver_keys, "both sides must agree");
+
+        let keys = [
+            shared,
+            sender_keys.sender_to_receiver,
+            sender_keys.receiver_to_sender,
+            sender_keys.signaling,
+        ];
+        for (i, a) in keys.iter().enumerate() {
+            for b in &keys[i + 1..] {
+                assert_ne!(a, b, "subkeys must differ from each other and the secret");
+            }
+        }
+    }
+
+    #[test]
+    fn test_resumed_sessions_get_fresh_keys() {
+        let exported = [9u8; 32];
+        let (sender_nonce, receiver_nonce) = ([1u8; 32], [2u8; 32]);
+        let keys = KeySchedule::resumed(&exported, &sender_nonce, &receiver_nonce).unwrap();
+        assert!(keys == KeySchedule::resumed(&exported, &sender_nonce, &receiver_nonce).unwrap());
+        assert!(keys != KeySchedule::derive(&exported).unwrap());
+
+        let next = KeySchedule::resumed(&exported, &sender_nonce, &[3u8; 32]).unwrap();
+        assert_ne!(keys.sender_to_receiver, next.sender_to_receiver);
+        assert_ne!(keys.receiver_to_sender, next.receiver_to_sender);
+        assert_ne!(keys.signaling, next.signaling);
+    }
+}
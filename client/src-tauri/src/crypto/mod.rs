@@ -1,3 +1,4 @@
 pub mod aes_gcm;
 pub mod checksum;
+pub mod keyschedule;
 pub mod spake;
@@ -1,3 +1,4 @@
+use ring::{hkdf, hmac};
 use spake2::{Ed25519Group, Identity, Password, Spake2};
 
 use crate::error::{AppError, AppResult};
@@ -5,6 +6,13 @@ use crate::error::{AppError, AppResult};
 /// Shared identity for symmetric SPAKE2 (both sides use the same).
 const SYMMETRIC_ID: &[u8] = b"relay-symmetric";
 
+/// HKDF info for the key that confirmation MACs are made with.
+const KEY_CONFIRM_INFO: &[u8] = b"relay key confirmation";
+
+/// Length of a symmetric SPAKE2 message over Ed25519: a side byte plus
+/// one compressed point.
+pub const SPAKE2_MESSAGE_LEN: usize = 33;
+
 pub struct KeyExchange {
     state: Option<Spake2<Ed25519Group>>,
     outbound_msg: Vec<u8>,
@@ -33,22 +41,73 @@ impl KeyExchange {
     }
 
     /// Consume the peer's message and derive the shared 32-byte key.
+    ///
+    /// A wrong code doesn't fail here: it yields a different key, which
+    /// [`check_key_confirmation`] catches. An error here means the peer's
+    /// message wasn't a SPAKE2 message at all.
     pub fn finish(mut self, peer_message: &[u8]) -> AppResult<[u8; 32]> {
         let state = self
             .state
             .take()
             .ok_or_else(|| AppError::Crypto("key exchange already consumed".into()))?;
 
+        if peer_message.len() != SPAKE2_MESSAGE_LEN {
+            return Err(malformed_message(peer_message.len()));
+        }
         let shared_key = state
             .finish(peer_message)
-            .map_err(|e| AppError::Crypto(format!("SPAKE2 finish failed: {e:?}")))?;
+            .map_err(|e| AppError::Crypto(format!("corrupt SPAKE2 message from peer: {e:?}")))?;
 
-        let mut key = [0u8; 32];
-        key.copy_from_slice(&shared_key[..32]);
-        Ok(key)
+        derive_key(&shared_key)
     }
 }
 
+/// The peer's SPAKE2 message was `len` bytes rather than
+/// [`SPAKE2_MESSAGE_LEN`]: cut short or padded on the way, not a code typo.
+pub fn malformed_message(len: usize) -> AppError {
+    AppError::Crypto(format!(
+        "malformed SPAKE2 message from peer: {len} bytes, expected {SPAKE2_MESSAGE_LEN}"
+    ))
+}
+
+/// The transfer key: the first 32 bytes of the SPAKE2 shared secret. The
+/// Ed25519 group yields exactly 32 today; a shorter secret is an error
+/// rather than a panic.
+fn derive_key(shared_key: &[u8]) -> AppResult<[u8; 32]> {
+    let bytes = shared_key
+        .get(..32)
+        .ok_or_else(|| AppError::Crypto("derived key too short".into()))?;
+    let mut key = [0u8; 32];
+    key.copy_from_slice(bytes);
+    Ok(key)
+}
+
+/// Proof that we derived `key`, for the peer to check before either side
+/// trusts it: an HMAC over our role, keyed from `key` through HKDF. The
+/// role keeps a peer's own proof from being echoed back to it.
+pub fn key_confirmation(key: &[u8; 32], role: &str) -> AppResult<[u8; 32]> {
+    let tag = hmac::sign(&confirmation_key(key)?, role.as_bytes());
+    let mut mac = [0u8; 32];
+    mac.copy_from_slice(tag.as_ref());
+    Ok(mac)
+}
+
+/// Check the peer's [`key_confirmation`] for `peer_role`. A mismatch means
+/// the two sides typed different codes, and fails with
+/// [`AppError::CodeMismatch`].
+pub fn check_key_confirmation(key: &[u8; 32], peer_role: &str, mac: &[u8]) -> AppResult<()> {
+    hmac::verify(&confirmation_key(key)?, peer_role.as_bytes(), mac)
+        .map_err(|_| AppError::CodeMismatch)
+}
+
+fn confirmation_key(key: &[u8; 32]) -> AppResult<hmac::Key> {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(key);
+    let okm = prk
+        .expand(&[KEY_CONFIRM_INFO], hmac::HMAC_SHA256)
+        .map_err(|_| AppError::Crypto("failed to derive confirmation key".into()))?;
+    Ok(hmac::Key::from(okm))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -68,6 +127,12 @@ mod tests {
         assert_eq!(sender_key, receiver_key, "both sides must derive the same key");
     }
 
+    #[test]
+    fn test_outbound_message_length() {
+        let kx = KeyExchange::new("7-guitar-palace");
+        assert_eq!(kx.outbound_message().len(), SPAKE2_MESSAGE_LEN);
+    }
+
     #[test]
     fn test_key_exchange_different_codes() {
         let sender = KeyExchange::new("7-guitar-palace");
@@ -81,4 +146,53 @@ mod tests {
 
         assert_ne!(sender_key, receiver_key, "different codes must produce different keys");
     }
+
+    #[test]
+    fn test_key_confirmation() {
+        let key = [9u8; 32];
+        let mac = key_confirmation(&key, "sender").unwrap();
+        check_key_confirmation(&key, "sender", &mac).unwrap();
+
+        // Echoed back to the sender, or made with another key, it fails.
+        for (key, role) in [(key, "receiver"), ([8u8; 32], "sender")] {
+            let err = check_key_confirmation(&key, role, &mac).unwrap_err();
+            assert!(
+                matches!(err, AppError::CodeMismatch),
+                "unexpected error: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_truncated_peer_message_is_malformed_not_mismatch() {
+        let sender = KeyExchange::new("7-guitar-palace");
+        let receiver = KeyExchange::new("7-guitar-palace");
+        let truncated = &receiver.outbound_message()[..SPAKE2_MESSAGE_LEN - 5];
+
+        let err = sender.finish(truncated).unwrap_err();
+        assert!(
+            matches!(&err, AppError::Crypto(msg)
+                if msg == "malformed SPAKE2 message from peer: 28 bytes, expected 33"),
+            "unexpected error: {err}"
+        );
+        assert!(!err.to_string().contains("code didn't match"), "{err}");
+    }
+
+    #[test]
+    fn test_code_mismatch_message_is_friendly() {
+        assert_eq!(
+            AppError::CodeMismatch.to_string(),
+            "The transfer code didn't match — double-check it and try again"
+        );
+    }
+
+    #[test]
+    fn test_short_shared_key_is_an_error() {
+        let err = derive_key(&[7u8; 16]).unwrap_err();
+        assert!(
+            matches!(&err, AppError::Crypto(msg) if msg == "derived key too short"),
+            "unexpected error: {err}"
+        );
+        assert_eq!(derive_key(&[7u8; 32]).unwrap(), [7u8; 32]);
+    }
 }
@@ -1,5 +1,8 @@
 use thiserror::Error;
 
+use crate::protocol::messages::CancelReason;
+use crate::transfer::space::SpaceResource;
+
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("Crypto error: {0}")]
@@ -29,17 +32,45 @@ pub enum AppError {
     #[error("Peer rejected transfer")]
     PeerRejected,
 
+    #[error("Peer cancelled transfer ({reason}): {detail}")]
+    PeerCancelled {
+        reason: CancelReason,
+        detail: String,
+    },
+
+    #[error("Not enough {resource} to receive: need {needed}, {available} free")]
+    InsufficientSpace {
+        resource: SpaceResource,
+        needed: u64,
+        available: u64,
+    },
+
     #[error("Checksum mismatch for file: {0}")]
     ChecksumMismatch(String),
 
+    #[error("Peer {0} is not on the allowlist")]
+    PeerNotAllowed(String),
+
+    #[error("Monthly transfer quota reached: {used} of {quota} bytes used")]
+    QuotaExceeded { used: u64, quota: u64 },
+
+    #[error("Nothing to send: no files or folders in the selection")]
+    NothingToSend,
+
     #[error("Code already in use")]
     CodeInUse,
 
     #[error("Connection timeout")]
     ConnectionTimeout,
 
+    #[error("Connection lost: the peer went silent for longer than the idle timeout")]
+    IdleTimeout,
+
     #[error("Invalid transfer code: {0}")]
     InvalidCode(String),
+
+    #[error("The transfer code didn't match — double-check it and try again")]
+    CodeMismatch,
 }
 
 impl serde::Serialize for AppError {
@@ -51,4 +82,12 @@ impl serde::Serialize for AppError {
     }
 }
 
+impl AppError {
+    /// The peer left: it said `Bye`, or closed its end of the connection.
+    /// Unlike a network failure, nothing on our side went wrong.
+    pub fn peer_disconnected() -> Self {
+        AppError::Transfer("peer disconnected".into())
+    }
+}
+
 pub type AppResult<T> = Result<T, AppError>;
@@ -5,26 +5,85 @@ pub mod network;
 pub mod protocol;
 pub mod transfer;
 
-use commands::{receive, send, transfer as transfer_cmds};
+use std::path::PathBuf;
+
+use commands::{receive, resume, send, transfer as transfer_cmds};
+use network::quic::CertStore;
+use tauri::Manager;
+use tracing::error;
+use transfer::history::HistoryLog;
+use transfer::journal::JournalDir;
+use transfer::portable::ResumeStateDir;
+use transfer::usage::UsageStore;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+    run_with_wordlist(None)
+}
+
+/// Run the app, making transfer codes from the wordlist at `wordlist`
+/// instead of the built-in one. Peers must use the same list. One that
+/// can't be loaded is logged and the built-in list used instead.
+pub fn run_with_wordlist(wordlist: Option<PathBuf>) {
     tracing_subscriber::fmt()
         .with_env_filter("relay=debug")
         .init();
 
+    if let Some(path) = wordlist {
+        if let Err(e) = transfer::code::use_wordlist_file(&path) {
+            // Codes won't match peers using the custom list, but the app
+            // still starts.
+            error!(
+                "cannot use wordlist {}: {e}; using the built-in one",
+                path.display()
+            );
+        }
+    }
+
     let (session_store, accept_store) = transfer_cmds::create_stores();
 
     tauri::Builder::default()
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_shell::init())
+        .plugin(tauri_plugin_opener::init())
         .manage(session_store)
         .manage(accept_store)
+        .manage(transfer_cmds::FinalizeChannelStore::default())
+        .setup(|app| {
+            let data_dir = app.path().app_data_dir()?;
+            app.manage(CertStore::persisted(data_dir.join("endpoint-cert.bin")));
+            // Named from when only receives were recorded.
+            app.manage(HistoryLog::new(data_dir.join("receive-history.jsonl")));
+            app.manage(JournalDir::new(data_dir.join("receive-journals")));
+            app.manage(ResumeStateDir::new(data_dir.join("resume-states")));
+            app.manage(UsageStore::new(data_dir.join("usage.json")));
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             send::start_send,
+            send::send_text,
+            send::preview_send,
             receive::start_receive,
             receive::accept_transfer,
+            receive::finalize_transfer,
+            receive::check_save_dir,
+            receive::verify_partial,
             transfer_cmds::cancel_transfer,
+            transfer_cmds::transfer_history,
+            transfer_cmds::clear_transfer_history,
+            transfer_cmds::usage_stats,
+            transfer_cmds::set_usage_quota,
+            transfer_cmds::cancel_discovery,
+            transfer_cmds::cancel_and_cleanup,
+            transfer_cmds::get_session_state,
+            transfer_cmds::pause_transfer,
+            transfer_cmds::resume_transfer,
+            transfer_cmds::set_network_metered,
+            transfer_cmds::local_fingerprint,
+            transfer_cmds::list_network_interfaces,
+            transfer_cmds::set_signaling_tls,
+            resume::export_resume_state,
+            resume::import_resume_state,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -2,5 +2,7 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 fn main() {
-    relay_lib::run()
+    // Deployments can swap in their own code wordlist.
+    let wordlist = std::env::var_os("RELAY_WORDLIST").map(std::path::PathBuf::from);
+    relay_lib::run_with_wordlist(wordlist)
 }
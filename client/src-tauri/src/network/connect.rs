@@ -0,0 +1,326 @@
+// Connection setup — once signaling has paired the peers and they share a
+// key, settle on a transport: a direct QUIC connection, or the signaling
+// server's relay. By default direct is tried first and the relay is the
+// fallback; a `ConnectionMode` can rule either one out.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::error::{AppError, AppResult};
+use crate::network::quic::QuicEndpoint;
+use crate::network::relay::RelayStream;
+use crate::network::signaling::{PeerInfo, SignalingClient};
+use crate::network::transport::Transport;
+use crate::transfer::progress::ProgressEvent;
+use crate::transfer::session::{TransferRole, TransferSession, TransferState};
+
+/// Timeout for the sender waiting for a QUIC connection from the receiver.
+pub const SENDER_QUIC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Timeout for the receiver trying to connect to sender via QUIC.
+pub const RECEIVER_QUIC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which transports a transfer may use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionMode {
+    /// Try a direct connection, and fall back to the relay if it fails.
+    #[default]
+    Auto,
+    /// Never relay: fail if the peers can't reach each other directly.
+    DirectOnly,
+    /// Go straight to the relay, without a QUIC endpoint.
+    RelayOnly,
+}
+
+impl ConnectionMode {
+    /// Whether a direct QUIC connection is attempted.
+    pub fn tries_direct(self) -> bool {
+        self != Self::RelayOnly
+    }
+
+    /// Whether the relay may carry the transfer.
+    pub fn allows_relay(self) -> bool {
+        self != Self::DirectOnly
+    }
+}
+
+/// What happened during the QUIC/relay race.
+enum RaceOutcome {
+    /// Direct QUIC connection succeeded.
+    QuicConnected(quinn::Connection),
+    /// QUIC failed or peer requested relay — need to fall back.
+    FallbackToRelay { reason: String },
+}
+
+/// Establish the sender's transport. `quic` is the endpoint the receiver was
+/// told to dial; without one — as in [`ConnectionMode::RelayOnly`] — the
+/// relay is requested straight away. A receiver that advertised an address
+/// of its own is dialed as well, and the two settle on one connection.
+pub async fn sender_transport(
+    mut signaling: SignalingClient,
+    quic: Option<&QuicEndpoint>,
+    encryption_key: &[u8; 32],
+    mode: ConnectionMode,
+    session: &TransferSession,
+    progress_tx: &mpsc::UnboundedSender<ProgressEvent>,
+) -> AppResult<Transport> {
+    let cancel = session.cancel_token.clone();
+    let Some(quic) = quic.filter(|_| mode.tries_direct()) else {
+        info!("send: relay-only, skipping the fingerprint exchange");
+        session.set_state(TransferState::Connecting).await;
+        return activate_relay(signaling, progress_tx).await;
+    };
+
+    // Exchange cert fingerprints (encrypted with SPAKE2 key)
+    let Some(peer_fingerprint) = signaling
+        .exchange_cert_fingerprint_or_relay(&quic.cert_fingerprint(), encryption_key)
+        .await?
+    else {
+        // A relay-only receiver never reveals its certificate, so it can't
+        // be checked against the allowlist.
+        if quic.has_peer_allowlist() {
+            signaling.disconnect().await.ok();
+            return Err(AppError::PeerNotAllowed(
+                "relay-only receiver without a certificate".into(),
+            ));
+        }
+        session.set_state(TransferState::Connecting).await;
+        return relay_unless_direct_only(signaling, mode, progress_tx).await;
+    };
+    info!("send: cert fingerprint exchange complete");
+    // Checked here as well as on accept so the relay path can't bypass it.
+    quic.check_peer_allowed(&peer_fingerprint)?;
+    session.set_state(TransferState::Connecting).await;
+    progress_tx.send(ProgressEvent::AttemptingDirect).ok();
+
+    // A receiver that registered an address is dialed too, and the lower
+    // fingerprint's connection kept; otherwise we only accept.
+    let peer_candidates = signaling
+        .peer_info()
+        .map(PeerInfo::candidate_addrs)
+        .unwrap_or_default();
+    let direct = async {
+        if peer_candidates.is_empty() {
+            quic.accept_any(&peer_fingerprint).await
+        } else {
+            info!("send: receiver advertised {peer_candidates:?} too, both sides dial");
+            quic.connect_simultaneous(&peer_candidates, &peer_fingerprint)
+                .await
+        }
+    };
+
+    // Race: wait for QUIC connection from receiver OR a relay request.
+    info!(
+        "send: waiting for QUIC connection (timeout {}s) or relay request",
+        SENDER_QUIC_TIMEOUT.as_secs()
+    );
+
+    let race_outcome: RaceOutcome = tokio::select! {
+        result = tokio::time::timeout(SENDER_QUIC_TIMEOUT, direct) => {
+            match result {
+                Ok(Ok(conn)) => {
+                    info!("send: direct QUIC connection established");
+                    RaceOutcome::QuicConnected(conn)
+                }
+                Ok(Err(e)) => {
+                    warn!("send: QUIC accept failed: {e}");
+                    RaceOutcome::FallbackToRelay { reason: format!("QUIC accept failed: {e}") }
+                }
+                Err(_) => {
+                    warn!("send: QUIC accept timed out");
+                    RaceOutcome::FallbackToRelay {
+                        reason: format!("no connection within {}s", SENDER_QUIC_TIMEOUT.as_secs()),
+                    }
+                }
+            }
+        }
+
+        result = signaling.check_for_relay_request() => {
+            match result {
+                Ok(true) => {
+                    info!("send: peer requested relay");
+                    RaceOutcome::FallbackToRelay { reason: "peer requested the relay".into() }
+                }
+                Err(AppError::Cancelled) => {
+                    return Err(AppError::Cancelled);
+                }
+                Ok(false) | Err(_) => {
+                    warn!("send: signaling message during QUIC wait");
+                    RaceOutcome::FallbackToRelay {
+                        reason: "unexpected signaling message".into(),
+                    }
+                }
+            }
+        }
+
+        _ = cancel.cancelled() => {
+            signaling.disconnect().await.ok();
+            return Err(AppError::Cancelled);
+        }
+    };
+
+    match race_outcome {
+        RaceOutcome::QuicConnected(conn) => {
+            // Direct connection — disconnect signaling, we don't need it anymore.
+            signaling.disconnect().await.ok();
+            report_connection_type(progress_tx, "direct");
+            Transport::direct(&conn, TransferRole::Sender).await
+        }
+        RaceOutcome::FallbackToRelay { reason } => {
+            report_direct_failed(progress_tx, reason);
+            relay_unless_direct_only(signaling, mode, progress_tx).await
+        }
+    }
+}
+
+/// Establish the receiver's transport by dialling the sender described by
+/// `peer_info` from `quic`. Without an endpoint — as in
+/// [`ConnectionMode::RelayOnly`] — the relay is requested straight away.
+/// If `signaling` registered our address, the sender dials us at the same
+/// time, and only the lower fingerprint's connection is kept.
+pub async fn receiver_transport(
+    mut signaling: SignalingClient,
+    quic: Option<&QuicEndpoint>,
+    peer_info: PeerInfo,
+    encryption_key: &[u8; 32],
+    mode: ConnectionMode,
+    session: &TransferSession,
+    progress_tx: &mpsc::UnboundedSender<ProgressEvent>,
+) -> AppResult<Transport> {
+    let Some(quic) = quic.filter(|_| mode.tries_direct()) else {
+        info!("receive: relay-only, skipping the fingerprint exchange");
+        session.set_state(TransferState::Connecting).await;
+        return activate_relay(signaling, progress_tx).await;
+    };
+
+    // Exchange cert fingerprints
+    let peer_fingerprint = signaling
+        .exchange_cert_fingerprint_or_relay(&quic.cert_fingerprint(), encryption_key)
+        .await?;
+    session.set_state(TransferState::Connecting).await;
+    let Some(peer_fingerprint) = peer_fingerprint else {
+        return relay_unless_direct_only(signaling, mode, progress_tx).await;
+    };
+    info!("receive: cert fingerprint exchange complete");
+    progress_tx.send(ProgressEvent::AttemptingDirect).ok();
+
+    // Try QUIC connection to sender, fall back to relay on timeout/failure.
+    // The sender may have re-advertised its address since peer_joined.
+    let peer_info = signaling.peer_info().cloned().unwrap_or(peer_info);
+    let candidates = peer_info.candidate_addrs();
+
+    if candidates.is_empty() {
+        warn!("receive: no usable peer address");
+        report_direct_failed(progress_tx, "no usable peer address".into());
+        return relay_unless_direct_only(signaling, mode, progress_tx).await;
+    }
+    info!(
+        "receive: racing QUIC connects to {candidates:?} (timeout {}s)",
+        RECEIVER_QUIC_TIMEOUT.as_secs()
+    );
+    // Having registered an address ourselves, the sender dials us too.
+    let both_dial = signaling.advertised_addr().is_some();
+    let direct = async {
+        if both_dial {
+            quic.connect_simultaneous(&candidates, &peer_fingerprint)
+                .await
+        } else {
+            quic.connect_any(&candidates, &peer_fingerprint).await
+        }
+    };
+    match tokio::time::timeout(RECEIVER_QUIC_TIMEOUT, direct).await {
+        Ok(Ok(conn)) => {
+            info!(
+                "receive: direct QUIC connection established to {}",
+                conn.remote_address()
+            );
+            signaling.disconnect().await.ok();
+            report_connection_type(progress_tx, "direct");
+            Transport::direct(&conn, TransferRole::Receiver).await
+        }
+        Ok(Err(e)) => {
+            warn!("receive: QUIC connect failed: {e}");
+            report_direct_failed(progress_tx, format!("QUIC connect failed: {e}"));
+            relay_unless_direct_only(signaling, mode, progress_tx).await
+        }
+        Err(_) => {
+            warn!("receive: QUIC connect timed out");
+            let secs = RECEIVER_QUIC_TIMEOUT.as_secs();
+            report_direct_failed(progress_tx, format!("no connection within {secs}s"));
+            relay_unless_direct_only(signaling, mode, progress_tx).await
+        }
+    }
+}
+
+/// Fall back to the relay, unless `mode` rules it out. Then the signaling
+/// connection is closed, so a peer waiting on the relay gives up too.
+async fn relay_unless_direct_only(
+    signaling: SignalingClient,
+    mode: ConnectionMode,
+    progress_tx: &mpsc::UnboundedSender<ProgressEvent>,
+) -> AppResult<Transport> {
+    if !mode.allows_relay() {
+        signaling.disconnect().await.ok();
+        return Err(AppError::Network(
+            "direct connection failed and relay is disabled (direct-only mode)".into(),
+        ));
+    }
+    info!("falling back to relay");
+    progress_tx.send(ProgressEvent::FallingBackToRelay).ok();
+    activate_relay(signaling, progress_tx).await
+}
+
+/// Request relay mode from the signaling server, then convert the WebSocket
+/// into a relay transport.
+async fn activate_relay(
+    mut signaling: SignalingClient,
+    progress_tx: &mpsc::UnboundedSender<ProgressEvent>,
+) -> AppResult<Transport> {
+    signaling.request_relay().await?;
+    report_connection_type(progress_tx, "relay");
+
+    let ws = signaling.into_ws();
+    Ok(Transport::Relayed {
+        ws: RelayStream::new(ws),
+    })
+}
+
+fn report_direct_failed(progress_tx: &mpsc::UnboundedSender<ProgressEvent>, reason: String) {
+    progress_tx
+        .send(ProgressEvent::DirectFailed { reason })
+        .ok();
+}
+
+fn report_connection_type(progress_tx: &mpsc::UnboundedSender<ProgressEvent>, kind: &str) {
+    progress_tx
+        .send(ProgressEvent::ConnectionTypeChanged {
+            connection_type: kind.into(),
+        })
+        .ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_mode_branches() {
+        assert!(ConnectionMode::Auto.tries_direct() && ConnectionMode::Auto.allows_relay());
+        assert!(ConnectionMode::DirectOnly.tries_direct());
+        assert!(!ConnectionMode::DirectOnly.allows_relay());
+        assert!(!ConnectionMode::RelayOnly.tries_direct());
+        assert!(ConnectionMode::RelayOnly.allows_relay());
+
+        assert_eq!(ConnectionMode::default(), ConnectionMode::Auto);
+        let mode: ConnectionMode = serde_json::from_str("\"direct_only\"").unwrap();
+        assert_eq!(mode, ConnectionMode::DirectOnly);
+        assert_eq!(
+            serde_json::to_string(&ConnectionMode::RelayOnly).unwrap(),
+            "\"relay_only\""
+        );
+    }
+}
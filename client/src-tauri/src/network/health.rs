@@ -0,0 +1,144 @@
+// Relay health — how a relayed transfer is faring. TCP hides loss on the
+// relay path, so its symptoms are measured instead: round trips that grow
+// as data queues up behind a slow hop, and writes or replies that stall.
+
+use std::time::{Duration, Instant};
+
+use crate::transfer::progress::ProgressEvent;
+
+/// How often the sender pings the receiver through the relay.
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often an otherwise idle relayed transfer pings the peer — while the
+/// receiver decides on the offer, say — so that proxies and load balancers
+/// along the way don't reap the WebSocket as dead.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A write that blocks, or a ping left unanswered, for this long counts as
+/// a stall.
+pub const STALL_THRESHOLD: Duration = Duration::from_millis(250);
+
+/// A transfer that gets nowhere at all for this long — no chunk, reply or
+/// ping in either direction — has stalled for good, and is given up on.
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Round-trip, throughput and stall measurements for one relayed send.
+#[derive(Debug)]
+pub struct RelayHealth {
+    started: Instant,
+    /// When the outstanding ping went out, and whether it's been counted
+    /// as a stall yet.
+    pending_ping: Option<(Instant, bool)>,
+    last_ping: Option<Instant>,
+    /// Smoothed round trip, weighted like TCP's SRTT.
+    rtt: Option<Duration>,
+    stalls: u32,
+    file_bytes: u64,
+    wire_bytes: u64,
+}
+
+impl Default for RelayHealth {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            pending_ping: None,
+            last_ping: None,
+            rtt: None,
+            stalls: 0,
+            file_bytes: 0,
+            wire_bytes: 0,
+        }
+    }
+}
+
+impl RelayHealth {
+    /// Whether to send another `Ping`: one at a time, [`PING_INTERVAL`] apart.
+    pub fn ping_due(&self) -> bool {
+        self.pending_ping.is_none()
+            && self
+                .last_ping
+                .is_none_or(|sent| sent.elapsed() >= PING_INTERVAL)
+    }
+
+    pub fn ping_sent(&mut self) {
+        let now = Instant::now();
+        self.pending_ping = Some((now, false));
+        self.last_ping = Some(now);
+    }
+
+    /// The outstanding ping was answered.
+    pub fn pong_received(&mut self) {
+        let Some((sent, stalled)) = self.pending_ping.take() else {
+            return;
+        };
+        let sample = sent.elapsed();
+        if !stalled && sample >= STALL_THRESHOLD {
+            self.stalls += 1;
+        }
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    /// Stop waiting on the outstanding ping. Pausing leaves its pong unread
+    /// for however long the pause lasts, which says nothing about the relay.
+    pub fn forget_ping(&mut self) {
+        self.pending_ping = None;
+    }
+
+    /// Record one write to the relay: `wire_bytes` carrying `file_bytes` of
+    /// file data, which took `took` to be accepted.
+    pub fn record_send(&mut self, file_bytes: u64, wire_bytes: u64, took: Duration) {
+        self.file_bytes += file_bytes;
+        self.wire_bytes += wire_bytes;
+        if took >= STALL_THRESHOLD {
+            self.stalls += 1;
+        }
+        // A ping still out past the threshold is a stall, counted once.
+        if let Some((sent, stalled @ false)) = &mut self.pending_ping {
+            if sent.elapsed() >= STALL_THRESHOLD {
+                *stalled = true;
+                self.stalls += 1;
+            }
+        }
+    }
+
+    /// The measurements so far, as a `ConnectionStats` event.
+    pub fn stats(&self) -> ProgressEvent {
+        let elapsed = self.started.elapsed().as_secs_f64().max(0.001);
+        ProgressEvent::ConnectionStats {
+            rtt_ms: self.rtt.map(|rtt| rtt.as_millis() as u32),
+            goodput_bps: (self.file_bytes as f64 / elapsed) as u64,
+            wire_bps: (self.wire_bytes as f64 / elapsed) as u64,
+            stalls: self.stalls,
+            stalls_per_minute: (self.stalls as f64 * 60.0 / elapsed) as f32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slow_replies_and_writes_count_as_stalls() {
+        let mut health = RelayHealth::default();
+        assert!(health.ping_due());
+        health.ping_sent();
+        assert!(!health.ping_due(), "only one ping at a time");
+
+        health.record_send(1000, 1016, Duration::from_millis(5));
+        health.record_send(1000, 1016, STALL_THRESHOLD * 2);
+        std::thread::sleep(STALL_THRESHOLD);
+        health.record_send(1000, 1016, Duration::from_millis(5));
+        // Counted while outstanding, not again when the pong arrives.
+        health.pong_received();
+
+        let ProgressEvent::ConnectionStats { rtt_ms, stalls, .. } = health.stats() else {
+            unreachable!();
+        };
+        assert_eq!(stalls, 2);
+        assert!(rtt_ms.unwrap() >= STALL_THRESHOLD.as_millis() as u32);
+    }
+}
@@ -0,0 +1,83 @@
+// Interfaces — the machine's network interfaces and their addresses, both
+// for the candidates a peer is told to try and for diagnostics.
+
+use std::net::IpAddr;
+
+use serde::Serialize;
+use tracing::warn;
+
+use crate::error::AppResult;
+
+/// A network interface and the addresses it has.
+#[derive(Debug, Clone, Serialize)]
+pub struct InterfaceInfo {
+    pub name: String,
+    pub ips: Vec<IpAddr>,
+    pub loopback: bool,
+    /// Operationally up: configured and with a working link.
+    pub up: bool,
+}
+
+/// Every interface with an address, in the order the OS lists them.
+/// Link-local addresses are left out unless `include_link_local`, and so
+/// is an interface that has nothing else.
+pub fn list(include_link_local: bool) -> AppResult<Vec<InterfaceInfo>> {
+    let mut interfaces: Vec<InterfaceInfo> = Vec::new();
+    for iface in if_addrs::get_if_addrs()? {
+        if iface.is_link_local() && !include_link_local {
+            continue;
+        }
+        let ip = iface.ip();
+        match interfaces.iter_mut().find(|known| known.name == iface.name) {
+            Some(known) if !known.ips.contains(&ip) => known.ips.push(ip),
+            Some(_) => {}
+            None => interfaces.push(InterfaceInfo {
+                loopback: iface.is_loopback(),
+                up: iface.is_oper_up(),
+                name: iface.name,
+                ips: vec![ip],
+            }),
+        }
+    }
+    Ok(interfaces)
+}
+
+/// Addresses a peer on the same network might reach this machine on: those
+/// of up, non-loopback interfaces. Link-local ones are useless without an
+/// interface scope, so they're left out.
+pub fn reachable_ips() -> Vec<IpAddr> {
+    let interfaces = list(false).unwrap_or_else(|e| {
+        warn!("failed to list network interfaces: {e}");
+        Vec::new()
+    });
+    let mut ips: Vec<IpAddr> = Vec::new();
+    for ip in interfaces
+        .into_iter()
+        .filter(|iface| iface.up && !iface.loopback)
+        .flat_map(|iface| iface.ips)
+    {
+        if !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+    ips
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loopback_is_listed() {
+        let interfaces = list(false).unwrap();
+        let loopback = interfaces
+            .iter()
+            .find(|iface| iface.loopback)
+            .unwrap_or_else(|| panic!("no loopback in {interfaces:?}"));
+        assert!(loopback.ips.iter().all(IpAddr::is_loopback));
+
+        assert!(reachable_ips().iter().all(|ip| !ip.is_loopback()));
+        let all = list(true).unwrap();
+        assert!(all.len() >= interfaces.len());
+    }
+}
@@ -1,4 +1,9 @@
+pub mod connect;
+pub mod health;
+pub mod interfaces;
 pub mod quic;
 pub mod relay;
 pub mod signaling;
+pub mod stun;
+pub mod tls;
 pub mod transport;
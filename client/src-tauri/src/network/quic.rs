@@ -1,57 +1,260 @@
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use quinn::{Connection, Endpoint, ServerConfig};
+use quinn::congestion::{BbrConfig, CubicConfig, NewRenoConfig};
+use quinn::{
+    Connection, Endpoint, EndpointConfig, IdleTimeout, ServerConfig, TransportConfig, VarInt,
+};
 use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::error::{AppError, AppResult};
+use crate::network::stun::{self, STUN_TIMEOUT};
+
+/// Congestion controller for QUIC connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CongestionControl {
+    /// quinn's default; a safe choice on any path.
+    #[default]
+    Cubic,
+    NewReno,
+    /// Model-based rather than loss-based, so it copes better with random
+    /// loss on Wi-Fi. Still experimental in quinn.
+    Bbr,
+}
+
+/// Which IP versions an endpoint's socket speaks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    #[default]
+    Ipv4,
+    /// One IPv6 socket that takes IPv4 traffic too, as v4-mapped addresses.
+    DualStack,
+    Ipv6,
+}
+
+impl AddressFamily {
+    /// Bind a UDP socket for this family on all interfaces.
+    fn bind(self, port: u16) -> AppResult<std::net::UdpSocket> {
+        let addr = match self {
+            Self::Ipv4 => SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+            Self::DualStack | Self::Ipv6 => SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        };
+        let bind = || -> std::io::Result<std::net::UdpSocket> {
+            let socket = socket2::Socket::new(
+                socket2::Domain::for_address(addr),
+                socket2::Type::DGRAM,
+                Some(socket2::Protocol::UDP),
+            )?;
+            // Set explicitly: the OS default differs (Windows is v6-only).
+            if addr.is_ipv6() {
+                socket.set_only_v6(self == Self::Ipv6)?;
+            }
+            socket.bind(&addr.into())?;
+            Ok(socket.into())
+        };
+        bind().map_err(|e| AppError::Network(format!("failed to bind UDP socket on {addr}: {e}")))
+    }
+}
+
+/// Congestion and liveness tuning for the connections an endpoint makes or
+/// accepts.
+///
+/// The defaults suit the internet, and a long transfer over it: pings keep
+/// a connection with nothing to send alive, and a peer has to go silent for
+/// a while before it's given up on. A larger `initial_window` lets small
+/// transfers finish at full speed instead of ending during slow start, but
+/// the first flight is sent before any loss or RTT feedback: on a congested,
+/// shared or high-latency path that burst overflows queues, triggers loss and
+/// retransmits, and crowds out other traffic. Only raise it for LAN or
+/// otherwise known-good links.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkOptions {
+    pub congestion: CongestionControl,
+    /// Initial congestion window in bytes. `None` keeps the controller's
+    /// default of about 14 KB (10 full-size packets).
+    pub initial_window: Option<u64>,
+    /// Most bytes a connection may have in flight, however far the
+    /// congestion window grows. `None` keeps quinn's flow-control windows
+    /// (1.25 MB per stream), which cap throughput at about 100 Mbit/s on a
+    /// 100 ms round trip; raise it for fast links with long round trips.
+    pub max_window: Option<u64>,
+    /// Close a connection the peer has been silent on this long, in
+    /// milliseconds. `None` means [`DEFAULT_IDLE_TIMEOUT`]; 0 never closes
+    /// it. The shorter of the two peers' timeouts applies.
+    pub idle_timeout_ms: Option<u64>,
+    /// Ping a connection with nothing to send this often, in milliseconds,
+    /// so a pause doesn't count as silence. `None` means
+    /// [`DEFAULT_KEEP_ALIVE_INTERVAL`]; 0 sends no pings.
+    pub keep_alive_ms: Option<u64>,
+    /// STUN server (`host:port`, e.g. [`stun::DEFAULT_STUN_SERVER`]) to
+    /// learn the endpoint's public address from. `None` leaves that to the
+    /// signaling server, which can't see past every NAT.
+    pub stun_server: Option<String>,
+    /// IP versions to listen and dial on.
+    pub family: AddressFamily,
+    /// Where the endpoint's certificate comes from. Not a setting: the app
+    /// fills in its persisted store, and the default is a fresh certificate.
+    #[serde(skip)]
+    pub identity: CertStore,
+}
+
+impl NetworkOptions {
+    fn transport_config(&self) -> Arc<TransportConfig> {
+        let mut transport = TransportConfig::default();
+        match self.congestion {
+            CongestionControl::Cubic => {
+                let mut config = CubicConfig::default();
+                if let Some(window) = self.initial_window {
+                    config.initial_window(window);
+                }
+                transport.congestion_controller_factory(Arc::new(config));
+            }
+            CongestionControl::NewReno => {
+                let mut config = NewRenoConfig::default();
+                if let Some(window) = self.initial_window {
+                    config.initial_window(window);
+                }
+                transport.congestion_controller_factory(Arc::new(config));
+            }
+            CongestionControl::Bbr => {
+                let mut config = BbrConfig::default();
+                if let Some(window) = self.initial_window {
+                    config.initial_window(window);
+                }
+                transport.congestion_controller_factory(Arc::new(config));
+            }
+        }
+        if let Some(window) = self.max_window {
+            transport
+                .send_window(window)
+                .stream_receive_window(VarInt::from_u64(window).unwrap_or(VarInt::MAX));
+        }
+        let idle_timeout = self
+            .idle_timeout_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+        transport.max_idle_timeout(
+            (!idle_timeout.is_zero())
+                .then(|| IdleTimeout::try_from(idle_timeout).unwrap_or(VarInt::MAX.into())),
+        );
+        let keep_alive = self
+            .keep_alive_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_KEEP_ALIVE_INTERVAL);
+        transport.keep_alive_interval((!keep_alive.is_zero()).then_some(keep_alive));
+        Arc::new(transport)
+    }
+}
+
+/// How long a peer may go silent before its connection is closed, unless
+/// [`NetworkOptions::idle_timeout_ms`] says otherwise. With pings every
+/// [`DEFAULT_KEEP_ALIVE_INTERVAL`], only a peer that's really gone stays
+/// silent this long.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often a quiet connection is pinged, unless
+/// [`NetworkOptions::keep_alive_ms`] says otherwise.
+pub const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long [`QuicEndpoint::shutdown`] waits for connections to drain.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Close code for the losing connection of a simultaneous connect.
+const REDUNDANT_CONNECTION: quinn::VarInt = quinn::VarInt::from_u32(2);
+
+/// Close code for a peer turned away by the allowlist.
+const PEER_NOT_ALLOWED: quinn::VarInt = quinn::VarInt::from_u32(3);
 
 /// A QUIC endpoint that can both listen (accept) and connect.
-/// Uses a self-signed certificate; authentication is via SPAKE2-derived key,
-/// not the TLS certificate chain.
+/// Uses a self-signed certificate. Peers are authenticated by pinning the
+/// certificate fingerprint exchanged under the SPAKE2-derived key, not by a
+/// certificate chain.
 pub struct QuicEndpoint {
     endpoint: Endpoint,
+    identity: Arc<EndpointIdentity>,
     cert_fingerprint: [u8; 32],
+    /// Applied to outgoing connections; incoming ones get it via the server config.
+    transport: Arc<TransportConfig>,
+    /// If set, only peers with one of these certificate fingerprints are accepted.
+    allowlist: Option<Arc<HashSet<[u8; 32]>>>,
+    /// Where the STUN server saw the endpoint's socket, if it was asked.
+    public_addr: Option<SocketAddr>,
 }
 
 impl QuicEndpoint {
-    /// Create a new QUIC endpoint bound to `0.0.0.0:{port}`.
+    /// Create a new QUIC endpoint bound to `0.0.0.0:{port}`; see
+    /// [`NetworkOptions::family`] for IPv6.
     /// Use port 0 for OS-assigned.
     pub async fn new(port: u16) -> AppResult<Self> {
-        // Generate self-signed cert
-        let subject_alt_names = vec!["relay.local".to_string()];
-        let cert_params = rcgen::CertificateParams::new(subject_alt_names)
-            .map_err(|e| AppError::Crypto(format!("cert params: {e}")))?;
-        let key_pair = rcgen::KeyPair::generate()
-            .map_err(|e| AppError::Crypto(format!("keygen: {e}")))?;
-        let cert = cert_params
-            .self_signed(&key_pair)
-            .map_err(|e| AppError::Crypto(format!("self-sign: {e}")))?;
+        Self::with_options(port, &NetworkOptions::default()).await
+    }
 
-        let cert_der = CertificateDer::from(cert.der().to_vec());
-        let key_der = PrivatePkcs8KeyDer::from(key_pair.serialize_der());
+    /// Like [`new`](Self::new), with custom congestion tuning. With a STUN
+    /// server, first asks it from the endpoint's socket for the public
+    /// address; one that doesn't answer within [`STUN_TIMEOUT`] leaves
+    /// [`public_addr`](Self::public_addr) unknown.
+    pub async fn with_options(port: u16, options: &NetworkOptions) -> AppResult<Self> {
+        let socket = options.family.bind(port)?;
+        let Some(server) = &options.stun_server else {
+            return Self::with_socket(socket, options);
+        };
 
-        // Compute fingerprint (SHA-256 of DER cert)
-        let mut hasher = Sha256::new();
-        hasher.update(cert_der.as_ref());
-        let fingerprint: [u8; 32] = hasher.finalize().into();
+        socket.set_nonblocking(true)?;
+        let socket = tokio::net::UdpSocket::from_std(socket)?;
+        let public_addr = match stun::public_addr(&socket, server, STUN_TIMEOUT).await {
+            Ok(addr) => {
+                info!("STUN server {server} sees us at {addr}");
+                Some(addr)
+            }
+            Err(e) => {
+                warn!("no public address from STUN server {server}: {e}");
+                None
+            }
+        };
+        let mut quic = Self::with_socket(socket.into_std()?, options)?;
+        quic.public_addr = public_addr;
+        Ok(quic)
+    }
+
+    fn with_socket(socket: std::net::UdpSocket, options: &NetworkOptions) -> AppResult<Self> {
+        let transport = options.transport_config();
+        let identity = options.identity.identity()?;
+        let cert_der = CertificateDer::from(identity.cert_der.clone());
+        let key_der = PrivatePkcs8KeyDer::from(identity.key_der.clone());
+        let fingerprint = fingerprint_of(&cert_der);
 
-        // Build server config (for accepting connections)
+        // Build server config (for accepting connections). Clients must
+        // present their certificate; `accept_any` checks it against the
+        // fingerprint exchanged over signaling.
         let server_crypto = rustls::ServerConfig::builder()
-            .with_no_client_auth()
+            .with_client_cert_verifier(Arc::new(AnyClientCert))
             .with_single_cert(vec![cert_der.clone()], key_der.into())
             .map_err(|e| AppError::Crypto(format!("server TLS config: {e}")))?;
 
-        let server_config = ServerConfig::with_crypto(Arc::new(
+        let mut server_config = ServerConfig::with_crypto(Arc::new(
             quinn::crypto::rustls::QuicServerConfig::try_from(server_crypto)
                 .map_err(|e| AppError::Crypto(format!("QUIC server config: {e}")))?,
         ));
+        server_config.transport_config(transport.clone());
 
-        let addr: SocketAddr = format!("0.0.0.0:{port}").parse().unwrap();
-        let endpoint = Endpoint::server(server_config, addr)
-            .map_err(|e| AppError::Network(format!("failed to bind QUIC endpoint: {e}")))?;
+        let runtime = quinn::default_runtime()
+            .ok_or_else(|| AppError::Network("no async runtime for QUIC".into()))?;
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(server_config),
+            socket,
+            runtime,
+        )
+        .map_err(|e| AppError::Network(format!("failed to bind QUIC endpoint: {e}")))?;
 
         info!(
             "QUIC endpoint listening on {}",
@@ -60,12 +263,41 @@ impl QuicEndpoint {
 
         Ok(Self {
             endpoint,
+            identity,
             cert_fingerprint: fingerprint,
+            transport,
+            allowlist: None,
+            public_addr: None,
         })
     }
 
-    /// Accept one incoming connection. Accepts any peer (Phase 1: LAN, no fingerprint check).
-    pub async fn accept_any(&self) -> AppResult<Connection> {
+    /// Only accept connections from peers whose certificate fingerprint is
+    /// in `fingerprints`, even if they know the transfer code.
+    pub fn with_peer_allowlist(mut self, fingerprints: impl IntoIterator<Item = [u8; 32]>) -> Self {
+        self.allowlist = Some(Arc::new(fingerprints.into_iter().collect()));
+        self
+    }
+
+    /// Whether only allowlisted peers are served.
+    pub fn has_peer_allowlist(&self) -> bool {
+        self.allowlist.is_some()
+    }
+
+    /// Fail with [`AppError::PeerNotAllowed`] if an allowlist is set and
+    /// `fingerprint` isn't on it.
+    pub fn check_peer_allowed(&self, fingerprint: &[u8; 32]) -> AppResult<()> {
+        match &self.allowlist {
+            Some(allowed) if !allowed.contains(fingerprint) => {
+                Err(AppError::PeerNotAllowed(fingerprint_hex(fingerprint)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Accept one incoming connection from whichever peer dials first,
+    /// provided its certificate's SHA-256 is `expected_fingerprint` and, with
+    /// an allowlist, on it.
+    pub async fn accept_any(&self, expected_fingerprint: &[u8; 32]) -> AppResult<Connection> {
         let incoming = self
             .endpoint
             .accept()
@@ -76,24 +308,52 @@ impl QuicEndpoint {
             .await
             .map_err(|e| AppError::Network(format!("failed to accept connection: {e}")))?;
 
+        let presented = conn
+            .peer_identity()
+            .and_then(|id| id.downcast::<Vec<CertificateDer<'static>>>().ok())
+            .and_then(|certs| certs.first().map(fingerprint_of));
+        if presented.as_ref() != Some(expected_fingerprint) {
+            conn.close(1u32.into(), b"certificate fingerprint mismatch");
+            return Err(AppError::Crypto(format!(
+                "peer at {} presented an unexpected certificate",
+                conn.remote_address()
+            )));
+        }
+        if let Err(e) = self.check_peer_allowed(expected_fingerprint) {
+            warn!("turning away {}: {e}", conn.remote_address());
+            conn.close(PEER_NOT_ALLOWED, b"peer not on allowlist");
+            return Err(e);
+        }
+
         info!("accepted QUIC connection from {}", conn.remote_address());
         Ok(conn)
     }
 
-    /// Connect to a peer at the given address.
+    /// Connect to a peer at the given address, accepting only the certificate
+    /// whose SHA-256 is `expected_fingerprint`.
     /// Uses the existing endpoint with a client config so the connection
     /// lifetime is tied to the endpoint (not dropped prematurely).
-    pub async fn connect(&self, addr: SocketAddr) -> AppResult<Connection> {
-        // Client config that accepts any cert (we rely on SPAKE2 for auth)
+    pub async fn connect(
+        &self,
+        addr: SocketAddr,
+        expected_fingerprint: &[u8; 32],
+    ) -> AppResult<Connection> {
         let client_crypto = rustls::ClientConfig::builder()
             .dangerous()
-            .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
-            .with_no_client_auth();
+            .with_custom_certificate_verifier(Arc::new(PinnedServerCert {
+                fingerprint: *expected_fingerprint,
+            }))
+            .with_client_auth_cert(
+                vec![CertificateDer::from(self.identity.cert_der.clone())],
+                PrivatePkcs8KeyDer::from(self.identity.key_der.clone()).into(),
+            )
+            .map_err(|e| AppError::Crypto(format!("client TLS config: {e}")))?;
 
-        let client_config = quinn::ClientConfig::new(Arc::new(
+        let mut client_config = quinn::ClientConfig::new(Arc::new(
             quinn::crypto::rustls::QuicClientConfig::try_from(client_crypto)
                 .map_err(|e| AppError::Crypto(format!("QUIC client config: {e}")))?,
         ));
+        client_config.transport_config(self.transport.clone());
 
         let conn = self
             .endpoint
@@ -106,66 +366,675 @@ impl QuicEndpoint {
         Ok(conn)
     }
 
+    /// Dial every address in `candidates` at once and keep the first
+    /// connection that completes. The other attempts are dropped, which
+    /// abandons them. Fails with the last error if none succeeds.
+    pub async fn connect_any(
+        &self,
+        candidates: &[SocketAddr],
+        expected_fingerprint: &[u8; 32],
+    ) -> AppResult<Connection> {
+        if candidates.is_empty() {
+            return Err(AppError::Network("no candidate addresses".into()));
+        }
+        let attempts = candidates
+            .iter()
+            .map(|&addr| Box::pin(self.connect(addr, expected_fingerprint)));
+        let (conn, _abandoned) = futures_util::future::select_ok(attempts).await?;
+        Ok(conn)
+    }
+
+    /// Connect to a peer that may be dialing us at the same time, as when
+    /// both sides registered an address.
+    ///
+    /// Both sides dial `candidates`, so either can punch through to the
+    /// other, but only the connection dialed by the side with the lower
+    /// certificate fingerprint is kept; the other is closed as soon as it's
+    /// established. Both ends settle on the same single connection.
+    pub async fn connect_simultaneous(
+        &self,
+        candidates: &[SocketAddr],
+        expected_fingerprint: &[u8; 32],
+    ) -> AppResult<Connection> {
+        if self.initiates(expected_fingerprint)? {
+            // Keep our own dial; turn away the peer's.
+            let turn_away = async {
+                while let Ok(conn) = self.accept_any(expected_fingerprint).await {
+                    conn.close(REDUNDANT_CONNECTION, b"redundant connection");
+                }
+                std::future::pending().await
+            };
+            tokio::select! {
+                result = self.connect_any(candidates, expected_fingerprint) => result,
+                never = turn_away => never,
+            }
+        } else {
+            // Dial anyway to open our side of the path, but wait for theirs.
+            let dial = async {
+                if let Ok(conn) = self.connect_any(candidates, expected_fingerprint).await {
+                    conn.close(REDUNDANT_CONNECTION, b"redundant connection");
+                }
+                std::future::pending().await
+            };
+            tokio::select! {
+                result = self.accept_any(expected_fingerprint) => result,
+                never = dial => never,
+            }
+        }
+    }
+
+    /// Whether our dial wins a simultaneous connect: the side with the
+    /// lower certificate fingerprint initiates.
+    fn initiates(&self, peer_fingerprint: &[u8; 32]) -> AppResult<bool> {
+        if self.cert_fingerprint == *peer_fingerprint {
+            return Err(AppError::Network("cannot connect to ourselves".into()));
+        }
+        Ok(self.cert_fingerprint < *peer_fingerprint)
+    }
+
     /// SHA-256 fingerprint of our certificate.
     pub fn cert_fingerprint(&self) -> [u8; 32] {
         self.cert_fingerprint
     }
 
+    /// The endpoint's address as seen from the internet, if a STUN server
+    /// told us.
+    pub fn public_addr(&self) -> Option<SocketAddr> {
+        self.public_addr
+    }
+
     /// Local address the endpoint is bound to.
     pub fn local_addr(&self) -> AppResult<SocketAddr> {
         self.endpoint
             .local_addr()
             .map_err(|e| AppError::Network(e.to_string()))
     }
+
+    /// Close the endpoint without cutting off data still in flight.
+    ///
+    /// Waits up to [`SHUTDOWN_TIMEOUT`] for peers to finish reading and close
+    /// their connections, then closes whatever is left and waits again for
+    /// the close to be delivered.
+    pub async fn shutdown(self) {
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, self.endpoint.wait_idle())
+            .await
+            .is_err()
+        {
+            warn!("QUIC endpoint still busy after {SHUTDOWN_TIMEOUT:?}, closing anyway");
+        }
+        self.endpoint.close(0u32.into(), b"done");
+        tokio::time::timeout(SHUTDOWN_TIMEOUT, self.endpoint.wait_idle())
+            .await
+            .ok();
+    }
+}
+
+/// Where endpoints get their certificate. Endpoints made with clones of one
+/// store share its certificate, generated on first use, or loaded from and
+/// saved to the store's file if it has one. A default store is in memory
+/// only, so endpoints made with separate default stores have separate
+/// certificates.
+#[derive(Clone, Default)]
+pub struct CertStore {
+    path: Option<PathBuf>,
+    identity: Arc<Mutex<Option<Arc<EndpointIdentity>>>>,
+}
+
+impl CertStore {
+    /// Persist the endpoint certificate at `path` and reuse it from then on,
+    /// so endpoints don't generate a fresh key pair each time.
+    pub fn persisted(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: Some(path.into()),
+            identity: Arc::default(),
+        }
+    }
+
+    /// SHA-256 fingerprint of the store's certificate, for peers to put on
+    /// their allowlists.
+    pub fn fingerprint(&self) -> AppResult<[u8; 32]> {
+        let identity = self.identity()?;
+        Ok(fingerprint_of(&CertificateDer::from(
+            identity.cert_der.clone(),
+        )))
+    }
+
+    /// The store's identity: cached in memory, else loaded from its file,
+    /// else freshly generated (and persisted when there is a file).
+    fn identity(&self) -> AppResult<Arc<EndpointIdentity>> {
+        let mut cached = self.identity.lock().unwrap();
+        if let Some(identity) = cached.as_ref() {
+            return Ok(identity.clone());
+        }
+
+        let identity = match &self.path {
+            Some(path) => EndpointIdentity::load_or_generate(path)?,
+            None => EndpointIdentity::generate()?,
+        };
+        let identity = Arc::new(identity);
+        *cached = Some(identity.clone());
+        Ok(identity)
+    }
+}
+
+impl std::fmt::Debug for CertStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertStore")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Two stores are equal when they hand out the same certificate.
+impl PartialEq for CertStore {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.identity, &other.identity)
+    }
+}
+
+impl Eq for CertStore {}
+
+/// Self-signed certificate and PKCS#8 key used by QUIC endpoints.
+#[derive(Serialize, Deserialize)]
+struct EndpointIdentity {
+    #[serde(with = "serde_bytes")]
+    cert_der: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    key_der: Vec<u8>,
+}
+
+impl EndpointIdentity {
+    fn generate() -> AppResult<Self> {
+        let subject_alt_names = vec!["relay.local".to_string()];
+        let cert_params = rcgen::CertificateParams::new(subject_alt_names)
+            .map_err(|e| AppError::Crypto(format!("cert params: {e}")))?;
+        let key_pair =
+            rcgen::KeyPair::generate().map_err(|e| AppError::Crypto(format!("keygen: {e}")))?;
+        let cert = cert_params
+            .self_signed(&key_pair)
+            .map_err(|e| AppError::Crypto(format!("self-sign: {e}")))?;
+
+        Ok(Self {
+            cert_der: cert.der().to_vec(),
+            key_der: key_pair.serialize_der(),
+        })
+    }
+
+    fn load_or_generate(path: &Path) -> AppResult<Self> {
+        let load_err = match Self::load(path) {
+            Ok(identity) => {
+                info!("loaded endpoint certificate from {}", path.display());
+                return Ok(identity);
+            }
+            Err(e) => e,
+        };
+
+        let identity = Self::generate().map_err(|gen_err| {
+            AppError::Crypto(format!(
+                "no usable endpoint certificate: generating failed ({gen_err}) \
+                 and loading {} failed ({load_err})",
+                path.display()
+            ))
+        })?;
+        if let Err(e) = identity.save(path) {
+            warn!("could not persist endpoint certificate: {e}");
+        }
+        Ok(identity)
+    }
+
+    fn load(path: &Path) -> AppResult<Self> {
+        let bytes = std::fs::read(path)?;
+        let identity: Self = rmp_serde::from_slice(&bytes)
+            .map_err(|e| AppError::Serialization(format!("stored certificate: {e}")))?;
+        // Make sure the key actually pairs with the certificate.
+        rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(identity.cert_der.clone())],
+                PrivatePkcs8KeyDer::from(identity.key_der.clone()).into(),
+            )
+            .map_err(|e| AppError::Crypto(format!("stored certificate: {e}")))?;
+        Ok(identity)
+    }
+
+    /// Write via a temp file so a crash never leaves a truncated certificate.
+    fn save(&self, path: &Path) -> AppResult<()> {
+        let bytes =
+            rmp_serde::to_vec(self).map_err(|e| AppError::Serialization(format!("encode: {e}")))?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, bytes)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+        }
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
 }
 
 impl Drop for QuicEndpoint {
     fn drop(&mut self) {
-        // Use wait_idle=false to avoid blocking in drop.
-        // The connection should drain naturally via QUIC's protocol.
+        // Best effort: closing doesn't wait for buffered stream data, so the
+        // last bytes can be lost. Use `shutdown` when delivery matters.
         self.endpoint.close(0u32.into(), b"done");
     }
 }
 
-/// Accepts any server certificate.
-/// Real authentication comes from SPAKE2 key agreement — if the peer
-/// can decrypt our file chunks, they know the transfer code.
+/// A certificate fingerprint as lowercase hex, the form allowlists use.
+pub fn fingerprint_hex(fingerprint: &[u8; 32]) -> String {
+    fingerprint.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Parse a fingerprint written by [`fingerprint_hex`]; colons and case are ignored.
+pub fn parse_fingerprint(text: &str) -> AppResult<[u8; 32]> {
+    let digits: String = text.chars().filter(|&c| c != ':').collect();
+    let invalid = || AppError::Crypto(format!("invalid certificate fingerprint: {text}"));
+    if digits.len() != 64 || !digits.is_ascii() {
+        return Err(invalid());
+    }
+    let mut fingerprint = [0u8; 32];
+    for (byte, pair) in fingerprint.iter_mut().zip(digits.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(fingerprint)
+}
+
+/// SHA-256 of a DER certificate, as exchanged over signaling.
+fn fingerprint_of(cert: &CertificateDer<'_>) -> [u8; 32] {
+    Sha256::digest(cert.as_ref()).into()
+}
+
+fn verify_tls12(
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &rustls::DigitallySignedStruct,
+) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+    rustls::crypto::verify_tls12_signature(
+        message,
+        cert,
+        dss,
+        &rustls::crypto::ring::default_provider().signature_verification_algorithms,
+    )
+}
+
+fn verify_tls13(
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &rustls::DigitallySignedStruct,
+) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+    rustls::crypto::verify_tls13_signature(
+        message,
+        cert,
+        dss,
+        &rustls::crypto::ring::default_provider().signature_verification_algorithms,
+    )
+}
+
+fn supported_schemes() -> Vec<rustls::SignatureScheme> {
+    rustls::crypto::ring::default_provider()
+        .signature_verification_algorithms
+        .supported_schemes()
+}
+
+/// Accepts only the self-signed server certificate whose fingerprint the
+/// peer sent us, encrypted with the SPAKE2 key. There is no CA chain: the
+/// fingerprint is what ties the QUIC connection to the transfer code.
 #[derive(Debug)]
-struct SkipServerVerification;
+struct PinnedServerCert {
+    fingerprint: [u8; 32],
+}
 
-impl rustls::client::danger::ServerCertVerifier for SkipServerVerification {
+impl rustls::client::danger::ServerCertVerifier for PinnedServerCert {
     fn verify_server_cert(
         &self,
-        _end_entity: &CertificateDer<'_>,
+        end_entity: &CertificateDer<'_>,
         _intermediates: &[CertificateDer<'_>],
         _server_name: &rustls::pki_types::ServerName<'_>,
         _ocsp_response: &[u8],
         _now: rustls::pki_types::UnixTime,
     ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        if fingerprint_of(end_entity) != self.fingerprint {
+            return Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ));
+        }
         Ok(rustls::client::danger::ServerCertVerified::assertion())
     }
 
     fn verify_tls12_signature(
         &self,
-        _message: &[u8],
-        _cert: &CertificateDer<'_>,
-        _dss: &rustls::DigitallySignedStruct,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        verify_tls12(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        verify_tls13(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        supported_schemes()
+    }
+}
+
+/// Requires a client certificate and proof of its key, but leaves judging
+/// the certificate to [`QuicEndpoint::accept_any`], which knows which
+/// fingerprint to expect for the transfer at hand.
+#[derive(Debug)]
+struct AnyClientCert;
+
+impl rustls::server::danger::ClientCertVerifier for AnyClientCert {
+    fn root_hint_subjects(&self) -> &[rustls::DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::server::danger::ClientCertVerified, rustls::Error> {
+        Ok(rustls::server::danger::ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
     ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+        verify_tls12(message, cert, dss)
     }
 
     fn verify_tls13_signature(
         &self,
-        _message: &[u8],
-        _cert: &CertificateDer<'_>,
-        _dss: &rustls::DigitallySignedStruct,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
     ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+        verify_tls13(message, cert, dss)
     }
 
     fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-        rustls::crypto::ring::default_provider()
-            .signature_verification_algorithms
-            .supported_schemes()
+        supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Endpoint options using `store` for the certificate.
+    fn with_store(store: &CertStore) -> NetworkOptions {
+        NetworkOptions {
+            identity: store.clone(),
+            ..NetworkOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_persisted_cert_reused() {
+        let temp = tempfile::tempdir().unwrap();
+        let store = temp.path().join("endpoint-cert.bin");
+        let options = with_store(&CertStore::persisted(&store));
+
+        let first = QuicEndpoint::with_options(0, &options).await.unwrap();
+        assert!(store.exists(), "certificate should be persisted");
+        let second = QuicEndpoint::with_options(0, &options).await.unwrap();
+        assert_eq!(first.cert_fingerprint(), second.cert_fingerprint());
+
+        // A fresh process (empty in-memory cache) loads the same certificate.
+        let reopened = CertStore::persisted(&store);
+        let third = QuicEndpoint::with_options(0, &with_store(&reopened))
+            .await
+            .unwrap();
+        assert_eq!(first.cert_fingerprint(), third.cert_fingerprint());
+        assert_eq!(reopened.fingerprint().unwrap(), first.cert_fingerprint());
+
+        // A corrupt store is replaced rather than failing endpoint creation.
+        std::fs::write(&store, b"garbage").unwrap();
+        let fourth = QuicEndpoint::with_options(0, &with_store(&CertStore::persisted(&store)))
+            .await
+            .unwrap();
+        assert_ne!(first.cert_fingerprint(), fourth.cert_fingerprint());
+        assert!(EndpointIdentity::load(&store).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_separate_stores_have_separate_certificates() {
+        let a = QuicEndpoint::new(0).await.unwrap();
+        let b = QuicEndpoint::new(0).await.unwrap();
+        assert_ne!(a.cert_fingerprint(), b.cert_fingerprint());
+
+        // Clones of one store share it.
+        let shared = with_store(&CertStore::default());
+        let c = QuicEndpoint::with_options(0, &shared).await.unwrap();
+        let d = QuicEndpoint::with_options(0, &shared.clone())
+            .await
+            .unwrap();
+        assert_eq!(c.cert_fingerprint(), d.cert_fingerprint());
+        assert_eq!(shared.identity.fingerprint().unwrap(), c.cert_fingerprint());
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_turns_away_unknown_peers() {
+        let receiver = QuicEndpoint::new(0).await.unwrap();
+        let receiver_fp = receiver.cert_fingerprint();
+
+        for (allowlist, allowed) in [([0xAAu8; 32], false), (receiver_fp, true)] {
+            let sender = QuicEndpoint::new(0)
+                .await
+                .unwrap()
+                .with_peer_allowlist([allowlist]);
+            let sender_fp = sender.cert_fingerprint();
+            assert_ne!(sender_fp, receiver_fp);
+            let addr: SocketAddr = format!("127.0.0.1:{}", sender.local_addr().unwrap().port())
+                .parse()
+                .unwrap();
+            let (accepted, dialed) = tokio::join!(
+                sender.accept_any(&receiver_fp),
+                receiver.connect(addr, &sender_fp)
+            );
+            if allowed {
+                accepted.expect("allowlisted peer should be accepted");
+                continue;
+            }
+            match accepted {
+                Err(AppError::PeerNotAllowed(fp)) => assert_eq!(fp, fingerprint_hex(&receiver_fp)),
+                other => panic!("expected PeerNotAllowed, got {other:?}"),
+            }
+            // The rejected peer sees the connection closed, not a hang.
+            let closed = tokio::time::timeout(Duration::from_secs(5), dialed.unwrap().closed())
+                .await
+                .expect("rejected connection left open");
+            assert!(matches!(
+                closed,
+                quinn::ConnectionError::ApplicationClosed(close) if close.error_code == PEER_NOT_ALLOWED
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_accepts_ipv4_and_ipv6() {
+        let dual_stack = NetworkOptions {
+            family: AddressFamily::DualStack,
+            ..NetworkOptions::default()
+        };
+        let listener = QuicEndpoint::with_options(0, &dual_stack).await.unwrap();
+        assert!(listener.local_addr().unwrap().is_ipv6());
+        let port = listener.local_addr().unwrap().port();
+        let listener_fp = listener.cert_fingerprint();
+
+        let v4 = QuicEndpoint::new(0).await.unwrap();
+        let v6 = QuicEndpoint::with_options(0, &dual_stack).await.unwrap();
+        for (dialer, ip) in [(&v4, "127.0.0.1"), (&v6, "::1")] {
+            let addr = SocketAddr::new(ip.parse().unwrap(), port);
+            let dialer_fp = dialer.cert_fingerprint();
+            let (accepted, dialed) = tokio::join!(
+                listener.accept_any(&dialer_fp),
+                dialer.connect(addr, &listener_fp)
+            );
+            accepted.unwrap_or_else(|e| panic!("{ip}: {e}"));
+            dialed.unwrap_or_else(|e| panic!("{ip}: {e}"));
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_hex_roundtrip() {
+        let fingerprint: [u8; 32] = std::array::from_fn(|i| (i * 9) as u8);
+        let hex = fingerprint_hex(&fingerprint);
+        assert_eq!(parse_fingerprint(&hex).unwrap(), fingerprint);
+        assert_eq!(parse_fingerprint(&hex.to_uppercase()).unwrap(), fingerprint);
+        assert!(parse_fingerprint(&hex[..62]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_delivers_final_message() {
+        let sender = QuicEndpoint::new(0).await.unwrap();
+        let receiver = QuicEndpoint::new(0).await.unwrap();
+        let addr: SocketAddr = format!("127.0.0.1:{}", receiver.local_addr().unwrap().port())
+            .parse()
+            .unwrap();
+        let payload = vec![0x5Au8; 4 * 1024 * 1024];
+        let sender_fingerprint = sender.cert_fingerprint();
+        let receiver_fingerprint = receiver.cert_fingerprint();
+
+        let reader = tokio::spawn(async move {
+            let conn = receiver.accept_any(&sender_fingerprint).await.unwrap();
+            let mut stream = conn.accept_uni().await.unwrap();
+            let received = stream.read_to_end(8 * 1024 * 1024).await.unwrap();
+            conn.close(0u32.into(), b"got it");
+            received
+        });
+
+        let conn = sender.connect(addr, &receiver_fingerprint).await.unwrap();
+        let mut stream = conn.open_uni().await.unwrap();
+        stream.write_all(&payload).await.unwrap();
+        stream.finish().unwrap();
+        // Most of the payload is still buffered here; closing outright would drop it.
+        sender.shutdown().await;
+
+        assert_eq!(reader.await.unwrap(), payload);
+    }
+
+    #[tokio::test]
+    async fn test_wrong_fingerprint_rejected() {
+        let dialer = QuicEndpoint::new(0).await.unwrap();
+        let listener = QuicEndpoint::new(0).await.unwrap();
+        let addr: SocketAddr = format!("127.0.0.1:{}", listener.local_addr().unwrap().port())
+            .parse()
+            .unwrap();
+        let listener_fingerprint = listener.cert_fingerprint();
+        let wrong = [0xEEu8; 32];
+
+        // The dialer expects a different server certificate.
+        let accepting = tokio::spawn(async move {
+            let result = listener.accept_any(&[0u8; 32]).await;
+            (listener, result)
+        });
+        let err = dialer.connect(addr, &wrong).await.unwrap_err();
+        assert!(matches!(err, AppError::Network(_)), "{err:?}");
+        let (listener, accepted) = accepting.await.unwrap();
+        assert!(accepted.is_err());
+
+        // The listener expects a different client certificate.
+        let accepting = tokio::spawn(async move { listener.accept_any(&wrong).await });
+        let conn = dialer.connect(addr, &listener_fingerprint).await.unwrap();
+        let err = accepting.await.unwrap().unwrap_err();
+        assert!(matches!(err, AppError::Crypto(_)), "{err:?}");
+        conn.closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_simultaneous_connect_keeps_one_connection() {
+        let a = QuicEndpoint::new(0).await.unwrap();
+        let b = QuicEndpoint::new(0).await.unwrap();
+        let loopback = |quic: &QuicEndpoint| -> SocketAddr {
+            format!("127.0.0.1:{}", quic.local_addr().unwrap().port())
+                .parse()
+                .unwrap()
+        };
+        let (a_addr, b_addr) = (loopback(&a), loopback(&b));
+        let (a_fp, b_fp) = (a.cert_fingerprint(), b.cert_fingerprint());
+        assert_ne!(a.initiates(&b_fp).unwrap(), b.initiates(&a_fp).unwrap());
+
+        let (conn_a, conn_b) = tokio::join!(
+            a.connect_simultaneous(&[b_addr], &b_fp),
+            b.connect_simultaneous(&[a_addr], &a_fp),
+        );
+        let (conn_a, conn_b) = (conn_a.unwrap(), conn_b.unwrap());
+
+        // Both ends hold the same connection: a stream opened on one arrives
+        // on the other.
+        let (mut send, _recv) = conn_a.open_bi().await.unwrap();
+        send.write_all(b"one").await.unwrap();
+        send.finish().unwrap();
+        let (_send, mut recv) = conn_b.accept_bi().await.unwrap();
+        assert_eq!(recv.read_to_end(16).await.unwrap(), b"one");
+        assert!(conn_a.close_reason().is_none());
+        assert!(conn_b.close_reason().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_closes_on_idle_timeout() {
+        use crate::network::transport::Transport;
+        use crate::protocol::messages::PeerMessage;
+        use crate::transfer::session::TransferRole;
+
+        // Without pings a quiet connection is closed as idle; with pings
+        // more often than the timeout it stays up past it.
+        for (keep_alive_ms, stays_up) in [(0, false), (100, true)] {
+            let quiet = QuicEndpoint::with_options(
+                0,
+                &NetworkOptions {
+                    idle_timeout_ms: Some(400),
+                    keep_alive_ms: Some(keep_alive_ms),
+                    ..NetworkOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+            let peer = QuicEndpoint::new(0).await.unwrap();
+            let addr: SocketAddr = format!("127.0.0.1:{}", peer.local_addr().unwrap().port())
+                .parse()
+                .unwrap();
+            let (quiet_fp, peer_fp) = (quiet.cert_fingerprint(), peer.cert_fingerprint());
+            let (accepted, dialed) =
+                tokio::join!(peer.accept_any(&quiet_fp), quiet.connect(addr, &peer_fp));
+            let (accepted, dialed) = (accepted.unwrap(), dialed.unwrap());
+
+            let mut sender = Transport::direct(&dialed, TransferRole::Sender)
+                .await
+                .unwrap();
+            sender.send_peer_message(&PeerMessage::Ping).await.unwrap();
+            let mut receiver = Transport::direct(&accepted, TransferRole::Receiver)
+                .await
+                .unwrap();
+            assert!(matches!(
+                receiver.recv_peer_message().await,
+                Ok(PeerMessage::Ping)
+            ));
+
+            let next =
+                tokio::time::timeout(Duration::from_millis(1500), receiver.recv_peer_message())
+                    .await;
+            if stays_up {
+                assert!(next.is_err(), "connection closed despite pings");
+            } else {
+                assert!(matches!(next, Ok(Err(AppError::IdleTimeout))), "{next:?}");
+            }
+        }
     }
 }
@@ -5,13 +5,20 @@
 // interface as QUIC streams but over WebSocket binary frames.
 //
 // Wire format: same as QUIC — 4-byte big-endian length prefix + MessagePack payload.
+// A message normally fills one binary frame; one larger than the frame limit
+// continues across the frames that follow, and the receiver reassembles it
+// from the length prefix.
 
 use futures_util::{SinkExt, StreamExt};
 use tokio_tungstenite::tungstenite::Message;
 use tracing::debug;
 
 use crate::error::{AppError, AppResult};
-use crate::protocol::messages::PeerMessage;
+use crate::protocol::messages::{decode_message, PeerMessage, MAX_MESSAGE_SIZE};
+
+/// Largest binary frame sent by default. The signaling server drops relay
+/// frames over 16MB, so larger messages are split to stay under that.
+pub const MAX_RELAY_FRAME: usize = 16 * 1024 * 1024;
 
 /// The underlying WebSocket stream type (same as signaling).
 pub type WsStream =
@@ -20,70 +27,125 @@ pub type WsStream =
 /// A relay stream wrapping a WebSocket for peer-to-peer message exchange.
 pub struct RelayStream {
     ws: WsStream,
+    max_frame: usize,
+    max_message: usize,
+    /// Frames of the message being received, length prefix included. Kept
+    /// here rather than in `recv_message` so a call dropped mid-message
+    /// loses nothing.
+    partial: Vec<u8>,
+    /// The payload length from `partial`'s prefix, once it's all arrived.
+    expected: Option<usize>,
 }
 
 impl RelayStream {
     /// Wrap an existing WebSocket connection as a relay stream.
     pub fn new(ws: WsStream) -> Self {
-        Self { ws }
+        Self {
+            ws,
+            max_frame: MAX_RELAY_FRAME,
+            max_message: MAX_MESSAGE_SIZE,
+            partial: Vec::new(),
+            expected: None,
+        }
+    }
+
+    /// Split outgoing messages into binary frames of at most `bytes`
+    /// (length prefix included) instead of [`MAX_RELAY_FRAME`].
+    pub fn with_max_frame(mut self, bytes: usize) -> Self {
+        self.max_frame = bytes.max(1);
+        self
+    }
+
+    /// Reject incoming messages larger than `bytes` instead of
+    /// [`MAX_MESSAGE_SIZE`].
+    pub fn with_max_message(mut self, bytes: usize) -> Self {
+        self.max_message = bytes;
+        self
     }
 
-    /// Send a PeerMessage as a binary WebSocket frame.
+    /// Send a PeerMessage as one binary WebSocket frame, or several if it's
+    /// over the frame limit.
     /// Format: 4-byte big-endian length + MessagePack payload.
     pub async fn send_message(&mut self, msg: &PeerMessage) -> AppResult<()> {
         let payload = rmp_serde::to_vec(msg)
             .map_err(|e| AppError::Serialization(format!("relay encode: {e}")))?;
 
         let len = payload.len() as u32;
-        let mut frame = Vec::with_capacity(4 + payload.len());
-        frame.extend_from_slice(&len.to_be_bytes());
-        frame.extend_from_slice(&payload);
+        let mut message = Vec::with_capacity(4 + payload.len());
+        message.extend_from_slice(&len.to_be_bytes());
+        message.extend_from_slice(&payload);
 
+        if message.len() <= self.max_frame {
+            return self.send_frame(message).await;
+        }
+        debug!(
+            "relay: splitting {}-byte message into {}-byte frames",
+            message.len(),
+            self.max_frame
+        );
+        for frame in message.chunks(self.max_frame) {
+            self.send_frame(frame.to_vec()).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_frame(&mut self, frame: Vec<u8>) -> AppResult<()> {
         self.ws
             .send(Message::Binary(frame.into()))
             .await
-            .map_err(|e| AppError::WebSocket(format!("relay send: {e}")))?;
-
-        Ok(())
+            .map_err(|e| AppError::WebSocket(format!("relay send: {e}")))
     }
 
-    /// Receive a PeerMessage from a binary WebSocket frame.
+    /// Receive a PeerMessage, reassembling it if it spans several binary
+    /// WebSocket frames. Cancel-safe: a call dropped between frames leaves
+    /// the ones it read for the next call to carry on from.
     pub async fn recv_message(&mut self) -> AppResult<PeerMessage> {
         loop {
             let raw = self
                 .ws
                 .next()
                 .await
-                .ok_or_else(|| AppError::WebSocket("relay connection closed".into()))?
+                .ok_or_else(AppError::peer_disconnected)?
                 .map_err(|e| AppError::WebSocket(format!("relay recv: {e}")))?;
 
             match raw {
                 Message::Binary(data) => {
-                    if data.len() < 4 {
-                        return Err(AppError::Transfer(
-                            "relay message too short (< 4 bytes)".into(),
-                        ));
+                    if data.is_empty() {
+                        return Err(AppError::Transfer("relay frame is empty".into()));
                     }
+                    self.partial.extend_from_slice(&data);
 
-                    let len =
-                        u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                    let buf = &self.partial;
+                    if self.expected.is_none() && buf.len() >= 4 {
+                        let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+                        if len > self.max_message {
+                            return Err(AppError::Transfer(format!(
+                                "relay message too large: {len} bytes (limit {})",
+                                self.max_message
+                            )));
+                        }
+                        self.expected = Some(len);
+                    }
+                    let Some(len) = self.expected else {
+                        continue;
+                    };
 
-                    if data.len() != 4 + len {
+                    let received = buf.len() - 4;
+                    if received < len {
+                        continue;
+                    }
+                    let buf = std::mem::take(&mut self.partial);
+                    self.expected = None;
+                    if received > len {
                         return Err(AppError::Transfer(format!(
-                            "relay message length mismatch: header says {len}, got {} payload bytes",
-                            data.len() - 4
+                            "relay message length mismatch: header says {len}, got {received} payload bytes"
                         )));
                     }
 
-                    let msg: PeerMessage = rmp_serde::from_slice(&data[4..]).map_err(|e| {
-                        AppError::Serialization(format!("relay decode: {e}"))
-                    })?;
-
-                    return Ok(msg);
-                }
-                Message::Close(_) => {
-                    return Err(AppError::WebSocket("relay connection closed by peer".into()));
+                    return decode_message(&buf[4..], "relay");
                 }
+                // The relay closes our end once the peer's is gone.
+                Message::Close(_) => return Err(AppError::peer_disconnected()),
                 Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {
                     continue;
                 }
@@ -102,3 +164,135 @@ impl RelayStream {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::MaybeTlsStream;
+
+    /// Two ends of a loopback WebSocket, unwrapped.
+    async fn ws_pair() -> (WsStream, WsStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::join!(
+            async {
+                let tcp = TcpStream::connect(addr).await.unwrap();
+                tokio_tungstenite::client_async(format!("ws://{addr}"), MaybeTlsStream::Plain(tcp))
+                    .await
+                    .unwrap()
+                    .0
+            },
+            async {
+                let (tcp, _) = listener.accept().await.unwrap();
+                tokio_tungstenite::accept_async(MaybeTlsStream::Plain(tcp))
+                    .await
+                    .unwrap()
+            }
+        )
+    }
+
+    fn chunk(len: usize) -> PeerMessage {
+        PeerMessage::FileChunk {
+            file_index: 0,
+            chunk_index: 7,
+            data: (0..len).map(|i| i as u8).collect(),
+            nonce: [3; 12],
+            compressed: false,
+        }
+    }
+
+    fn assert_chunk(msg: PeerMessage, len: usize) {
+        match msg {
+            PeerMessage::FileChunk {
+                chunk_index, data, ..
+            } => {
+                assert_eq!(chunk_index, 7);
+                assert_eq!(data, (0..len).map(|i| i as u8).collect::<Vec<_>>());
+            }
+            other => panic!("expected a chunk, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_reassembled_across_frames() {
+        let (mut raw, ws) = ws_pair().await;
+        let mut relay = RelayStream::new(ws);
+
+        // One message, its length prefix and payload split over two frames.
+        let payload = rmp_serde::to_vec(&chunk(1000)).unwrap();
+        let mut message = (payload.len() as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(&payload);
+        let (first, second) = message.split_at(2);
+        raw.send(Message::Binary(first.to_vec().into()))
+            .await
+            .unwrap();
+        raw.send(Message::Binary(second.to_vec().into()))
+            .await
+            .unwrap();
+        assert_chunk(relay.recv_message().await.unwrap(), 1000);
+
+        // A sender with a small frame limit splits, and the next message
+        // still starts on a frame of its own.
+        let mut sender = RelayStream::new(raw).with_max_frame(256);
+        sender.send_message(&chunk(1000)).await.unwrap();
+        sender.send_message(&PeerMessage::Ping).await.unwrap();
+        assert_chunk(relay.recv_message().await.unwrap(), 1000);
+        assert!(matches!(
+            relay.recv_message().await.unwrap(),
+            PeerMessage::Ping
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_polling_keeps_frames_of_a_split_message() {
+        use crate::network::transport::Transport;
+
+        let (mut raw, ws) = ws_pair().await;
+        let mut transport = Transport::Relayed {
+            ws: RelayStream::new(ws),
+        };
+        let payload = rmp_serde::to_vec(&chunk(1000)).unwrap();
+        let mut message = (payload.len() as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(&payload);
+        let (first, second) = message.split_at(300);
+
+        // A poll that reads the first frame and gives up must not drop it.
+        raw.send(Message::Binary(first.to_vec().into()))
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(transport.try_recv_peer_message().is_none());
+
+        raw.send(Message::Binary(second.to_vec().into()))
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let msg = transport
+            .try_recv_peer_message()
+            .expect("message should be complete")
+            .unwrap();
+        assert_chunk(msg, 1000);
+
+        // The next message starts cleanly.
+        RelayStream::new(raw)
+            .send_message(&PeerMessage::Ping)
+            .await
+            .unwrap();
+        assert!(matches!(
+            transport.recv_peer_message().await.unwrap(),
+            PeerMessage::Ping
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_rejected() {
+        let (raw, ws) = ws_pair().await;
+        let mut sender = RelayStream::new(raw).with_max_frame(64);
+        let mut relay = RelayStream::new(ws).with_max_message(512);
+
+        sender.send_message(&chunk(1000)).await.unwrap();
+        let err = relay.recv_message().await.unwrap_err();
+        assert!(err.to_string().contains("too large"), "{err}");
+    }
+}
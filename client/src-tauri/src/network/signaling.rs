@@ -7,17 +7,50 @@
 // 4. Exchange SPAKE2 messages (forwarded by server)
 // 5. Exchange cert fingerprints (encrypted with SPAKE2-derived key)
 // 6. Send "disconnect" and close
+//
+// Sender and receiver may use different servers if those servers federate:
+// the second one to register is bridged to the other's server, which runs
+// signaling and relay as usual. Nothing changes on the client side.
+//
+// With a `ReconnectPolicy`, a connection lost before the transport is up is
+// re-established, backing off between attempts, and re-registered under the
+// same code. When the server re-announces the pair, both sides replay their
+// last handshake message; the exchanges ignore any copy they already
+// consumed. A replay is the same SPAKE2 message, never a fresh one, so a
+// drop mid-exchange can't leave the peers with different keys.
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
 
 use base64::prelude::*;
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
-use tracing::{debug, info};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
 
 use crate::crypto::aes_gcm::{ChunkDecryptor, ChunkEncryptor};
+use crate::crypto::spake::{
+    check_key_confirmation, key_confirmation, malformed_message, SPAKE2_MESSAGE_LEN,
+};
 use crate::error::{AppError, AppResult};
+use crate::network::interfaces;
+use crate::network::tls::{self, SignalingTls, WsStream};
+use crate::transfer::code::redacted;
+
+/// Largest SPAKE2 message accepted from the peer, in decoded bytes. Anything
+/// much past [`SPAKE2_MESSAGE_LEN`] is not a SPAKE2 message.
+pub const MAX_SPAKE2_MESSAGE: usize = 2 * SPAKE2_MESSAGE_LEN;
+
+/// How long to wait for the other side to join unless told otherwise.
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Tries at each server in [`SignalingClient::connect_any`] before moving
+/// on to the next.
+const CONNECT_ATTEMPTS: u32 = 3;
+/// Pause before the second try at a server; doubles with each further try.
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(250);
 
 /// Information about a peer's network addresses.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,10 +62,79 @@ pub struct PeerInfo {
     pub local_ip: String,
     #[serde(default)]
     pub local_port: u16,
+    /// Every address the peer may be reachable on: its interfaces, then
+    /// its STUN-reflexive address. Empty from older peers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub candidates: Vec<SocketAddr>,
+}
+
+impl PeerInfo {
+    /// Addresses to try for this peer, without repeats: the advertised
+    /// candidates, then the local and public addresses older peers send
+    /// on their own.
+    pub fn candidate_addrs(&self) -> Vec<SocketAddr> {
+        let legacy = [
+            (&self.local_ip, self.local_port),
+            (&self.public_ip, self.public_port),
+        ]
+        .into_iter()
+        .filter(|&(_, port)| port > 0)
+        .filter_map(|(ip, port)| {
+            // IPv6 literals may come bracketed, as in a URL.
+            let ip = ip.trim_start_matches('[').trim_end_matches(']');
+            Some(SocketAddr::new(ip.parse().ok()?, port))
+        });
+
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        for addr in self.candidates.iter().copied().chain(legacy) {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        addrs
+    }
+}
+
+/// How a `SignalingClient` recovers when its connection drops during
+/// discovery (before relay mode or a direct connection takes over).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Reconnects allowed over the client's lifetime.
+    pub max_attempts: u32,
+    /// Pause before the first attempt, giving the server time to notice the
+    /// drop. Doubles with each further attempt.
+    pub delay: Duration,
+    /// Longest pause between attempts, however many have failed.
+    pub max_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Fail on the first dropped connection.
+    pub const NEVER: Self = Self {
+        max_attempts: 0,
+        delay: Duration::ZERO,
+        max_delay: Duration::ZERO,
+    };
+
+    /// Pause before reconnect `attempt` (counting from 1).
+    fn delay_before(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(16);
+        (self.delay * (1 << doublings)).min(self.max_delay)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+        }
+    }
 }
 
 /// Message format matching the Go server's SignalMessage.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct SignalMessage {
     #[serde(rename = "type")]
     msg_type: String,
@@ -48,68 +150,175 @@ struct SignalMessage {
     payload: Option<serde_json::Value>,
 }
 
-type WsStream =
-    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
-
 /// WebSocket client for the signaling server.
 pub struct SignalingClient {
     ws: WsStream,
+    /// `/ws/{code}` URL, kept for reconnecting.
+    url: String,
+    /// How the server's certificate is checked, kept for reconnecting.
+    tls: SignalingTls,
+    /// Role we registered as, replayed after a reconnect.
+    role: Option<String>,
+    /// The QUIC address we registered, if any.
+    advertised_addr: Option<SocketAddr>,
+    /// The public address STUN reported for `advertised_addr`.
+    public_addr: Option<SocketAddr>,
+    /// The peer's most recent network info (peer_joined or address_update).
+    peer_info: Option<PeerInfo>,
+    /// Aborts any wait on the server; see [`with_cancel`](Self::with_cancel).
+    cancel: CancellationToken,
+    reconnect: ReconnectPolicy,
+    reconnects_used: u32,
+    /// Our most recent SPAKE2 or fingerprint message, replayed when the
+    /// peer (re)joins in case it was lost with a dropped connection.
+    last_handshake: Option<SignalMessage>,
+    /// Cleared once signaling hands over to relay mode; reconnecting after
+    /// that would lose relay state.
+    discovering: bool,
+    /// A peer message read ahead of the exchange it belongs to, handed
+    /// back by the next read.
+    pending: Option<SignalMessage>,
 }
 
 impl SignalingClient {
     /// Connect to the signaling server for the given transfer code.
     pub async fn connect(server_url: &str, code: &str) -> AppResult<Self> {
+        Self::connect_with_tls(server_url, code, SignalingTls::default()).await
+    }
+
+    /// Connect as [`connect`](Self::connect), checking a `wss://` server's
+    /// certificate per `tls`.
+    pub async fn connect_with_tls(
+        server_url: &str,
+        code: &str,
+        tls: SignalingTls,
+    ) -> AppResult<Self> {
         // Normalize URL: strip trailing slash, build ws path
         let base = server_url.trim_end_matches('/');
         let url = format!("{base}/ws/{code}");
-        info!("signaling: connecting to {url}");
+        info!("signaling: connecting to {base} (code {})", redacted(code));
 
-        let (ws, _response) = connect_async(&url)
-            .await
-            .map_err(|e| AppError::WebSocket(format!("failed to connect: {e}")))?;
+        let ws = tls::connect(&url, &tls).await?;
 
         info!("signaling: connected");
-        Ok(Self { ws })
+        Ok(Self {
+            ws,
+            url,
+            tls,
+            role: None,
+            advertised_addr: None,
+            public_addr: None,
+            peer_info: None,
+            cancel: CancellationToken::new(),
+            reconnect: ReconnectPolicy::NEVER,
+            reconnects_used: 0,
+            last_handshake: None,
+            discovering: true,
+            pending: None,
+        })
+    }
+
+    /// Connect to the first of `server_urls` that answers, trying each a
+    /// few times, backing off between tries, before moving on to the next.
+    /// Fails with every server's error if none does.
+    ///
+    /// Peers only find each other on the same server (or federating ones),
+    /// so both should be given the same list in the same order: each then
+    /// lands on the first one up.
+    pub async fn connect_any(
+        server_urls: &[String],
+        code: &str,
+        tls: SignalingTls,
+    ) -> AppResult<Self> {
+        let mut failures = Vec::new();
+        for server_url in server_urls {
+            for attempt in 1..=CONNECT_ATTEMPTS {
+                match Self::connect_with_tls(server_url, code, tls.clone()).await {
+                    Ok(client) => return Ok(client),
+                    Err(e) if attempt == CONNECT_ATTEMPTS => {
+                        warn!("signaling: giving up on {server_url}: {e}");
+                        failures.push(format!("{server_url}: {e}"));
+                    }
+                    Err(e) => {
+                        let delay = CONNECT_RETRY_DELAY * (1 << (attempt - 1));
+                        warn!("signaling: {server_url}: {e}; retrying in {delay:?}");
+                        tokio::time::sleep(delay).await;
+                    }
+                }
+            }
+        }
+        Err(AppError::WebSocket(if failures.is_empty() {
+            "no signaling server given".into()
+        } else {
+            format!("no signaling server reachable ({})", failures.join("; "))
+        }))
+    }
+
+    /// Stop waiting on the server as soon as `cancel` fires: the pending call
+    /// disconnects from signaling and returns `AppError::Cancelled`.
+    pub fn with_cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
     }
 
-    /// Register with the signaling server as sender or receiver.
+    /// Reconnect and resume according to `policy` if the connection drops
+    /// during discovery.
+    pub fn with_reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Advertise `addr` as our public address when registering, instead of
+    /// leaving the server to work it out. It should come from STUN on the
+    /// socket whose local address is registered.
+    pub fn with_public_addr(mut self, addr: Option<SocketAddr>) -> Self {
+        self.public_addr = addr;
+        self
+    }
+
+    /// Register with the signaling server as sender or receiver. If the
+    /// connection dropped since `connect`, reconnect per the policy and
+    /// register on the new one.
     pub async fn register(
         &mut self,
         role: &str,
         local_addr: Option<SocketAddr>,
     ) -> AppResult<()> {
-        let peer_info = local_addr.map(|addr| {
-            let ip = addr.ip();
-            // Replace unspecified (0.0.0.0) with actual local IP
-            let local_ip = if ip.is_unspecified() {
-                get_local_ip().unwrap_or_else(|| "127.0.0.1".to_string())
-            } else {
-                ip.to_string()
-            };
-            PeerInfo {
-                public_ip: String::new(), // server detects this
-                public_port: 0,
-                local_ip,
-                local_port: addr.port(),
-            }
-        });
+        self.role = Some(role.into());
+        self.advertised_addr = local_addr;
+        match self.send_register().await {
+            Err(e) => self.reconnect_after(e).await,
+            ok => ok,
+        }
+    }
 
-        let msg = SignalMessage {
-            msg_type: "register".into(),
-            role: Some(role.into()),
-            peer_info,
-            message: None,
-            code: None,
-            payload: None,
-        };
+    /// The QUIC address we registered, if any.
+    pub fn advertised_addr(&self) -> Option<SocketAddr> {
+        self.advertised_addr
+    }
 
-        self.send_json(&msg).await?;
-        info!("signaling: registered as {role}");
-        Ok(())
+    /// The peer's latest known network info, including any address updates
+    /// received since `wait_for_peer`.
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        self.peer_info.as_ref()
     }
 
-    /// Wait for the peer to join. Returns the peer's network info.
-    pub async fn wait_for_peer(&mut self) -> AppResult<PeerInfo> {
+    /// Wait up to `timeout` for the peer to join. Returns the peer's network
+    /// info. On timeout the connection is closed and
+    /// `AppError::ConnectionTimeout` is returned; if another peer already
+    /// registered in our role under the code, `AppError::CodeInUse`.
+    pub async fn wait_for_peer(&mut self, timeout: Duration) -> AppResult<PeerInfo> {
+        match tokio::time::timeout(timeout, self.recv_peer_joined()).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.close().await;
+                info!("signaling: no peer within {timeout:?}, disconnected");
+                Err(AppError::ConnectionTimeout)
+            }
+        }
+    }
+
+    async fn recv_peer_joined(&mut self) -> AppResult<PeerInfo> {
         loop {
             let msg = self.recv_json().await?;
             match msg.msg_type.as_str() {
@@ -118,12 +327,10 @@ impl SignalingClient {
                         AppError::WebSocket("peer_joined missing peer_info".into())
                     })?;
                     info!("signaling: peer joined (public={}:{})", info.public_ip, info.public_port);
+                    self.peer_info = Some(info.clone());
                     return Ok(info);
                 }
-                "error" => {
-                    let err_msg = msg.message.unwrap_or_else(|| "unknown error".into());
-                    return Err(AppError::WebSocket(format!("server error: {err_msg}")));
-                }
+                "error" => return Err(server_error(msg)),
                 other => {
                     debug!("signaling: ignoring message type '{other}' while waiting for peer");
                 }
@@ -131,8 +338,24 @@ impl SignalingClient {
         }
     }
 
+    /// Like [`wait_for_peer`](Self::wait_for_peer), but give up at `deadline`:
+    /// the registration is dropped so the code stops working, and
+    /// `AppError::SessionExpired` is returned.
+    pub async fn wait_for_peer_until(&mut self, deadline: Instant) -> AppResult<PeerInfo> {
+        match tokio::time::timeout_at(deadline, self.recv_peer_joined()).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.close().await;
+                info!("signaling: no peer before code expiry, disconnected");
+                Err(AppError::SessionExpired)
+            }
+        }
+    }
+
     /// Exchange SPAKE2 messages through the signaling server.
-    /// Sends our outbound message, receives the peer's message.
+    /// Sends our outbound message, receives the peer's message. One too
+    /// short to be a SPAKE2 message fails here, as malformed, rather than
+    /// later looking like a mistyped code.
     pub async fn exchange_spake2(&mut self, outbound: &[u8]) -> AppResult<Vec<u8>> {
         // Send our SPAKE2 message
         let encoded = BASE64_STANDARD.encode(outbound);
@@ -144,7 +367,7 @@ impl SignalingClient {
             peer_info: None,
             payload: None,
         };
-        self.send_json(&msg).await?;
+        self.send_handshake(msg).await?;
         debug!("signaling: sent SPAKE2 message ({} bytes)", outbound.len());
 
         // Wait for peer's SPAKE2 message
@@ -155,16 +378,25 @@ impl SignalingClient {
                     let encoded = msg.message.ok_or_else(|| {
                         AppError::WebSocket("spake2 message missing payload".into())
                     })?;
+                    // Check the encoded length first so a hostile server can't
+                    // make us decode an arbitrarily large blob.
+                    let limit = MAX_SPAKE2_MESSAGE;
+                    if encoded.len() > limit.div_ceil(3) * 4 {
+                        return Err(oversized_spake2(encoded.len() / 4 * 3, limit));
+                    }
                     let decoded = BASE64_STANDARD
                         .decode(&encoded)
                         .map_err(|e| AppError::WebSocket(format!("bad base64: {e}")))?;
+                    if decoded.len() > limit {
+                        return Err(oversized_spake2(decoded.len(), limit));
+                    }
+                    if decoded.len() < SPAKE2_MESSAGE_LEN {
+                        return Err(malformed_message(decoded.len()));
+                    }
                     debug!("signaling: received SPAKE2 message ({} bytes)", decoded.len());
                     return Ok(decoded);
                 }
-                "error" => {
-                    let err_msg = msg.message.unwrap_or_else(|| "unknown error".into());
-                    return Err(AppError::WebSocket(format!("server error: {err_msg}")));
-                }
+                "error" => return Err(server_error(msg)),
                 other => {
                     debug!("signaling: ignoring '{other}' during SPAKE2 exchange");
                 }
@@ -172,6 +404,101 @@ impl SignalingClient {
         }
     }
 
+    /// Swap fresh random nonces with the peer, for a resumed session that
+    /// skips SPAKE2 to derive a key of its own from the imported one.
+    /// Returns the peer's nonce.
+    pub async fn exchange_resume_nonce(&mut self, ours: &[u8; 32]) -> AppResult<[u8; 32]> {
+        let msg = SignalMessage {
+            msg_type: "resume_nonce".into(),
+            message: Some(BASE64_STANDARD.encode(ours)),
+            role: None,
+            code: None,
+            peer_info: None,
+            payload: None,
+        };
+        self.send_handshake(msg).await?;
+        debug!("signaling: sent resume nonce");
+
+        loop {
+            let msg = self.recv_json().await?;
+            match msg.msg_type.as_str() {
+                "resume_nonce" => {
+                    let encoded = msg.message.ok_or_else(|| {
+                        AppError::WebSocket("resume_nonce missing payload".into())
+                    })?;
+                    let decoded = BASE64_STANDARD
+                        .decode(&encoded)
+                        .map_err(|e| AppError::WebSocket(format!("bad base64: {e}")))?;
+                    return decoded.try_into().map_err(|_| {
+                        AppError::Crypto("peer's resume nonce is not 32 bytes".into())
+                    });
+                }
+                "error" => return Err(server_error(msg)),
+                other => {
+                    debug!("signaling: ignoring '{other}' during resume nonce exchange");
+                }
+            }
+        }
+    }
+
+    /// Confirm that both sides derived the same key from SPAKE2, before
+    /// anything is encrypted with it. Each side sends an HMAC over its role;
+    /// a peer's that doesn't check out means the codes differed, and fails
+    /// with `AppError::CodeMismatch`.
+    ///
+    /// A peer that predates confirmation goes straight on to the fingerprint
+    /// exchange (or the relay); its message is kept for that, the key is left
+    /// to fail there if it's wrong, and this returns false. Such a peer also
+    /// predates the [`KeySchedule`](crate::crypto::keyschedule::KeySchedule).
+    pub async fn confirm_key(&mut self, encryption_key: &[u8; 32]) -> AppResult<bool> {
+        let role = self
+            .role
+            .clone()
+            .ok_or_else(|| AppError::WebSocket("key confirmation before register".into()))?;
+        let peer_role = if role == "sender" {
+            "receiver"
+        } else {
+            "sender"
+        };
+        let mac = key_confirmation(encryption_key, &role)?;
+        let msg = SignalMessage {
+            msg_type: "key_confirm".into(),
+            message: Some(BASE64_STANDARD.encode(mac)),
+            role: None,
+            code: None,
+            peer_info: None,
+            payload: None,
+        };
+        self.send_handshake(msg).await?;
+        debug!("signaling: sent key confirmation");
+
+        loop {
+            let msg = self.recv_json().await?;
+            match msg.msg_type.as_str() {
+                "key_confirm" => {
+                    let encoded = msg
+                        .message
+                        .ok_or_else(|| AppError::WebSocket("key_confirm missing payload".into()))?;
+                    let mac = BASE64_STANDARD
+                        .decode(&encoded)
+                        .map_err(|e| AppError::WebSocket(format!("bad base64: {e}")))?;
+                    check_key_confirmation(encryption_key, peer_role, &mac)?;
+                    debug!("signaling: peer confirmed the key");
+                    return Ok(true);
+                }
+                "cert_fingerprint" | "relay_request" => {
+                    info!("signaling: peer doesn't confirm keys, skipping confirmation");
+                    self.pending = Some(msg);
+                    return Ok(false);
+                }
+                "error" => return Err(server_error(msg)),
+                other => {
+                    debug!("signaling: ignoring '{other}' during key confirmation");
+                }
+            }
+        }
+    }
+
     /// Exchange QUIC certificate fingerprints, encrypted with the SPAKE2-derived key.
     /// Returns the peer's cert fingerprint.
     pub async fn exchange_cert_fingerprint(
@@ -179,6 +506,21 @@ impl SignalingClient {
         our_fingerprint: &[u8; 32],
         encryption_key: &[u8; 32],
     ) -> AppResult<[u8; 32]> {
+        self.exchange_cert_fingerprint_or_relay(our_fingerprint, encryption_key)
+            .await?
+            .ok_or_else(|| {
+                AppError::Network("peer asked for the relay instead of a direct connection".into())
+            })
+    }
+
+    /// Like [`exchange_cert_fingerprint`](Self::exchange_cert_fingerprint),
+    /// but a relay-only peer, which skips the exchange and asks for the relay
+    /// straight away, ends it with `None`.
+    pub async fn exchange_cert_fingerprint_or_relay(
+        &mut self,
+        our_fingerprint: &[u8; 32],
+        encryption_key: &[u8; 32],
+    ) -> AppResult<Option<[u8; 32]>> {
         // Encrypt our fingerprint
         let encryptor = ChunkEncryptor::new(encryption_key)?;
         let (ciphertext, nonce) = encryptor.encrypt_one(our_fingerprint)?;
@@ -197,7 +539,7 @@ impl SignalingClient {
             peer_info: None,
             payload: None,
         };
-        self.send_json(&msg).await?;
+        self.send_handshake(msg).await?;
         debug!("signaling: sent cert fingerprint");
 
         // Wait for peer's cert fingerprint
@@ -234,12 +576,13 @@ impl SignalingClient {
                     let mut fingerprint = [0u8; 32];
                     fingerprint.copy_from_slice(&plaintext);
                     debug!("signaling: received peer cert fingerprint");
-                    return Ok(fingerprint);
+                    return Ok(Some(fingerprint));
                 }
-                "error" => {
-                    let err_msg = msg.message.unwrap_or_else(|| "unknown error".into());
-                    return Err(AppError::WebSocket(format!("server error: {err_msg}")));
+                "relay_request" => {
+                    info!("signaling: peer requested relay instead of a fingerprint");
+                    return Ok(None);
                 }
+                "error" => return Err(server_error(msg)),
                 other => {
                     debug!("signaling: ignoring '{other}' during fingerprint exchange");
                 }
@@ -250,6 +593,7 @@ impl SignalingClient {
     /// Request relay mode from the signaling server.
     /// Sends a relay_request, waits for relay_active confirmation.
     pub async fn request_relay(&mut self) -> AppResult<()> {
+        self.discovering = false;
         let msg = SignalMessage {
             msg_type: "relay_request".into(),
             role: None,
@@ -284,6 +628,12 @@ impl SignalingClient {
                     let err_msg = msg.message.unwrap_or_else(|| "unknown error".into());
                     return Err(AppError::WebSocket(format!("relay error: {err_msg}")));
                 }
+                // Without it the relay never activates, so don't wait forever.
+                "peer_disconnected" => {
+                    return Err(AppError::Network(
+                        "peer left before the relay was activated".into(),
+                    ));
+                }
                 other => {
                     debug!("signaling: ignoring '{other}' while waiting for relay_active");
                 }
@@ -294,19 +644,22 @@ impl SignalingClient {
     /// Check for an incoming relay request from the peer.
     /// Returns Ok(true) if a relay_request was received, Ok(false) for other messages.
     pub async fn check_for_relay_request(&mut self) -> AppResult<bool> {
-        let msg = self.recv_json().await?;
-        match msg.msg_type.as_str() {
-            "relay_request" => {
-                info!("signaling: peer requested relay");
-                Ok(true)
-            }
-            "error" => {
-                let err_msg = msg.message.unwrap_or_else(|| "unknown error".into());
-                Err(AppError::WebSocket(format!("server error: {err_msg}")))
-            }
-            other => {
-                debug!("signaling: got '{other}' instead of relay_request");
-                Ok(false)
+        loop {
+            let msg = self.recv_json().await?;
+            match msg.msg_type.as_str() {
+                "relay_request" => {
+                    info!("signaling: peer requested relay");
+                    return Ok(true);
+                }
+                "error" => return Err(server_error(msg)),
+                // A replay from a peer that reconnected; the exchange is done.
+                "spake2" | "cert_fingerprint" => {
+                    debug!("signaling: ignoring replayed '{}'", msg.msg_type);
+                }
+                other => {
+                    debug!("signaling: got '{other}' instead of relay_request");
+                    return Ok(false);
+                }
             }
         }
     }
@@ -319,6 +672,32 @@ impl SignalingClient {
 
     /// Send a disconnect message and close the WebSocket.
     pub async fn disconnect(mut self) -> AppResult<()> {
+        self.close().await;
+        info!("signaling: disconnected");
+        Ok(())
+    }
+
+    // -- Internal helpers --
+
+    /// Register under the role and address recorded by `register`.
+    async fn send_register(&mut self) -> AppResult<()> {
+        let role = self.role.clone().unwrap_or_default();
+        let msg = SignalMessage {
+            msg_type: "register".into(),
+            role: Some(role.clone()),
+            peer_info: self
+                .advertised_addr
+                .map(|addr| local_peer_info(addr, self.public_addr)),
+            message: None,
+            code: None,
+            payload: None,
+        };
+        self.send_json(&msg).await?;
+        info!("signaling: registered as {role}");
+        Ok(())
+    }
+
+    async fn close(&mut self) {
         let msg = SignalMessage {
             msg_type: "disconnect".into(),
             role: None,
@@ -329,11 +708,53 @@ impl SignalingClient {
         };
         self.send_json(&msg).await.ok(); // best-effort
         self.ws.close(None).await.ok();
-        info!("signaling: disconnected");
-        Ok(())
     }
 
-    // -- Internal helpers --
+    /// Send a handshake message, remembering it for replay. If the send
+    /// fails, reconnect; the replay on rejoin delivers it.
+    async fn send_handshake(&mut self, msg: SignalMessage) -> AppResult<()> {
+        let result = self.send_json(&msg).await;
+        self.last_handshake = Some(msg);
+        match result {
+            Err(e) => self.reconnect_after(e).await,
+            ok => ok,
+        }
+    }
+
+    /// Re-establish a dropped connection and re-register, if the policy
+    /// allows. Otherwise hand back `cause`.
+    async fn reconnect_after(&mut self, cause: AppError) -> AppResult<()> {
+        if self.role.is_none() || !self.discovering {
+            return Err(cause);
+        }
+        while self.reconnects_used < self.reconnect.max_attempts {
+            self.reconnects_used += 1;
+            let delay = self.reconnect.delay_before(self.reconnects_used);
+            warn!(
+                "signaling: {cause}; reconnecting in {delay:?} (attempt {}/{})",
+                self.reconnects_used, self.reconnect.max_attempts
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.cancel.cancelled() => return Err(AppError::Cancelled),
+            }
+            let ws = match tls::connect(&self.url, &self.tls).await {
+                Ok(ws) => ws,
+                Err(e) => {
+                    warn!("signaling: reconnect failed: {e}");
+                    continue;
+                }
+            };
+            self.ws = ws;
+            if let Err(e) = self.send_register().await {
+                warn!("signaling: re-register failed: {e}");
+                continue;
+            }
+            info!("signaling: reconnected");
+            return Ok(());
+        }
+        Err(cause)
+    }
 
     async fn send_json(&mut self, msg: &SignalMessage) -> AppResult<()> {
         let json = serde_json::to_string(msg)
@@ -346,22 +767,65 @@ impl SignalingClient {
     }
 
     async fn recv_json(&mut self) -> AppResult<SignalMessage> {
+        if let Some(msg) = self.pending.take() {
+            return Ok(msg);
+        }
+        let cancel = self.cancel.clone();
         loop {
-            let raw = self
-                .ws
-                .next()
-                .await
-                .ok_or_else(|| AppError::WebSocket("connection closed".into()))?
-                .map_err(|e| AppError::WebSocket(format!("recv: {e}")))?;
+            let next = tokio::select! {
+                next = self.ws.next() => Some(next),
+                _ = cancel.cancelled() => None,
+            };
+            let Some(next) = next else {
+                self.close().await;
+                info!("signaling: cancelled, disconnected");
+                return Err(AppError::Cancelled);
+            };
+            let raw = match next {
+                Some(Ok(raw)) => raw,
+                Some(Err(e)) => {
+                    self.reconnect_after(AppError::WebSocket(format!("recv: {e}")))
+                        .await?;
+                    continue;
+                }
+                None => {
+                    self.reconnect_after(AppError::WebSocket("connection closed".into()))
+                        .await?;
+                    continue;
+                }
+            };
 
             match raw {
                 Message::Text(text) => {
                     let msg: SignalMessage = serde_json::from_str(&text)
                         .map_err(|e| AppError::WebSocket(format!("deserialize: {e}")))?;
+
+                    // Address updates can arrive at any point; record them
+                    // instead of surfacing them to each exchange loop.
+                    if msg.msg_type == "address_update" {
+                        if let Some(info) = msg.peer_info {
+                            info!("signaling: peer address updated (port {})", info.local_port);
+                            self.peer_info = Some(info);
+                        }
+                        continue;
+                    }
+
+                    // The peer (re)joined after we started the handshake: it
+                    // may have missed our last message, so send it again.
+                    if msg.msg_type == "peer_joined" {
+                        if let Some(last) = self.last_handshake.clone() {
+                            info!("signaling: peer rejoined, replaying '{}'", last.msg_type);
+                            self.peer_info = msg.peer_info.or(self.peer_info.take());
+                            self.send_json(&last).await?;
+                            continue;
+                        }
+                    }
+
                     return Ok(msg);
                 }
                 Message::Close(_) => {
-                    return Err(AppError::WebSocket("server closed connection".into()));
+                    self.reconnect_after(AppError::WebSocket("server closed connection".into()))
+                        .await?;
                 }
                 Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {
                     // tokio-tungstenite handles ping/pong automatically
@@ -376,11 +840,167 @@ impl SignalingClient {
     }
 }
 
-/// Get the local network IP by connecting a UDP socket to a public address.
-/// This doesn't send any data — it just lets the OS pick the right interface.
-fn get_local_ip() -> Option<String> {
-    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
-    socket.connect("8.8.8.8:80").ok()?;
-    let addr = socket.local_addr().ok()?;
-    Some(addr.ip().to_string())
+/// The error a server `error` message stands for. A code already taken by
+/// another peer in the same role is `CodeInUse`, so a sender can start over
+/// with a fresh one.
+fn server_error(msg: SignalMessage) -> AppError {
+    match msg.code.as_deref() {
+        Some("CODE_IN_USE") => AppError::CodeInUse,
+        _ => {
+            let err_msg = msg.message.unwrap_or_else(|| "unknown error".into());
+            AppError::WebSocket(format!("server error: {err_msg}"))
+        }
+    }
+}
+
+fn oversized_spake2(len: usize, limit: usize) -> AppError {
+    AppError::Crypto(format!(
+        "peer SPAKE2 message too large: {len} bytes (limit {limit})"
+    ))
+}
+
+/// Build the registration info for a local QUIC address and, if known, its
+/// public counterpart.
+fn local_peer_info(addr: SocketAddr, public: Option<SocketAddr>) -> PeerInfo {
+    // A socket bound to 0.0.0.0 answers on every IPv4 interface; one bound
+    // to [::] on every IPv6 one, and on IPv4 ones too if it's dual-stack.
+    // IPv4 comes first, so an IPv6 address is the local IP only when it's
+    // the sole route.
+    let mut ips: Vec<IpAddr> = if addr.ip().is_unspecified() {
+        let mut ips: Vec<IpAddr> = interfaces::reachable_ips()
+            .into_iter()
+            .filter(|ip| addr.is_ipv6() || ip.is_ipv4())
+            .collect();
+        ips.sort_by_key(IpAddr::is_ipv6);
+        ips
+    } else {
+        vec![addr.ip()]
+    };
+    if ips.is_empty() {
+        ips.push(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    let mut candidates: Vec<SocketAddr> = ips
+        .iter()
+        .map(|&ip| SocketAddr::new(ip, addr.port()))
+        .collect();
+    candidates.extend(public);
+    PeerInfo {
+        // Empty for the server to fill in from the connection.
+        public_ip: public.map(|a| a.ip().to_string()).unwrap_or_default(),
+        public_port: public.map_or(0, |a| a.port()),
+        local_ip: ips[0].to_string(),
+        local_port: addr.port(),
+        candidates,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Collects formatted log output so tests can inspect it.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_log_masks_code() {
+        crate::transfer::code::set_log_redaction(true);
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        // Nothing listens on port 1; only the log line matters.
+        let _ = SignalingClient::connect("ws://127.0.0.1:1", "7-guitar-palace").await;
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            output.contains("7-***-***"),
+            "code not logged masked: {output}"
+        );
+        assert!(
+            !output.contains("guitar"),
+            "code leaked into logs: {output}"
+        );
+    }
+
+    #[test]
+    fn test_reconnect_backoff_doubles_to_cap() {
+        let policy = ReconnectPolicy::default();
+        let delays: Vec<u64> = (1..=6)
+            .map(|attempt| policy.delay_before(attempt).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [500, 1000, 2000, 4000, 8000, 8000]);
+        assert_eq!(policy.delay_before(u32::MAX), policy.max_delay);
+    }
+
+    #[test]
+    fn test_candidates_cover_interfaces_and_legacy_fields() {
+        let public: SocketAddr = "203.0.113.7:41000".parse().unwrap();
+        let info = local_peer_info("0.0.0.0:5000".parse().unwrap(), Some(public));
+        assert_eq!(info.candidates.last(), Some(&public));
+        assert!(info.candidates[..info.candidates.len() - 1]
+            .iter()
+            .all(|addr| addr.is_ipv4() && addr.port() == 5000 && !addr.ip().is_unspecified()));
+        assert_eq!(info.local_ip, info.candidates[0].ip().to_string());
+
+        // An older peer sends no candidates; its two fields still count,
+        // and nothing is tried twice.
+        let old = PeerInfo {
+            public_ip: "203.0.113.7".into(),
+            public_port: 41000,
+            local_ip: "192.168.1.5".into(),
+            local_port: 41000,
+            candidates: Vec::new(),
+        };
+        let json = serde_json::to_string(&old).unwrap();
+        assert!(!json.contains("candidates"), "{json}");
+        let both = PeerInfo {
+            candidates: vec![public],
+            ..old.clone()
+        };
+        assert_eq!(
+            both.candidate_addrs(),
+            [public, "192.168.1.5:41000".parse().unwrap()]
+        );
+        assert_eq!(
+            old.candidate_addrs()[0],
+            "192.168.1.5:41000".parse().unwrap()
+        );
+
+        // A [::] socket may be dual-stack: IPv4 interfaces lead, and IPv6
+        // literals parse with or without brackets.
+        let dual = local_peer_info("[::]:5000".parse().unwrap(), None);
+        assert!(dual
+            .candidates
+            .windows(2)
+            .all(|w| w[0].is_ipv4() || w[1].is_ipv6()));
+        let bracketed = PeerInfo {
+            public_ip: String::new(),
+            public_port: 0,
+            local_ip: "[::1]".into(),
+            local_port: 5000,
+            candidates: Vec::new(),
+        };
+        assert_eq!(bracketed.candidate_addrs(), ["[::1]:5000".parse().unwrap()]);
+    }
 }
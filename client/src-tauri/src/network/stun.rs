@@ -0,0 +1,243 @@
+// STUN (RFC 5389) public address discovery — asks a STUN server which
+// address and port our UDP packets arrive from, i.e. what a NAT maps the
+// socket to. Only the Binding request is implemented, unauthenticated.
+//
+// The query has to go out from the socket QUIC will use: a NAT maps each
+// local port separately, so another socket's mapping says nothing about it.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use crate::error::{AppError, AppResult};
+
+/// A public STUN server, for callers that don't run their own.
+pub const DEFAULT_STUN_SERVER: &str = "stun.l.google.com:19302";
+
+/// How long to wait for a STUN answer before carrying on without one.
+pub const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Pause before resending an unanswered request; doubles with each resend.
+const RETRANSMIT_AFTER: Duration = Duration::from_millis(250);
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const HEADER_LEN: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+/// Ask the STUN server at `server` (`host:port`) for the address `socket`
+/// is seen from. Fails with `AppError::ConnectionTimeout` if no answer
+/// arrives within `timeout`.
+pub async fn public_addr(
+    socket: &UdpSocket,
+    server: &str,
+    timeout: Duration,
+) -> AppResult<SocketAddr> {
+    let txn: [u8; 12] = rand::random();
+    let request = binding_request(&txn);
+    let exchange = async {
+        let local = socket.local_addr()?;
+        let resolved: Vec<SocketAddr> = tokio::net::lookup_host(server).await?.collect();
+        // A dual-stack socket reaches IPv4 servers through v4-mapped addresses.
+        let server_addr = resolved
+            .iter()
+            .find(|addr| addr.is_ipv4() == local.is_ipv4())
+            .copied()
+            .or_else(|| match resolved.first()? {
+                SocketAddr::V4(v4) if local.is_ipv6() => Some(SocketAddr::new(
+                    IpAddr::V6(v4.ip().to_ipv6_mapped()),
+                    v4.port(),
+                )),
+                _ => None,
+            })
+            .ok_or_else(|| AppError::Network(format!("no usable address for {server}")))?;
+
+        let mut buf = [0u8; 512];
+        let mut wait = RETRANSMIT_AFTER;
+        loop {
+            socket.send_to(&request, server_addr).await?;
+            let resend = tokio::time::sleep(wait);
+            tokio::pin!(resend);
+            loop {
+                tokio::select! {
+                    received = socket.recv_from(&mut buf) => {
+                        let (n, from) = received?;
+                        if from != server_addr {
+                            continue;
+                        }
+                        if let Some(mapped) = parse_binding_response(&buf[..n], &txn) {
+                            return Ok::<_, AppError>(mapped);
+                        }
+                    }
+                    _ = &mut resend => break,
+                }
+            }
+            wait *= 2;
+        }
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| AppError::ConnectionTimeout)?
+}
+
+fn binding_request(txn: &[u8; 12]) -> [u8; HEADER_LEN] {
+    let mut msg = [0u8; HEADER_LEN];
+    msg[0..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // Length (bytes 2..4) stays zero: no attributes.
+    msg[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    msg[8..20].copy_from_slice(txn);
+    msg
+}
+
+/// The mapped address in a Binding success answering `txn`, preferring
+/// XOR-MAPPED-ADDRESS (which NATs that rewrite addresses in payloads leave
+/// alone) over the older MAPPED-ADDRESS.
+fn parse_binding_response(msg: &[u8], txn: &[u8; 12]) -> Option<SocketAddr> {
+    if msg.len() < HEADER_LEN
+        || msg[0..2] != BINDING_SUCCESS.to_be_bytes()
+        || msg[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || msg[8..20] != txn[..]
+    {
+        return None;
+    }
+    let len = u16::from_be_bytes([msg[2], msg[3]]) as usize;
+    let mut attrs = msg.get(HEADER_LEN..HEADER_LEN + len)?;
+    let mut mapped = None;
+    while attrs.len() >= 4 {
+        let kind = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let value_len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + value_len)?;
+        match kind {
+            ATTR_XOR_MAPPED_ADDRESS => return parse_address(value, Some(txn)),
+            ATTR_MAPPED_ADDRESS => mapped = parse_address(value, None),
+            _ => {}
+        }
+        // Values are padded to a multiple of four bytes.
+        attrs = attrs
+            .get(4 + value_len.next_multiple_of(4)..)
+            .unwrap_or_default();
+    }
+    mapped
+}
+
+/// Decode an address attribute, undoing the XOR with the magic cookie and
+/// transaction ID if it's an XOR-MAPPED-ADDRESS.
+fn parse_address(value: &[u8], xor_txn: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    let mut mask = [0u8; 16];
+    if let Some(txn) = xor_txn {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(txn);
+    }
+    let port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?])
+        ^ u16::from_be_bytes([mask[0], mask[1]]);
+    let ip = match *value.get(1)? {
+        FAMILY_IPV4 => {
+            let bytes: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            IpAddr::from(std::array::from_fn::<u8, 4, _>(|i| bytes[i] ^ mask[i]))
+        }
+        FAMILY_IPV6 => {
+            let bytes: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            IpAddr::from(std::array::from_fn::<u8, 16, _>(|i| bytes[i] ^ mask[i]))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Binding success for `txn` reporting `addr`, after an attribute the
+    /// parser should skip.
+    fn binding_success(txn: &[u8], addr: SocketAddr) -> Vec<u8> {
+        let mut attrs = Vec::new();
+        // SOFTWARE, 5 bytes padded to 8.
+        attrs.extend_from_slice(&[0x80, 0x22, 0x00, 0x05]);
+        attrs.extend_from_slice(b"relay\0\0\0");
+
+        let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+        mask.extend_from_slice(txn);
+        let (family, ip) = match addr.ip() {
+            IpAddr::V4(ip) => (FAMILY_IPV4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (FAMILY_IPV6, ip.octets().to_vec()),
+        };
+        let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+        attrs.extend_from_slice(&ATTR_XOR_MAPPED_ADDRESS.to_be_bytes());
+        attrs.extend_from_slice(&(4 + ip.len() as u16).to_be_bytes());
+        attrs.extend_from_slice(&[0, family]);
+        attrs.extend_from_slice(&port.to_be_bytes());
+        attrs.extend(ip.iter().zip(&mask).map(|(b, m)| b ^ m));
+
+        let mut msg = BINDING_SUCCESS.to_be_bytes().to_vec();
+        msg.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        msg.extend_from_slice(txn);
+        msg.extend(attrs);
+        msg
+    }
+
+    #[tokio::test]
+    async fn test_public_addr_from_local_responder() {
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = responder.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            // Drop the first request, as a lossy path might.
+            responder.recv_from(&mut buf).await.unwrap();
+            let (n, from) = responder.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, HEADER_LEN);
+            let reply = binding_success(&buf[8..20], from);
+            responder.send_to(&reply, from).await.unwrap();
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mapped = public_addr(&socket, &server, STUN_TIMEOUT).await.unwrap();
+        assert_eq!(mapped, socket.local_addr().unwrap());
+
+        // A server that never answers times out.
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = silent.local_addr().unwrap().to_string();
+        let result = public_addr(&socket, &server, Duration::from_millis(300)).await;
+        assert!(
+            matches!(result, Err(AppError::ConnectionTimeout)),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_quic_endpoint_learns_its_own_mapping() {
+        use crate::network::quic::{NetworkOptions, QuicEndpoint};
+
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let options = NetworkOptions {
+            stun_server: Some(responder.local_addr().unwrap().to_string()),
+            ..NetworkOptions::default()
+        };
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (_, from) = responder.recv_from(&mut buf).await.unwrap();
+            let reply = binding_success(&buf[8..20], from);
+            responder.send_to(&reply, from).await.unwrap();
+        });
+
+        let quic = QuicEndpoint::with_options(0, &options).await.unwrap();
+        let public = quic.public_addr().expect("no public address");
+        assert_eq!(public.port(), quic.local_addr().unwrap().port());
+    }
+
+    #[test]
+    fn test_parse_rejects_other_transactions_and_reads_ipv6() {
+        let txn = [7u8; 12];
+        let addr: SocketAddr = "[2001:db8::1]:40000".parse().unwrap();
+        let reply = binding_success(&txn, addr);
+        assert_eq!(parse_binding_response(&reply, &txn), Some(addr));
+        assert_eq!(parse_binding_response(&reply, &[8u8; 12]), None);
+        assert_eq!(parse_binding_response(&reply[..HEADER_LEN + 4], &txn), None);
+    }
+}
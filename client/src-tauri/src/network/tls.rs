@@ -0,0 +1,239 @@
+// Signaling over TLS — connecting to a `wss://` signaling server, checked
+// against the system's root certificates, extra roots for a private CA, or
+// a pinned certificate.
+//
+// Plain `ws://` URLs connect as before. The trust settings are process-wide,
+// like the endpoint certificate store: an organization running its own
+// server sets them once, and every transfer's signaling uses them.
+
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::http::Uri;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::debug;
+
+use crate::error::{AppError, AppResult};
+use crate::network::quic::{fingerprint_hex, parse_fingerprint};
+use crate::transfer::code::redacted;
+
+pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// How a `wss://` signaling server's certificate is checked. The default
+/// trusts the system's root certificates, as a browser would.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SignalingTls {
+    /// PEM certificates of CAs to trust besides the system roots, for a
+    /// server whose certificate comes from a private CA.
+    pub extra_roots_pem: Vec<String>,
+    /// SHA-256 of the server's certificate, as [`fingerprint_hex`] writes
+    /// it. When set, that certificate alone is accepted, whoever issued it
+    /// and whatever name or dates it carries.
+    pub pinned_fingerprint: Option<String>,
+}
+
+/// The trust settings signaling connections use.
+static CONFIGURED: Mutex<Option<SignalingTls>> = Mutex::new(None);
+
+/// Check every signaling server's certificate per `tls` from now on.
+/// Fails, leaving the settings as they were, if a root or the pin can't be
+/// parsed.
+pub fn set_configured(tls: SignalingTls) -> AppResult<()> {
+    connector(&tls)?;
+    *CONFIGURED.lock().unwrap() = Some(tls);
+    Ok(())
+}
+
+/// The trust settings set with [`set_configured`], or the default.
+pub fn configured() -> SignalingTls {
+    CONFIGURED.lock().unwrap().clone().unwrap_or_default()
+}
+
+/// `text` as it may appear in an error about `url`: the transfer code that
+/// ends a `/ws/{code}` path is masked like any other logged code.
+fn scrubbed(url: &str, text: &str) -> String {
+    match url.rsplit_once("/ws/") {
+        Some((_, code)) if !code.is_empty() => text.replace(code, &redacted(code).to_string()),
+        _ => text.to_string(),
+    }
+}
+
+/// Open a WebSocket to `url`, over TLS checked per `tls` for `wss://`.
+pub async fn connect(url: &str, tls: &SignalingTls) -> AppResult<WsStream> {
+    let uri: Uri = url.parse().map_err(|e| {
+        AppError::WebSocket(format!(
+            "invalid signaling URL '{}': {e}",
+            scrubbed(url, url)
+        ))
+    })?;
+    if uri.scheme_str() != Some("wss") {
+        // Some of tungstenite's errors quote the URL.
+        let (ws, _response) = tokio_tungstenite::connect_async(url)
+            .await
+            .map_err(|e| AppError::WebSocket(scrubbed(url, &format!("failed to connect: {e}"))))?;
+        return Ok(ws);
+    }
+
+    let host = uri
+        .host()
+        .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+        .ok_or_else(|| {
+            AppError::WebSocket(format!("no host in signaling URL '{}'", scrubbed(url, url)))
+        })?;
+    let port = uri.port_u16().unwrap_or(443);
+    let (connector, pin) = connector(tls)?;
+    let tcp = TcpStream::connect((host, port))
+        .await
+        .map_err(|e| AppError::WebSocket(format!("failed to connect: {e}")))?;
+    let stream = connector
+        .connect(host, tcp)
+        .await
+        .map_err(|e| AppError::WebSocket(format!("TLS handshake with {host} failed: {e}")))?;
+
+    if let Some(pin) = pin {
+        let presented = stream
+            .get_ref()
+            .peer_certificate()
+            .ok()
+            .flatten()
+            .and_then(|cert| cert.to_der().ok())
+            .map(|der| <[u8; 32]>::from(Sha256::digest(der)));
+        if presented != Some(pin) {
+            return Err(AppError::WebSocket(format!(
+                "certificate of {host} doesn't match the pinned fingerprint {}",
+                fingerprint_hex(&pin)
+            )));
+        }
+        debug!("signaling: {host} presented the pinned certificate");
+    }
+
+    let (ws, _response) = tokio_tungstenite::client_async(url, MaybeTlsStream::NativeTls(stream))
+        .await
+        .map_err(|e| AppError::WebSocket(format!("failed to connect: {e}")))?;
+    Ok(ws)
+}
+
+/// A TLS connector trusting what `tls` says, and the pin, if any, for the
+/// caller to check once connected.
+fn connector(tls: &SignalingTls) -> AppResult<(tokio_native_tls::TlsConnector, Option<[u8; 32]>)> {
+    let mut builder = native_tls::TlsConnector::builder();
+    for pem in &tls.extra_roots_pem {
+        let root = native_tls::Certificate::from_pem(pem.as_bytes())
+            .map_err(|e| AppError::WebSocket(format!("invalid root certificate: {e}")))?;
+        builder.add_root_certificate(root);
+    }
+    let pin = tls
+        .pinned_fingerprint
+        .as_deref()
+        .map(parse_fingerprint)
+        .transpose()?;
+    if pin.is_some() {
+        // The pin stands in for the chain and name checks.
+        builder
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
+    let connector = builder
+        .build()
+        .map_err(|e| AppError::WebSocket(format!("failed to set up TLS: {e}")))?;
+    Ok((connector.into(), pin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use tokio::net::TcpListener;
+
+    /// A `wss://` server with a certificate for 127.0.0.1 from a private
+    /// CA. Returns its URL, the CA's PEM and the certificate's fingerprint.
+    async fn private_server() -> (String, String, [u8; 32]) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "Relay test CA");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "relay signaling");
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        let identity =
+            native_tls::Identity::from_pkcs8(cert.pem().as_bytes(), key.serialize_pem().as_bytes())
+                .unwrap();
+        let acceptor =
+            tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("wss://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(stream) = acceptor.accept(tcp).await else {
+                        return;
+                    };
+                    if let Ok(mut ws) = tokio_tungstenite::accept_async(stream).await {
+                        while ws.next().await.is_some() {}
+                    }
+                });
+            }
+        });
+        (url, ca.pem(), Sha256::digest(cert.der()).into())
+    }
+
+    #[tokio::test]
+    async fn test_private_server_needs_its_root_or_pin() {
+        let (url, ca_pem, fingerprint) = private_server().await;
+        let url = format!("{url}/ws/7-guitar-palace");
+
+        let untrusted = connect(&url, &SignalingTls::default()).await;
+        assert!(
+            matches!(&untrusted, Err(AppError::WebSocket(e)) if e.contains("TLS handshake")),
+            "{:?}",
+            untrusted.err()
+        );
+
+        let with_root = SignalingTls {
+            extra_roots_pem: vec![ca_pem],
+            ..SignalingTls::default()
+        };
+        connect(&url, &with_root).await.expect("trusted root");
+
+        let pinned = SignalingTls {
+            pinned_fingerprint: Some(fingerprint_hex(&fingerprint)),
+            ..SignalingTls::default()
+        };
+        connect(&url, &pinned).await.expect("pinned certificate");
+
+        let wrong_pin = SignalingTls {
+            pinned_fingerprint: Some(fingerprint_hex(&[7u8; 32])),
+            ..SignalingTls::default()
+        };
+        let mismatch = connect(&url, &wrong_pin).await;
+        assert!(
+            matches!(&mismatch, Err(AppError::WebSocket(e)) if e.contains("pinned")),
+            "{:?}",
+            mismatch.err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bad_url_errors_mask_the_code() {
+        crate::transfer::code::set_log_redaction(true);
+        let url = "ws://bad host/ws/7-guitar-palace";
+        let message = connect(url, &SignalingTls::default())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("ws://bad host/ws/7-***-***"), "{message}");
+        assert!(!message.contains("guitar"), "{message}");
+    }
+}
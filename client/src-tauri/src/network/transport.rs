@@ -2,12 +2,18 @@
 //
 // Both sender and receiver pipelines use `Transport` instead of raw QUIC streams,
 // allowing seamless fallback from direct QUIC to relay mode.
+//
+// On a direct connection the data stream's direction follows the transfer role,
+// not the QUIC handshake: the sender always opens it and the receiver always
+// accepts it, whichever side dialled the connection.
 
 use crate::error::{AppError, AppResult};
 use crate::network::relay::RelayStream;
 use crate::protocol::messages::PeerMessage;
+use crate::transfer::session::TransferRole;
 
-use quinn::{RecvStream, SendStream};
+use futures_util::FutureExt;
+use quinn::{Connection, RecvStream, SendStream};
 
 /// A bidirectional transport for exchanging PeerMessages.
 pub enum Transport {
@@ -15,6 +21,9 @@ pub enum Transport {
     Direct {
         send: SendStream,
         recv: RecvStream,
+        /// The connection the streams belong to, for files sent on streams
+        /// of their own; `None` keeps everything on these two.
+        conn: Option<Connection>,
     },
     /// Relayed through the signaling server's WebSocket.
     Relayed {
@@ -23,6 +32,29 @@ pub enum Transport {
 }
 
 impl Transport {
+    /// Set up the data stream on an established QUIC connection for `role`.
+    ///
+    /// The sender opens the stream; the receiver accepts it. QUIC only
+    /// announces a stream once data is written on it, which works because the
+    /// sender always speaks first (the file offer).
+    pub async fn direct(conn: &Connection, role: TransferRole) -> AppResult<Self> {
+        let (send, recv) = match role {
+            TransferRole::Sender => conn
+                .open_bi()
+                .await
+                .map_err(|e| AppError::Network(format!("failed to open stream: {e}")))?,
+            TransferRole::Receiver => conn
+                .accept_bi()
+                .await
+                .map_err(|e| AppError::Network(format!("failed to accept stream: {e}")))?,
+        };
+        Ok(Transport::Direct {
+            send,
+            recv,
+            conn: Some(conn.clone()),
+        })
+    }
+
     /// Send a PeerMessage to the remote peer.
     pub async fn send_peer_message(&mut self, msg: &PeerMessage) -> AppResult<()> {
         match self {
@@ -33,16 +65,41 @@ impl Transport {
         }
     }
 
-    /// Receive a PeerMessage from the remote peer.
+    /// Receive a PeerMessage from the remote peer. A peer that has left,
+    /// saying `Bye` or closing its end, is reported as
+    /// [`AppError::peer_disconnected`] on either transport.
     pub async fn recv_peer_message(&mut self) -> AppResult<PeerMessage> {
-        match self {
+        let msg = match self {
             Transport::Direct { recv, .. } => {
                 crate::protocol::messages::read_message(recv).await
             }
             Transport::Relayed { ws } => ws.recv_message().await,
+        };
+        msg.and_then(said_bye)
+    }
+
+    /// A message the peer has already sent, without waiting for one. Only
+    /// the relay can be polled like this; a direct transport always says
+    /// `None`.
+    pub fn try_recv_peer_message(&mut self) -> Option<AppResult<PeerMessage>> {
+        match self {
+            // Nothing is lost if the read doesn't complete: the WebSocket
+            // buffers partial frames, and the relay stream the frames of a
+            // message split across several.
+            Transport::Relayed { ws } => ws
+                .recv_message()
+                .now_or_never()
+                .map(|msg| msg.and_then(said_bye)),
+            Transport::Direct { .. } => None,
         }
     }
 
+    /// Tell the peer we're leaving on purpose. Best effort: a peer that's
+    /// already gone has nothing to be told.
+    pub async fn say_bye(&mut self) {
+        self.send_peer_message(&PeerMessage::Bye).await.ok();
+    }
+
     /// Signal that we're done sending (QUIC finish / WebSocket close).
     pub async fn finish_send(&mut self) -> AppResult<()> {
         match self {
@@ -55,8 +112,96 @@ impl Transport {
         }
     }
 
+    /// The QUIC connection under a direct transport, if it can open more
+    /// streams.
+    pub fn connection(&self) -> Option<&Connection> {
+        match self {
+            Transport::Direct { conn, .. } => conn.as_ref(),
+            Transport::Relayed { .. } => None,
+        }
+    }
+
     /// Whether this transport is going through the relay server.
     pub fn is_relayed(&self) -> bool {
         matches!(self, Transport::Relayed { .. })
     }
+
+    /// "direct" or "relay", as progress events name the connection.
+    pub fn connection_type(&self) -> &'static str {
+        if self.is_relayed() {
+            "relay"
+        } else {
+            "direct"
+        }
+    }
+}
+
+/// `msg`, unless it's the peer saying `Bye`.
+fn said_bye(msg: PeerMessage) -> AppResult<PeerMessage> {
+    match msg {
+        PeerMessage::Bye => Err(AppError::peer_disconnected()),
+        msg => Ok(msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::quic::QuicEndpoint;
+    use std::net::SocketAddr;
+
+    fn loopback(quic: &QuicEndpoint) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], quic.local_addr().unwrap().port()))
+    }
+
+    #[tokio::test]
+    async fn test_direct_stream_follows_role_not_dialer() {
+        for sender_dials in [false, true] {
+            let sender = QuicEndpoint::new(0).await.unwrap();
+            let receiver = QuicEndpoint::new(0).await.unwrap();
+            let sender_fp = sender.cert_fingerprint();
+            let receiver_fp = receiver.cert_fingerprint();
+
+            let (sender_conn, receiver_conn) = if sender_dials {
+                tokio::join!(
+                    sender.connect(loopback(&receiver), &receiver_fp),
+                    receiver.accept_any(&sender_fp)
+                )
+            } else {
+                tokio::join!(
+                    sender.accept_any(&receiver_fp),
+                    receiver.connect(loopback(&sender), &sender_fp)
+                )
+            };
+            let (sender_conn, receiver_conn) = (sender_conn.unwrap(), receiver_conn.unwrap());
+
+            // The receiver only sees the stream once the sender writes on it.
+            let mut sending = Transport::direct(&sender_conn, TransferRole::Sender)
+                .await
+                .unwrap();
+            sending.send_peer_message(&PeerMessage::Ping).await.unwrap();
+            let mut receiving = Transport::direct(&receiver_conn, TransferRole::Receiver)
+                .await
+                .unwrap();
+
+            assert!(
+                matches!(
+                    receiving.recv_peer_message().await.unwrap(),
+                    PeerMessage::Ping
+                ),
+                "sender_dials: {sender_dials}"
+            );
+            receiving
+                .send_peer_message(&PeerMessage::Pong)
+                .await
+                .unwrap();
+            assert!(
+                matches!(
+                    sending.recv_peer_message().await.unwrap(),
+                    PeerMessage::Pong
+                ),
+                "sender_dials: {sender_dials}"
+            );
+        }
+    }
 }
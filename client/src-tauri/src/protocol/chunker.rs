@@ -1,59 +1,301 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::sync::CancellationToken;
 
 use crate::crypto::aes_gcm::ChunkEncryptor;
-use crate::crypto::checksum::StreamingChecksum;
-use crate::error::AppResult;
+use crate::crypto::checksum::{ChallengeMac, ChecksumAlgorithm, StreamingChecksum};
+use crate::error::{AppError, AppResult};
+use crate::protocol::pieces::{PieceHashConfig, PieceHasher};
 
-/// Chunk size: 256KB
+/// Chunk size: 256KB. Used over the relay, where a lost message costs a
+/// whole chunk to send again.
 pub const CHUNK_SIZE: usize = 256 * 1024;
 
+/// Chunk size for direct connections: 1MB, for less per-message overhead.
+pub const DIRECT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Smallest chunk size a chunker will use.
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Largest chunk size a chunker will use, and so the most a compressed
+/// chunk may inflate to on the receiver.
+pub const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// The chunk size to use when none is asked for.
+pub fn default_chunk_size(relayed: bool) -> usize {
+    if relayed {
+        CHUNK_SIZE
+    } else {
+        DIRECT_CHUNK_SIZE
+    }
+}
+
+/// How often a tailing chunker looks for new data at the end of its file.
+const TAIL_POLL: Duration = Duration::from_millis(200);
+
+/// Where the bytes of an outgoing file come from.
+#[derive(Debug, Clone)]
+pub enum FileSource {
+    /// A file on disk.
+    Path(PathBuf),
+    /// Bytes held in memory, e.g. a text snippet.
+    Memory(Vec<u8>),
+    /// Bytes from a reader such as stdin or a pipe, which can only be read
+    /// once.
+    Stream(StreamSource),
+}
+
+/// A reader handed over for sending. Clones share it, and whichever opens
+/// it first gets it.
+#[derive(Clone)]
+pub struct StreamSource(Arc<Mutex<Option<Box<dyn AsyncRead + Send + Unpin>>>>);
+
+impl StreamSource {
+    pub fn new(reader: impl AsyncRead + Send + Unpin + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Some(Box::new(reader)))))
+    }
+}
+
+impl std::fmt::Debug for StreamSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StreamSource")
+    }
+}
+
+impl From<PathBuf> for FileSource {
+    fn from(path: PathBuf) -> Self {
+        FileSource::Path(path)
+    }
+}
+
+impl FileSource {
+    /// Open a fresh reader positioned at the start of the content. A
+    /// stream opens only once.
+    pub async fn open(&self) -> AppResult<Box<dyn AsyncRead + Send + Unpin>> {
+        Ok(match self {
+            FileSource::Path(path) => Box::new(tokio::fs::File::open(path).await?),
+            FileSource::Memory(bytes) => Box::new(std::io::Cursor::new(bytes.clone())),
+            FileSource::Stream(stream) => stream
+                .0
+                .lock()
+                .unwrap()
+                .take()
+                .ok_or_else(|| AppError::Transfer("stream has already been read".into()))?,
+        })
+    }
+
+    /// Whether the content can be opened more than once, as hashing it
+    /// ahead of sending needs.
+    pub fn rereadable(&self) -> bool {
+        !matches!(self, FileSource::Stream(_))
+    }
+}
+
 /// Reads a file in chunks, encrypts each chunk, and computes a SHA-256 checksum.
 pub struct FileChunker {
-    file: tokio::fs::File,
+    reader: Box<dyn AsyncRead + Send + Unpin>,
     encryptor: ChunkEncryptor,
     checksum: StreamingChecksum,
+    file_index: u16,
     chunk_index: u32,
+    bytes_read: u64,
     buf: Vec<u8>,
+    pieces: Option<PieceHasher>,
+    challenge: Option<ChallengeMac>,
+    compress: bool,
+    tail: Option<CancellationToken>,
 }
 
+/// zstd level for compressed chunks; favours speed over ratio.
+const COMPRESSION_LEVEL: i32 = 3;
+
 impl FileChunker {
-    pub async fn new(path: &Path, encryptor: ChunkEncryptor) -> AppResult<Self> {
-        let file = tokio::fs::File::open(path).await?;
+    /// `file_index` is the file's position in the offer; it's authenticated
+    /// with every chunk. `chunk_size` is clamped to between
+    /// [`MIN_CHUNK_SIZE`] and [`MAX_CHUNK_SIZE`].
+    pub async fn new(
+        path: &Path,
+        file_index: u16,
+        encryptor: ChunkEncryptor,
+        chunk_size: usize,
+    ) -> AppResult<Self> {
+        let source = FileSource::Path(path.to_path_buf());
+        Self::from_source(&source, file_index, encryptor, chunk_size).await
+    }
+
+    pub async fn from_source(
+        source: &FileSource,
+        file_index: u16,
+        encryptor: ChunkEncryptor,
+        chunk_size: usize,
+    ) -> AppResult<Self> {
+        // A shared encryptor would carry its nonce prefix across files.
+        debug_assert!(encryptor.is_fresh(), "each file needs its own encryptor");
         Ok(Self {
-            file,
+            reader: source.open().await?,
             encryptor,
             checksum: StreamingChecksum::new(),
+            file_index,
             chunk_index: 0,
-            buf: vec![0u8; CHUNK_SIZE],
+            bytes_read: 0,
+            buf: vec![0u8; chunk_size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)],
+            pieces: None,
+            challenge: None,
+            compress: false,
+            tail: None,
         })
     }
 
+    /// Also compute a piece-hash list over the plaintext.
+    pub fn with_piece_hashes(mut self, config: PieceHashConfig) -> AppResult<Self> {
+        self.pieces = Some(PieceHasher::new(config)?);
+        Ok(self)
+    }
+
+    /// Checksum the file with `algorithm` instead of SHA-256.
+    pub fn with_checksum(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        self.checksum = StreamingChecksum::with_algorithm(algorithm);
+        self
+    }
+
+    /// zstd-compress each chunk before encrypting it, unless that wouldn't
+    /// make it smaller.
+    pub fn with_compression(mut self) -> Self {
+        self.compress = true;
+        self
+    }
+
+    /// Keep reading past the end of the file, like `tail -f`, for a file
+    /// that's still being written. The file only ends once `stop` is
+    /// cancelled and everything up to then has been read.
+    pub fn with_tail(mut self, stop: CancellationToken) -> Self {
+        self.tail = Some(stop);
+        self
+    }
+
     /// Read the next chunk, encrypt it.
     /// Returns `None` when the file is fully read.
-    /// Returns `Some((encrypted_data, nonce, chunk_index))`.
-    pub async fn next_chunk(&mut self) -> AppResult<Option<(Vec<u8>, [u8; 12], u32)>> {
-        let bytes_read = self.file.read(&mut self.buf).await?;
+    /// Returns `Some((encrypted_data, nonce, chunk_index, compressed,
+    /// plaintext_len))`, where `plaintext_len` is the file bytes the chunk
+    /// carries, before compression and encryption.
+    pub async fn next_chunk(&mut self) -> AppResult<Option<(Vec<u8>, [u8; 12], u32, bool, u64)>> {
+        let bytes_read = loop {
+            let n = self.reader.read(&mut self.buf).await?;
+            match &self.tail {
+                Some(stop) if n == 0 && !stop.is_cancelled() => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(TAIL_POLL) => {}
+                        // One more read picks up whatever came before the stop.
+                        _ = stop.cancelled() => {}
+                    }
+                }
+                _ => break n,
+            }
+        };
         if bytes_read == 0 {
             return Ok(None);
         }
+        self.bytes_read += bytes_read as u64;
 
         let plaintext = &self.buf[..bytes_read];
 
         // Update checksum with plaintext before encryption
         self.checksum.update(plaintext);
+        if let Some(pieces) = self.pieces.as_mut() {
+            pieces.update(plaintext);
+        }
+        if let Some(challenge) = self.challenge.as_mut() {
+            challenge.update(plaintext);
+        }
 
-        // Encrypt
-        let (ciphertext, nonce) = self.encryptor.encrypt_chunk(plaintext)?;
+        // Compress; already-compressed data usually comes out larger, so
+        // it goes as is.
+        let compressed = if self.compress {
+            zstd::bulk::compress(plaintext, COMPRESSION_LEVEL)
+                .ok()
+                .filter(|packed| packed.len() < plaintext.len())
+        } else {
+            None
+        };
+        let is_compressed = compressed.is_some();
 
+        // Encrypt
         let index = self.chunk_index;
+        let (ciphertext, nonce) = self.encryptor.encrypt_chunk(
+            compressed.as_deref().unwrap_or(plaintext),
+            self.file_index,
+            index,
+            is_compressed,
+        )?;
+
         self.chunk_index += 1;
 
-        Ok(Some((ciphertext, nonce, index)))
+        Ok(Some((
+            ciphertext,
+            nonce,
+            index,
+            is_compressed,
+            bytes_read as u64,
+        )))
+    }
+
+    /// Read past the first `len` bytes without sending them, for a receiver
+    /// that already has them. They still count towards the checksum.
+    pub async fn skip(&mut self, len: u64) -> AppResult<()> {
+        let mut remaining = len;
+        while remaining > 0 {
+            let want = remaining.min(self.buf.len() as u64) as usize;
+            let n = self.reader.read(&mut self.buf[..want]).await?;
+            if n == 0 {
+                return Err(AppError::Transfer(format!(
+                    "cannot resume at byte {len}: file is shorter"
+                )));
+            }
+            let prefix = &self.buf[..n];
+            self.checksum.update(prefix);
+            if let Some(pieces) = self.pieces.as_mut() {
+                pieces.update(prefix);
+            }
+            if let Some(challenge) = self.challenge.as_mut() {
+                challenge.update(prefix);
+            }
+            remaining -= n as u64;
+        }
+        self.bytes_read += len;
+        Ok(())
+    }
+
+    /// Bytes of the file read so far, skipped ones included.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Encrypt the following chunks under the next key in the ratchet.
+    pub fn ratchet_key(&mut self) -> AppResult<()> {
+        self.encryptor.ratchet()
+    }
+
+    /// Also compute a challenge MAC over the plaintext.
+    pub fn with_challenge(mut self, nonce: &[u8; 32]) -> Self {
+        self.challenge = Some(ChallengeMac::new(nonce));
+        self
+    }
+
+    /// The piece-hash list, once the file has been fully read.
+    /// `None` unless piece hashing was enabled.
+    pub fn take_piece_hashes(&mut self) -> Option<Vec<u8>> {
+        self.pieces.take().map(PieceHasher::finish)
+    }
+
+    /// The challenge MAC state; `None` unless a challenge was set.
+    pub fn take_challenge(&mut self) -> Option<ChallengeMac> {
+        self.challenge.take()
     }
 
-    /// Finalize and return the SHA-256 checksum of the original (plaintext) file.
+    /// Finalize and return the checksum of the original (plaintext) file.
     pub fn finalize(self) -> [u8; 32] {
         self.checksum.finalize()
     }
@@ -1,33 +1,193 @@
-use quinn::{RecvStream, SendStream};
+use quinn::{ConnectionError, ReadError, ReadExactError, RecvStream, SendStream, WriteError};
 use serde::{Deserialize, Serialize};
 
+use crate::crypto::checksum::ChecksumAlgorithm;
 use crate::error::{AppError, AppResult};
+use crate::protocol::pieces::PieceHashConfig;
+
+/// Wire format version, exchanged in `Hello`. Bump it for any change an
+/// older peer couldn't decode.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// `Hello` feature: zstd-compressed chunks.
+pub const FEATURE_COMPRESSION: &str = "compression";
+/// `Hello` feature: BLAKE3 file checksums.
+pub const FEATURE_BLAKE3: &str = "blake3";
+/// `Hello` feature: understands `CreateDir`.
+pub const FEATURE_EMPTY_DIRS: &str = "empty_dirs";
+/// `Hello` feature: answers `Ping` with `Pong` mid-transfer.
+pub const FEATURE_PING: &str = "ping";
+/// `Hello` feature: understands `FileBatch`.
+pub const FEATURE_BATCH: &str = "batch";
+/// `Hello` feature: understands `LocalProbe` and `LocalSource`.
+pub const FEATURE_LOCAL_COPY: &str = "local_copy";
+/// `Hello` feature: answers `Ping` with `Pong` before the transfer starts
+/// too, while waiting for the offer or for the user to accept it.
+pub const FEATURE_KEEPALIVE: &str = "keepalive";
+/// `Hello` feature: answers `HaveFiles` with `SkipFiles`.
+pub const FEATURE_HAVE_FILES: &str = "have_files";
+/// `Hello` feature: recreates files with a `link_target` as symlinks.
+pub const FEATURE_SYMLINKS: &str = "symlinks";
+/// `Hello` feature: understands `FileAcceptPartial`.
+pub const FEATURE_PARTIAL_ACCEPT: &str = "partial_accept";
+/// `Hello` feature: takes the files named in `ParallelFiles` on QUIC
+/// streams of their own. Only advertised over a direct connection.
+pub const FEATURE_PARALLEL_STREAMS: &str = "parallel_streams";
+/// `Hello` feature: unpacks an offered tar whose `FileOffer` proposes
+/// `archive`.
+pub const FEATURE_ARCHIVE: &str = "archive";
 
 /// All messages exchanged between peers over QUIC.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum PeerMessage {
+    /// Either → Either, first on every transfer: who I am. Versions must
+    /// match; `features` lists the optional extensions this peer supports.
+    Hello {
+        protocol_version: u16,
+        features: Vec<String>,
+    },
+
+    /// Sender → Receiver, just before `FileOffer`: the file at `path` holds
+    /// `token`. A receiver that finds it there is on the sender's machine,
+    /// and may agree to `local_copy`. Only sent to peers advertising
+    /// [`FEATURE_LOCAL_COPY`].
+    LocalProbe {
+        path: String,
+        token: [u8; 32],
+    },
+
     /// Sender → Receiver: here's what I want to send.
-    FileOffer { files: Vec<FileInfo> },
+    /// `piece_hashes` announces that each file will be followed by a piece-hash list.
+    /// `challenge` is a random nonce; when set, the receiver must answer each
+    /// file with a `ChallengeResponse` before `FileVerified`.
+    /// `features` are what the sender would like to use; only those the
+    /// receiver's `FileAccept` agrees to are.
+    FileOffer {
+        files: Vec<FileInfo>,
+        #[serde(default)]
+        piece_hashes: Option<PieceHashConfig>,
+        #[serde(default)]
+        challenge: Option<[u8; 32]>,
+        #[serde(default, skip_serializing_if = "TransferFeatures::is_plain")]
+        features: TransferFeatures,
+    },
 
-    /// Receiver → Sender: I accept the transfer.
-    FileAccept,
+    /// Receiver → Sender, before `FileAccept`: I already have the first
+    /// `bytes_received` bytes of this file, send only the rest.
+    ResumeRequest {
+        file_index: u16,
+        bytes_received: u64,
+    },
+
+    /// Receiver → Sender, before `FileAccept`: offered files I already have
+    /// whole. Only sent to peers advertising [`FEATURE_HAVE_FILES`], which
+    /// answer with `SkipFiles`.
+    HaveFiles {
+        entries: Vec<HaveFile>,
+    },
+
+    /// Sender → Receiver, answering `HaveFiles`: these offered files match
+    /// what the receiver has, and won't be sent.
+    SkipFiles {
+        file_indices: Vec<u16>,
+    },
+
+    /// Receiver → Sender: I accept the transfer, using these of the offered
+    /// `features`. An older receiver agrees to none.
+    FileAccept {
+        #[serde(default, skip_serializing_if = "TransferFeatures::is_plain")]
+        features: TransferFeatures,
+    },
+
+    /// Receiver → Sender: I accept only the offered files at
+    /// `accepted_indices`; send nothing for the rest. Only sent to peers
+    /// advertising [`FEATURE_PARTIAL_ACCEPT`].
+    FileAcceptPartial {
+        accepted_indices: Vec<u16>,
+        #[serde(default, skip_serializing_if = "TransferFeatures::is_plain")]
+        features: TransferFeatures,
+    },
 
     /// Receiver → Sender: I decline the transfer.
     FileDecline,
 
-    /// Sender → Receiver: one encrypted chunk of file data.
+    /// Sender → Receiver, after `FileAccept`: recreate this empty folder,
+    /// which has no files in the offer to bring it along. Only sent to peers
+    /// advertising [`FEATURE_EMPTY_DIRS`].
+    CreateDir {
+        relative_path: String,
+    },
+
+    /// Sender → Receiver, after `FileAccept`: the files at `file_indices`
+    /// come each on a unidirectional stream of its own, which opens with the
+    /// file's index (2 bytes, big-endian) and carries its chunks and
+    /// `FileComplete`. Nothing else is sent on this stream until each is
+    /// verified. Only sent to peers advertising [`FEATURE_PARALLEL_STREAMS`].
+    ParallelFiles {
+        file_indices: Vec<u16>,
+    },
+
+    /// Sender → Receiver: one encrypted chunk of file data. `compressed`
+    /// means the plaintext was zstd-compressed before encryption.
     FileChunk {
         file_index: u16,
         chunk_index: u32,
         #[serde(with = "serde_bytes")]
         data: Vec<u8>,
         nonce: [u8; 12],
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        compressed: bool,
     },
 
-    /// Sender → Receiver: file transfer complete, verify checksum.
+    /// Sender → Receiver, once `local_copy` is agreed: copy this file from
+    /// `path`, an absolute path on the shared machine, in place of its
+    /// chunks. Its `FileComplete` follows as usual.
+    LocalSource {
+        file_index: u16,
+        path: String,
+    },
+
+    /// Sender → Receiver: chunks of this file from `chunk_index` on are
+    /// encrypted under the next key in the ratchet.
+    KeyRatchet {
+        file_index: u16,
+        chunk_index: u32,
+    },
+
+    /// Sender → Receiver: concatenated piece digests for a file, sent after
+    /// its last chunk when the offer asked for piece hashes.
+    PieceHashes {
+        file_index: u16,
+        #[serde(with = "serde_bytes")]
+        hashes: Vec<u8>,
+    },
+
+    /// Sender → Receiver: a streaming file stopped growing at `size` bytes.
+    /// Its `FileComplete` follows.
+    StreamEnd {
+        file_index: u16,
+        size: u64,
+    },
+
+    /// Sender → Receiver: file transfer complete, verify checksum. Despite
+    /// the name, `sha256` is a BLAKE3 digest when that was agreed on.
     FileComplete { file_index: u16, sha256: [u8; 32] },
 
+    /// Sender → Receiver: several small files whole, each standing for its
+    /// chunks and `FileComplete`, and each answered with `FileVerified` as
+    /// usual. Only sent to peers advertising [`FEATURE_BATCH`].
+    FileBatch {
+        files: Vec<BatchedFile>,
+    },
+
+    /// Receiver → Sender: HMAC-SHA256 of the bytes it wrote, keyed by the
+    /// offer's challenge nonce. Proves possession rather than just asserting it.
+    ChallengeResponse {
+        file_index: u16,
+        mac: [u8; 32],
+    },
+
     /// Receiver → Sender: checksum verified.
     FileVerified { file_index: u16 },
 
@@ -35,21 +195,225 @@ pub enum PeerMessage {
     TransferComplete,
 
     /// Either → Either: cancel the transfer.
-    Cancel { reason: String },
+    Cancel {
+        reason: CancelReason,
+        detail: String,
+    },
 
-    /// Keepalive
+    /// Either → Either: we're leaving on purpose, after `TransferComplete`
+    /// or a cancel. The last message on the transport; a peer reading it
+    /// reports [`AppError::peer_disconnected`]. Peers that stop reading
+    /// before it never see it.
+    Bye,
+
+    /// Sender → Receiver, answered at once with `Pong`: during a relayed
+    /// transfer to time the relay's round trip, and while paused to keep the
+    /// connection from idling out. Only sent to peers advertising
+    /// [`FEATURE_PING`], and before `FileAccept` only to peers advertising
+    /// [`FEATURE_KEEPALIVE`].
     Ping,
     Pong,
 }
 
+/// Why a transfer was cancelled, so the peer can tell the user what happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelReason {
+    /// The user on the other side cancelled.
+    UserCancelled,
+    /// The receiver declined the offer.
+    Declined,
+    /// The receiver ran out of disk space.
+    DiskFull,
+    /// The peer gave up waiting for us.
+    Timeout,
+    /// A file failed checksum verification.
+    ChecksumMismatch,
+    /// The peers speak different protocol versions.
+    IncompatibleVersion,
+    /// Any other local failure; see the detail text.
+    Error,
+}
+
+impl CancelReason {
+    /// Pick the reason to report to the peer when a local error aborts the transfer.
+    pub fn for_error(err: &AppError) -> Self {
+        match err {
+            AppError::Cancelled => CancelReason::UserCancelled,
+            AppError::ConnectionTimeout => CancelReason::Timeout,
+            AppError::ChecksumMismatch(_) => CancelReason::ChecksumMismatch,
+            AppError::InsufficientSpace { .. } => CancelReason::DiskFull,
+            AppError::Io(e) if e.kind() == std::io::ErrorKind::StorageFull => {
+                CancelReason::DiskFull
+            }
+            _ => CancelReason::Error,
+        }
+    }
+}
+
+impl std::fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            CancelReason::UserCancelled => "cancelled by user",
+            CancelReason::Declined => "declined",
+            CancelReason::DiskFull => "disk full",
+            CancelReason::Timeout => "timed out",
+            CancelReason::ChecksumMismatch => "checksum mismatch",
+            CancelReason::IncompatibleVersion => "incompatible protocol version",
+            CancelReason::Error => "error",
+        };
+        f.write_str(text)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileInfo {
     pub name: String,
     pub size: u64,
     /// For folder support (Phase 3): relative path within the folder.
     pub relative_path: Option<String>,
+    /// Other paths, relative to the save directory, that get a copy of this
+    /// file's content. Filled in when the sender deduplicates its selection.
+    #[serde(default)]
+    pub duplicates: Vec<String>,
+    /// Content type hint, e.g. [`MIME_TEXT_PLAIN`] for text snippets.
+    #[serde(default)]
+    pub mime_hint: Option<String>,
+    /// Still being written to and sent as it grows: `size` is only what
+    /// existed at offer time, and the file ends at the sender's `StreamEnd`.
+    /// Also set, with a `size` of 0, for a stream of unknown length.
+    #[serde(default)]
+    pub streaming: bool,
+    /// Modification time on the sender, in unix seconds. The receiver gives
+    /// the saved file the same one; older senders leave it out.
+    #[serde(default)]
+    pub modified: Option<u64>,
+    /// Unix permission bits on the sender. Only read/write/execute are
+    /// reapplied, and only on Unix receivers.
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// The whole file's checksum, in the offer's algorithm, when the sender
+    /// hashed everything before offering. Lets a resuming receiver check
+    /// what it already has, and `FileComplete` must match it.
+    #[serde(default)]
+    pub checksum: Option<[u8; 32]>,
+    /// Set for a symbolic link, to what it points at: a path relative to the
+    /// link's folder. Such a file has no content, and is only offered to
+    /// peers advertising [`FEATURE_SYMLINKS`].
+    #[serde(default)]
+    pub link_target: Option<String>,
+}
+
+/// Optional protocol extensions, negotiated through `FileOffer` and
+/// `FileAccept`.
+///
+/// They travel as one field because MessagePack structs are positional:
+/// when all are off the field is left out, and the messages look exactly as
+/// they did to peers that predate it. Two separately omitted fields would
+/// shift into each other's place.
+///
+/// For the same reason, trailing flags that are off are left out of the
+/// struct itself, but only trailing ones: a flag that's on keeps every
+/// field before it in place.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct TransferFeatures {
+    /// zstd-compress chunks that shrink.
+    #[serde(default)]
+    pub compression: bool,
+    /// Algorithm behind `FileComplete`'s checksum.
+    #[serde(default)]
+    pub checksum: ChecksumAlgorithm,
+    /// The peers share a machine: files can come as `LocalSource` paths.
+    /// Left out when it and everything after it are off, so older peers
+    /// never see it.
+    #[serde(default)]
+    pub local_copy: bool,
+    /// The offer's one file is a tar of the whole selection, which the
+    /// receiver unpacks under the save directory instead of saving. Only
+    /// proposed to peers advertising [`FEATURE_ARCHIVE`].
+    #[serde(default)]
+    pub archive: bool,
+}
+
+impl TransferFeatures {
+    fn is_plain(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Serialize for TransferFeatures {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let len = if self.archive {
+            4
+        } else if self.local_copy {
+            3
+        } else {
+            2
+        };
+        let mut state = serializer.serialize_struct("TransferFeatures", len)?;
+        state.serialize_field("compression", &self.compression)?;
+        state.serialize_field("checksum", &self.checksum)?;
+        if len >= 3 {
+            state.serialize_field("local_copy", &self.local_copy)?;
+        } else {
+            state.skip_field("local_copy")?;
+        }
+        if len >= 4 {
+            state.serialize_field("archive", &self.archive)?;
+        } else {
+            state.skip_field("archive")?;
+        }
+        state.end()
+    }
+}
+
+/// An offered file the receiver already has, as listed in `HaveFiles`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HaveFile {
+    /// The offer's [`FileInfo::target_path`].
+    pub relative_path: String,
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+/// One file in a `FileBatch`: its chunks in order, numbered from 0, and its
+/// checksum as `FileComplete` would carry it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchedFile {
+    pub file_index: u16,
+    pub chunks: Vec<BatchedChunk>,
+    pub sha256: [u8; 32],
+}
+
+/// A chunk inside a [`BatchedFile`], as `FileChunk` would carry it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchedChunk {
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+    pub nonce: [u8; 12],
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool,
+}
+
+/// MIME hint for plain-text content the receiver may show inline.
+pub const MIME_TEXT_PLAIN: &str = "text/plain";
+
+/// MIME hint for the tar an archive-mode send offers in place of its files.
+pub const MIME_TAR: &str = "application/x-tar";
+
+impl FileInfo {
+    /// Where this file lands relative to the save directory.
+    pub fn target_path(&self) -> &str {
+        self.relative_path.as_deref().unwrap_or(&self.name)
+    }
 }
 
+/// Largest message either transport accepts, length prefix excluded.
+/// Generous for large chunks.
+pub const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
 /// Read one length-prefixed MessagePack message from a QUIC receive stream.
 pub async fn read_message(stream: &mut RecvStream) -> AppResult<PeerMessage> {
     // Read 4-byte length prefix (big-endian u32)
@@ -57,12 +421,11 @@ pub async fn read_message(stream: &mut RecvStream) -> AppResult<PeerMessage> {
     stream
         .read_exact(&mut len_buf)
         .await
-        .map_err(|e| AppError::Network(format!("failed to read message length: {e}")))?;
+        .map_err(|e| read_failed("message length", e))?;
 
     let len = u32::from_be_bytes(len_buf) as usize;
 
-    // Sanity check: max message size 256MB (generous for large chunks)
-    if len > 256 * 1024 * 1024 {
+    if len > MAX_MESSAGE_SIZE {
         return Err(AppError::Transfer(format!(
             "message too large: {len} bytes"
         )));
@@ -73,11 +436,93 @@ pub async fn read_message(stream: &mut RecvStream) -> AppResult<PeerMessage> {
     stream
         .read_exact(&mut payload)
         .await
-        .map_err(|e| AppError::Network(format!("failed to read message payload: {e}")))?;
+        .map_err(|e| read_failed("message payload", e))?;
+
+    decode_message(&payload, "QUIC")
+}
+
+/// Decode one MessagePack payload that arrived over `transport`.
+///
+/// A failure reports what kind of problem it was, the payload's length, how
+/// far decoding got, and its leading bytes — only the array header and
+/// variant name, since what follows may be file names or key material.
+pub fn decode_message(payload: &[u8], transport: &str) -> AppResult<PeerMessage> {
+    rmp_serde::from_slice(payload).map_err(|e| {
+        // Decode again from a cursor to see where it gave up; cold path only.
+        let mut cursor = std::io::Cursor::new(payload);
+        let _ = PeerMessage::deserialize(&mut rmp_serde::Deserializer::new(&mut cursor));
+        let shown = structural_prefix_len(payload);
+        let redacted = match payload.len() - shown {
+            0 => String::new(),
+            n => format!(" +{n} bytes redacted"),
+        };
+        AppError::Serialization(format!(
+            "failed to decode {transport} message: {} ({} bytes, stopped at byte {}, starts {}{redacted})",
+            decode_failure(&e),
+            payload.len(),
+            cursor.position(),
+            payload[..shown]
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>(),
+        ))
+    })
+}
+
+/// What kind of decode error `e` is. Its own message can't be shown: serde
+/// quotes the offending value, so a wrong-typed string field would put a
+/// file name in the log.
+fn decode_failure(e: &rmp_serde::decode::Error) -> &'static str {
+    use rmp_serde::decode::Error;
+    match e {
+        Error::InvalidMarkerRead(e) | Error::InvalidDataRead(e)
+            if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+        {
+            "ended early"
+        }
+        Error::InvalidMarkerRead(_) | Error::InvalidDataRead(_) => "read error",
+        Error::TypeMismatch(_) => "type mismatch",
+        Error::OutOfRange => "number out of range",
+        Error::LengthMismatch(_) => "length mismatch",
+        Error::Utf8Error(_) => "string isn't UTF-8",
+        Error::DepthLimitExceeded => "nested too deep",
+        Error::Syntax(msg) | Error::Uncategorized(msg) => {
+            const KINDS: &[(&str, &str)] = &[
+                ("invalid type", "type mismatch"),
+                ("invalid value", "invalid value"),
+                ("invalid length", "length mismatch"),
+                ("unknown variant", "unknown variant"),
+                ("unknown field", "unknown field"),
+                ("missing field", "missing field"),
+                ("duplicate field", "duplicate field"),
+            ];
+            KINDS
+                .iter()
+                .find(|(prefix, _)| msg.starts_with(prefix))
+                .map_or("malformed message", |&(_, kind)| kind)
+        }
+    }
+}
+
+/// Most bytes of an unrecognizable payload shown in a decode error.
+const UNKNOWN_PREFIX_MAX: usize = 8;
 
-    // Deserialize
-    rmp_serde::from_slice(&payload)
-        .map_err(|e| AppError::Serialization(format!("failed to decode message: {e}")))
+/// How many leading bytes of `payload` are safe to show: the array header
+/// and variant-name string every message opens with, or a few bytes of
+/// framing that doesn't look like a message at all.
+fn structural_prefix_len(payload: &[u8]) -> usize {
+    let header = match payload.first() {
+        Some(0x90..=0x9f) => 1,
+        Some(0xdc) => 3,
+        Some(0xdd) => 5,
+        _ => return payload.len().min(UNKNOWN_PREFIX_MAX),
+    };
+    let tag = match payload.get(header) {
+        Some(&b @ 0xa0..=0xbf) => 1 + (b & 0x1f) as usize,
+        Some(0xd9) => payload.get(header + 1).map_or(1, |&n| 2 + n as usize),
+        _ => 0,
+    };
+    (header + tag).min(payload.len())
 }
 
 /// Write one length-prefixed MessagePack message to a QUIC send stream.
@@ -89,16 +534,41 @@ pub async fn write_message(stream: &mut SendStream, msg: &PeerMessage) -> AppRes
     stream
         .write_all(&len.to_be_bytes())
         .await
-        .map_err(|e| AppError::Network(format!("failed to write message length: {e}")))?;
+        .map_err(|e| write_failed("message length", e))?;
 
     stream
         .write_all(&payload)
         .await
-        .map_err(|e| AppError::Network(format!("failed to write message payload: {e}")))?;
+        .map_err(|e| write_failed("message payload", e))?;
 
     Ok(())
 }
 
+/// A failed read of `what`: [`AppError::IdleTimeout`] if the connection
+/// was closed for going silent, [`AppError::peer_disconnected`] if the peer
+/// finished the stream between messages or closed the connection,
+/// otherwise a network error.
+fn read_failed(what: &str, e: ReadExactError) -> AppError {
+    match e {
+        ReadExactError::ReadError(ReadError::ConnectionLost(ConnectionError::TimedOut)) => {
+            AppError::IdleTimeout
+        }
+        ReadExactError::FinishedEarly(0)
+        | ReadExactError::ReadError(ReadError::ConnectionLost(
+            ConnectionError::ApplicationClosed(_),
+        )) => AppError::peer_disconnected(),
+        e => AppError::Network(format!("failed to read {what}: {e}")),
+    }
+}
+
+/// Like [`read_failed`], for a failed write.
+fn write_failed(what: &str, e: WriteError) -> AppError {
+    match e {
+        WriteError::ConnectionLost(ConnectionError::TimedOut) => AppError::IdleTimeout,
+        e => AppError::Network(format!("failed to write {what}: {e}")),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -106,30 +576,121 @@ mod tests {
     #[test]
     fn test_serialize_deserialize_all_variants() {
         let messages = vec![
+            PeerMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                features: vec![FEATURE_COMPRESSION.into()],
+            },
             PeerMessage::FileOffer {
                 files: vec![FileInfo {
                     name: "test.txt".into(),
                     size: 1024,
                     relative_path: None,
+                    duplicates: vec!["copy/test.txt".into()],
+                    mime_hint: None,
+                    streaming: false,
+                    modified: None,
+                    mode: None,
+                    checksum: None,
+                    link_target: None,
+                }],
+                piece_hashes: Some(PieceHashConfig {
+                    piece_size: 1 << 18,
+                    algorithm: crate::protocol::pieces::PieceHashAlgorithm::Sha1,
+                }),
+                challenge: Some([0x5A; 32]),
+                features: TransferFeatures {
+                    compression: true,
+                    checksum: ChecksumAlgorithm::Blake3,
+                    local_copy: true,
+                    archive: false,
+                },
+            },
+            PeerMessage::ResumeRequest {
+                file_index: 1,
+                bytes_received: 3 * 1024 * 1024,
+            },
+            PeerMessage::HaveFiles {
+                entries: vec![HaveFile {
+                    relative_path: "photos/a.jpg".into(),
+                    size: 1234,
+                    sha256: [5u8; 32],
                 }],
             },
-            PeerMessage::FileAccept,
+            PeerMessage::SkipFiles {
+                file_indices: vec![0, 3],
+            },
+            PeerMessage::FileAccept {
+                features: TransferFeatures {
+                    compression: false,
+                    checksum: ChecksumAlgorithm::Blake3,
+                    local_copy: false,
+                    archive: true,
+                },
+            },
+            PeerMessage::FileAcceptPartial {
+                accepted_indices: vec![0, 2],
+                features: TransferFeatures::default(),
+            },
             PeerMessage::FileDecline,
+            PeerMessage::LocalProbe {
+                path: "/tmp/relay-probe".into(),
+                token: [0x77; 32],
+            },
+            PeerMessage::LocalSource {
+                file_index: 3,
+                path: "/home/user/Movies/trip.mov".into(),
+            },
+            PeerMessage::CreateDir {
+                relative_path: "project/assets".into(),
+            },
+            PeerMessage::ParallelFiles {
+                file_indices: vec![0, 3, 4],
+            },
+            PeerMessage::FileBatch {
+                files: vec![BatchedFile {
+                    file_index: 2,
+                    chunks: vec![BatchedChunk {
+                        data: vec![7u8; 48],
+                        nonce: [3u8; 12],
+                        compressed: true,
+                    }],
+                    sha256: [0xCD; 32],
+                }],
+            },
             PeerMessage::FileChunk {
                 file_index: 0,
                 chunk_index: 42,
                 data: vec![1, 2, 3, 4],
                 nonce: [0u8; 12],
+                compressed: true,
+            },
+            PeerMessage::KeyRatchet {
+                file_index: 0,
+                chunk_index: 64,
+            },
+            PeerMessage::PieceHashes {
+                file_index: 0,
+                hashes: vec![0xCD; 40],
+            },
+            PeerMessage::StreamEnd {
+                file_index: 0,
+                size: 5 * 1024 * 1024,
             },
             PeerMessage::FileComplete {
                 file_index: 0,
                 sha256: [0xAB; 32],
             },
+            PeerMessage::ChallengeResponse {
+                file_index: 0,
+                mac: [0x11; 32],
+            },
             PeerMessage::FileVerified { file_index: 0 },
             PeerMessage::TransferComplete,
             PeerMessage::Cancel {
-                reason: "test".into(),
+                reason: CancelReason::DiskFull,
+                detail: "test".into(),
             },
+            PeerMessage::Bye,
             PeerMessage::Ping,
             PeerMessage::Pong,
         ];
@@ -142,4 +703,116 @@ mod tests {
             assert_eq!(encoded, re_encoded, "roundtrip failed for {msg:?}");
         }
     }
+
+    #[test]
+    fn test_decode_error_has_context_without_contents() {
+        let offer = PeerMessage::FileOffer {
+            files: vec![FileInfo {
+                name: "salary-review.pdf".into(),
+                size: 4096,
+                relative_path: None,
+                duplicates: Vec::new(),
+                mime_hint: None,
+                streaming: false,
+                modified: None,
+                mode: None,
+                checksum: None,
+                link_target: None,
+            }],
+            piece_hashes: None,
+            challenge: None,
+            features: TransferFeatures::default(),
+        };
+        let mut payload = rmp_serde::to_vec(&offer).unwrap();
+        payload.truncate(payload.len() - 3);
+
+        let err = decode_message(&payload, "relay").unwrap_err().to_string();
+        assert!(err.contains("relay message"), "{err}");
+        assert!(err.contains(&format!("({} bytes", payload.len())), "{err}");
+        assert!(err.contains("stopped at byte"), "{err}");
+        // The variant name is shown; the file name isn't.
+        let tag: String = b"file_offer".iter().map(|b| format!("{b:02x}")).collect();
+        assert!(err.contains(&tag), "{err}");
+        let name: String = b"salary".iter().map(|b| format!("{b:02x}")).collect();
+        assert!(!err.contains(&name) && !err.contains("salary"), "{err}");
+        assert!(err.contains("bytes redacted"), "{err}");
+
+        // Garbage that isn't a message shows only a few bytes.
+        let err = decode_message(&[0xc1; 64], "QUIC").unwrap_err().to_string();
+        assert!(
+            err.contains("starts c1c1c1c1c1c1c1c1 +56 bytes redacted"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_decode_error_names_kind_not_value() {
+        // A file name where the size should be.
+        let files = vec![("secret-file-name.pdf", "secret-file-name.pdf")];
+        let payload = rmp_serde::to_vec(&("file_offer", (files,))).unwrap();
+        let err = decode_message(&payload, "QUIC").unwrap_err().to_string();
+        assert!(err.contains(": type mismatch ("), "{err}");
+        assert!(!err.contains("secret"), "{err}");
+
+        let payload = rmp_serde::to_vec(&("secret-variant",)).unwrap();
+        let err = decode_message(&payload, "QUIC").unwrap_err().to_string();
+        assert!(err.contains(": unknown variant ("), "{err}");
+        assert!(!err.contains("secret-variant"), "{err}");
+    }
+
+    #[test]
+    fn test_plain_features_match_older_peers() {
+        // Before features were negotiated, FileAccept was a unit variant.
+        let older = rmp_serde::to_vec(&("file_accept",)).unwrap();
+        let accept = PeerMessage::FileAccept {
+            features: TransferFeatures::default(),
+        };
+        assert_eq!(rmp_serde::to_vec(&accept).unwrap(), older);
+        let decoded: PeerMessage = rmp_serde::from_slice(&older).unwrap();
+        assert!(matches!(
+            decoded,
+            PeerMessage::FileAccept { features } if features == TransferFeatures::default()
+        ));
+    }
+
+    #[test]
+    fn test_archive_keeps_local_copy_in_place() {
+        let local_only = TransferFeatures {
+            local_copy: true,
+            ..TransferFeatures::default()
+        };
+        let archive_only = TransferFeatures {
+            archive: true,
+            ..TransferFeatures::default()
+        };
+        // A peer that predates `archive` reads the local-copy layout as before.
+        let older = rmp_serde::to_vec(&(false, ChecksumAlgorithm::default(), true)).unwrap();
+        assert_eq!(rmp_serde::to_vec(&local_only).unwrap(), older);
+        for features in [local_only, archive_only] {
+            let encoded = rmp_serde::to_vec(&features).unwrap();
+            let decoded: TransferFeatures = rmp_serde::from_slice(&encoded).unwrap();
+            assert_eq!(decoded, features);
+        }
+    }
+
+    #[test]
+    fn test_cancel_reason_for_error() {
+        assert_eq!(
+            CancelReason::for_error(&AppError::Cancelled),
+            CancelReason::UserCancelled
+        );
+        assert_eq!(
+            CancelReason::for_error(&AppError::ChecksumMismatch("a.txt".into())),
+            CancelReason::ChecksumMismatch
+        );
+        let full = std::io::Error::from(std::io::ErrorKind::StorageFull);
+        assert_eq!(
+            CancelReason::for_error(&AppError::Io(full)),
+            CancelReason::DiskFull
+        );
+        assert_eq!(
+            CancelReason::for_error(&AppError::Network("reset".into())),
+            CancelReason::Error
+        );
+    }
 }
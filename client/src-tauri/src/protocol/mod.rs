@@ -1,3 +1,5 @@
 pub mod chunker;
 pub mod messages;
+pub mod pieces;
 pub mod reassembler;
+pub mod sink;
@@ -1,48 +1,350 @@
 use std::path::Path;
+use std::time::{Duration, Instant};
 
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::crypto::aes_gcm::ChunkDecryptor;
-use crate::crypto::checksum::StreamingChecksum;
+use crate::crypto::aes_gcm::{ChunkDecryptor, NonceWindow};
+use crate::crypto::checksum::{ChallengeMac, ChecksumAlgorithm, StreamingChecksum};
 use crate::error::{AppError, AppResult};
+use crate::protocol::chunker::MAX_CHUNK_SIZE;
+use crate::protocol::pieces::{PieceHashConfig, PieceHasher};
+use crate::protocol::sink::{ChunkSink, FileSink};
 
-/// Receives encrypted chunks, decrypts them, writes to a file, and verifies checksum.
+/// How often received data is flushed and synced to disk.
+///
+/// Flushing bounds how much received-but-unwritten data a crash can lose,
+/// at the cost of some throughput. A flush happens when either threshold is hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    /// Flush after this many bytes have been written since the last flush.
+    pub every_bytes: Option<u64>,
+    /// Flush when this much time has passed since the last flush.
+    pub every: Option<Duration>,
+}
+
+impl FlushPolicy {
+    /// Never flush periodically (only when the file is finished).
+    pub const NEVER: Self = Self {
+        every_bytes: None,
+        every: None,
+    };
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self {
+            every_bytes: Some(16 * 1024 * 1024),
+            every: Some(Duration::from_secs(2)),
+        }
+    }
+}
+
+/// Where decrypted data goes.
+enum Output {
+    Sink(Box<dyn ChunkSink>),
+    Memory(Vec<u8>),
+}
+
+/// Scratch space for opening chunks, reused from one chunk to the next so a
+/// multi-GB transfer doesn't allocate for every 256KB chunk. Ciphertext is
+/// decrypted where it arrived, so needs no buffer here.
+#[derive(Default)]
+struct ChunkBuffers {
+    /// Decompressed plaintext, one chunk long.
+    inflated: Vec<u8>,
+    decompressor: Option<zstd::bulk::Decompressor<'static>>,
+}
+
+impl ChunkBuffers {
+    /// Decrypt `ciphertext` in place, then decompress it if `compressed`,
+    /// returning the plaintext.
+    fn open<'a>(
+        &'a mut self,
+        decryptor: &ChunkDecryptor,
+        ciphertext: &'a mut [u8],
+        nonce: &[u8; 12],
+        file_index: u16,
+        chunk_index: u32,
+        compressed: bool,
+    ) -> AppResult<&'a [u8]> {
+        let plaintext = decryptor.decrypt_chunk_in_place(
+            ciphertext,
+            nonce,
+            file_index,
+            chunk_index,
+            compressed,
+        )?;
+        if !compressed {
+            return Ok(plaintext);
+        }
+
+        let decompressor = match &mut self.decompressor {
+            Some(decompressor) => decompressor,
+            empty => empty.insert(zstd::bulk::Decompressor::new()?),
+        };
+        // Bounded by the largest chunk size, so a hostile peer can't make
+        // us inflate a small chunk into gigabytes.
+        self.inflated.resize(MAX_CHUNK_SIZE, 0);
+        let len = decompressor
+            .decompress_to_buffer(plaintext, &mut self.inflated[..])
+            .map_err(|e| {
+                AppError::Transfer(format!("chunk {chunk_index} failed to decompress: {e}"))
+            })?;
+        Ok(&self.inflated[..len])
+    }
+}
+
+/// Receives encrypted chunks, decrypts them, writes to a sink (a file unless
+/// told otherwise), and verifies checksum.
 pub struct FileReassembler {
-    file: tokio::fs::File,
+    output: Output,
     decryptor: ChunkDecryptor,
+    buffers: ChunkBuffers,
+    nonces: NonceWindow,
     checksum: StreamingChecksum,
     bytes_written: u64,
+    /// The size the file was offered at, if known up front.
+    expected_size: Option<u64>,
+    /// `bytes_written` as of the last flush.
+    flushed_bytes: u64,
+    chunks_written: u32,
+    flush_policy: FlushPolicy,
+    unflushed_bytes: u64,
+    last_flush: Instant,
+    pieces: Option<PieceHasher>,
+    challenge: Option<ChallengeMac>,
 }
 
 impl FileReassembler {
-    pub async fn new(path: &Path, decryptor: ChunkDecryptor) -> AppResult<Self> {
-        // Create parent directories if needed
-        if let Some(parent) = path.parent() {
-            tokio::fs::create_dir_all(parent).await?;
-        }
+    pub async fn new(
+        path: &Path,
+        decryptor: ChunkDecryptor,
+        flush_policy: FlushPolicy,
+    ) -> AppResult<Self> {
+        let file = FileSink::create(path).await?;
+        Ok(Self::with_sink(Box::new(file), decryptor, flush_policy))
+    }
+
+    /// Write the decrypted content to a caller-supplied sink.
+    pub fn with_sink(
+        sink: Box<dyn ChunkSink>,
+        decryptor: ChunkDecryptor,
+        flush_policy: FlushPolicy,
+    ) -> Self {
+        Self::with_output(Output::Sink(sink), decryptor, flush_policy)
+    }
 
-        let file = tokio::fs::File::create(path).await?;
-        Ok(Self {
-            file,
+    /// Collect the decrypted content in memory instead of writing a file.
+    /// Retrieve it with [`take_buffer`](Self::take_buffer).
+    pub fn in_memory(decryptor: ChunkDecryptor) -> Self {
+        Self::with_output(Output::Memory(Vec::new()), decryptor, FlushPolicy::NEVER)
+    }
+
+    fn with_output(output: Output, decryptor: ChunkDecryptor, flush_policy: FlushPolicy) -> Self {
+        Self {
+            output,
             decryptor,
+            buffers: ChunkBuffers::default(),
+            nonces: NonceWindow::new(),
             checksum: StreamingChecksum::new(),
             bytes_written: 0,
-        })
+            expected_size: None,
+            flushed_bytes: 0,
+            chunks_written: 0,
+            flush_policy,
+            unflushed_bytes: 0,
+            last_flush: Instant::now(),
+            pieces: None,
+            challenge: None,
+        }
+    }
+
+    /// Verify against a checksum made with `algorithm` instead of SHA-256.
+    pub fn with_checksum(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        self.checksum = StreamingChecksum::with_algorithm(algorithm);
+        self
+    }
+
+    /// Refuse to verify unless exactly `size` bytes were written.
+    pub fn with_expected_size(mut self, size: u64) -> Self {
+        self.expected_size = Some(size);
+        self
     }
 
-    /// Decrypt and write one chunk.
-    pub async fn write_chunk(&mut self, ciphertext: &[u8], nonce: &[u8; 12]) -> AppResult<()> {
-        let plaintext = self.decryptor.decrypt_chunk(ciphertext, nonce)?;
+    /// Also compute a piece-hash list over the decrypted content.
+    pub fn with_piece_hashes(mut self, config: PieceHashConfig) -> AppResult<Self> {
+        self.pieces = Some(PieceHasher::new(config)?);
+        Ok(self)
+    }
+
+    /// Also compute a challenge MAC over the decrypted content.
+    pub fn with_challenge(mut self, nonce: &[u8; 32]) -> Self {
+        self.challenge = Some(ChallengeMac::new(nonce));
+        self
+    }
+
+    /// The piece-hash list of everything written so far.
+    /// `None` unless piece hashing was enabled.
+    pub fn take_piece_hashes(&mut self) -> Option<Vec<u8>> {
+        self.pieces.take().map(PieceHasher::finish)
+    }
+
+    /// The challenge MAC state; `None` unless a challenge was set.
+    pub fn take_challenge(&mut self) -> Option<ChallengeMac> {
+        self.challenge.take()
+    }
+
+    /// Decrypt and write chunk `chunk_index` of file `file_index`,
+    /// decompressing it afterwards if the sender marked it `compressed`.
+    /// The ciphertext is decrypted in its own buffer, which is used up.
+    /// Chunks must arrive in order, and a nonce seen before for this file is
+    /// rejected without decrypting; the transfer must then be restarted
+    /// with a fresh key exchange.
+    pub async fn write_chunk(
+        &mut self,
+        mut ciphertext: Vec<u8>,
+        nonce: &[u8; 12],
+        file_index: u16,
+        chunk_index: u32,
+        compressed: bool,
+    ) -> AppResult<()> {
+        if chunk_index != self.chunks_written {
+            return Err(AppError::Crypto(format!(
+                "chunk {chunk_index} arrived out of order (expected {})",
+                self.chunks_written
+            )));
+        }
+        self.nonces.observe(nonce)?;
+
+        // Taken out for the duration so the plaintext can borrow them while
+        // it's written.
+        let mut buffers = std::mem::take(&mut self.buffers);
+        let written = match buffers.open(
+            &self.decryptor,
+            &mut ciphertext,
+            nonce,
+            file_index,
+            chunk_index,
+            compressed,
+        ) {
+            Ok(plaintext) => self.write_plaintext(plaintext).await,
+            Err(e) => Err(e),
+        };
+        self.buffers = buffers;
+        written?;
+        self.chunks_written += 1;
+
+        if self.flush_due() {
+            self.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Take `len` bytes already in the output, read back from `prefix`, as
+    /// if they had just been received, so a resumed file still verifies
+    /// against the sender's checksum.
+    pub async fn resume_from(
+        &mut self,
+        prefix: &mut (dyn AsyncRead + Send + Unpin),
+        len: u64,
+    ) -> AppResult<()> {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut remaining = len;
+        while remaining > 0 {
+            let want = remaining.min(buf.len() as u64) as usize;
+            let n = prefix.read(&mut buf[..want]).await?;
+            if n == 0 {
+                return Err(AppError::Transfer(format!(
+                    "partial file ends before byte {len}"
+                )));
+            }
+            self.absorb(&buf[..n]);
+            remaining -= n as u64;
+        }
+        self.bytes_written += len;
+        self.flushed_bytes = self.bytes_written;
+        Ok(())
+    }
+
+    /// Write `len` bytes read from `source`, for a file that arrived other
+    /// than as chunks (copied from the sender's disk on the same machine).
+    pub async fn write_from(
+        &mut self,
+        source: &mut (dyn AsyncRead + Send + Unpin),
+        len: u64,
+    ) -> AppResult<()> {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut remaining = len;
+        while remaining > 0 {
+            let want = remaining.min(buf.len() as u64) as usize;
+            let n = source.read(&mut buf[..want]).await?;
+            if n == 0 {
+                return Err(AppError::Transfer(format!("source ends before byte {len}")));
+            }
+            self.write_plaintext(&buf[..n]).await?;
+            remaining -= n as u64;
+        }
+        Ok(())
+    }
 
-        self.checksum.update(&plaintext);
-        self.file.write_all(&plaintext).await?;
+    async fn write_plaintext(&mut self, plaintext: &[u8]) -> AppResult<()> {
+        self.absorb(plaintext);
+        match &mut self.output {
+            Output::Sink(sink) => sink.write(plaintext).await?,
+            Output::Memory(buf) => buf.extend_from_slice(plaintext),
+        }
         self.bytes_written += plaintext.len() as u64;
+        self.unflushed_bytes += plaintext.len() as u64;
+        Ok(())
+    }
 
+    fn absorb(&mut self, plaintext: &[u8]) {
+        self.checksum.update(plaintext);
+        if let Some(pieces) = self.pieces.as_mut() {
+            pieces.update(plaintext);
+        }
+        if let Some(challenge) = self.challenge.as_mut() {
+            challenge.update(plaintext);
+        }
+    }
+
+    /// Flush buffered writes and sync the file's data to disk.
+    pub async fn flush(&mut self) -> AppResult<()> {
+        if let Output::Sink(sink) = &mut self.output {
+            sink.flush().await?;
+        }
+        self.flushed_bytes = self.bytes_written;
+        self.unflushed_bytes = 0;
+        self.last_flush = Instant::now();
         Ok(())
     }
 
-    /// Verify the file's SHA-256 checksum matches the expected value.
+    fn flush_due(&self) -> bool {
+        let by_bytes = self
+            .flush_policy
+            .every_bytes
+            .is_some_and(|limit| self.unflushed_bytes >= limit);
+        let by_time = self
+            .flush_policy
+            .every
+            .is_some_and(|interval| self.last_flush.elapsed() >= interval);
+        by_bytes || by_time
+    }
+
+    /// Verify the file's checksum matches the expected value, and its
+    /// length the expected size if one was set. A wrong length is reported
+    /// as such, without hashing.
     pub fn verify(self, expected: &[u8; 32]) -> AppResult<()> {
+        if let Some(size) = self
+            .expected_size
+            .filter(|&size| size != self.bytes_written)
+        {
+            return Err(AppError::Transfer(format!(
+                "received {} bytes, but {size} were offered",
+                self.bytes_written
+            )));
+        }
         let actual = self.checksum.finalize();
         if actual != *expected {
             return Err(AppError::ChecksumMismatch(format!(
@@ -57,8 +359,364 @@ impl FileReassembler {
     pub fn bytes_written(&self) -> u64 {
         self.bytes_written
     }
+
+    /// How much of the file is known to be on disk: everything up to the
+    /// last flush.
+    pub fn flushed_bytes(&self) -> u64 {
+        self.flushed_bytes
+    }
+
+    /// Switch to the next key in the ratchet. `chunk_index` is where the
+    /// sender switched; a mismatch means the two sides are out of step.
+    pub fn ratchet_key(&mut self, chunk_index: u32) -> AppResult<()> {
+        if chunk_index != self.chunks_written {
+            return Err(AppError::Crypto(format!(
+                "key ratchet at chunk {chunk_index}, but {} chunks received",
+                self.chunks_written
+            )));
+        }
+        self.decryptor.ratchet()
+    }
+
+    /// The collected content of an in-memory reassembler; `None` for sinks.
+    pub fn take_buffer(&mut self) -> Option<Vec<u8>> {
+        match &mut self.output {
+            Output::Memory(buf) => Some(std::mem::take(buf)),
+            Output::Sink(_) => None,
+        }
+    }
 }
 
 fn hex(bytes: &[u8]) -> String {
     bytes.iter().map(|b| format!("{b:02x}")).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::aes_gcm::ChunkEncryptor;
+    use crate::crypto::checksum::ChecksumAlgorithm;
+    use crate::protocol::chunker::{
+        default_chunk_size, FileChunker, FileSource, CHUNK_SIZE, DIRECT_CHUNK_SIZE, MIN_CHUNK_SIZE,
+    };
+
+    #[tokio::test]
+    async fn test_flush_every_chunk_makes_data_visible() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("out.bin");
+        let key = [7u8; 32];
+        let mut encryptor = ChunkEncryptor::new(&key).unwrap();
+        let policy = FlushPolicy {
+            every_bytes: Some(1024),
+            every: None,
+        };
+        let mut reassembler =
+            FileReassembler::new(&path, ChunkDecryptor::new(&key).unwrap(), policy)
+                .await
+                .unwrap();
+
+        for i in 1..=4u32 {
+            let (ciphertext, nonce) = encryptor
+                .encrypt_chunk(&[i as u8; 1024], 0, i - 1, false)
+                .unwrap();
+            reassembler
+                .write_chunk(ciphertext, &nonce, 0, i - 1, false)
+                .await
+                .unwrap();
+            // Each chunk crosses the byte threshold, so it must already be on disk.
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), u64::from(i) * 1024);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_respects_byte_threshold() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("out.bin");
+        let key = [7u8; 32];
+        let mut encryptor = ChunkEncryptor::new(&key).unwrap();
+        let policy = FlushPolicy {
+            every_bytes: Some(3000),
+            every: None,
+        };
+        let mut reassembler =
+            FileReassembler::new(&path, ChunkDecryptor::new(&key).unwrap(), policy)
+                .await
+                .unwrap();
+
+        for i in 0..2 {
+            let (ciphertext, nonce) = encryptor.encrypt_chunk(&[1u8; 1024], 0, i, false).unwrap();
+            reassembler
+                .write_chunk(ciphertext, &nonce, 0, i, false)
+                .await
+                .unwrap();
+        }
+        assert_eq!(reassembler.unflushed_bytes, 2048, "below threshold, no flush yet");
+
+        let (ciphertext, nonce) = encryptor.encrypt_chunk(&[1u8; 1024], 0, 2, false).unwrap();
+        reassembler
+            .write_chunk(ciphertext, &nonce, 0, 2, false)
+            .await
+            .unwrap();
+        assert_eq!(reassembler.unflushed_bytes, 0, "threshold crossed, flushed");
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 3072);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_nonce_aborts() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("out.bin");
+        let key = [7u8; 32];
+        let mut encryptor = ChunkEncryptor::new(&key).unwrap();
+        let mut reassembler = FileReassembler::new(
+            &path,
+            ChunkDecryptor::new(&key).unwrap(),
+            FlushPolicy::NEVER,
+        )
+        .await
+        .unwrap();
+
+        let (ciphertext, nonce) = encryptor.encrypt_chunk(&[1u8; 1024], 0, 0, false).unwrap();
+        reassembler
+            .write_chunk(ciphertext.clone(), &nonce, 0, 0, false)
+            .await
+            .unwrap();
+
+        // Replayed as the next chunk so it gets past the ordering check.
+        let err = reassembler
+            .write_chunk(ciphertext, &nonce, 0, 1, false)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, AppError::Crypto(msg) if msg == "nonce reuse detected"),
+            "unexpected error: {err}"
+        );
+        assert_eq!(reassembler.bytes_written(), 1024);
+    }
+
+    #[tokio::test]
+    async fn test_compression_falls_back_for_incompressible_chunks() {
+        let key = [7u8; 32];
+        let text: Vec<u8> = b"hello relay "
+            .iter()
+            .copied()
+            .cycle()
+            .take(CHUNK_SIZE)
+            .collect();
+        // xorshift output: nothing for zstd to find.
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let noise: Vec<u8> = (0..CHUNK_SIZE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let content = [text.as_slice(), noise.as_slice()].concat();
+
+        let mut chunker = FileChunker::from_source(
+            &FileSource::Memory(content.clone()),
+            0,
+            ChunkEncryptor::new(&key).unwrap(),
+            CHUNK_SIZE,
+        )
+        .await
+        .unwrap()
+        .with_compression();
+        let mut reassembler = FileReassembler::in_memory(ChunkDecryptor::new(&key).unwrap());
+
+        let mut flags = Vec::new();
+        while let Some((data, nonce, index, compressed, _)) = chunker.next_chunk().await.unwrap() {
+            if compressed {
+                assert!(data.len() < CHUNK_SIZE / 10, "text should shrink");
+            } else {
+                assert_eq!(data.len(), CHUNK_SIZE + 16, "noise goes as is");
+            }
+            flags.push(compressed);
+            reassembler
+                .write_chunk(data, &nonce, 0, index, compressed)
+                .await
+                .unwrap();
+        }
+        assert_eq!(flags, vec![true, false]);
+        assert_eq!(reassembler.take_buffer().unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_large_file_reuses_chunk_buffers() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("in.bin");
+        let path = temp.path().join("out.bin");
+        let key = [7u8; 32];
+        // Text and noise by turns, so both the plain and compressed paths run.
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let content: Vec<u8> = (0..CHUNK_SIZE * 40 + 123)
+            .map(|i| {
+                if (i / CHUNK_SIZE).is_multiple_of(2) {
+                    (i % 251) as u8
+                } else {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                }
+            })
+            .collect();
+        std::fs::write(&source, &content).unwrap();
+
+        let mut chunker = FileChunker::from_source(
+            &FileSource::Path(source),
+            0,
+            ChunkEncryptor::new(&key).unwrap(),
+            CHUNK_SIZE,
+        )
+        .await
+        .unwrap()
+        .with_compression();
+        let mut reassembler = FileReassembler::new(
+            &path,
+            ChunkDecryptor::new(&key).unwrap(),
+            FlushPolicy::default(),
+        )
+        .await
+        .unwrap();
+
+        let mut buffers = None;
+        while let Some((data, nonce, index, compressed, _)) = chunker.next_chunk().await.unwrap() {
+            reassembler
+                .write_chunk(data, &nonce, 0, index, compressed)
+                .await
+                .unwrap();
+            // Once the buffer has held a full chunk, it's never reallocated.
+            let current = reassembler.buffers.inflated.as_ptr();
+            if index >= 2 {
+                assert_eq!(*buffers.get_or_insert(current), current, "chunk {index}");
+            }
+        }
+        reassembler.flush().await.unwrap();
+        reassembler.verify(&chunker.finalize()).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_size_roundtrip_and_clamping() {
+        let key = [7u8; 32];
+        let content: Vec<u8> = (0..3 * 1024 * 1024 + 5).map(|i| (i % 239) as u8).collect();
+        // Requested size, and the largest chunk it should produce.
+        for (requested, expected) in [
+            (2 * 1024 * 1024, 2 * 1024 * 1024),
+            (1, MIN_CHUNK_SIZE),
+            (usize::MAX, content.len()),
+        ] {
+            let mut chunker = FileChunker::from_source(
+                &FileSource::Memory(content.clone()),
+                0,
+                ChunkEncryptor::new(&key).unwrap(),
+                requested,
+            )
+            .await
+            .unwrap()
+            .with_compression();
+            let mut reassembler = FileReassembler::in_memory(ChunkDecryptor::new(&key).unwrap());
+            let mut largest = 0;
+            while let Some((data, nonce, index, compressed, _)) =
+                chunker.next_chunk().await.unwrap()
+            {
+                let before = reassembler.bytes_written();
+                reassembler
+                    .write_chunk(data, &nonce, 0, index, compressed)
+                    .await
+                    .unwrap();
+                largest = largest.max(reassembler.bytes_written() - before);
+            }
+            assert_eq!(largest, expected as u64, "requested {requested}");
+            assert_eq!(reassembler.take_buffer().unwrap(), content);
+            reassembler.verify(&chunker.finalize()).unwrap();
+        }
+
+        assert_eq!(default_chunk_size(true), CHUNK_SIZE);
+        assert_eq!(default_chunk_size(false), DIRECT_CHUNK_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_checksum_algorithms_roundtrip_and_catch_mismatch() {
+        let key = [7u8; 32];
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 1000)
+            .map(|i| (i % 241) as u8)
+            .collect();
+        for algorithm in [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Blake3] {
+            let receive = |checksum: [u8; 32]| {
+                let content = content.clone();
+                async move {
+                    let mut chunker = FileChunker::from_source(
+                        &FileSource::Memory(content),
+                        0,
+                        ChunkEncryptor::new(&key).unwrap(),
+                        CHUNK_SIZE,
+                    )
+                    .await
+                    .unwrap()
+                    .with_checksum(algorithm);
+                    let mut reassembler =
+                        FileReassembler::in_memory(ChunkDecryptor::new(&key).unwrap())
+                            .with_checksum(algorithm);
+                    while let Some((data, nonce, index, compressed, _)) =
+                        chunker.next_chunk().await.unwrap()
+                    {
+                        reassembler
+                            .write_chunk(data, &nonce, 0, index, compressed)
+                            .await
+                            .unwrap();
+                    }
+                    let sent = chunker.finalize();
+                    (sent, reassembler.verify(&checksum))
+                }
+            };
+
+            let (sent, _) = receive([0; 32]).await;
+            let (_, verified) = receive(sent).await;
+            verified.unwrap_or_else(|e| panic!("{algorithm:?}: {e}"));
+
+            let mut corrupted = sent;
+            corrupted[0] ^= 1;
+            let (_, verified) = receive(corrupted).await;
+            assert!(
+                matches!(verified, Err(AppError::ChecksumMismatch(_))),
+                "{algorithm:?} missed a mismatch"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_size_mismatch_caught_before_checksum() {
+        let key = [7u8; 32];
+        let content = vec![3u8; CHUNK_SIZE + 500];
+        for offered in [content.len() as u64 + 1, content.len() as u64 - 1] {
+            let mut chunker = FileChunker::from_source(
+                &FileSource::Memory(content.clone()),
+                0,
+                ChunkEncryptor::new(&key).unwrap(),
+                CHUNK_SIZE,
+            )
+            .await
+            .unwrap();
+            let mut reassembler = FileReassembler::in_memory(ChunkDecryptor::new(&key).unwrap())
+                .with_expected_size(offered);
+            while let Some((data, nonce, index, compressed, _)) =
+                chunker.next_chunk().await.unwrap()
+            {
+                reassembler
+                    .write_chunk(data, &nonce, 0, index, compressed)
+                    .await
+                    .unwrap();
+            }
+
+            // The checksum matches; only the length gives it away.
+            let err = reassembler.verify(&chunker.finalize()).unwrap_err();
+            assert!(
+                matches!(&err, AppError::Transfer(msg) if msg.contains(&format!("{offered} were offered"))),
+                "unexpected error: {err}"
+            );
+        }
+    }
+}
@@ -0,0 +1,122 @@
+// Chunk sinks — where a reassembler puts decrypted file content.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use futures_util::future::BoxFuture;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::protocol::messages::FileInfo;
+
+/// Destination for one received file's decrypted bytes, in order.
+pub trait ChunkSink: Send {
+    /// Append `data` to the file.
+    fn write<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Make everything written so far durable. Called per the receive's
+    /// flush policy and once more before the file is verified.
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// Opens a [`ChunkSink`] per received file in place of a file on disk.
+///
+/// Lets an embedder receive into a virtual filesystem, object store, or
+/// anything else while the usual decryption, verification and progress
+/// reporting still apply.
+pub trait SinkFactory: fmt::Debug + Send + Sync {
+    /// Open the sink for `file`. `path` is where it would have been saved:
+    /// already sanitized and inside the save directory.
+    fn open<'a>(
+        &'a self,
+        path: &'a Path,
+        file: &'a FileInfo,
+    ) -> BoxFuture<'a, io::Result<Box<dyn ChunkSink>>>;
+
+    /// Create a copy of the finished file at `original` under `target`, for
+    /// identical files the sender folded into one. Unsupported by default,
+    /// which fails the receive if the sender deduplicated.
+    fn duplicate<'a>(
+        &'a self,
+        _original: &'a Path,
+        _target: &'a Path,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async { Err(io::ErrorKind::Unsupported.into()) })
+    }
+}
+
+/// The default sink: a file on the local filesystem.
+pub struct FileSink {
+    file: tokio::fs::File,
+}
+
+impl FileSink {
+    /// Create (or truncate) the file at `path`, along with its parent directories.
+    pub async fn create(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = tokio::fs::File::create(path).await?;
+        Ok(Self { file })
+    }
+
+    /// Reopen the partial file at `path`, keeping its first `len` bytes and
+    /// appending after them.
+    pub async fn resume(path: &Path, len: u64) -> io::Result<Self> {
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+        file.set_len(len).await?;
+        file.seek(io::SeekFrom::Start(len)).await?;
+        Ok(Self { file })
+    }
+}
+
+impl ChunkSink for FileSink {
+    fn write<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(self.file.write_all(data))
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            self.file.flush().await?;
+            self.file.sync_data().await
+        })
+    }
+}
+
+/// Throws the bytes away, for a file received only to be skipped. The
+/// reassembler still decrypts and verifies them.
+pub struct DiscardSink;
+
+impl ChunkSink for DiscardSink {
+    fn write<'a>(&'a mut self, _data: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Receives every file into a [`DiscardSink`]: decrypted and verified like
+/// any other, then thrown away. For checking that a transfer arrives
+/// intact, or timing one, without writing anything.
+#[derive(Debug, Default)]
+pub struct VerifyOnly;
+
+impl SinkFactory for VerifyOnly {
+    fn open<'a>(
+        &'a self,
+        _path: &'a Path,
+        _file: &'a FileInfo,
+    ) -> BoxFuture<'a, io::Result<Box<dyn ChunkSink>>> {
+        Box::pin(async { Ok(Box::new(DiscardSink) as Box<dyn ChunkSink>) })
+    }
+
+    fn duplicate<'a>(
+        &'a self,
+        _original: &'a Path,
+        _target: &'a Path,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
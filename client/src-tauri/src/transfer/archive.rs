@@ -0,0 +1,637 @@
+// Archive mode — a whole selection sent as one tar stream, so folders of
+// thousands of tiny files don't pay a `FileComplete`/`FileVerified` round
+// trip each.
+//
+// The sender writes the tar as it goes and offers it as a single file of
+// exactly known size, which the usual chunker encrypts and checksums. A
+// receiver that agrees to `archive` unpacks it under the save directory as
+// chunks arrive; every entry's path goes through `sanitize_path` first. One
+// that doesn't simply saves the tar.
+
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use futures_util::future::BoxFuture;
+use tar::{EntryType, Header};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::error::{AppError, AppResult};
+use crate::protocol::chunker::{FileSource, StreamSource};
+use crate::protocol::messages::{FileInfo, MIME_TAR};
+use crate::protocol::sink::ChunkSink;
+use crate::transfer::receiver::{check_no_linked_dirs, sanitize_path, set_mode, set_modified};
+
+/// Tar block size; headers take one, and content is padded to a multiple.
+const BLOCK: u64 = 512;
+/// Longest path a header holds itself. Longer ones are carried by a GNU
+/// long-name entry just before it.
+const NAME_LEN: usize = 100;
+/// Bytes of tar the sender's writer task may get ahead of the chunker.
+const PIPE_SIZE: usize = 256 * 1024;
+/// Decrypted chunks waiting for the receiver's unpacking thread.
+const UNPACK_BACKLOG: usize = 8;
+
+/// One entry of an outgoing archive.
+enum Entry {
+    Dir {
+        path: String,
+    },
+    File {
+        path: String,
+        source: FileSource,
+        size: u64,
+        mode: u32,
+        modified: u64,
+    },
+}
+
+impl Entry {
+    fn path(&self) -> &str {
+        match self {
+            Entry::Dir { path } | Entry::File { path, .. } => path,
+        }
+    }
+
+    /// Bytes this entry takes up in the archive, headers included.
+    fn archived_len(&self) -> u64 {
+        let name = self.path().len();
+        let long_name = if name > NAME_LEN {
+            BLOCK + padded(name as u64 + 1)
+        } else {
+            0
+        };
+        let content = match self {
+            Entry::Dir { .. } => 0,
+            Entry::File { size, .. } => padded(*size),
+        };
+        long_name + BLOCK + content
+    }
+}
+
+/// Pack `files` and the folders in `empty_dirs` into one tar archive, read
+/// from the returned source as it's written. Each file lands at its
+/// `target_path()`, and again at each of its duplicates. The archive's size
+/// is known up front; a file that shrinks in the meantime fails the read.
+///
+/// Streams can't be read twice, and links and streaming files have no
+/// fixed content, so none may be among `files`.
+pub fn pack(
+    files: Vec<FileSource>,
+    infos: Vec<FileInfo>,
+    empty_dirs: &[String],
+) -> (FileSource, FileInfo) {
+    let name = archive_name(&infos);
+    let mut entries: Vec<Entry> = empty_dirs
+        .iter()
+        .map(|dir| Entry::Dir {
+            path: format!("{}/", dir.trim_end_matches('/')),
+        })
+        .collect();
+    for (source, info) in files.into_iter().zip(infos) {
+        debug_assert!(source.rereadable() && !info.streaming && info.link_target.is_none());
+        for path in
+            std::iter::once(info.target_path()).chain(info.duplicates.iter().map(String::as_str))
+        {
+            entries.push(Entry::File {
+                path: path.into(),
+                source: source.clone(),
+                size: info.size,
+                mode: info.mode.unwrap_or(0o644),
+                modified: info.modified.unwrap_or(0),
+            });
+        }
+    }
+    let size = entries.iter().map(Entry::archived_len).sum::<u64>() + 2 * BLOCK;
+
+    let (mut writer, pipe) = tokio::io::duplex(PIPE_SIZE);
+    let failure = Arc::new(Mutex::new(None));
+    let reader = PackedArchive {
+        pipe,
+        failure: failure.clone(),
+    };
+    tokio::spawn(async move {
+        // Noted before the pipe closes, so the reader sees it at the end.
+        if let Err(e) = write_entries(&mut writer, entries).await {
+            *failure.lock().unwrap() = Some(e);
+        }
+    });
+
+    let info = FileInfo {
+        name,
+        size,
+        relative_path: None,
+        duplicates: Vec::new(),
+        mime_hint: Some(MIME_TAR.into()),
+        streaming: false,
+        modified: None,
+        mode: None,
+        checksum: None,
+        link_target: None,
+    };
+    (FileSource::Stream(StreamSource::new(reader)), info)
+}
+
+/// What to call the archive: after the folder everything is in, if there's
+/// just one.
+fn archive_name(infos: &[FileInfo]) -> String {
+    let mut tops = infos
+        .iter()
+        .map(|info| info.relative_path.as_deref()?.split('/').next());
+    match tops.next() {
+        Some(Some(top)) if tops.all(|t| t == Some(top)) => format!("{top}.tar"),
+        _ => "relay-archive.tar".into(),
+    }
+}
+
+/// `len` rounded up to whole blocks.
+fn padded(len: u64) -> u64 {
+    len.div_ceil(BLOCK) * BLOCK
+}
+
+/// The archive's read end: the writer task's output, ending in the error
+/// that stopped it, if any.
+struct PackedArchive {
+    pipe: DuplexStream,
+    failure: Arc<Mutex<Option<io::Error>>>,
+}
+
+impl AsyncRead for PackedArchive {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.pipe).poll_read(cx, buf))?;
+        if buf.filled().len() == filled {
+            if let Some(e) = self.failure.lock().unwrap().take() {
+                return Poll::Ready(Err(e));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+async fn write_entries(out: &mut (impl AsyncWrite + Unpin), entries: Vec<Entry>) -> io::Result<()> {
+    for entry in entries {
+        match entry {
+            Entry::Dir { path } => {
+                write_header(out, &path, EntryType::Directory, 0, 0o755, 0).await?;
+            }
+            Entry::File {
+                path,
+                source,
+                size,
+                mode,
+                modified,
+            } => {
+                write_header(out, &path, EntryType::Regular, size, mode, modified).await?;
+                let mut content = source
+                    .open()
+                    .await
+                    .map_err(|e| io::Error::other(e.to_string()))?
+                    .take(size);
+                let copied = tokio::io::copy(&mut content, out).await?;
+                if copied < size {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("'{path}' shrank while being archived"),
+                    ));
+                }
+                write_padding(out, size).await?;
+            }
+        }
+    }
+    // Two empty blocks end the archive.
+    out.write_all(&[0; 2 * BLOCK as usize]).await
+}
+
+/// Write the header for an entry at `path`, preceded by a long-name entry
+/// if the path doesn't fit.
+async fn write_header(
+    out: &mut (impl AsyncWrite + Unpin),
+    path: &str,
+    kind: EntryType,
+    size: u64,
+    mode: u32,
+    modified: u64,
+) -> io::Result<()> {
+    let name = path.as_bytes();
+    if name.len() > NAME_LEN {
+        let mut long_name = Header::new_gnu();
+        set_name(&mut long_name, b"././@LongLink");
+        long_name.set_entry_type(EntryType::GNULongName);
+        long_name.set_size(name.len() as u64 + 1);
+        long_name.set_mode(0o644);
+        long_name.set_mtime(0);
+        long_name.set_cksum();
+        out.write_all(long_name.as_bytes()).await?;
+        out.write_all(name).await?;
+        out.write_all(&[0]).await?;
+        write_padding(out, name.len() as u64 + 1).await?;
+    }
+    let mut header = Header::new_gnu();
+    set_name(&mut header, &name[..name.len().min(NAME_LEN)]);
+    header.set_entry_type(kind);
+    header.set_size(size);
+    header.set_mode(mode & 0o7777);
+    header.set_mtime(modified);
+    header.set_cksum();
+    out.write_all(header.as_bytes()).await
+}
+
+/// Copied in as is: the paths are ours, and `set_path` would refuse ones
+/// that don't fit rather than leave them to a long-name entry.
+fn set_name(header: &mut Header, name: &[u8]) {
+    header.as_old_mut().name[..name.len()].copy_from_slice(name);
+}
+
+/// Zeros up to the end of the block `len` bytes of content left off in.
+async fn write_padding(out: &mut (impl AsyncWrite + Unpin), len: u64) -> io::Result<()> {
+    let padding = (padded(len) - len) as usize;
+    out.write_all(&[0; BLOCK as usize][..padding]).await
+}
+
+/// What an archive left under the save directory, relative to it.
+#[derive(Debug, Default)]
+pub struct Unpacked {
+    pub files: Vec<PathBuf>,
+    pub dirs: Vec<PathBuf>,
+}
+
+/// Start unpacking a `size`-byte archive under `dir`, refusing more than
+/// `max_files` files. Its bytes go in through the returned sink, in order;
+/// the unpacking runs on a thread of its own.
+pub fn unpacker(dir: &Path, size: u64, max_files: Option<usize>) -> (ArchiveSink, ArchiveUnpack) {
+    let (tx, rx) = mpsc::channel(UNPACK_BACKLOG);
+    let failure = Arc::new(Mutex::new(None));
+    let mut reader = ChannelReader {
+        chunks: rx,
+        current: Vec::new(),
+        pos: 0,
+        remaining: size,
+    };
+    let task = {
+        let dir = dir.to_path_buf();
+        let failure = failure.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut unpacked = Unpacked::default();
+            match unpack(&mut reader, &dir, max_files, &mut unpacked) {
+                Ok(()) => Ok(unpacked),
+                Err(e) => {
+                    // Noted before the reader drops, so a sink left writing
+                    // into it can say why.
+                    *failure.lock().unwrap() = Some(e.to_string());
+                    for file in &unpacked.files {
+                        std::fs::remove_file(dir.join(file)).ok();
+                    }
+                    Err(e)
+                }
+            }
+        })
+    };
+    let sink = ArchiveSink {
+        chunks: tx,
+        failure,
+    };
+    let handle = ArchiveUnpack {
+        task,
+        dir: dir.to_path_buf(),
+    };
+    (sink, handle)
+}
+
+/// Feeds a received archive to its unpacking thread.
+pub struct ArchiveSink {
+    chunks: mpsc::Sender<Vec<u8>>,
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+impl ArchiveSink {
+    /// Why unpacking stopped before taking everything.
+    fn stopped(&self) -> io::Error {
+        let reason = self.failure.lock().unwrap().clone();
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            reason.unwrap_or_else(|| "archive unpacking stopped".into()),
+        )
+    }
+}
+
+impl ChunkSink for ArchiveSink {
+    fn write<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            if data.is_empty() {
+                return Ok(());
+            }
+            self.chunks
+                .send(data.to_vec())
+                .await
+                .map_err(|_| self.stopped())
+        })
+    }
+
+    /// Every entry is synced as it's finished, so there's nothing to flush;
+    /// this only reports an unpacking that has failed.
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            if self.failure.lock().unwrap().is_some() {
+                return Err(self.stopped());
+            }
+            Ok(())
+        })
+    }
+}
+
+/// The thread unpacking an archive.
+pub struct ArchiveUnpack {
+    task: JoinHandle<AppResult<Unpacked>>,
+    dir: PathBuf,
+}
+
+impl ArchiveUnpack {
+    /// Wait for the last entry to be written, once the sink has been
+    /// dropped. Fails if the archive was cut short or held a bad entry, in
+    /// which case the files it did write are gone.
+    pub async fn finish(self) -> AppResult<Unpacked> {
+        self.task
+            .await
+            .map_err(|e| AppError::Transfer(format!("archive unpacking failed: {e}")))?
+    }
+
+    /// Wait for unpacking to stop, then remove whatever it wrote.
+    pub async fn discard(self) {
+        let dir = self.dir.clone();
+        if let Ok(unpacked) = self.finish().await {
+            for file in unpacked.files {
+                tokio::fs::remove_file(dir.join(file)).await.ok();
+            }
+        }
+    }
+}
+
+/// Unpack every entry `reader` holds under `dir`, noting what's written in
+/// `unpacked` as it goes.
+fn unpack(
+    reader: &mut ChannelReader,
+    dir: &Path,
+    max_files: Option<usize>,
+    unpacked: &mut Unpacked,
+) -> AppResult<()> {
+    let mut archive = tar::Archive::new(&mut *reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = String::from_utf8(entry.path_bytes().into_owned())
+            .map_err(|_| AppError::Transfer("archive entry path isn't UTF-8".into()))?;
+        let rel = sanitize_path(&name)?;
+        check_no_linked_dirs(dir, &rel)?;
+        let path = dir.join(&rel);
+        match entry.header().entry_type() {
+            EntryType::Directory => {
+                std::fs::create_dir_all(&path)?;
+                unpacked.dirs.push(rel);
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                if let Some(max) = max_files.filter(|&max| unpacked.files.len() >= max) {
+                    return Err(AppError::Transfer(format!(
+                        "archive holds more than the limit of {max} files"
+                    )));
+                }
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                // Replace a link already there rather than write through it.
+                if std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.is_symlink()) {
+                    std::fs::remove_file(&path)?;
+                }
+                let mut file = std::fs::File::create(&path)?;
+                unpacked.files.push(rel);
+                io::copy(&mut entry, &mut file)?;
+                file.sync_data()?;
+                drop(file);
+                set_mode(&path, entry.header().mode()?);
+                let modified = entry.header().mtime()?;
+                if modified > 0 {
+                    set_modified(&path, modified);
+                }
+            }
+            kind => warn!("receiver: skipping archive entry '{name}' of type {kind:?}"),
+        }
+    }
+    // Take whatever follows the end of the archive, so the sink never
+    // waits on a reader that has stopped.
+    io::copy(reader, &mut io::sink())?;
+    Ok(())
+}
+
+/// Reads the chunks a sink hands over, in order. Running out before
+/// `remaining` bytes is an error: the transfer stopped partway.
+struct ChannelReader {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    current: Vec<u8>,
+    pos: usize,
+    remaining: u64,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.current.len() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => {
+                    self.current = chunk;
+                    self.pos = 0;
+                }
+                None if self.remaining == 0 => return Ok(0),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "archive ended early",
+                    ))
+                }
+            }
+        }
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        self.remaining = self.remaining.saturating_sub(n as u64);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_info(rel: &str, size: u64) -> FileInfo {
+        FileInfo {
+            name: rel.rsplit('/').next().unwrap().into(),
+            size,
+            relative_path: Some(rel.into()),
+            duplicates: Vec::new(),
+            mime_hint: None,
+            streaming: false,
+            modified: Some(1_600_000_000),
+            mode: Some(0o600),
+            checksum: None,
+            link_target: None,
+        }
+    }
+
+    /// Feed `archive` through a sink `chunk` bytes at a time and wait for
+    /// the unpacking to finish.
+    async fn unpack_bytes(archive: &[u8], dir: &Path, chunk: usize) -> AppResult<Unpacked> {
+        let (mut sink, unpack) = unpacker(dir, archive.len() as u64, None);
+        for piece in archive.chunks(chunk) {
+            if sink.write(piece).await.is_err() {
+                break;
+            }
+        }
+        drop(sink);
+        unpack.finish().await
+    }
+
+    #[tokio::test]
+    async fn test_pack_then_unpack_round_trips() {
+        let temp = tempfile::tempdir().unwrap();
+        let long = format!("site/{}/deep.txt", "nested-folder-name".repeat(8));
+        let rels = [
+            "site/empty.txt",
+            "site/css/a.css",
+            "site/index.html",
+            long.as_str(),
+        ];
+        let mut files = Vec::new();
+        let mut infos = Vec::new();
+        for (i, rel) in rels.iter().enumerate() {
+            let content = "x".repeat(i * 700);
+            files.push(FileSource::Memory(content.clone().into_bytes()));
+            infos.push(file_info(rel, content.len() as u64));
+        }
+        infos[2].duplicates = vec!["site/copy.html".into()];
+
+        let (source, info) = pack(files, infos, &["site/assets".into()]);
+        assert_eq!(info.name, "site.tar");
+        assert_eq!(info.mime_hint.as_deref(), Some(MIME_TAR));
+        let mut archive = Vec::new();
+        source
+            .open()
+            .await
+            .unwrap()
+            .read_to_end(&mut archive)
+            .await
+            .unwrap();
+        assert_eq!(archive.len() as u64, info.size);
+
+        let out = temp.path().join("out");
+        let unpacked = unpack_bytes(&archive, &out, 1000).await.unwrap();
+        assert_eq!(unpacked.files.len(), 5);
+        assert_eq!(unpacked.dirs, [PathBuf::from("site/assets")]);
+        assert!(out.join("site/assets").is_dir());
+        assert_eq!(std::fs::read(out.join("site/empty.txt")).unwrap(), b"");
+        assert_eq!(
+            std::fs::read(out.join("site/index.html")).unwrap().len(),
+            1400
+        );
+        assert_eq!(
+            std::fs::read(out.join("site/copy.html")).unwrap().len(),
+            1400
+        );
+        assert_eq!(std::fs::read(out.join(&long)).unwrap().len(), 2100);
+        let modified = std::fs::metadata(out.join("site/css/a.css"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(
+            modified,
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unpack_rejects_traversal() {
+        let temp = tempfile::tempdir().unwrap();
+        let out = temp.path().join("out");
+        std::fs::create_dir_all(&out).unwrap();
+
+        let mut archive = Vec::new();
+        write_header(&mut archive, "ok.txt", EntryType::Regular, 2, 0o644, 0)
+            .await
+            .unwrap();
+        archive.extend_from_slice(b"ok");
+        write_padding(&mut archive, 2).await.unwrap();
+        // A path `set_path` wouldn't write, put in by hand.
+        write_header(&mut archive, "../evil.txt", EntryType::Regular, 4, 0o644, 0)
+            .await
+            .unwrap();
+        archive.extend_from_slice(b"evil");
+        write_padding(&mut archive, 4).await.unwrap();
+        archive.extend_from_slice(&[0; 2 * BLOCK as usize]);
+
+        let err = unpack_bytes(&archive, &out, 512).await.unwrap_err();
+        assert!(err.to_string().contains("path traversal"), "{err}");
+        assert!(!temp.path().join("evil.txt").exists());
+        // What came before the bad entry is cleaned up too.
+        assert!(!out.join("ok.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unpack_refuses_links_already_on_disk() {
+        let temp = tempfile::tempdir().unwrap();
+        let out = temp.path().join("out");
+        let outside = temp.path().join("outside");
+        std::fs::create_dir_all(out.join("docs")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        // Left by an earlier receive, or by anything else.
+        std::os::unix::fs::symlink(&outside, out.join("docs/shared")).unwrap();
+
+        let (source, info) = pack(
+            vec![FileSource::Memory(b"planted".to_vec())],
+            vec![file_info("docs/shared/planted.txt", 7)],
+            &[],
+        );
+        let mut archive = Vec::new();
+        source
+            .open()
+            .await
+            .unwrap()
+            .read_to_end(&mut archive)
+            .await
+            .unwrap();
+        assert_eq!(archive.len() as u64, info.size);
+
+        let err = unpack_bytes(&archive, &out, 512).await.unwrap_err();
+        assert!(err.to_string().contains("through the link"), "{err}");
+        assert!(!outside.join("planted.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_unpack_fails_when_cut_short() {
+        let temp = tempfile::tempdir().unwrap();
+        let (source, info) = pack(
+            vec![FileSource::Memory(vec![7; 3000])],
+            vec![file_info("a/b.bin", 3000)],
+            &[],
+        );
+        let mut archive = Vec::new();
+        source
+            .open()
+            .await
+            .unwrap()
+            .read_to_end(&mut archive)
+            .await
+            .unwrap();
+
+        let (mut sink, unpack) = unpacker(temp.path(), info.size, None);
+        sink.write(&archive[..1024]).await.unwrap();
+        drop(sink);
+        assert!(unpack.finish().await.is_err());
+        assert!(!temp.path().join("a/b.bin").exists());
+    }
+}
@@ -1,9 +1,104 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
 use rand::Rng;
 
 use crate::error::{AppError, AppResult};
 
 const WORDLIST: &str = include_str!("../../../wordlist.txt");
 
+/// How many words a wordlist holds: one byte of entropy per word.
+pub const WORDLIST_LEN: usize = 256;
+
+/// The words codes are made from: exactly 256 of them, no repeats.
+///
+/// A code only parses against the list it was generated from, so both peers
+/// need the same list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Wordlist {
+    words: Vec<String>,
+}
+
+impl Wordlist {
+    /// Parse one word per line; blank lines and `#` comments are skipped.
+    pub fn parse(text: &str) -> AppResult<Self> {
+        let words: Vec<String> = text
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect();
+        if words.len() != WORDLIST_LEN {
+            return Err(AppError::InvalidCode(format!(
+                "wordlist must have {WORDLIST_LEN} words, found {}",
+                words.len()
+            )));
+        }
+        let mut seen = HashSet::new();
+        for word in &words {
+            if word.contains(|c: char| c == '-' || c.is_whitespace()) {
+                return Err(AppError::InvalidCode(format!(
+                    "wordlist entry '{word}' contains '-' or whitespace"
+                )));
+            }
+            if !seen.insert(word) {
+                return Err(AppError::InvalidCode(format!(
+                    "duplicate word in wordlist: '{word}'"
+                )));
+            }
+        }
+        Ok(Self { words })
+    }
+
+    /// Load and validate the list in the file at `path`.
+    pub fn load(path: &Path) -> AppResult<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// The list built into the app.
+    pub fn embedded() -> Arc<Wordlist> {
+        static EMBEDDED: OnceLock<Arc<Wordlist>> = OnceLock::new();
+        EMBEDDED
+            .get_or_init(|| Arc::new(Self::parse(WORDLIST).expect("embedded wordlist is valid")))
+            .clone()
+    }
+
+    pub fn words(&self) -> &[String] {
+        &self.words
+    }
+
+    fn contains(&self, word: &str) -> bool {
+        self.words.iter().any(|w| w == word)
+    }
+}
+
+/// The wordlist in use, if one was supplied at startup.
+static ACTIVE_WORDLIST: Mutex<Option<Arc<Wordlist>>> = Mutex::new(None);
+
+/// Generate and parse codes with `list` from now on instead of the
+/// embedded list.
+pub fn set_wordlist(list: Wordlist) {
+    *ACTIVE_WORDLIST.lock().unwrap() = Some(Arc::new(list));
+}
+
+/// Generate and parse codes with the list in the file at `path` from now
+/// on. A missing or invalid list fails, leaving the list in use as it was.
+pub fn use_wordlist_file(path: &Path) -> AppResult<()> {
+    set_wordlist(Wordlist::load(path)?);
+    Ok(())
+}
+
+/// The supplied wordlist, or the embedded one.
+fn active_wordlist() -> Arc<Wordlist> {
+    ACTIVE_WORDLIST
+        .lock()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(Wordlist::embedded)
+}
+
 /// A human-friendly transfer code: "{digit}-{word}-{word}"
 #[derive(Debug, Clone)]
 pub struct TransferCode {
@@ -13,13 +108,18 @@ pub struct TransferCode {
 }
 
 impl TransferCode {
-    /// Generate a random transfer code.
+    /// Generate a random transfer code from the active wordlist.
     pub fn generate() -> Self {
+        Self::generate_from(&active_wordlist())
+    }
+
+    /// Generate a random transfer code from `list`.
+    pub fn generate_from(list: &Wordlist) -> Self {
         let mut rng = rand::rng();
-        let words = wordlist();
+        let words = list.words();
         let digit = rng.random_range(0..10u8);
-        let word1 = words[rng.random_range(0..words.len())].to_string();
-        let word2 = words[rng.random_range(0..words.len())].to_string();
+        let word1 = words[rng.random_range(0..words.len())].clone();
+        let word2 = words[rng.random_range(0..words.len())].clone();
         Self {
             digit,
             word1,
@@ -32,8 +132,13 @@ impl TransferCode {
         format!("{}-{}-{}", self.digit, self.word1, self.word2)
     }
 
-    /// Parse a code string like "7-guitar-palace"
+    /// Parse a code string like "7-guitar-palace" against the active wordlist.
     pub fn parse(code: &str) -> AppResult<Self> {
+        Self::parse_with(code, &active_wordlist())
+    }
+
+    /// Parse a code string against `list`, the list it was generated from.
+    pub fn parse_with(code: &str, list: &Wordlist) -> AppResult<Self> {
         let parts: Vec<&str> = code.trim().splitn(3, '-').collect();
         if parts.len() != 3 {
             return Err(AppError::InvalidCode(
@@ -49,16 +154,15 @@ impl TransferCode {
             return Err(AppError::InvalidCode("digit must be 0-9".into()));
         }
 
-        let words = wordlist();
         let word1 = parts[1].to_lowercase();
         let word2 = parts[2].to_lowercase();
 
-        if !words.contains(&word1.as_str()) {
+        if !list.contains(&word1) {
             return Err(AppError::InvalidCode(format!(
                 "unknown word: '{word1}'"
             )));
         }
-        if !words.contains(&word2.as_str()) {
+        if !list.contains(&word2) {
             return Err(AppError::InvalidCode(format!(
                 "unknown word: '{word2}'"
             )));
@@ -78,17 +182,44 @@ impl std::fmt::Display for TransferCode {
     }
 }
 
-fn wordlist() -> Vec<&'static str> {
-    WORDLIST
-        .lines()
-        .map(|l| l.trim())
-        .filter(|l| !l.is_empty() && !l.starts_with('#'))
-        .collect()
+/// Whether codes are masked in log output. On by default in release builds.
+static REDACT_CODES: AtomicBool = AtomicBool::new(!cfg!(debug_assertions));
+
+/// Turn masking of transfer codes in logs on or off.
+pub fn set_log_redaction(enabled: bool) {
+    REDACT_CODES.store(enabled, Ordering::Relaxed);
+}
+
+/// Wrap a code for logging. Displays as e.g. `7-***-***` while redaction is on.
+pub fn redacted(code: &str) -> Redacted<'_> {
+    Redacted(code)
+}
+
+/// A transfer code as it should appear in logs; see [`redacted`].
+pub struct Redacted<'a>(&'a str);
+
+impl std::fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !REDACT_CODES.load(Ordering::Relaxed) {
+            return f.write_str(self.0);
+        }
+        // Keep the digit so logs from one session can still be told apart.
+        match self.0.trim().split_once('-') {
+            Some((digit, words)) => {
+                f.write_str(digit)?;
+                for _ in words.split('-') {
+                    f.write_str("-***")?;
+                }
+                Ok(())
+            }
+            None => f.write_str("***"),
+        }
+    }
 }
 
-/// Get a copy of the wordlist for the frontend.
+/// Get a copy of the active wordlist for the frontend.
 pub fn get_wordlist() -> Vec<String> {
-    wordlist().into_iter().map(String::from).collect()
+    active_wordlist().words().to_vec()
 }
 
 #[cfg(test)]
@@ -97,15 +228,20 @@ mod tests {
 
     #[test]
     fn test_wordlist_has_256_words() {
-        let words = wordlist();
-        assert_eq!(words.len(), 256, "wordlist must contain exactly 256 words");
+        let words = Wordlist::embedded();
+        assert_eq!(
+            words.words().len(),
+            256,
+            "wordlist must contain exactly 256 words"
+        );
     }
 
     #[test]
     fn test_wordlist_no_duplicates() {
-        let words = wordlist();
+        let list = Wordlist::embedded();
+        let words = &list.words;
         let mut seen = std::collections::HashSet::new();
-        for w in &words {
+        for w in words {
             assert!(seen.insert(w), "duplicate word: {w}");
         }
     }
@@ -122,6 +258,13 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_redacted_masks_words() {
+        set_log_redaction(true);
+        assert_eq!(redacted("7-guitar-palace").to_string(), "7-***-***");
+        assert_eq!(redacted("typo").to_string(), "***");
+    }
+
     #[test]
     fn test_parse_invalid_format() {
         assert!(TransferCode::parse("invalid").is_err());
@@ -129,4 +272,50 @@ mod tests {
         assert!(TransferCode::parse("7-notaword-palace").is_err());
         assert!(TransferCode::parse("10-guitar-palace").is_err());
     }
+
+    #[test]
+    fn test_custom_wordlist_generates_and_parses() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("words.txt");
+        let words: Vec<String> = (0..256).map(|i| format!("kelp{i:03}")).collect();
+        std::fs::write(&path, format!("# curated\n{}\n", words.join("\n"))).unwrap();
+
+        let list = Wordlist::load(&path).unwrap();
+        for _ in 0..20 {
+            let code = TransferCode::generate_from(&list);
+            assert!(code.word1.starts_with("kelp") && code.word2.starts_with("kelp"));
+            let parsed = TransferCode::parse_with(&code.to_code_string(), &list).unwrap();
+            assert_eq!(parsed.to_code_string(), code.to_code_string());
+            // Only the list it came from knows its words.
+            assert!(
+                TransferCode::parse_with(&code.to_code_string(), &Wordlist::embedded()).is_err()
+            );
+        }
+
+        let short = words[..255].join("\n");
+        assert!(matches!(
+            Wordlist::parse(&short),
+            Err(AppError::InvalidCode(_))
+        ));
+        let mut repeated = words.clone();
+        repeated[255] = repeated[0].clone();
+        assert!(matches!(
+            Wordlist::parse(&repeated.join("\n")),
+            Err(AppError::InvalidCode(_))
+        ));
+    }
+
+    #[test]
+    fn test_bad_wordlist_file_keeps_the_list_in_use() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("words.txt");
+        std::fs::write(&path, "too\nfew\nwords\n").unwrap();
+
+        assert!(matches!(
+            use_wordlist_file(&path),
+            Err(AppError::InvalidCode(_))
+        ));
+        assert!(use_wordlist_file(&temp.path().join("missing.txt")).is_err());
+        assert!(Arc::ptr_eq(&active_wordlist(), &Wordlist::embedded()));
+    }
 }
@@ -0,0 +1,128 @@
+// Collisions — what a receive does about a file already where a received
+// one would be saved.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::error::{AppError, AppResult};
+
+/// Give up looking for a free ` (n)` name after this many.
+const MAX_RENAME_ATTEMPTS: u32 = 10_000;
+
+/// What to do when a received file would land on an existing one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CollisionPolicy {
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Save alongside it as `name (1).ext`, `name (2).ext` and so on.
+    Rename,
+    /// Keep the existing file and throw the received one away.
+    Skip,
+    /// Abort the receive.
+    Fail,
+}
+
+/// Where a file bound for `path` is saved under `policy`: `path` itself or
+/// a free name next to it, or `None` if it's to be thrown away. Paths in
+/// `claimed`, picked for other files of the same receive, count as taken.
+pub async fn resolve(
+    path: &Path,
+    policy: CollisionPolicy,
+    claimed: &HashSet<PathBuf>,
+) -> AppResult<Option<PathBuf>> {
+    if !is_taken(path, claimed).await {
+        return Ok(Some(path.to_path_buf()));
+    }
+    match policy {
+        CollisionPolicy::Overwrite => Ok(Some(path.to_path_buf())),
+        CollisionPolicy::Skip => Ok(None),
+        CollisionPolicy::Fail => Err(AppError::Transfer(format!(
+            "'{}' already exists",
+            path.display()
+        ))),
+        CollisionPolicy::Rename => {
+            for n in 1..=MAX_RENAME_ATTEMPTS {
+                let candidate = numbered(path, n);
+                if !is_taken(&candidate, claimed).await {
+                    return Ok(Some(candidate));
+                }
+            }
+            Err(AppError::Transfer(format!(
+                "no free name for '{}'",
+                path.display()
+            )))
+        }
+    }
+}
+
+async fn is_taken(path: &Path, claimed: &HashSet<PathBuf>) -> bool {
+    claimed.contains(path) || tokio::fs::symlink_metadata(path).await.is_ok()
+}
+
+/// `path` with ` (n)` before its extension: `report (2).pdf`.
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    let mut name = OsString::from(path.file_stem().unwrap_or_default());
+    name.push(format!(" ({n})"));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numbered_goes_before_the_extension() {
+        assert_eq!(
+            numbered(Path::new("out/report.pdf"), 2),
+            Path::new("out/report (2).pdf")
+        );
+        assert_eq!(
+            numbered(Path::new("out/Makefile"), 1),
+            Path::new("out/Makefile (1)")
+        );
+        assert_eq!(numbered(Path::new(".bashrc"), 1), Path::new(".bashrc (1)"));
+    }
+
+    #[tokio::test]
+    async fn test_each_policy_against_an_existing_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("report.pdf");
+        std::fs::write(&path, b"old").unwrap();
+        std::fs::write(temp.path().join("report (1).pdf"), b"older").unwrap();
+        let claimed = HashSet::from([temp.path().join("report (2).pdf")]);
+
+        let resolved = |policy| resolve(&path, policy, &claimed);
+        assert_eq!(
+            resolved(CollisionPolicy::Overwrite).await.unwrap(),
+            Some(path.clone())
+        );
+        assert_eq!(
+            resolved(CollisionPolicy::Rename).await.unwrap(),
+            Some(temp.path().join("report (3).pdf"))
+        );
+        assert_eq!(resolved(CollisionPolicy::Skip).await.unwrap(), None);
+        assert!(resolved(CollisionPolicy::Fail).await.is_err());
+
+        // Nothing in the way: every policy saves where asked.
+        let fresh = temp.path().join("summary.pdf");
+        for policy in [
+            CollisionPolicy::Rename,
+            CollisionPolicy::Skip,
+            CollisionPolicy::Fail,
+        ] {
+            assert_eq!(
+                resolve(&fresh, policy, &claimed).await.unwrap(),
+                Some(fresh.clone())
+            );
+        }
+    }
+}
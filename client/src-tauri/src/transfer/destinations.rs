@@ -0,0 +1,88 @@
+// Per-item destinations — a receiver can send each top-level item of an
+// offer to its own directory, chosen after seeing the offer.
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::error::{AppError, AppResult};
+
+/// Save directories for an offer's top-level items, shared between a
+/// session and its pipeline like the pause token. Filled in before the
+/// offer is accepted; items without one go to the save directory.
+#[derive(Debug, Clone, Default)]
+pub struct Destinations {
+    inner: Arc<Mutex<HashMap<String, PathBuf>>>,
+}
+
+impl Destinations {
+    /// Save the top-level item `item` — a flat file's name or a folder's
+    /// name — under `dir`, which must be absolute and free of `..`.
+    pub fn set(&self, item: impl Into<String>, dir: impl Into<PathBuf>) -> AppResult<()> {
+        let dir = dir.into();
+        if !dir.is_absolute() || dir.components().any(|c| c == Component::ParentDir) {
+            return Err(AppError::Transfer(format!(
+                "destination must be an absolute path without '..': {}",
+                dir.display()
+            )));
+        }
+        self.inner.lock().unwrap().insert(item.into(), dir);
+        Ok(())
+    }
+
+    /// Every destination chosen so far.
+    pub(crate) fn snapshot(&self) -> HashMap<String, PathBuf> {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+/// The top-level item a received file belongs to, from its sanitized path.
+pub(crate) fn top_level(rel: &Path) -> Option<String> {
+    match rel.components().next()? {
+        Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+        _ => None,
+    }
+}
+
+/// The directory the file at sanitized path `rel` lands under: its item's
+/// destination if one was chosen, otherwise `base`.
+pub(crate) fn root<'a>(
+    base: &'a Path,
+    destinations: &'a HashMap<String, PathBuf>,
+    rel: &Path,
+) -> &'a Path {
+    top_level(rel)
+        .and_then(|item| destinations.get(&item))
+        .map_or(base, PathBuf::as_path)
+}
+
+/// Where the file at sanitized path `rel` lands: under its item's
+/// destination if one was chosen, otherwise under `base`.
+pub(crate) fn place(base: &Path, destinations: &HashMap<String, PathBuf>, rel: &Path) -> PathBuf {
+    root(base, destinations, rel).join(rel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_destinations_reject_relative_and_traversal() {
+        let destinations = Destinations::default();
+        let root = std::env::temp_dir();
+        assert!(destinations.set("photos", "photos").is_err());
+        assert!(destinations.set("photos", root.join("../etc")).is_err());
+        destinations.set("photos", root.join("pictures")).unwrap();
+
+        let chosen = destinations.snapshot();
+        let base = root.join("downloads");
+        assert_eq!(
+            place(&base, &chosen, Path::new("photos/2024/a.jpg")),
+            root.join("pictures/photos/2024/a.jpg")
+        );
+        assert_eq!(
+            place(&base, &chosen, Path::new("notes.txt")),
+            base.join("notes.txt")
+        );
+    }
+}
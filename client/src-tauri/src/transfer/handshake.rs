@@ -0,0 +1,92 @@
+// Protocol handshake — the first message each way on every transfer, so
+// peers that can't understand each other stop with a clear error instead of
+// failing to decode whatever comes next.
+
+use tracing::{info, warn};
+
+use crate::error::{AppError, AppResult};
+use crate::network::transport::Transport;
+use crate::protocol::messages::{
+    CancelReason, PeerMessage, FEATURE_ARCHIVE, FEATURE_BATCH, FEATURE_BLAKE3, FEATURE_COMPRESSION,
+    FEATURE_EMPTY_DIRS, FEATURE_HAVE_FILES, FEATURE_KEEPALIVE, FEATURE_LOCAL_COPY,
+    FEATURE_PARALLEL_STREAMS, FEATURE_PARTIAL_ACCEPT, FEATURE_PING, FEATURE_SYMLINKS,
+    PROTOCOL_VERSION,
+};
+
+/// What the peer said about itself in its `Hello`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerHello {
+    pub protocol_version: u16,
+    pub features: Vec<String>,
+}
+
+impl PeerHello {
+    /// Whether the peer advertised `feature`, e.g. [`FEATURE_COMPRESSION`].
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// Send our `Hello` and read the peer's. A peer on another protocol version,
+/// or one that predates the handshake, is told why with a `Cancel` and the
+/// transfer fails.
+pub async fn exchange_hello(transport: &mut Transport) -> AppResult<PeerHello> {
+    let mut features: Vec<String> = vec![
+        FEATURE_COMPRESSION.into(),
+        FEATURE_BLAKE3.into(),
+        FEATURE_EMPTY_DIRS.into(),
+        FEATURE_PING.into(),
+        FEATURE_BATCH.into(),
+        FEATURE_LOCAL_COPY.into(),
+        FEATURE_KEEPALIVE.into(),
+        FEATURE_HAVE_FILES.into(),
+        FEATURE_PARTIAL_ACCEPT.into(),
+        FEATURE_ARCHIVE.into(),
+    ];
+    // Only Unix receivers know how to make a link.
+    if cfg!(unix) {
+        features.push(FEATURE_SYMLINKS.into());
+    }
+    // Streams of their own need a QUIC connection to open them on.
+    if transport.connection().is_some() {
+        features.push(FEATURE_PARALLEL_STREAMS.into());
+    }
+    transport
+        .send_peer_message(&PeerMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            features,
+        })
+        .await?;
+    let peer = match transport.recv_peer_message().await? {
+        PeerMessage::Hello {
+            protocol_version,
+            features,
+        } => PeerHello {
+            protocol_version,
+            features,
+        },
+        // Clients from before the handshake open with their first real message.
+        _ => PeerHello {
+            protocol_version: 0,
+            features: Vec::new(),
+        },
+    };
+
+    if peer.protocol_version != PROTOCOL_VERSION {
+        let detail = format!(
+            "peer uses protocol v{}, we use v{PROTOCOL_VERSION}",
+            peer.protocol_version
+        );
+        warn!("handshake: {detail}");
+        transport
+            .send_peer_message(&PeerMessage::Cancel {
+                reason: CancelReason::IncompatibleVersion,
+                detail: detail.clone(),
+            })
+            .await
+            .ok();
+        return Err(AppError::Transfer(detail));
+    }
+    info!("handshake: peer features {:?}", peer.features);
+    Ok(peer)
+}
@@ -0,0 +1,361 @@
+// Transfer history — an append-only JSON-lines log of finished transfers,
+// so the UI can show what was sent and received after the app restarts.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::crypto::checksum::ChecksumAlgorithm;
+use crate::error::{AppError, AppResult};
+use crate::protocol::messages::FileInfo;
+use crate::transfer::session::TransferRole;
+
+/// Rotate the log once it would grow past this size (1 MiB).
+pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024;
+
+/// One finished (or failed) transfer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Unix timestamp (seconds) when the transfer ended.
+    pub finished_at: u64,
+    /// Which side of the transfer this was. Entries from before sends were
+    /// recorded are all receives.
+    #[serde(default = "receiver_role")]
+    pub role: TransferRole,
+    /// The code the transfer was made with, if it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// `"direct"` or `"relay"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection_type: Option<String>,
+    /// From the start of the transfer to its end, in seconds.
+    #[serde(default)]
+    pub duration_seconds: f64,
+    /// Where a receive saved the files; empty for a send.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub save_dir: String,
+    pub files: Vec<HistoryFile>,
+    pub total_bytes: u64,
+    /// What the receiver got, as far as this side knows.
+    pub bytes_received: u64,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn receiver_role() -> TransferRole {
+    TransferRole::Receiver
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryFile {
+    pub name: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relative_path: Option<String>,
+    /// Hex SHA-256, set once the file passed verification.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Hex BLAKE3 instead, for transfers that used it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blake3: Option<String>,
+}
+
+impl HistoryEntry {
+    /// Start a record for an offer we've just received.
+    pub fn for_offer(save_dir: &Path, files: &[FileInfo]) -> Self {
+        Self {
+            role: TransferRole::Receiver,
+            save_dir: save_dir.to_string_lossy().into_owned(),
+            ..Self::for_send(files)
+        }
+    }
+
+    /// Start a record for files we're about to offer.
+    pub fn for_send(files: &[FileInfo]) -> Self {
+        Self {
+            finished_at: 0,
+            role: TransferRole::Sender,
+            code: None,
+            connection_type: None,
+            duration_seconds: 0.0,
+            save_dir: String::new(),
+            files: files
+                .iter()
+                .map(|f| HistoryFile {
+                    name: f.name.clone(),
+                    size: f.size,
+                    relative_path: f.relative_path.clone(),
+                    sha256: None,
+                    blake3: None,
+                })
+                .collect(),
+            total_bytes: files.iter().map(|f| f.size).sum(),
+            bytes_received: 0,
+            success: false,
+            error: None,
+        }
+    }
+
+    /// Note the transfer's code and whether it went over the relay.
+    pub fn with_connection(mut self, code: Option<String>, relayed: bool) -> Self {
+        self.code = code;
+        self.connection_type = Some(if relayed { "relay" } else { "direct" }.into());
+        self
+    }
+
+    /// Mark a file as verified with the given checksum.
+    pub fn set_verified(
+        &mut self,
+        file_index: usize,
+        algorithm: ChecksumAlgorithm,
+        digest: &[u8; 32],
+    ) {
+        if let Some(file) = self.files.get_mut(file_index) {
+            let slot = match algorithm {
+                ChecksumAlgorithm::Sha256 => &mut file.sha256,
+                ChecksumAlgorithm::Blake3 => &mut file.blake3,
+            };
+            *slot = Some(digest.iter().map(|b| format!("{b:02x}")).collect());
+        }
+    }
+
+    /// Stamp the outcome, end time and how long the transfer took.
+    pub fn finish(&mut self, result: &AppResult<()>, duration: Duration) {
+        self.duration_seconds = duration.as_secs_f64();
+        self.finished_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.success = result.is_ok();
+        self.error = result.as_ref().err().map(|e| e.to_string());
+    }
+}
+
+/// Append-only history file with single-generation rotation: when the log
+/// would exceed `max_bytes` it's moved to `<path>.1`, replacing the previous one.
+#[derive(Debug, Clone)]
+pub struct HistoryLog {
+    path: PathBuf,
+    max_bytes: u64,
+    /// Serializes appends and rotation between concurrent transfers.
+    lock: Arc<Mutex<()>>,
+}
+
+impl HistoryLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_bytes: DEFAULT_MAX_BYTES,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn rotated_path(&self) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(".1");
+        PathBuf::from(name)
+    }
+
+    /// Append one entry as a JSON line, rotating first if needed.
+    pub async fn append(&self, entry: &HistoryEntry) -> AppResult<()> {
+        let mut line = serde_json::to_string(entry)
+            .map_err(|e| AppError::Serialization(format!("history entry: {e}")))?;
+        line.push('\n');
+
+        let _guard = self.lock.lock().await;
+
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let current = match tokio::fs::metadata(&self.path).await {
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        };
+        if current > 0 && current + line.len() as u64 > self.max_bytes {
+            info!("history: rotating {}", self.path.display());
+            tokio::fs::rename(&self.path, self.rotated_path()).await?;
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Up to `limit` most recent entries, newest first.
+    /// Lines that fail to parse (e.g. a torn write) are skipped.
+    pub async fn recent(&self, limit: usize) -> AppResult<Vec<HistoryEntry>> {
+        let _guard = self.lock.lock().await;
+
+        let mut entries = Vec::new();
+        for path in [self.rotated_path(), self.path.clone()] {
+            let text = match tokio::fs::read_to_string(&path).await {
+                Ok(text) => text,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for line in text.lines().filter(|l| !l.trim().is_empty()) {
+                match serde_json::from_str::<HistoryEntry>(line) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => warn!("history: skipping unreadable entry: {e}"),
+                }
+            }
+        }
+
+        entries.reverse();
+        entries.truncate(limit);
+        Ok(entries)
+    }
+
+    /// Forget every entry, rotated ones included.
+    pub async fn clear(&self) -> AppResult<()> {
+        let _guard = self.lock.lock().await;
+
+        for path in [self.rotated_path(), self.path.clone()] {
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        info!("history: cleared {}", self.path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str) -> FileInfo {
+        FileInfo {
+                name: name.into(),
+                size: 10,
+                relative_path: None,
+                duplicates: Vec::new(),
+                mime_hint: None,
+                streaming: false,
+                modified: None,
+                mode: None,
+            checksum: None,
+            link_target: None,
+        }
+    }
+
+    fn entry(name: &str) -> HistoryEntry {
+        let mut entry = HistoryEntry::for_offer(Path::new("/tmp/downloads"), &[file(name)]);
+        entry.finish(&Ok(()), Duration::ZERO);
+        entry
+    }
+
+    #[tokio::test]
+    async fn test_recent_is_newest_first() {
+        let temp = tempfile::tempdir().unwrap();
+        let log = HistoryLog::new(temp.path().join("history.jsonl"));
+        for name in ["a", "b", "c"] {
+            log.append(&entry(name)).await.unwrap();
+        }
+
+        let recent = log.recent(2).await.unwrap();
+        let names: Vec<&str> = recent.iter().map(|e| e.files[0].name.as_str()).collect();
+        assert_eq!(names, ["c", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_rotation_caps_size_and_keeps_recent() {
+        let temp = tempfile::tempdir().unwrap();
+        let line_len = serde_json::to_string(&entry("x")).unwrap().len() as u64 + 1;
+        let log = HistoryLog::new(temp.path().join("history.jsonl")).with_max_bytes(line_len * 2);
+
+        for name in ["1", "2", "3", "4", "5"] {
+            log.append(&entry(name)).await.unwrap();
+        }
+
+        assert!(std::fs::metadata(log.path()).unwrap().len() <= line_len * 2);
+        let names: Vec<String> = log
+            .recent(10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.files[0].name.clone())
+            .collect();
+        // Only the current and one rotated generation survive.
+        assert_eq!(names, ["5", "4", "3"]);
+    }
+
+    #[tokio::test]
+    async fn test_sends_and_receives_read_back_then_cleared() {
+        let temp = tempfile::tempdir().unwrap();
+        let log = HistoryLog::new(temp.path().join("history.jsonl"));
+        let mut sent = HistoryEntry::for_send(&[file("slides.key")])
+            .with_connection(Some("4-ocean-lantern".into()), false);
+        sent.finish(&Ok(()), Duration::from_millis(1500));
+        let mut received = HistoryEntry::for_offer(Path::new("/tmp/in"), &[file("notes.md")])
+            .with_connection(Some("9-velvet-compass".into()), true);
+        received.finish(&Err(AppError::Cancelled), Duration::from_secs(3));
+        log.append(&sent).await.unwrap();
+        log.append(&received).await.unwrap();
+
+        let recent = log.recent(10).await.unwrap();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].role, TransferRole::Receiver);
+        assert_eq!(recent[0].code.as_deref(), Some("9-velvet-compass"));
+        assert_eq!(recent[0].connection_type.as_deref(), Some("relay"));
+        assert!(!recent[0].success && recent[0].error.is_some());
+        assert_eq!(recent[0].duration_seconds, 3.0);
+        assert_eq!(recent[1].role, TransferRole::Sender);
+        assert_eq!(recent[1].files[0].name, "slides.key");
+        assert_eq!(recent[1].connection_type.as_deref(), Some("direct"));
+        assert!(recent[1].success);
+        assert_eq!(recent[1].duration_seconds, 1.5);
+        assert!(recent[1].save_dir.is_empty());
+
+        log.clear().await.unwrap();
+        assert!(log.recent(10).await.unwrap().is_empty());
+        // Still usable afterwards.
+        log.append(&sent).await.unwrap();
+        assert_eq!(log.recent(10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_entries_from_before_sends_were_recorded_are_receives() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("history.jsonl");
+        std::fs::write(
+            &path,
+            r#"{"finished_at":1,"save_dir":"/in","files":[],"total_bytes":0,"bytes_received":0,"success":true}"#,
+        )
+        .unwrap();
+
+        let recent = HistoryLog::new(path).recent(10).await.unwrap();
+        assert_eq!(recent[0].role, TransferRole::Receiver);
+        assert_eq!(recent[0].save_dir, "/in");
+    }
+
+    #[tokio::test]
+    async fn test_missing_log_is_empty() {
+        let temp = tempfile::tempdir().unwrap();
+        let log = HistoryLog::new(temp.path().join("none.jsonl"));
+        assert!(log.recent(10).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,273 @@
+// Receive journal — per-file state persisted while a receive is in flight,
+// so files left behind by a crash can be checked against what the sender
+// promised instead of being trusted or thrown away.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+
+use crate::crypto::checksum::{ChecksumAlgorithm, StreamingChecksum};
+use crate::error::{AppError, AppResult};
+
+/// Where receive journals live, one `<session id>.json` per receive.
+#[derive(Debug, Clone)]
+pub struct JournalDir {
+    dir: PathBuf,
+}
+
+impl JournalDir {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Journal path for a session. Only session ids are accepted, so a
+    /// caller-supplied token can't point outside the journal directory.
+    pub fn path_for(&self, session_id: &str) -> AppResult<PathBuf> {
+        let id = uuid::Uuid::parse_str(session_id)
+            .map_err(|_| AppError::Transfer(format!("invalid session token: {session_id}")))?;
+        Ok(self.dir.join(format!("{id}.json")))
+    }
+}
+
+/// One file written to disk by a receive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalFile {
+    /// Position in the sender's offer.
+    pub file_index: usize,
+    pub path: PathBuf,
+    pub size: u64,
+    /// Hex SHA-256 from the sender's `FileComplete`, recorded before the
+    /// local copy is verified. `None` until then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Hex BLAKE3 instead, when the transfer used that.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blake3: Option<String>,
+}
+
+/// Persisted state of one receive. Rewritten atomically on every change and
+/// removed once the receive succeeds; anything still on disk was interrupted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReceiveJournal {
+    pub files: Vec<JournalFile>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+/// What's on disk for a journaled file, judged against the sender's checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PartialFileStatus {
+    /// Full length and matches the sender's checksum.
+    Complete,
+    /// Missing, short, or never given a checksum; needs to be received again.
+    Partial,
+    /// Full length (or longer) but the content doesn't match.
+    Corrupt,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PartialFileReport {
+    pub path: String,
+    pub size: u64,
+    pub bytes_on_disk: u64,
+    pub status: PartialFileStatus,
+}
+
+impl ReceiveJournal {
+    /// Start a journal at `path` for `files` and write it out.
+    pub async fn create(path: PathBuf, files: Vec<JournalFile>) -> AppResult<Self> {
+        let journal = Self { files, path };
+        journal.save().await?;
+        Ok(journal)
+    }
+
+    /// Read a journal left by an earlier receive.
+    pub async fn load(path: &Path) -> AppResult<Self> {
+        let text = tokio::fs::read_to_string(path).await?;
+        let mut journal: Self = serde_json::from_str(&text)
+            .map_err(|e| AppError::Serialization(format!("receive journal: {e}")))?;
+        journal.path = path.to_path_buf();
+        Ok(journal)
+    }
+
+    /// Record the checksum the sender announced for a file.
+    pub async fn record_checksum(
+        &mut self,
+        file_index: usize,
+        algorithm: ChecksumAlgorithm,
+        digest: &[u8; 32],
+    ) -> AppResult<()> {
+        let Some(file) = self.files.iter_mut().find(|f| f.file_index == file_index) else {
+            return Ok(());
+        };
+        let slot = match algorithm {
+            ChecksumAlgorithm::Sha256 => &mut file.sha256,
+            ChecksumAlgorithm::Blake3 => &mut file.blake3,
+        };
+        *slot = Some(digest.iter().map(|b| format!("{b:02x}")).collect());
+        self.save().await
+    }
+
+    /// Drop the journal once the receive has finished cleanly.
+    pub async fn remove(self) -> AppResult<()> {
+        match tokio::fs::remove_file(&self.path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Re-check every journaled file against the bytes now on disk.
+    pub async fn verify(&self) -> AppResult<Vec<PartialFileReport>> {
+        let mut reports = Vec::with_capacity(self.files.len());
+        for file in &self.files {
+            let bytes_on_disk = match tokio::fs::metadata(&file.path).await {
+                Ok(meta) => meta.len(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e.into()),
+            };
+            let status = if bytes_on_disk > file.size {
+                PartialFileStatus::Corrupt
+            } else if bytes_on_disk < file.size {
+                PartialFileStatus::Partial
+            } else {
+                let expected = match (&file.sha256, &file.blake3) {
+                    (Some(hex), _) => Some((ChecksumAlgorithm::Sha256, hex)),
+                    (None, Some(hex)) => Some((ChecksumAlgorithm::Blake3, hex)),
+                    (None, None) => None,
+                };
+                match expected {
+                    None => PartialFileStatus::Partial,
+                    Some((algorithm, expected))
+                        if *expected == hash_file(&file.path, algorithm).await? =>
+                    {
+                        PartialFileStatus::Complete
+                    }
+                    Some(_) => PartialFileStatus::Corrupt,
+                }
+            };
+            reports.push(PartialFileReport {
+                path: file.path.to_string_lossy().into_owned(),
+                size: file.size,
+                bytes_on_disk,
+                status,
+            });
+        }
+        Ok(reports)
+    }
+
+    async fn save(&self) -> AppResult<()> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| AppError::Serialization(format!("receive journal: {e}")))?;
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write-then-rename so a crash mid-save leaves the previous version.
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, json).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+}
+
+/// Hex checksum of a file's current contents.
+async fn hash_file(path: &Path, algorithm: ChecksumAlgorithm) -> AppResult<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut checksum = StreamingChecksum::with_algorithm(algorithm);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        checksum.update(&buf[..n]);
+    }
+    Ok(checksum
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    #[tokio::test]
+    async fn test_verify_after_crash_reports_each_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let journal_path = JournalDir::new(temp.path().join("journals"))
+            .path_for(&uuid::Uuid::new_v4().to_string())
+            .unwrap();
+
+        let contents: [&[u8]; 4] = [
+            b"good file",
+            b"bitflip file",
+            b"half written",
+            b"never started",
+        ];
+        let files = contents
+            .iter()
+            .enumerate()
+            .map(|(i, data)| JournalFile {
+                file_index: i,
+                path: temp.path().join(format!("file-{i}")),
+                size: data.len() as u64,
+                sha256: None,
+                blake3: None,
+            })
+            .collect();
+        let mut journal = ReceiveJournal::create(journal_path.clone(), files)
+            .await
+            .unwrap();
+
+        // Files 0 and 1 were fully written and their checksums recorded,
+        // one per algorithm; file 1 then got damaged. File 2 was cut off
+        // mid-write.
+        std::fs::write(temp.path().join("file-0"), contents[0]).unwrap();
+        let sha: [u8; 32] = Sha256::digest(contents[0]).into();
+        journal
+            .record_checksum(0, ChecksumAlgorithm::Sha256, &sha)
+            .await
+            .unwrap();
+        std::fs::write(temp.path().join("file-1"), contents[1]).unwrap();
+        let blake = *blake3::hash(contents[1]).as_bytes();
+        journal
+            .record_checksum(1, ChecksumAlgorithm::Blake3, &blake)
+            .await
+            .unwrap();
+        std::fs::write(temp.path().join("file-1"), b"bitflip filE").unwrap();
+        std::fs::write(temp.path().join("file-2"), &contents[2][..4]).unwrap();
+        // The app dies here: nothing removes the journal.
+        drop(journal);
+
+        let reports = ReceiveJournal::load(&journal_path)
+            .await
+            .unwrap()
+            .verify()
+            .await
+            .unwrap();
+        let statuses: Vec<_> = reports.iter().map(|r| r.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                PartialFileStatus::Complete,
+                PartialFileStatus::Corrupt,
+                PartialFileStatus::Partial,
+                PartialFileStatus::Partial,
+            ]
+        );
+        assert_eq!(reports[2].bytes_on_disk, 4);
+        assert_eq!(reports[3].bytes_on_disk, 0);
+    }
+
+    #[test]
+    fn test_journal_path_rejects_non_session_tokens() {
+        let dir = JournalDir::new("/tmp/journals");
+        assert!(dir.path_for("../../etc/passwd").is_err());
+        assert!(dir.path_for(&uuid::Uuid::new_v4().to_string()).is_ok());
+    }
+}
@@ -0,0 +1,140 @@
+// Same-host transfers — when sender and receiver run on one machine, the
+// receiver copies the sender's files straight off the disk instead of
+// having them streamed over QUIC or the relay.
+//
+// Addresses can't tell whether the peer is local (NAT hairpinning, relays,
+// containers), so the sender proves it: it writes a random token to a probe
+// file and names it alongside the offer. A receiver that can read the token
+// back shares the sender's filesystem. The copy is still checked against
+// the sender's checksum.
+
+use std::io;
+use std::path::Path;
+
+use tracing::debug;
+
+use crate::error::{AppError, AppResult};
+
+/// A token written to a file for the receiver to find. The file is removed
+/// when this is dropped.
+#[derive(Debug)]
+pub struct LocalProbe {
+    path: String,
+    token: [u8; 32],
+}
+
+impl LocalProbe {
+    /// Write a fresh token to a file in the system temp directory.
+    pub async fn create() -> AppResult<Self> {
+        let token: [u8; 32] = rand::random();
+        let path = std::env::temp_dir()
+            .join(format!("relay-probe-{}", uuid::Uuid::new_v4()))
+            .into_os_string()
+            .into_string()
+            .map_err(|_| AppError::Transfer("temp directory path isn't UTF-8".into()))?;
+        tokio::fs::write(&path, token).await?;
+        Ok(Self { path, token })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn token(&self) -> [u8; 32] {
+        self.token
+    }
+}
+
+impl Drop for LocalProbe {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Whether the file at `path` holds `token`, i.e. the sender that wrote it
+/// shares this machine's filesystem.
+pub async fn same_host(path: &str, token: &[u8; 32]) -> bool {
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        return false;
+    }
+    // Only ever read a token-sized regular file.
+    match tokio::fs::metadata(path).await {
+        Ok(meta) if meta.is_file() && meta.len() == token.len() as u64 => {}
+        _ => return false,
+    }
+    tokio::fs::read(path)
+        .await
+        .is_ok_and(|found| found == token)
+}
+
+/// Copy `src` to `dst`, sharing its blocks through a copy-on-write clone
+/// where the filesystem supports one (APFS, Btrfs, XFS) and copying the
+/// bytes otherwise. Returns whether the clone worked.
+pub async fn copy_file(src: &Path, dst: &Path) -> io::Result<bool> {
+    let (from, to) = (src.to_path_buf(), dst.to_path_buf());
+    match tokio::task::spawn_blocking(move || reflink(&from, &to)).await? {
+        Ok(()) => return Ok(true),
+        Err(e) => debug!("local: no reflink for {}: {e}", dst.display()),
+    }
+    tokio::fs::copy(src, dst).await?;
+    Ok(false)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let src = std::fs::File::open(src)?;
+    let dst = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dst)?;
+    // SAFETY: both descriptors are open for the duration of the call.
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let src = CString::new(src.as_os_str().as_bytes())?;
+    let dst_c = CString::new(dst.as_os_str().as_bytes())?;
+    // clonefile(2) won't replace an existing file.
+    match std::fs::remove_file(dst) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    // SAFETY: both paths are valid NUL-terminated strings.
+    if unsafe { libc::clonefile(src.as_ptr(), dst_c.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe_found_only_with_its_token() {
+        let probe = LocalProbe::create().await.unwrap();
+        let path = probe.path().to_string();
+        assert!(same_host(&path, &probe.token()).await);
+        assert!(!same_host(&path, &[0u8; 32]).await);
+        assert!(!same_host("relative/probe", &probe.token()).await);
+
+        drop(probe);
+        assert!(!Path::new(&path).exists());
+    }
+}
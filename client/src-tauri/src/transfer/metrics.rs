@@ -0,0 +1,187 @@
+// Transfer metrics — process-wide counters for long-running deployments,
+// exported in the Prometheus text format. Only built with `--features metrics`.
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use crate::error::AppResult;
+use crate::transfer::session::TransferRole;
+
+/// Counters for one role (sending or receiving).
+#[derive(Debug, Default)]
+struct RoleCounters {
+    started: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+    bytes: AtomicU64,
+    /// Time spent in finished transfers, in milliseconds.
+    duration_ms: AtomicU64,
+}
+
+/// Counters updated by the send and receive pipelines.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    send: RoleCounters,
+    receive: RoleCounters,
+    direct: AtomicU64,
+    relayed: AtomicU64,
+}
+
+impl Metrics {
+    /// The registry shared by every transfer in this process.
+    pub fn global() -> Arc<Metrics> {
+        static GLOBAL: OnceLock<Arc<Metrics>> = OnceLock::new();
+        GLOBAL.get_or_init(Arc::default).clone()
+    }
+
+    fn role(&self, role: TransferRole) -> &RoleCounters {
+        match role {
+            TransferRole::Sender => &self.send,
+            TransferRole::Receiver => &self.receive,
+        }
+    }
+
+    /// Count a transfer starting over a direct or relayed transport.
+    pub fn transfer_started(&self, role: TransferRole, relayed: bool) {
+        self.role(role).started.fetch_add(1, Ordering::Relaxed);
+        let transport = if relayed { &self.relayed } else { &self.direct };
+        transport.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count file content sent or received (plaintext bytes).
+    pub fn add_bytes(&self, role: TransferRole, bytes: u64) {
+        self.role(role).bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a transfer's outcome and how long it ran.
+    pub fn transfer_finished(&self, role: TransferRole, elapsed: Duration, result: &AppResult<()>) {
+        let counters = self.role(role);
+        let outcome = if result.is_ok() {
+            &counters.completed
+        } else {
+            &counters.failed
+        };
+        outcome.fetch_add(1, Ordering::Relaxed);
+        counters
+            .duration_ms
+            .fetch_add(elapsed.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// All counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let roles = [("send", &self.send), ("receive", &self.receive)];
+        let mut family =
+            |name: &str, kind: &str, help: &str, value: &dyn Fn(&RoleCounters) -> String| {
+                let _ = writeln!(out, "# HELP {name} {help}");
+                let _ = writeln!(out, "# TYPE {name} {kind}");
+                for (label, counters) in roles {
+                    let _ = writeln!(out, "{name}{{role=\"{label}\"}} {}", value(counters));
+                }
+            };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        family(
+            "relay_transfers_started_total",
+            "counter",
+            "Transfers that reached a connected transport.",
+            &|c| load(&c.started).to_string(),
+        );
+        family(
+            "relay_transfers_completed_total",
+            "counter",
+            "Transfers that finished successfully.",
+            &|c| load(&c.completed).to_string(),
+        );
+        family(
+            "relay_transfers_failed_total",
+            "counter",
+            "Transfers that ended in an error or cancellation.",
+            &|c| load(&c.failed).to_string(),
+        );
+        family(
+            "relay_transfer_bytes_total",
+            "counter",
+            "File content moved, in bytes.",
+            &|c| load(&c.bytes).to_string(),
+        );
+        family(
+            "relay_transfer_average_speed_bytes",
+            "gauge",
+            "Bytes per second averaged over all finished transfers.",
+            &|c| match load(&c.duration_ms) {
+                0 => "0".into(),
+                ms => (load(&c.bytes).saturating_mul(1000) / ms).to_string(),
+            },
+        );
+
+        let (direct, relayed) = (load(&self.direct), load(&self.relayed));
+        let _ = writeln!(
+            out,
+            "# HELP relay_transfers_by_transport_total Transfers by transport."
+        );
+        let _ = writeln!(out, "# TYPE relay_transfers_by_transport_total counter");
+        let _ = writeln!(
+            out,
+            "relay_transfers_by_transport_total{{transport=\"direct\"}} {direct}"
+        );
+        let _ = writeln!(
+            out,
+            "relay_transfers_by_transport_total{{transport=\"relay\"}} {relayed}"
+        );
+        let ratio = match direct + relayed {
+            0 => 0.0,
+            total => relayed as f64 / total as f64,
+        };
+        let _ = writeln!(
+            out,
+            "# HELP relay_transfers_relayed_ratio Share of transfers that fell back to the relay."
+        );
+        let _ = writeln!(out, "# TYPE relay_transfers_relayed_ratio gauge");
+        let _ = writeln!(out, "relay_transfers_relayed_ratio {ratio}");
+        out
+    }
+}
+
+/// The process-wide metrics in Prometheus text format.
+pub fn metrics_snapshot() -> String {
+    Metrics::global().render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AppError;
+
+    #[test]
+    fn test_render_reports_outcomes_and_ratio() {
+        let metrics = Metrics::default();
+        metrics.transfer_started(TransferRole::Sender, false);
+        metrics.add_bytes(TransferRole::Sender, 4000);
+        metrics.transfer_finished(TransferRole::Sender, Duration::from_secs(2), &Ok(()));
+        metrics.transfer_started(TransferRole::Receiver, true);
+        metrics.transfer_finished(
+            TransferRole::Receiver,
+            Duration::from_secs(1),
+            &Err(AppError::Cancelled),
+        );
+
+        let text = metrics.render();
+        for line in [
+            "relay_transfers_started_total{role=\"send\"} 1",
+            "relay_transfers_completed_total{role=\"send\"} 1",
+            "relay_transfers_failed_total{role=\"receive\"} 1",
+            "relay_transfer_bytes_total{role=\"send\"} 4000",
+            "relay_transfer_average_speed_bytes{role=\"send\"} 2000",
+            "relay_transfers_by_transport_total{transport=\"relay\"} 1",
+            "relay_transfers_relayed_ratio 0.5",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+    }
+}
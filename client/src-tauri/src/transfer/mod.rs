@@ -1,5 +1,24 @@
+pub mod archive;
 pub mod code;
+pub mod collision;
+pub mod destinations;
+pub mod handshake;
+pub mod history;
+pub mod journal;
+pub mod local;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod opener;
+pub mod partials;
+pub mod portable;
 pub mod progress;
 pub mod receiver;
+pub mod resume;
+pub mod selection;
 pub mod sender;
 pub mod session;
+pub mod space;
+pub mod staging;
+pub mod streams;
+pub mod throttle;
+pub mod usage;
@@ -0,0 +1,255 @@
+// After-receive actions — revealing or opening what arrived, once a receive
+// has finished successfully.
+
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::error::{AppError, AppResult};
+
+/// Extensions desktops run or install rather than show.
+const EXECUTABLE_EXTENSIONS: &[&str] = &[
+    "app", "appimage", "bat", "cmd", "com", "command", "cpl", "deb", "desktop", "dmg", "exe",
+    "hta", "jar", "js", "jse", "lnk", "msi", "pif", "pkg", "ps1", "py", "reg", "rpm", "run", "scr",
+    "sh", "vbe", "vbs", "workflow", "wsf",
+];
+
+/// How native executables and scripts begin: ELF, PE, Mach-O (32- and
+/// 64-bit, either byte order, and universal), and an interpreter line.
+const EXECUTABLE_MAGIC: &[&[u8]] = &[
+    b"\x7fELF",
+    b"MZ",
+    b"\xfe\xed\xfa\xce",
+    b"\xfe\xed\xfa\xcf",
+    b"\xce\xfa\xed\xfe",
+    b"\xcf\xfa\xed\xfe",
+    b"\xca\xfe\xba\xbe",
+    b"#!",
+];
+
+/// What to do with the received files once a receive succeeds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OnCompleteAction {
+    /// Leave them be.
+    #[default]
+    None,
+    /// Show them in the system file manager.
+    Reveal,
+    /// Open a single received file in its default application. Several
+    /// files, or an executable, are revealed instead.
+    Open,
+}
+
+/// Hands paths to the desktop environment.
+pub trait Opener: fmt::Debug + Send + Sync {
+    /// Show `path` in the file manager: a file selected in its folder, or a
+    /// directory's contents.
+    fn reveal(&self, path: &Path) -> AppResult<()>;
+
+    /// Open the file at `path` with its default application.
+    fn open(&self, path: &Path) -> AppResult<()>;
+}
+
+/// Carry out `action` for a receive that wrote `files` under `roots`: the
+/// save directory first, then any per-item destinations. A single file is
+/// revealed or opened itself; anything else reveals the save directory.
+/// Paths that don't exist or lie outside every root are refused. The
+/// sender chose the file, so one that opening would run is only revealed.
+pub fn run_on_complete(
+    action: OnCompleteAction,
+    opener: &dyn Opener,
+    roots: &[&Path],
+    files: &[PathBuf],
+) -> AppResult<()> {
+    if action == OnCompleteAction::None {
+        return Ok(());
+    }
+    let Some(save_dir) = roots.first() else {
+        return Ok(());
+    };
+    let single = match files {
+        [file] => Some(contained(roots, file)?),
+        _ => None,
+    };
+    match (action, single) {
+        (OnCompleteAction::Open, Some(file)) => {
+            if is_executable(&file)? {
+                warn!(
+                    "not opening {}: it's executable, revealing it instead",
+                    file.display()
+                );
+                return opener.reveal(&file);
+            }
+            opener.open(&file)
+        }
+        (OnCompleteAction::Reveal, Some(file)) => opener.reveal(&file),
+        _ => opener.reveal(&save_dir.canonicalize()?),
+    }
+}
+
+/// `file`, resolved, if it exists inside one of `roots` (each resolved).
+fn contained(roots: &[&Path], file: &Path) -> AppResult<PathBuf> {
+    let resolved = file.canonicalize()?;
+    let inside = roots
+        .iter()
+        .filter_map(|root| root.canonicalize().ok())
+        .any(|root| resolved.starts_with(root));
+    if !inside || !resolved.is_file() {
+        return Err(AppError::Transfer(format!(
+            "refusing to open {}: not a received file",
+            file.display()
+        )));
+    }
+    Ok(resolved)
+}
+
+/// Whether opening `file` would run it: it has its exec bit set, an
+/// extension desktops run, or the start of an executable or script.
+fn is_executable(file: &Path) -> AppResult<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if std::fs::metadata(file)?.permissions().mode() & 0o111 != 0 {
+            return Ok(true);
+        }
+    }
+    let extension = file
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    if extension.is_some_and(|e| EXECUTABLE_EXTENSIONS.contains(&e.as_str())) {
+        return Ok(true);
+    }
+    let mut head = Vec::with_capacity(4);
+    std::fs::File::open(file)?.take(4).read_to_end(&mut head)?;
+    Ok(EXECUTABLE_MAGIC.iter().any(|magic| head.starts_with(magic)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct RecordingOpener(Mutex<Vec<(&'static str, PathBuf)>>);
+
+    impl Opener for RecordingOpener {
+        fn reveal(&self, path: &Path) -> AppResult<()> {
+            self.0.lock().unwrap().push(("reveal", path.to_path_buf()));
+            Ok(())
+        }
+
+        fn open(&self, path: &Path) -> AppResult<()> {
+            self.0.lock().unwrap().push(("open", path.to_path_buf()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_single_file_opens_and_folder_reveals() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().canonicalize().unwrap();
+        let report = dir.join("report.pdf");
+        std::fs::write(&report, b"%PDF").unwrap();
+        std::fs::create_dir(dir.join("album")).unwrap();
+        let photos = [dir.join("album/a.jpg"), dir.join("album/b.jpg")];
+        for photo in &photos {
+            std::fs::write(photo, b"jpg").unwrap();
+        }
+
+        let opener = RecordingOpener::default();
+        let single = std::slice::from_ref(&report);
+        let roots = [dir.as_path()];
+        run_on_complete(OnCompleteAction::Open, &opener, &roots, single).unwrap();
+        run_on_complete(OnCompleteAction::Reveal, &opener, &roots, single).unwrap();
+        run_on_complete(OnCompleteAction::Open, &opener, &roots, &photos).unwrap();
+        run_on_complete(OnCompleteAction::None, &opener, &roots, single).unwrap();
+        assert_eq!(
+            *opener.0.lock().unwrap(),
+            vec![
+                ("open", report.clone()),
+                ("reveal", report.clone()),
+                ("reveal", dir.clone()),
+            ]
+        );
+
+        // Nothing outside the save directory gets opened.
+        let outside = tempfile::NamedTempFile::new().unwrap();
+        let err = run_on_complete(
+            OnCompleteAction::Open,
+            &opener,
+            &roots,
+            &[outside.path().to_path_buf()],
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, AppError::Transfer(_)),
+            "unexpected error: {err}"
+        );
+        assert_eq!(opener.0.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_executables_are_revealed_not_opened() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().canonicalize().unwrap();
+        let installer = dir.join("Setup.EXE");
+        std::fs::write(&installer, b"installer").unwrap();
+        let script = dir.join("notes.txt");
+        std::fs::write(&script, b"#!/bin/sh\nrm -rf ~\n").unwrap();
+        let mut executables = vec![installer, script];
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let tool = dir.join("tool.dat");
+            std::fs::write(&tool, b"data").unwrap();
+            std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+            executables.push(tool);
+        }
+
+        let opener = RecordingOpener::default();
+        for file in &executables {
+            run_on_complete(
+                OnCompleteAction::Open,
+                &opener,
+                &[dir.as_path()],
+                std::slice::from_ref(file),
+            )
+            .unwrap();
+        }
+        let expected: Vec<_> = executables.iter().map(|f| ("reveal", f.clone())).collect();
+        assert_eq!(*opener.0.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_file_in_a_destination_opens() {
+        let temp = tempfile::tempdir().unwrap();
+        let save_dir = temp.path().join("downloads");
+        let pictures = temp.path().join("pictures");
+        std::fs::create_dir_all(&save_dir).unwrap();
+        std::fs::create_dir_all(pictures.join("photos")).unwrap();
+        let photo = pictures.join("photos/a.jpg");
+        std::fs::write(&photo, b"jpg").unwrap();
+
+        let opener = RecordingOpener::default();
+        let single = std::slice::from_ref(&photo);
+        // Refused while only the save directory counts...
+        assert!(run_on_complete(OnCompleteAction::Open, &opener, &[&save_dir], single).is_err());
+        // ...and opened once its destination does.
+        run_on_complete(
+            OnCompleteAction::Open,
+            &opener,
+            &[&save_dir, &pictures],
+            single,
+        )
+        .unwrap();
+        assert_eq!(
+            *opener.0.lock().unwrap(),
+            vec![("open", photo.canonicalize().unwrap())]
+        );
+    }
+}
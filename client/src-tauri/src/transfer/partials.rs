@@ -0,0 +1,126 @@
+// Partial files — what an unfinished receive has put on disk, shared with
+// its session so a cancel can clean up whichever phase the receive was in.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::transfer::{resume, staging};
+
+/// A cloneable record of a receive's unfinished files, shared between a
+/// session and its pipeline like the pause token.
+#[derive(Debug, Clone, Default)]
+pub struct PartialFiles {
+    inner: Arc<PartialInner>,
+}
+
+#[derive(Debug, Default)]
+struct PartialInner {
+    files: Mutex<Vec<PathBuf>>,
+    staging: Mutex<Option<PathBuf>>,
+    removed: AtomicUsize,
+    /// Held for as long as a receive is running.
+    running: tokio::sync::Mutex<()>,
+}
+
+impl PartialFiles {
+    /// Mark a receive as running until the guard is dropped.
+    pub(crate) async fn running(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.inner.running.lock().await
+    }
+
+    /// Note a file the receive is about to write.
+    pub(crate) fn track(&self, path: &Path) {
+        self.inner.files.lock().unwrap().push(path.to_path_buf());
+    }
+
+    /// The file at `path` was received in full; it's no longer partial.
+    pub(crate) fn untrack(&self, path: &Path) {
+        self.inner.files.lock().unwrap().retain(|p| p != path);
+    }
+
+    /// Note the staging directory the receive writes into.
+    pub(crate) fn set_staging(&self, dir: &Path) {
+        *self.inner.staging.lock().unwrap() = Some(dir.to_path_buf());
+    }
+
+    /// Remove the file at `path`, counting it if it was there.
+    pub(crate) async fn remove(&self, path: &Path) {
+        if tokio::fs::remove_file(path).await.is_ok() {
+            self.inner.removed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Remove the staging directory, counting the files in it.
+    pub(crate) async fn discard_staging(&self) {
+        let Some(dir) = self.inner.staging.lock().unwrap().take() else {
+            return;
+        };
+        let staged = staging::staged_files(&dir).await.map_or(0, |f| f.len());
+        self.inner.removed.fetch_add(staged, Ordering::Relaxed);
+        staging::discard(&dir).await;
+    }
+
+    /// Remove every unfinished file, with any resume sidecar, and the
+    /// staging directory.
+    pub(crate) async fn remove_all(&self) {
+        let files = std::mem::take(&mut *self.inner.files.lock().unwrap());
+        for path in &files {
+            self.remove(path).await;
+            resume::remove(path).await;
+        }
+        self.discard_staging().await;
+    }
+
+    /// How many partial files have been removed so far.
+    pub fn removed(&self) -> usize {
+        self.inner.removed.load(Ordering::Relaxed)
+    }
+
+    /// Wait for a cancelled receive to stop, then remove whatever it left
+    /// behind, including files kept for resuming. Returns how many partial
+    /// files the receive's cancellation removed in all.
+    pub async fn clean_up(&self) -> usize {
+        let _stopped = self.running().await;
+        self.remove_all().await;
+        self.removed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_clean_up_waits_for_receive_then_removes() {
+        let temp = tempfile::tempdir().unwrap();
+        let partials = PartialFiles::default();
+        let done = temp.path().join("done.txt");
+        let half = temp.path().join("half.bin");
+        let staging = staging::staging_dir(temp.path());
+        std::fs::create_dir_all(staging.join("album")).unwrap();
+        std::fs::write(staging.join("album/a.jpg"), b"jpg").unwrap();
+        for path in [&done, &half] {
+            std::fs::write(path, b"data").unwrap();
+            partials.track(path);
+        }
+        partials.untrack(&done);
+        partials.set_staging(&staging);
+
+        let guard = partials.running().await;
+        let cleanup = tokio::spawn({
+            let partials = partials.clone();
+            async move { partials.clean_up().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!cleanup.is_finished(), "must wait for the receive to stop");
+        assert!(half.exists());
+
+        drop(guard);
+        assert_eq!(cleanup.await.unwrap(), 2);
+        assert!(done.exists());
+        assert!(!half.exists());
+        assert!(!staging.exists());
+    }
+}
@@ -0,0 +1,242 @@
+// Portable resume state — what it takes to pick an interrupted transfer up
+// again from another network: the code, the key both sides derived, and
+// what was being sent or where it was going. The key is sealed under a
+// passphrase, so the exported file is safe to leave lying around.
+
+use std::fmt;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use base64::prelude::*;
+use ring::pbkdf2;
+use ring::rand::SecureRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::aes_gcm::{ChunkDecryptor, ChunkEncryptor};
+use crate::error::{AppError, AppResult};
+
+/// PBKDF2-HMAC-SHA256 rounds for new exports; the count is stored in the
+/// file so it can be raised later.
+pub const PBKDF2_ITERATIONS: u32 = 600_000;
+
+const SEALED_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+
+/// The key a session's transfer is encrypted with, once there is one.
+/// Shared between a session and its pipeline: the pipeline records the key
+/// SPAKE2 produced, or uses one already set from an import instead of
+/// running SPAKE2 again.
+#[derive(Clone, Default)]
+pub struct SessionKey(Arc<OnceLock<[u8; 32]>>);
+
+impl SessionKey {
+    /// A key carried over from an exported session.
+    pub fn imported(key: [u8; 32]) -> Self {
+        let slot = Self::default();
+        slot.set(key);
+        slot
+    }
+
+    pub fn get(&self) -> Option<[u8; 32]> {
+        self.0.get().copied()
+    }
+
+    /// Record the derived key. Only the first key set is kept.
+    pub fn set(&self, key: [u8; 32]) {
+        self.0.set(key).ok();
+    }
+}
+
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.0.get().is_some() {
+            "set"
+        } else {
+            "unset"
+        };
+        write!(f, "SessionKey({state})")
+    }
+}
+
+/// What a resumed session picks up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "role", rename_all = "camelCase")]
+pub enum ResumeTarget {
+    /// Send these files and folders again; the receiver asks only for what
+    /// it's missing.
+    Send { paths: Vec<PathBuf> },
+    /// Receive into this directory, continuing its partial files.
+    Receive { save_dir: PathBuf },
+}
+
+/// Everything a session needs to carry on elsewhere.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortableState {
+    /// The transfer code, for meeting the peer on the signaling server again.
+    pub code: String,
+    /// The key derived by the original SPAKE2 exchange. Both sides must
+    /// import their state so neither runs SPAKE2 again; each resumed
+    /// session derives its own keys from this one.
+    pub key: [u8; 32],
+    pub target: ResumeTarget,
+}
+
+impl fmt::Debug for PortableState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PortableState")
+            .field("code", &"<redacted>")
+            .field("key", &"<redacted>")
+            .field("target", &self.target)
+            .finish()
+    }
+}
+
+/// On-disk form: the state as JSON, AES-256-GCM encrypted under a key
+/// stretched from the passphrase.
+#[derive(Serialize, Deserialize)]
+struct SealedState {
+    version: u8,
+    iterations: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Encrypt `state` under `passphrase`, ready to write to a file.
+pub fn seal(state: &PortableState, passphrase: &str) -> AppResult<Vec<u8>> {
+    if passphrase.is_empty() {
+        return Err(AppError::Crypto("passphrase must not be empty".into()));
+    }
+    let mut salt = [0u8; SALT_LEN];
+    ring::rand::SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| AppError::Crypto("failed to generate salt".into()))?;
+    let key = passphrase_key(passphrase, &salt, PBKDF2_ITERATIONS)?;
+
+    let json = serde_json::to_vec(state)
+        .map_err(|e| AppError::Serialization(format!("resume state: {e}")))?;
+    let (ciphertext, nonce) = ChunkEncryptor::new(&key)?.encrypt_one(&json)?;
+    let sealed = SealedState {
+        version: SEALED_VERSION,
+        iterations: PBKDF2_ITERATIONS,
+        salt: BASE64_STANDARD.encode(salt),
+        nonce: BASE64_STANDARD.encode(nonce),
+        ciphertext: BASE64_STANDARD.encode(ciphertext),
+    };
+    serde_json::to_vec_pretty(&sealed)
+        .map_err(|e| AppError::Serialization(format!("resume state: {e}")))
+}
+
+/// Decrypt a file written by [`seal`]. A wrong passphrase and a tampered
+/// file fail the same way.
+pub fn unseal(sealed: &[u8], passphrase: &str) -> AppResult<PortableState> {
+    let malformed = |what: &str| AppError::Serialization(format!("resume state: {what}"));
+    let sealed: SealedState =
+        serde_json::from_slice(sealed).map_err(|e| malformed(&e.to_string()))?;
+    if sealed.version != SEALED_VERSION {
+        return Err(malformed(&format!(
+            "unsupported version {}",
+            sealed.version
+        )));
+    }
+    let decode = |field: &str, value: &str| {
+        BASE64_STANDARD
+            .decode(value)
+            .map_err(|_| malformed(&format!("bad {field}")))
+    };
+    let salt = decode("salt", &sealed.salt)?;
+    let nonce: [u8; 12] = decode("nonce", &sealed.nonce)?
+        .try_into()
+        .map_err(|_| malformed("bad nonce"))?;
+    let ciphertext = decode("ciphertext", &sealed.ciphertext)?;
+
+    let key = passphrase_key(passphrase, &salt, sealed.iterations)?;
+    let json = ChunkDecryptor::new(&key)?
+        .decrypt_one(&ciphertext, &nonce)
+        .map_err(|_| AppError::Crypto("wrong passphrase or corrupted resume state".into()))?;
+    serde_json::from_slice(&json).map_err(|e| malformed(&e.to_string()))
+}
+
+fn passphrase_key(passphrase: &str, salt: &[u8], iterations: u32) -> AppResult<[u8; 32]> {
+    let iterations = NonZeroU32::new(iterations)
+        .ok_or_else(|| AppError::Serialization("resume state: zero iterations".into()))?;
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    Ok(key)
+}
+
+/// Where exported resume states are kept.
+pub struct ResumeStateDir {
+    dir: PathBuf,
+}
+
+impl ResumeStateDir {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Export path for a session. Only session ids are accepted, so a
+    /// caller-supplied token can't point outside the directory.
+    pub fn path_for(&self, session_id: &str) -> AppResult<PathBuf> {
+        let id = uuid::Uuid::parse_str(session_id)
+            .map_err(|_| AppError::Transfer(format!("invalid session token: {session_id}")))?;
+        Ok(self.dir.join(format!("{id}.relay-state")))
+    }
+
+    /// Seal `state` and write it for `session_id`, returning the path.
+    pub async fn export(
+        &self,
+        session_id: &str,
+        state: &PortableState,
+        passphrase: &str,
+    ) -> AppResult<PathBuf> {
+        let path = self.path_for(session_id)?;
+        let sealed = seal(state, passphrase)?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(&path, sealed).await?;
+        Ok(path)
+    }
+}
+
+/// Read and decrypt an exported resume state.
+pub async fn import(path: &Path, passphrase: &str) -> AppResult<PortableState> {
+    let sealed = tokio::fs::read(path).await?;
+    unseal(&sealed, passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_roundtrip_and_wrong_passphrase() {
+        let state = PortableState {
+            code: "7-guitar-palace".into(),
+            key: [9u8; 32],
+            target: ResumeTarget::Receive {
+                save_dir: PathBuf::from("/tmp/incoming"),
+            },
+        };
+        let sealed = seal(&state, "correct horse").unwrap();
+        let text = String::from_utf8(sealed.clone()).unwrap();
+        assert!(
+            !text.contains("guitar"),
+            "code must not be stored in the clear"
+        );
+
+        assert_eq!(unseal(&sealed, "correct horse").unwrap(), state);
+        let err = unseal(&sealed, "battery staple").unwrap_err();
+        assert!(
+            matches!(err, AppError::Crypto(_)),
+            "unexpected error: {err}"
+        );
+        assert!(seal(&state, "").is_err());
+    }
+}
@@ -2,6 +2,11 @@ use std::collections::VecDeque;
 use std::time::Instant;
 
 use serde::Serialize;
+use tokio::sync::mpsc;
+
+use super::session::PauseReason;
+use crate::error::AppError;
+use crate::protocol::messages::CancelReason;
 
 /// Tracks transfer progress, calculates speed and ETA.
 pub struct ProgressTracker {
@@ -12,6 +17,8 @@ pub struct ProgressTracker {
     speed_samples: VecDeque<(Instant, u64)>,
     /// Max age of samples in the window (3 seconds).
     window_secs: f64,
+    /// Fastest `speed_bps` seen so far.
+    peak_speed_bps: u64,
 }
 
 impl ProgressTracker {
@@ -26,6 +33,7 @@ impl ProgressTracker {
             bytes_total,
             speed_samples: samples,
             window_secs: 3.0,
+            peak_speed_bps: 0,
         }
     }
 
@@ -44,6 +52,7 @@ impl ProgressTracker {
                 break;
             }
         }
+        self.peak_speed_bps = self.peak_speed_bps.max(self.speed_bps());
     }
 
     /// Current transfer speed in bytes per second (moving average).
@@ -98,6 +107,12 @@ impl ProgressTracker {
         }
         (self.bytes_transferred as f64 / elapsed) as u64
     }
+
+    /// Fastest moving-average speed over the transfer so far; never below
+    /// the overall average, which a transfer too short to sample can be.
+    pub fn peak_speed_bps(&self) -> u64 {
+        self.peak_speed_bps.max(self.average_speed())
+    }
 }
 
 /// Events emitted to the frontend via Tauri events.
@@ -115,14 +130,38 @@ pub enum ProgressEvent {
         current_file: String,
         percent: f32,
     },
+    /// How far one file has got. `file_bytes` counts from the start of the
+    /// file, so a resumed file starts part-way; `TransferProgress` counts
+    /// only this session's bytes, the sum of each file's growth.
+    FileProgress {
+        file_index: u16,
+        file_bytes: u64,
+        file_total: u64,
+        percent: f32,
+    },
     FileCompleted {
         name: String,
     },
+    /// The receiver took a file straight from the sender's disk on the same
+    /// machine; `reflinked` if as a copy-on-write clone.
+    LocalCopy {
+        name: String,
+        reflinked: bool,
+    },
+    /// The sender reading its files to checksum them before the offer.
+    Hashing {
+        bytes_hashed: u64,
+        bytes_total: u64,
+        current_file: String,
+    },
     TransferComplete {
         duration_seconds: u32,
         average_speed: u64,
         total_bytes: u64,
         file_count: u32,
+        /// "direct" or "relay", as in `ConnectionTypeChanged`.
+        connection_type: String,
+        peak_speed_bps: u64,
     },
     Error {
         message: String,
@@ -134,14 +173,103 @@ pub enum ProgressEvent {
     ConnectionTypeChanged {
         connection_type: String,
     },
+    /// Trying a direct QUIC connection to the peer.
+    AttemptingDirect,
+    /// The direct connection didn't come about, e.g. it timed out.
+    DirectFailed {
+        reason: String,
+    },
+    /// Asking the signaling server to relay the transfer instead.
+    FallingBackToRelay,
+    /// How the relay path is holding up, from the sender of a relayed
+    /// transfer. `goodput_bps` is file data per second; `wire_bps` is
+    /// everything handed to the relay, its ceiling. A slow relay shows a
+    /// long `rtt_ms` and stalls; a slow disk shows neither.
+    ConnectionStats {
+        rtt_ms: Option<u32>,
+        goodput_bps: u64,
+        wire_bps: u64,
+        stalls: u32,
+        stalls_per_minute: f32,
+    },
+    Paused {
+        reason: PauseReason,
+    },
+    Resumed,
+    /// No peer joined before the code's TTL ran out; the send was cancelled.
+    Expired,
+    /// Another sender already holds the code; the send was abandoned and
+    /// can be started again under a fresh one.
+    CodeInUse,
+    PeerCancelled {
+        reason: CancelReason,
+        detail: String,
+    },
+    TextReceived {
+        name: String,
+        text: String,
+    },
+    /// Something the user selected was left out of the transfer.
+    FileSkipped {
+        path: String,
+        reason: String,
+    },
+    /// Every file is received and verified into `staging_dir`, waiting on
+    /// inspection before it's moved into the save directory.
+    StagingReady {
+        staging_dir: String,
+        files: Vec<String>,
+    },
+}
+
+impl ProgressEvent {
+    /// Progress through file `file_index`: `file_bytes` of `file_total`.
+    /// A streaming file can outgrow its offered size; it stays at 100%.
+    pub(crate) fn file_progress(file_index: u16, file_bytes: u64, file_total: u64) -> Self {
+        let percent = if file_total == 0 {
+            100.0
+        } else {
+            (file_bytes as f64 / file_total as f64 * 100.0).min(100.0) as f32
+        };
+        Self::FileProgress {
+            file_index,
+            file_bytes,
+            file_total,
+            percent,
+        }
+    }
+}
+
+/// Report a cancellation received from the peer and build the matching error.
+pub(crate) fn peer_cancelled(
+    progress_tx: &mpsc::UnboundedSender<ProgressEvent>,
+    reason: CancelReason,
+    detail: String,
+) -> AppError {
+    progress_tx
+        .send(ProgressEvent::PeerCancelled {
+            reason,
+            detail: detail.clone(),
+        })
+        .ok();
+    AppError::PeerCancelled { reason, detail }
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct FileOfferInfo {
     pub name: String,
     pub size: u64,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Where the file goes under the save folder, for files sent as part of
+    /// a folder, so the prompt can group them before they're accepted.
+    #[serde(rename = "relativePath", skip_serializing_if = "Option::is_none")]
     pub relative_path: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub duplicates: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime_hint: Option<String>,
+    /// Still growing; `size` is only where it started.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub streaming: bool,
 }
 
 #[cfg(test)]
@@ -174,4 +302,49 @@ mod tests {
         // Should be roughly 10 MB/s (1MB in 0.1s) — allow wide tolerance
         assert!(speed > 1_000_000, "speed should be > 1 MB/s, got {speed}");
     }
+
+    #[test]
+    fn test_peak_speed_keeps_the_fastest_window() {
+        let mut tracker = ProgressTracker::new(10_000_000);
+        sleep(Duration::from_millis(50));
+        tracker.update(2_000_000);
+        let fast = tracker.speed_bps();
+        sleep(Duration::from_millis(200));
+        tracker.update(10_000);
+        assert!(tracker.speed_bps() < fast);
+        assert_eq!(tracker.peak_speed_bps(), fast);
+    }
+
+    #[test]
+    fn test_file_offer_keeps_relative_paths() {
+        let file = |name: &str, relative_path: Option<&str>| FileOfferInfo {
+            name: name.into(),
+            size: 12,
+            relative_path: relative_path.map(Into::into),
+            duplicates: Vec::new(),
+            mime_hint: None,
+            streaming: false,
+        };
+        let event = ProgressEvent::FileOffer {
+            session_id: "s1".into(),
+            files: vec![
+                file("a.txt", Some("photos/2024/a.txt")),
+                file("b.txt", Some("photos/b.txt")),
+                file("notes.txt", None),
+            ],
+        };
+
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "fileOffer");
+        let paths: Vec<_> = json["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f.get("relativePath").and_then(|p| p.as_str()))
+            .collect();
+        assert_eq!(
+            paths,
+            [Some("photos/2024/a.txt"), Some("photos/b.txt"), None]
+        );
+    }
 }
@@ -1,16 +1,140 @@
 // Receiver pipeline — orchestrates the full receive flow.
 
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
+use tokio::io::AsyncReadExt;
 use tokio::sync::{mpsc, oneshot};
 use tracing::{info, warn};
 
 use crate::crypto::aes_gcm::ChunkDecryptor;
+use crate::crypto::checksum::ChecksumAlgorithm;
 use crate::error::{AppError, AppResult};
+use crate::network::health::STALL_TIMEOUT;
 use crate::network::transport::Transport;
-use crate::protocol::messages::PeerMessage;
-use crate::protocol::reassembler::FileReassembler;
-use crate::transfer::progress::{FileOfferInfo, ProgressEvent, ProgressTracker};
+use crate::protocol::messages::{
+    CancelReason, FileInfo, HaveFile, PeerMessage, FEATURE_HAVE_FILES, FEATURE_PARTIAL_ACCEPT,
+    FEATURE_PING, MIME_TEXT_PLAIN,
+};
+use crate::protocol::reassembler::{FileReassembler, FlushPolicy};
+use crate::protocol::sink::{DiscardSink, FileSink, SinkFactory};
+use crate::transfer::archive::{self, Unpacked};
+use crate::transfer::collision::{self, CollisionPolicy};
+use crate::transfer::destinations::{self, Destinations};
+use crate::transfer::handshake;
+use crate::transfer::history::{HistoryEntry, HistoryLog};
+use crate::transfer::journal::{JournalFile, ReceiveJournal};
+use crate::transfer::local;
+#[cfg(feature = "metrics")]
+use crate::transfer::metrics::Metrics;
+use crate::transfer::opener::{self, OnCompleteAction, Opener};
+use crate::transfer::partials::PartialFiles;
+use crate::transfer::progress::{peer_cancelled, FileOfferInfo, ProgressEvent, ProgressTracker};
+use crate::transfer::resume::{self, ResumeState};
+use crate::transfer::selection::Selection;
+use crate::transfer::session::PauseToken;
+#[cfg(feature = "metrics")]
+use crate::transfer::session::TransferRole;
+use crate::transfer::space::{check_space, SpaceProbe};
+use crate::transfer::staging::{self, StagingInspector};
+use crate::transfer::streams::Inbound;
+use crate::transfer::usage::UsageStore;
+
+/// Largest text snippet surfaced inline rather than saved (1 MiB).
+pub const MAX_INLINE_TEXT: u64 = 1024 * 1024;
+
+/// Options and controls for the receive pipeline.
+#[derive(Debug, Clone, Default)]
+pub struct ReceiveOptions {
+    /// How often received data is flushed to disk.
+    pub flush_policy: FlushPolicy,
+    /// Pausing stops reading from the transport, which back-pressures the sender.
+    pub pause: PauseToken,
+    /// Where to record the outcome once an offer has been received.
+    pub history: Option<HistoryLog>,
+    /// The transfer code, noted in the history record.
+    pub code: Option<String>,
+    /// Deliver small plain-text files as `ProgressEvent::TextReceived`
+    /// instead of saving them.
+    pub inline_text: bool,
+    /// Checked against the offer before the user is asked; a receive that
+    /// would run out of bytes or inodes is declined automatically.
+    pub space_probe: Option<Arc<dyn SpaceProbe>>,
+    /// Where to keep a journal of per-file state while receiving, so
+    /// `verify_partial` can check what a crash left behind.
+    pub journal: Option<PathBuf>,
+    /// Receive into sinks from this factory instead of files under the save
+    /// directory; `VerifyOnly` checks everything and keeps nothing.
+    /// Disk-only extras (space check, journal, piece-hash sidecars, empty
+    /// folders) are skipped for files it handles.
+    pub sink_factory: Option<Arc<dyn SinkFactory>>,
+    /// Hard cap on the whole receive, however well it's progressing.
+    /// Exceeding it cancels with `AppError::ConnectionTimeout` and removes
+    /// files that weren't finished.
+    pub max_total_duration: Option<Duration>,
+    /// Give up, as with `max_total_duration`, once the sender has sent
+    /// nothing at all for this long; each message starts the wait afresh,
+    /// so a slow but steady transfer carries on. `None` uses
+    /// [`STALL_TIMEOUT`]. Not for senders that don't ping while paused, or
+    /// for streaming files, which can go quiet for as long as they like.
+    pub stall_timeout: Option<Duration>,
+    /// Receive into a staging directory and let this decide, once every
+    /// file is verified, whether they are moved into the save directory.
+    /// Rejected or failed receives leave the save directory untouched.
+    /// Ignored when `sink_factory` is set.
+    pub inspector: Option<Arc<dyn StagingInspector>>,
+    /// Keep partial files when a receive fails, noting in a `.relay-resume`
+    /// sidecar how much of each is on disk, and continue them on the next
+    /// receive of the same files into the same directory.
+    pub resume: bool,
+    /// What to do with the saved files once the receive succeeds.
+    /// Needs `opener`; files handed to a `sink_factory` are left alone.
+    pub on_complete: OnCompleteAction,
+    /// Carries out `on_complete`.
+    pub opener: Option<Arc<dyn Opener>>,
+    /// Records the files this receive leaves unfinished.
+    pub partials: PartialFiles,
+    /// Per-item save directories, read once the offer is accepted. When
+    /// staging for an `inspector`, applied as the files are promoted.
+    pub destinations: Destinations,
+    /// Which offered files to accept, read once the offer is accepted.
+    pub selection: Selection,
+    /// Where to count the bytes received. Offers are refused once its
+    /// monthly quota is used up.
+    pub usage: Option<UsageStore>,
+    /// Before accepting, hash the offered files already in the save
+    /// directory and tell the sender, which skips those it has identical
+    /// copies of. Ignored when staging, and for files with duplicates.
+    pub skip_existing: bool,
+    /// What to do about a file already where a received one would be
+    /// saved. A partial file this receive is resuming doesn't count.
+    /// Ignored when staging, and for files handed to a `sink_factory`.
+    pub on_collision: CollisionPolicy,
+    /// Decline offers of more than this many bytes in all, and abort if a
+    /// growing stream takes the receive past it.
+    pub max_total_bytes: Option<u64>,
+    /// Decline offers of more than this many files, counting the copies of
+    /// deduplicated ones.
+    pub max_file_count: Option<usize>,
+    /// Where to count this transfer.
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<Arc<Metrics>>,
+}
+
+impl ReceiveOptions {
+    fn shows_inline(&self, file: &FileInfo) -> bool {
+        self.inline_text
+            && file.mime_hint.as_deref() == Some(MIME_TEXT_PLAIN)
+            && file.size <= MAX_INLINE_TEXT
+            && !file.streaming
+    }
+
+    fn writes_to_disk(&self, file: &FileInfo) -> bool {
+        self.sink_factory.is_none() && !self.shows_inline(file)
+    }
+}
 
 /// Run the receiver pipeline over an established transport (QUIC or relay).
 pub async fn run_receive(
@@ -20,7 +144,102 @@ pub async fn run_receive(
     progress_tx: mpsc::UnboundedSender<ProgressEvent>,
     accept_rx: oneshot::Receiver<bool>,
     cancel: tokio_util::sync::CancellationToken,
+    options: ReceiveOptions,
+) -> AppResult<()> {
+    let history = options.history.clone();
+    let usage = options.usage.clone();
+    let started = std::time::Instant::now();
+    let max_total_duration = options.max_total_duration;
+    let keep_partials = options.resume;
+    #[cfg(feature = "metrics")]
+    let metrics = options.metrics.clone();
+    #[cfg(feature = "metrics")]
+    if let Some(metrics) = &metrics {
+        metrics.transfer_started(TransferRole::Receiver, transport.is_relayed());
+    }
+
+    let partials = options.partials.clone();
+    let _running = partials.running().await;
+    let staging = (options.inspector.is_some() && options.sink_factory.is_none())
+        .then(|| staging::staging_dir(&save_dir));
+    if let Some(dir) = &staging {
+        partials.set_staging(dir);
+    }
+    let mut record = None;
+    let receive = receive_files(
+        save_dir,
+        staging.as_deref(),
+        encryption_key,
+        accept_rx,
+        options,
+        ReceiveContext {
+            transport: &mut *transport,
+            progress_tx,
+            cancel,
+            record: &mut record,
+        },
+    );
+    let result = match max_total_duration {
+        Some(limit) => match tokio::time::timeout(limit, receive).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("receiver: gave up after {limit:?}");
+                if !keep_partials {
+                    partials.remove_all().await;
+                }
+                Err(abort(transport, AppError::ConnectionTimeout).await)
+            }
+        },
+        None => receive.await,
+    };
+    if result.is_err() {
+        partials.discard_staging().await;
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(metrics) = &metrics {
+        metrics.transfer_finished(TransferRole::Receiver, started.elapsed(), &result);
+    }
+
+    if let Some(usage) = &usage {
+        if let Err(e) = usage.save().await {
+            warn!("receiver: failed to record usage: {e}");
+        }
+    }
+
+    if let (Some(log), Some(mut entry)) = (history, record) {
+        entry.finish(&result, started.elapsed());
+        if let Err(e) = log.append(&entry).await {
+            warn!("receiver: failed to record history: {e}");
+        }
+    }
+    result
+}
+
+/// What each stage of a receive talks over, reports to and is stopped by.
+struct ReceiveContext<'a> {
+    transport: &'a mut Transport,
+    progress_tx: mpsc::UnboundedSender<ProgressEvent>,
+    cancel: tokio_util::sync::CancellationToken,
+    /// Filled in once an offer has been received.
+    record: &'a mut Option<HistoryEntry>,
+}
+
+async fn receive_files(
+    save_dir: PathBuf,
+    staging: Option<&Path>,
+    encryption_key: [u8; 32],
+    mut accept_rx: oneshot::Receiver<bool>,
+    options: ReceiveOptions,
+    ctx: ReceiveContext<'_>,
 ) -> AppResult<()> {
+    let ReceiveContext {
+        transport,
+        progress_tx,
+        cancel,
+        record,
+    } = ctx;
+    let partials = &options.partials;
     info!("receiver: waiting for file offer");
     progress_tx
         .send(ProgressEvent::StateChanged {
@@ -28,14 +247,86 @@ pub async fn run_receive(
         })
         .ok();
 
-    // Receive file offer
-    let offer = transport.recv_peer_message().await?;
-    let files = match offer {
-        PeerMessage::FileOffer { files } => files,
+    // Receive file offer, which a sender offering local copying prefaces
+    // with its probe.
+    let (peer, probe, offer) = tokio::select! {
+        received = async {
+            let peer = handshake::exchange_hello(transport).await?;
+            match recv_answering_pings(transport).await? {
+                PeerMessage::LocalProbe { path, token } => {
+                    let offer = recv_answering_pings(transport).await?;
+                    Ok::<_, AppError>((peer, Some((path, token)), offer))
+                }
+                offer => Ok((peer, None, offer)),
+            }
+        } => received?,
+        _ = cancel.cancelled() => return Err(AppError::Cancelled),
+    };
+    // Every feature a sender can offer is supported, so take them all;
+    // local copying only if the sender turns out to be on this machine.
+    let (files, piece_hashes, challenge, mut features) = match offer {
+        PeerMessage::FileOffer {
+            files,
+            piece_hashes,
+            challenge,
+            features,
+        } => (files, piece_hashes, challenge, features),
+        // A sender with nothing to send says so instead of offering.
+        PeerMessage::Cancel { reason, detail } => {
+            warn!("receiver: sender cancelled before offering: {reason}");
+            return Err(peer_cancelled(&progress_tx, reason, detail));
+        }
         _ => return Err(AppError::Transfer("expected FileOffer message".into())),
     };
 
+    if features.local_copy {
+        features.local_copy = match &probe {
+            Some((path, token)) => local::same_host(path, token).await,
+            None => false,
+        };
+        info!("receiver: sender on this machine: {}", features.local_copy);
+    }
+    // Unpack an archive only where its entries can land as they come:
+    // straight on disk, with nothing to resume and nothing to rename.
+    // Anywhere else it's kept as the tar it is.
+    if features.archive {
+        features.archive = matches!(
+            files.as_slice(),
+            [file] if !file.streaming && options.writes_to_disk(file)
+        ) && !options.resume
+            && options.on_collision == CollisionPolicy::Overwrite;
+        info!("receiver: unpacking the archive: {}", features.archive);
+    }
+
     info!("receiver: got offer for {} file(s)", files.len());
+    *record = Some(
+        HistoryEntry::for_offer(&save_dir, &files)
+            .with_connection(options.code.clone(), transport.is_relayed()),
+    );
+
+    if let Some(usage) = &options.usage {
+        if let Err(e) = usage.check_quota().await {
+            warn!("receiver: declining offer: {e}");
+            return Err(abort(transport, e).await);
+        }
+    }
+
+    if let Some(probe) = &options.space_probe {
+        let on_disk = files.iter().filter(|f| options.writes_to_disk(f));
+        if let Err(e) = check_space(probe.as_ref(), &save_dir, on_disk) {
+            warn!("receiver: declining offer: {e}");
+            return Err(abort(transport, e).await);
+        }
+    }
+
+    if let Err(e) = check_limits(&options, &files) {
+        warn!("receiver: declining offer: {e}");
+        transport
+            .send_peer_message(&PeerMessage::FileDecline)
+            .await
+            .ok();
+        return Err(e);
+    }
 
     // Notify frontend about the offer
     let offer_infos: Vec<FileOfferInfo> = files
@@ -44,6 +335,9 @@ pub async fn run_receive(
             name: f.name.clone(),
             size: f.size,
             relative_path: f.relative_path.clone(),
+            duplicates: f.duplicates.clone(),
+            mime_hint: f.mime_hint.clone(),
+            streaming: f.streaming,
         })
         .collect();
     progress_tx
@@ -53,10 +347,27 @@ pub async fn run_receive(
         })
         .ok();
 
-    // Wait for user acceptance
-    let accepted = tokio::select! {
-        result = accept_rx => result.unwrap_or(false),
-        _ = cancel.cancelled() => false,
+    // Wait for user acceptance. Over the relay the sender pings meanwhile,
+    // and either way it may give up waiting. It sends nothing else until we
+    // answer, so a direct read cut short here can only lose a `Cancel` from
+    // a sender that has already gone.
+    let accepted = loop {
+        tokio::select! {
+            result = &mut accept_rx => break result.unwrap_or(false),
+            _ = cancel.cancelled() => break false,
+            msg = transport.recv_peer_message() => match msg? {
+                PeerMessage::Ping => transport.send_peer_message(&PeerMessage::Pong).await?,
+                PeerMessage::Cancel { reason, detail } => {
+                    warn!("receiver: sender cancelled before we accepted: {reason}");
+                    return Err(peer_cancelled(&progress_tx, reason, detail));
+                }
+                _ => {
+                    return Err(AppError::Transfer(
+                        "unexpected message while awaiting acceptance".into(),
+                    ));
+                }
+            },
+        }
     };
 
     if !accepted {
@@ -66,72 +377,425 @@ pub async fn run_receive(
         return Err(AppError::Cancelled);
     }
 
-    transport
-        .send_peer_message(&PeerMessage::FileAccept)
-        .await?;
-    progress_tx
-        .send(ProgressEvent::StateChanged {
-            state: "transferring".into(),
+    // Files left out of a partial accept. A sender that can't leave them
+    // out sends them anyway, and they're thrown away like a skipped
+    // collision.
+    let mut unselected = vec![false; files.len()];
+    let selection = options.selection.snapshot();
+    if let Some(indices) = &selection {
+        unselected.fill(true);
+        for &file_index in indices {
+            match unselected.get_mut(file_index as usize) {
+                Some(declined) => *declined = false,
+                None => {
+                    let err = AppError::Transfer(format!("no offered file {file_index} to accept"));
+                    return Err(abort(transport, err).await);
+                }
+            }
+        }
+        if !unselected.contains(&false) {
+            info!("receiver: no files selected, declining");
+            transport
+                .send_peer_message(&PeerMessage::FileDecline)
+                .await?;
+            return Err(AppError::Cancelled);
+        }
+    }
+    let partial = selection.is_some() && peer.supports(FEATURE_PARTIAL_ACCEPT);
+    let total_bytes: u64 = files
+        .iter()
+        .zip(&unselected)
+        .filter(|(_, &declined)| !(declined && partial))
+        .map(|(f, _)| f.size)
+        .sum();
+
+    // Files go straight to the save directory unless they're staged first.
+    let target_dir = staging.unwrap_or(&save_dir);
+    let rel_paths = files
+        .iter()
+        .map(|f| match &f.relative_path {
+            Some(rel_path) => sanitize_path(rel_path),
+            None => Ok(PathBuf::from(sanitize_filename(&f.name))),
         })
-        .ok();
+        .collect::<AppResult<Vec<_>>>()?;
+    // Staged files are written under the staging directory as offered, and
+    // only go to their destinations once promoted.
+    let chosen = options.destinations.snapshot();
+    if let Err(e) = check_destinations(&chosen, &rel_paths).await {
+        return Err(abort(transport, e).await);
+    }
+    let destinations = match staging {
+        None => chosen.clone(),
+        Some(_) => Default::default(),
+    };
+    if let Err(e) = check_links(&files, &rel_paths) {
+        return Err(abort(transport, e).await);
+    }
+    if let Err(e) = check_saved_links(target_dir, &destinations, &files, &rel_paths) {
+        return Err(abort(transport, e).await);
+    }
+
+    // Files the sender agreed we already have; they're left as they are.
+    let mut skipped = vec![false; files.len()];
+    let mut existing = Vec::new();
+    if options.skip_existing
+        && staging.is_none()
+        && !features.archive
+        && peer.supports(FEATURE_HAVE_FILES)
+    {
+        existing = existing_files(&options, &files, &rel_paths, target_dir, &destinations).await;
+        existing.retain(|(file_index, _)| !unselected[*file_index]);
+    }
+    if !existing.is_empty() {
+        info!(
+            "receiver: already have {} offered file(s), asking to skip them",
+            existing.len()
+        );
+        let entries = existing.iter().map(|(_, have)| have.clone()).collect();
+        transport
+            .send_peer_message(&PeerMessage::HaveFiles { entries })
+            .await?;
+        match recv_answering_pings(transport).await? {
+            PeerMessage::SkipFiles { file_indices } => {
+                for file_index in file_indices {
+                    let idx = file_index as usize;
+                    if !existing.iter().any(|(i, _)| *i == idx) {
+                        let err = AppError::Transfer(format!(
+                            "sender skipped file {file_index}, which we don't have"
+                        ));
+                        return Err(abort(transport, err).await);
+                    }
+                    skipped[idx] = true;
+                }
+            }
+            PeerMessage::Cancel { reason, detail } => {
+                warn!("receiver: sender cancelled before sending: {reason}");
+                return Err(peer_cancelled(&progress_tx, reason, detail));
+            }
+            _ => return Err(AppError::Transfer("expected SkipFiles message".into())),
+        }
+    }
 
-    let total_bytes: u64 = files.iter().map(|f| f.size).sum();
-    let mut tracker = ProgressTracker::new(total_bytes);
+    // Where each file goes, decided before any is created. Files that would
+    // land on an existing one are dealt with per the collision policy;
+    // discarded ones are received but leave the existing file as it is.
+    let mut landing = Vec::with_capacity(files.len());
+    let mut discarded = vec![false; files.len()];
+    let mut claimed = HashSet::new();
+    for (file_index, (file_info, rel_path)) in files.iter().zip(&rel_paths).enumerate() {
+        let placed = destinations::place(target_dir, &destinations, rel_path);
+        if unselected[file_index] {
+            discarded[file_index] = true;
+            landing.push(placed);
+            continue;
+        }
+        let resuming = options.resume
+            && tokio::fs::try_exists(resume::sidecar_path(&placed))
+                .await
+                .unwrap_or(false);
+        let path = if skipped[file_index]
+            || staging.is_some()
+            || features.archive
+            || !options.writes_to_disk(file_info)
+            || resuming
+        {
+            placed
+        } else {
+            match collision::resolve(&placed, options.on_collision, &claimed).await {
+                Ok(Some(path)) => {
+                    if path != placed {
+                        info!(
+                            "receiver: saving '{}' as {}",
+                            file_info.name,
+                            path.display()
+                        );
+                    }
+                    path
+                }
+                Ok(None) => {
+                    discarded[file_index] = true;
+                    placed
+                }
+                Err(e) => return Err(abort(transport, e).await),
+            }
+        };
+        claimed.insert(path.clone());
+        landing.push(path);
+    }
 
     // Create reassemblers for each file
     let mut reassemblers: Vec<Option<FileReassembler>> = Vec::new();
-    for file_info in &files {
-        // Determine file path: use relative_path for folder transfers, name for flat files
-        let file_path = if let Some(ref rel_path) = file_info.relative_path {
-            let safe_rel = sanitize_path(rel_path)?;
-            let full = save_dir.join(&safe_rel);
-            // Create parent directories for nested files
-            if let Some(parent) = full.parent() {
-                tokio::fs::create_dir_all(parent).await?;
-            }
-            full
+    let mut file_paths: Vec<PathBuf> = Vec::new();
+    // Empty folders the sender asked for, relative to the save directory.
+    let mut created_dirs: Vec<PathBuf> = Vec::new();
+    // Bytes of each file already on disk from an earlier receive.
+    let mut resumed: Vec<u64> = Vec::new();
+    // Where each link goes and what it points at, by file index. A link is
+    // made when its (empty) file completes.
+    let mut links: HashMap<usize, (PathBuf, String)> = HashMap::new();
+    // The thread unpacking an agreed archive, and what it left on disk.
+    let mut unpacking = None;
+    let mut unpacked = Unpacked::default();
+    for (file_index, (file_info, file_path)) in files.iter().zip(landing).enumerate() {
+        if discarded[file_index] || file_info.link_target.is_some() {
+            if unselected[file_index] {
+                info!("receiver: '{}' not selected", file_info.name);
+                progress_tx
+                    .send(ProgressEvent::FileSkipped {
+                        path: file_info.target_path().into(),
+                        reason: "not selected".into(),
+                    })
+                    .ok();
+            } else if discarded[file_index] {
+                info!(
+                    "receiver: '{}' already exists, discarding it",
+                    file_info.name
+                );
+                progress_tx
+                    .send(ProgressEvent::FileSkipped {
+                        path: file_info.target_path().into(),
+                        reason: "already exists".into(),
+                    })
+                    .ok();
+            } else if let (Some(target), true) =
+                (&file_info.link_target, options.writes_to_disk(file_info))
+            {
+                links.insert(file_index, (file_path.clone(), target.clone()));
+            }
+            // From here on it's left as it is, like a file the sender skips;
+            // a link has no content to keep anyway.
+            skipped[file_index] = true;
+            file_paths.push(file_path);
+            resumed.push(0);
+            if unselected[file_index] && partial {
+                // The sender won't send it at all.
+                reassemblers.push(None);
+                continue;
+            }
+            let decryptor = ChunkDecryptor::new(&encryption_key)?;
+            let mut reassembler =
+                FileReassembler::with_sink(Box::new(DiscardSink), decryptor, options.flush_policy)
+                    .with_checksum(features.checksum);
+            if let Some(config) = piece_hashes {
+                reassembler = reassembler.with_piece_hashes(config)?;
+            }
+            if let Some(nonce) = &challenge {
+                reassembler = reassembler.with_challenge(nonce);
+            }
+            if !file_info.streaming {
+                reassembler = reassembler.with_expected_size(file_info.size);
+            }
+            reassemblers.push(Some(reassembler));
+            continue;
+        }
+
+        if skipped[file_index] {
+            info!("receiver: keeping '{}' as it is", file_info.name);
+            if let (Some(entry), Some((_, have))) = (
+                record.as_mut(),
+                existing.iter().find(|(i, _)| *i == file_index),
+            ) {
+                entry.set_verified(file_index, ChecksumAlgorithm::Sha256, &have.sha256);
+            }
+            progress_tx
+                .send(ProgressEvent::FileSkipped {
+                    path: file_info.target_path().into(),
+                    reason: "already received".into(),
+                })
+                .ok();
+            reassemblers.push(None);
+            file_paths.push(file_path);
+            resumed.push(file_info.size);
+            continue;
+        }
+
+        let resume_at = if options.resume && options.writes_to_disk(file_info) && !file_info.streaming
+        {
+            let expected = file_info.checksum.map(|sum| (features.checksum, sum));
+            resume::resumable_prefix(&file_path, file_info.size, expected).await
         } else {
-            let safe_name = sanitize_filename(&file_info.name);
-            save_dir.join(&safe_name)
+            0
         };
 
         let decryptor = ChunkDecryptor::new(&encryption_key)?;
-        let reassembler = FileReassembler::new(&file_path, decryptor).await?;
+        let mut reassembler = if options.shows_inline(file_info) {
+            FileReassembler::in_memory(decryptor)
+        } else if features.archive {
+            let (sink, unpack) =
+                archive::unpacker(target_dir, file_info.size, options.max_file_count);
+            unpacking = Some(unpack);
+            FileReassembler::with_sink(Box::new(sink), decryptor, options.flush_policy)
+        } else if let Some(factory) = &options.sink_factory {
+            let sink = factory.open(&file_path, file_info).await?;
+            FileReassembler::with_sink(sink, decryptor, options.flush_policy)
+        } else if resume_at > 0 {
+            partials.track(&file_path);
+            let sink = FileSink::resume(&file_path, resume_at).await?;
+            FileReassembler::with_sink(Box::new(sink), decryptor, options.flush_policy)
+        } else {
+            partials.track(&file_path);
+            resume::remove(&file_path).await;
+            FileReassembler::new(&file_path, decryptor, options.flush_policy).await?
+        };
+        reassembler = reassembler.with_checksum(features.checksum);
+        if let Some(config) = piece_hashes {
+            reassembler = reassembler.with_piece_hashes(config)?;
+        }
+        if let Some(nonce) = &challenge {
+            reassembler = reassembler.with_challenge(nonce);
+        }
+        if !file_info.streaming {
+            reassembler = reassembler.with_expected_size(file_info.size);
+        }
+        if resume_at > 0 {
+            let mut prefix = tokio::fs::File::open(&file_path).await?.take(resume_at);
+            reassembler.resume_from(&mut prefix, resume_at).await?;
+            info!(
+                "receiver: resuming '{}' at byte {resume_at}",
+                file_info.name
+            );
+            transport
+                .send_peer_message(&PeerMessage::ResumeRequest {
+                    file_index: file_index as u16,
+                    bytes_received: resume_at,
+                })
+                .await?;
+        }
         reassemblers.push(Some(reassembler));
+        file_paths.push(file_path);
+        resumed.push(resume_at);
     }
 
+    let accept = if partial {
+        PeerMessage::FileAcceptPartial {
+            accepted_indices: (0..files.len() as u16)
+                .filter(|&i| !unselected[i as usize])
+                .collect(),
+            features,
+        }
+    } else {
+        PeerMessage::FileAccept { features }
+    };
+    transport.send_peer_message(&accept).await?;
+    progress_tx
+        .send(ProgressEvent::StateChanged {
+            state: "transferring".into(),
+        })
+        .ok();
+
+    // Progress covers only what's actually received this time.
+    let already_had = resumed.iter().sum::<u64>();
+    let mut tracker = ProgressTracker::new(total_bytes - already_had);
+    // What each file's resume sidecar last recorded.
+    let mut recorded = resumed.clone();
+
+    let mut journal = match &options.journal {
+        Some(path) => {
+            let entries = files
+                .iter()
+                .zip(&file_paths)
+                .enumerate()
+                .filter(|(file_index, (info, _))| {
+                    options.writes_to_disk(info) && !skipped[*file_index] && !features.archive
+                })
+                .map(|(file_index, (info, path))| JournalFile {
+                    file_index,
+                    path: path.clone(),
+                    size: info.size,
+                    sha256: None,
+                    blake3: None,
+                })
+                .collect();
+            match ReceiveJournal::create(path.clone(), entries).await {
+                Ok(journal) => Some(journal),
+                Err(e) => {
+                    warn!("receiver: not journaling this receive: {e}");
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    // Messages unpacked from a `FileBatch`, handled before reading more.
+    let mut unpacked: VecDeque<PeerMessage> = VecDeque::new();
+    // Files coming on streams of their own, read instead of the transport
+    // until they're all complete.
+    let mut inbound: Option<Inbound> = None;
+
+    let stall_timeout = options.stall_timeout.unwrap_or(STALL_TIMEOUT);
+    let stall = (peer.supports(FEATURE_PING) && !files.iter().any(|f| f.streaming))
+        .then_some(stall_timeout);
+
     // Receive chunks until TransferComplete
     loop {
-        let msg = tokio::select! {
-            result = transport.recv_peer_message() => result?,
-            _ = cancel.cancelled() => {
-                transport.send_peer_message(&PeerMessage::Cancel {
-                    reason: "cancelled by receiver".into(),
-                }).await.ok();
-                // Clean up partial files
-                for file_info in &files {
-                    let file_path = if let Some(ref rel_path) = file_info.relative_path {
-                        if let Ok(safe_rel) = sanitize_path(rel_path) {
-                            save_dir.join(&safe_rel)
+        if inbound.as_ref().is_some_and(Inbound::is_done) {
+            inbound = None;
+        }
+        if options.pause.is_paused() {
+            info!("receiver: paused");
+            // A sender left blocked on us gives up as if we'd stalled, so
+            // take a message now and then even while paused.
+            tokio::select! {
+                _ = options.pause.wait_while_paused() => info!("receiver: resumed"),
+                _ = cancel.cancelled() => {}
+                _ = tokio::time::sleep(stall_timeout / 2) => {}
+            }
+        }
+
+        let msg = if let Some(msg) = unpacked.pop_front() {
+            msg
+        } else {
+            tokio::select! {
+                result = async {
+                    let Some(inbound) = inbound.as_mut() else {
+                        return recv_unless_stalled(transport, stall).await;
+                    };
+                    match inbound.recv().await {
+                        Ok(msg) => Ok(Some(msg)),
+                        Err(e) => Err(stream_failed(transport, &progress_tx, e).await),
+                    }
+                } => match result? {
+                    Some(msg) => msg,
+                    None => {
+                        warn!("receiver: nothing from the sender for {stall_timeout:?}");
+                        if options.resume {
+                            save_resume_points(&options, &files, &file_paths, &skipped, &mut reassemblers).await;
                         } else {
-                            continue;
+                            partials.remove_all().await;
                         }
-                    } else {
-                        let safe_name = sanitize_filename(&file_info.name);
-                        save_dir.join(&safe_name)
-                    };
-                    tokio::fs::remove_file(&file_path).await.ok();
-                }
-                return Err(AppError::Cancelled);
-            },
+                        return Err(abort(transport, AppError::ConnectionTimeout).await);
+                    }
+                },
+                _ = cancel.cancelled() => {
+                    transport.send_peer_message(&PeerMessage::Cancel {
+                        reason: CancelReason::UserCancelled,
+                        detail: "cancelled by receiver".into(),
+                    }).await.ok();
+                    transport.say_bye().await;
+                    if options.resume {
+                        // Keep what's on disk for the next receive to pick up.
+                        save_resume_points(&options, &files, &file_paths, &skipped, &mut reassemblers).await;
+                        return Err(AppError::Cancelled);
+                    }
+                    // Clean up partial files
+                    for ((file_info, file_path), &skip) in files.iter().zip(&file_paths).zip(&skipped) {
+                        if options.writes_to_disk(file_info) && !skip {
+                            partials.remove(file_path).await;
+                        }
+                    }
+                    return Err(AppError::Cancelled);
+                },
+            }
         };
 
         match msg {
             PeerMessage::FileChunk {
                 file_index,
+                chunk_index,
                 data,
                 nonce,
-                ..
+                compressed,
             } => {
                 let idx = file_index as usize;
                 if idx >= reassemblers.len() {
@@ -143,11 +807,113 @@ pub async fn run_receive(
                     .as_mut()
                     .ok_or_else(|| AppError::Transfer("file already completed".into()))?;
 
-                // data.len() before decryption includes the auth tag (16 bytes)
-                let plaintext_size = if data.len() > 16 { data.len() - 16 } else { data.len() };
-                reassembler.write_chunk(&data, &nonce).await?;
+                // What the chunk decrypted (and decompressed) to.
+                let written_before = reassembler.bytes_written();
+                let wire_bytes = data.len() as u64;
+                if let Err(e) = reassembler
+                    .write_chunk(data, &nonce, file_index, chunk_index, compressed)
+                    .await
+                {
+                    return Err(abort(transport, e).await);
+                }
+                let file_bytes = reassembler.bytes_written();
+                let plaintext_size = file_bytes - written_before;
+                // Offered sizes are the sender's word; streams alone may grow.
+                if !files[idx].streaming && file_bytes > files[idx].size {
+                    let err = AppError::Transfer(format!(
+                        "'{}' is larger than the {} bytes offered",
+                        files[idx].name, files[idx].size
+                    ));
+                    return Err(abort(transport, err).await);
+                }
+                let received = already_had + tracker.bytes_transferred() + plaintext_size;
+                if let Some(max) = options.max_total_bytes.filter(|&max| received > max) {
+                    let err =
+                        AppError::Transfer(format!("receive exceeds the limit of {max} bytes"));
+                    return Err(abort(transport, err).await);
+                }
+                if let Some(usage) = &options.usage {
+                    usage.add(transport.is_relayed(), wire_bytes).await;
+                }
+
+                if options.resume && !skipped[idx] && reassembler.flushed_bytes() > recorded[idx] {
+                    recorded[idx] = reassembler.flushed_bytes();
+                    let state = ResumeState {
+                        size: files[idx].size,
+                        bytes_written: recorded[idx],
+                    };
+                    if let Err(e) = resume::save(&file_paths[idx], state).await {
+                        warn!("receiver: failed to update resume sidecar: {e}");
+                    }
+                }
+
+                tracker.update(plaintext_size);
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = &options.metrics {
+                    metrics.add_bytes(TransferRole::Receiver, plaintext_size);
+                }
+                if let Some(entry) = record.as_mut() {
+                    entry.bytes_received = tracker.bytes_transferred();
+                }
+                progress_tx
+                    .send(ProgressEvent::file_progress(
+                        file_index,
+                        file_bytes,
+                        files[idx].size,
+                    ))
+                    .ok();
+                progress_tx
+                    .send(ProgressEvent::TransferProgress {
+                        bytes_transferred: tracker.bytes_transferred(),
+                        bytes_total: tracker.bytes_total(),
+                        speed_bps: tracker.speed_bps(),
+                        eta_seconds: tracker.eta_seconds(),
+                        current_file: files[idx].name.clone(),
+                        percent: tracker.percent(),
+                    })
+                    .ok();
+            }
+            PeerMessage::LocalSource { file_index, path } => {
+                let idx = file_index as usize;
+                let reassembler = reassemblers
+                    .get_mut(idx)
+                    .and_then(Option::as_mut)
+                    .filter(|r| features.local_copy && r.bytes_written() == 0)
+                    .ok_or_else(|| AppError::Transfer("unexpected local source".into()))?;
+                let copied = receive_local(
+                    Path::new(&path),
+                    &files[idx],
+                    &file_paths[idx],
+                    reassembler,
+                    options.writes_to_disk(&files[idx]) && !skipped[idx],
+                )
+                .await;
+                let reflinked = match copied {
+                    Ok(reflinked) => reflinked,
+                    Err(e) => return Err(abort(transport, e).await),
+                };
+                info!(
+                    "receiver: copied '{}' locally (reflink: {reflinked})",
+                    files[idx].name
+                );
 
-                tracker.update(plaintext_size as u64);
+                tracker.update(files[idx].size);
+                if let Some(entry) = record.as_mut() {
+                    entry.bytes_received = tracker.bytes_transferred();
+                }
+                progress_tx
+                    .send(ProgressEvent::LocalCopy {
+                        name: files[idx].name.clone(),
+                        reflinked,
+                    })
+                    .ok();
+                progress_tx
+                    .send(ProgressEvent::file_progress(
+                        file_index,
+                        files[idx].size,
+                        files[idx].size,
+                    ))
+                    .ok();
                 progress_tx
                     .send(ProgressEvent::TransferProgress {
                         bytes_transferred: tracker.bytes_transferred(),
@@ -159,18 +925,200 @@ pub async fn run_receive(
                     })
                     .ok();
             }
+            PeerMessage::KeyRatchet {
+                file_index,
+                chunk_index,
+            } => {
+                let ratcheted = reassemblers
+                    .get_mut(file_index as usize)
+                    .and_then(|r| r.as_mut())
+                    .ok_or_else(|| AppError::Transfer("unexpected key ratchet".into()))
+                    .and_then(|r| r.ratchet_key(chunk_index));
+                if let Err(e) = ratcheted {
+                    return Err(abort(transport, e).await);
+                }
+            }
+            PeerMessage::PieceHashes { file_index, hashes } => {
+                let idx = file_index as usize;
+                let computed = reassemblers
+                    .get_mut(idx)
+                    .and_then(|r| r.as_mut())
+                    .and_then(|r| r.take_piece_hashes())
+                    .ok_or_else(|| AppError::Transfer("unexpected piece hashes".into()))?;
+                if computed != hashes {
+                    let err = AppError::ChecksumMismatch(format!(
+                        "piece hashes differ for '{}'",
+                        files[idx].name
+                    ));
+                    return Err(abort(transport, err).await);
+                }
+                if options.writes_to_disk(&files[idx]) && !skipped[idx] {
+                    tokio::fs::write(pieces_path(&file_paths[idx]), &hashes).await?;
+                }
+            }
+            PeerMessage::Ping => {
+                transport.send_peer_message(&PeerMessage::Pong).await?;
+            }
+            PeerMessage::CreateDir { relative_path } => {
+                let rel = sanitize_path(&relative_path)?;
+                if options.sink_factory.is_none() {
+                    let root = destinations::root(target_dir, &destinations, &rel);
+                    if let Err(e) = check_no_linked_dirs(root, &rel) {
+                        return Err(abort(transport, e).await);
+                    }
+                    tokio::fs::create_dir_all(root.join(&rel)).await?;
+                    created_dirs.push(rel);
+                }
+            }
+            PeerMessage::ParallelFiles { file_indices } => {
+                let pending = file_indices
+                    .iter()
+                    .all(|&i| reassemblers.get(i as usize).is_some_and(Option::is_some));
+                let conn = transport.connection().cloned();
+                let Some(conn) = conn.filter(|_| pending) else {
+                    let err = AppError::Transfer("unexpected parallel files".into());
+                    return Err(abort(transport, err).await);
+                };
+                info!(
+                    "receiver: {} file(s) coming on streams of their own",
+                    file_indices.len()
+                );
+                inbound = Some(Inbound::accept(conn, &file_indices));
+            }
+            PeerMessage::StreamEnd { file_index, size } => {
+                let idx = file_index as usize;
+                let written = reassemblers
+                    .get(idx)
+                    .and_then(Option::as_ref)
+                    .map(FileReassembler::bytes_written);
+                match (files.get(idx), written) {
+                    (Some(file), Some(written)) if file.streaming && written == size => {
+                        info!("receiver: stream '{}' ended at {size} bytes", file.name);
+                    }
+                    _ => {
+                        let err = AppError::Transfer(format!(
+                            "unexpected end of stream for file {file_index}"
+                        ));
+                        return Err(abort(transport, err).await);
+                    }
+                }
+            }
             PeerMessage::FileComplete {
                 file_index,
                 sha256,
             } => {
                 let idx = file_index as usize;
-                let reassembler = reassemblers[idx]
+                let mut reassembler = reassemblers[idx]
                     .take()
                     .ok_or_else(|| AppError::Transfer("file already completed".into()))?;
 
-                reassembler.verify(&sha256)?;
+                if let Some(journal) = journal.as_mut() {
+                    if let Err(e) = journal
+                        .record_checksum(idx, features.checksum, &sha256)
+                        .await
+                    {
+                        warn!("receiver: failed to update journal: {e}");
+                    }
+                }
+
+                if files[idx].checksum.is_some_and(|offered| offered != sha256) {
+                    let err = AppError::Transfer(format!(
+                        "'{}' changed on the sender after it was offered",
+                        files[idx].name
+                    ));
+                    return Err(abort(transport, err).await);
+                }
+
+                let inline = reassembler.take_buffer();
+                let challenge = reassembler.take_challenge();
+                // Surface deferred write errors (e.g. disk full) before verifying.
+                let verified = match reassembler.flush().await {
+                    Ok(()) => reassembler.verify(&sha256),
+                    Err(e) => Err(e),
+                };
+                // An archive's last entries may still be on their way to disk.
+                let verified = match unpacking.take() {
+                    Some(unpack) if verified.is_ok() => unpack.finish().await.map(|done| {
+                        info!(
+                            "receiver: unpacked {} file(s) from '{}'",
+                            done.files.len(),
+                            files[idx].name
+                        );
+                        created_dirs.extend(done.dirs.iter().cloned());
+                        unpacked = done;
+                    }),
+                    Some(unpack) => {
+                        unpack.discard().await;
+                        verified
+                    }
+                    None => verified,
+                };
+                if options.resume && !skipped[idx] {
+                    // Done, or not worth resuming: either way start afresh.
+                    resume::remove(&file_paths[idx]).await;
+                }
+                if let Err(e) = verified {
+                    return Err(abort(transport, e).await);
+                }
                 info!("receiver: file '{}' verified", files[idx].name);
+                partials.untrack(&file_paths[idx]);
+                if let Some((path, target)) = links.remove(&idx) {
+                    if let Err(e) = create_link(&path, &target).await {
+                        return Err(abort(transport, e).await);
+                    }
+                    info!("receiver: linked {} -> {target}", path.display());
+                }
+                if let Some(entry) = record.as_mut() {
+                    entry.set_verified(idx, features.checksum, &sha256);
+                }
+                if files[idx].size == 0 && !files[idx].streaming {
+                    // No chunks came, so nothing has shown its progress.
+                    progress_tx
+                        .send(ProgressEvent::file_progress(file_index, 0, 0))
+                        .ok();
+                }
+
+                if let Some(bytes) = inline {
+                    progress_tx
+                        .send(ProgressEvent::TextReceived {
+                            name: files[idx].name.clone(),
+                            text: String::from_utf8_lossy(&bytes).into_owned(),
+                        })
+                        .ok();
+                } else if !skipped[idx] {
+                    // The sink is closed by now, so nothing touches the file after this.
+                    let on_disk = options.sink_factory.is_none();
+                    if let Some(mode) = files[idx].mode.filter(|_| on_disk) {
+                        set_mode(&file_paths[idx], mode);
+                    }
+                    let modified = files[idx].modified.filter(|_| on_disk);
+                    if let Some(modified) = modified {
+                        set_modified(&file_paths[idx], modified);
+                    }
+                    for duplicate in &files[idx].duplicates {
+                        let target = destinations::place(
+                            target_dir,
+                            &destinations,
+                            &sanitize_path(duplicate)?,
+                        );
+                        match &options.sink_factory {
+                            Some(factory) => factory.duplicate(&file_paths[idx], &target).await?,
+                            None => materialize_duplicate(&file_paths[idx], &target).await?,
+                        }
+                        if let Some(modified) = modified {
+                            set_modified(&target, modified);
+                        }
+                    }
+                }
 
+                if let Some(challenge) = challenge {
+                    transport
+                        .send_peer_message(&PeerMessage::ChallengeResponse {
+                            file_index,
+                            mac: challenge.finalize(),
+                        })
+                        .await?;
+                }
                 transport
                     .send_peer_message(&PeerMessage::FileVerified { file_index })
                     .await?;
@@ -181,13 +1129,31 @@ pub async fn run_receive(
                     })
                     .ok();
             }
+            PeerMessage::FileBatch { files: batch } => {
+                for file in batch {
+                    for (chunk_index, chunk) in file.chunks.into_iter().enumerate() {
+                        unpacked.push_back(PeerMessage::FileChunk {
+                            file_index: file.file_index,
+                            chunk_index: chunk_index as u32,
+                            data: chunk.data,
+                            nonce: chunk.nonce,
+                            compressed: chunk.compressed,
+                        });
+                    }
+                    unpacked.push_back(PeerMessage::FileComplete {
+                        file_index: file.file_index,
+                        sha256: file.sha256,
+                    });
+                }
+            }
             PeerMessage::TransferComplete => {
                 info!("receiver: transfer complete");
+                transport.say_bye().await;
                 break;
             }
-            PeerMessage::Cancel { reason } => {
+            PeerMessage::Cancel { reason, detail } => {
                 warn!("receiver: sender cancelled: {reason}");
-                return Err(AppError::Transfer(format!("sender cancelled: {reason}")));
+                return Err(peer_cancelled(&progress_tx, reason, detail));
             }
             _ => {
                 return Err(AppError::Transfer("unexpected message during transfer".into()));
@@ -195,15 +1161,397 @@ pub async fn run_receive(
         }
     }
 
+    if let Some(journal) = journal {
+        journal.remove().await.ok();
+    }
+
+    if let (Some(staging), Some(inspector)) = (staging, &options.inspector) {
+        let staged = staging::staged_files(staging).await?;
+        progress_tx
+            .send(ProgressEvent::StagingReady {
+                staging_dir: staging.display().to_string(),
+                files: staged.iter().map(|p| p.display().to_string()).collect(),
+            })
+            .ok();
+        info!(
+            "receiver: waiting on inspection of {} staged file(s)",
+            staged.len()
+        );
+        let approved = tokio::select! {
+            approved = inspector.inspect(staging, &staged) => approved,
+            _ = cancel.cancelled() => return Err(AppError::Cancelled),
+        };
+        if !approved {
+            warn!("receiver: staged files rejected at inspection");
+            return Err(AppError::Transfer(
+                "received files were rejected at inspection".into(),
+            ));
+        }
+        staging::promote(staging, &save_dir, &chosen, &staged).await?;
+        // Only files are moved out of staging.
+        for rel in &created_dirs {
+            let root = destinations::root(&save_dir, &chosen, rel);
+            check_no_linked_dirs(root, rel)?;
+            tokio::fs::create_dir_all(root.join(rel)).await?;
+        }
+    }
+
     progress_tx
         .send(ProgressEvent::TransferComplete {
             duration_seconds: tracker.elapsed_seconds(),
             average_speed: tracker.average_speed(),
             total_bytes,
-            file_count: files.len() as u32,
+            file_count: unselected.iter().filter(|&&declined| !declined).count() as u32,
+            connection_type: transport.connection_type().into(),
+            peak_speed_bps: tracker.peak_speed_bps(),
         })
         .ok();
 
+    if let Some(opener) = options
+        .opener
+        .as_deref()
+        .filter(|_| options.sink_factory.is_none())
+    {
+        // Where everything ended up, duplicates included.
+        let mut saved = Vec::new();
+        for ((file, path), &discard) in files.iter().zip(&file_paths).zip(&discarded) {
+            if !options.writes_to_disk(file) || discard {
+                continue;
+            }
+            if features.archive {
+                saved.extend(unpacked.files.iter().map(|rel| save_dir.join(rel)));
+                continue;
+            }
+            if staging.is_none() {
+                saved.push(path.clone());
+            } else {
+                let rel = path.strip_prefix(target_dir).unwrap_or(path);
+                saved.push(destinations::place(&save_dir, &chosen, rel));
+            }
+            for duplicate in &file.duplicates {
+                saved.push(destinations::place(
+                    &save_dir,
+                    &chosen,
+                    &sanitize_path(duplicate)?,
+                ));
+            }
+        }
+        let roots: Vec<&Path> = std::iter::once(save_dir.as_path())
+            .chain(chosen.values().map(PathBuf::as_path))
+            .collect();
+        if let Err(e) = opener::run_on_complete(options.on_complete, opener, &roots, &saved) {
+            warn!("receiver: on-complete action failed: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// The offered files already saved where this receive would put them, by
+/// index, each as a `HaveFiles` entry. Only same-sized files are hashed;
+/// one that can't be read is simply sent again.
+async fn existing_files(
+    options: &ReceiveOptions,
+    files: &[FileInfo],
+    rel_paths: &[PathBuf],
+    target_dir: &Path,
+    destinations: &HashMap<String, PathBuf>,
+) -> Vec<(usize, HaveFile)> {
+    let mut existing = Vec::new();
+    for (file_index, (info, rel_path)) in files.iter().zip(rel_paths).enumerate() {
+        if !options.writes_to_disk(info)
+            || info.streaming
+            || info.link_target.is_some()
+            || !info.duplicates.is_empty()
+        {
+            continue;
+        }
+        let path = destinations::place(target_dir, destinations, rel_path);
+        match tokio::fs::symlink_metadata(&path).await {
+            Ok(meta) if meta.is_file() && meta.len() == info.size => {}
+            _ => continue,
+        }
+        match resume::hash_file(&path, ChecksumAlgorithm::Sha256).await {
+            Ok(sha256) => existing.push((
+                file_index,
+                HaveFile {
+                    relative_path: info.target_path().into(),
+                    size: info.size,
+                    sha256,
+                },
+            )),
+            Err(e) => warn!("receiver: couldn't hash '{}': {e}", path.display()),
+        }
+    }
+    existing
+}
+
+/// Refuse an offer bigger than the receive's limits allow.
+fn check_limits(options: &ReceiveOptions, files: &[FileInfo]) -> AppResult<()> {
+    let file_count: usize = files.iter().map(|f| 1 + f.duplicates.len()).sum();
+    if let Some(max) = options.max_file_count.filter(|&max| file_count > max) {
+        return Err(AppError::Transfer(format!(
+            "offer of {file_count} files exceeds the limit of {max}"
+        )));
+    }
+    let total: u64 = files.iter().map(|f| f.size).sum();
+    if let Some(max) = options.max_total_bytes.filter(|&max| total > max) {
+        return Err(AppError::Transfer(format!(
+            "offer of {total} bytes exceeds the limit of {max} bytes"
+        )));
+    }
+    Ok(())
+}
+
+/// Make sure every chosen destination names an offered top-level item and
+/// is a writable directory, creating it if needed.
+async fn check_destinations(
+    destinations: &HashMap<String, PathBuf>,
+    rel_paths: &[PathBuf],
+) -> AppResult<()> {
+    for (item, dir) in destinations {
+        if !rel_paths
+            .iter()
+            .any(|rel| destinations::top_level(rel).as_ref() == Some(item))
+        {
+            return Err(AppError::Transfer(format!(
+                "destination given for '{item}', which wasn't offered"
+            )));
+        }
+        check_save_dir(dir).await?;
+    }
+    Ok(())
+}
+
+/// Flush every unfinished file on disk and record how far it got.
+async fn save_resume_points(
+    options: &ReceiveOptions,
+    files: &[FileInfo],
+    file_paths: &[PathBuf],
+    skipped: &[bool],
+    reassemblers: &mut [Option<FileReassembler>],
+) {
+    for (idx, slot) in reassemblers.iter_mut().enumerate() {
+        let Some(reassembler) = slot.as_mut() else {
+            continue;
+        };
+        if !options.writes_to_disk(&files[idx])
+            || skipped[idx]
+            || reassembler.flush().await.is_err()
+        {
+            continue;
+        }
+        let state = ResumeState {
+            size: files[idx].size,
+            bytes_written: reassembler.flushed_bytes(),
+        };
+        resume::save(&file_paths[idx], state).await.ok();
+    }
+}
+
+/// Sidecar holding a received file's piece-hash list: `<file>.pieces`.
+pub fn pieces_path(file_path: &Path) -> PathBuf {
+    let mut name = file_path.as_os_str().to_owned();
+    name.push(".pieces");
+    PathBuf::from(name)
+}
+
+/// Give a received file the sender's modification time. Only logged on
+/// failure: the content itself is already verified.
+pub(crate) fn set_modified(path: &Path, modified: u64) {
+    let mtime = filetime::FileTime::from_unix_time(modified.min(i64::MAX as u64) as i64, 0);
+    if let Err(e) = filetime::set_file_mtime(path, mtime) {
+        warn!(
+            "receiver: failed to set modification time of {}: {e}",
+            path.display()
+        );
+    }
+}
+
+/// Reapply the sender's permission bits: read/write/execute only, and never
+/// more than this process's umask lets a new file have. Ignored off Unix.
+pub(crate) fn set_mode(path: &Path, mode: u32) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let allowed = 0o777 & !process_umask();
+        let permissions = std::fs::Permissions::from_mode(mode & allowed);
+        if let Err(e) = std::fs::set_permissions(path, permissions) {
+            warn!(
+                "receiver: failed to set permissions of {}: {e}",
+                path.display()
+            );
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+}
+
+/// The process umask, found once by creating a probe file with mode 0o777.
+/// Reading it with `umask(2)` would briefly change it for every thread.
+#[cfg(unix)]
+fn process_umask() -> u32 {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    static UMASK: std::sync::OnceLock<u32> = std::sync::OnceLock::new();
+    *UMASK.get_or_init(|| {
+        let probe = std::env::temp_dir().join(format!(".relay-umask-{}", uuid::Uuid::new_v4()));
+        let created = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o777)
+            .open(&probe)
+            .and_then(|file| file.metadata());
+        std::fs::remove_file(&probe).ok();
+        match created {
+            Ok(meta) => !meta.permissions().mode() & 0o777,
+            // Assume the common default rather than fail the receive.
+            Err(_) => 0o022,
+        }
+    })
+}
+
+/// Place a copy of a received file at `target`, hardlinking when the
+/// filesystem allows it.
+async fn materialize_duplicate(source: &Path, target: &Path) -> AppResult<()> {
+    if source == target {
+        return Ok(());
+    }
+    if let Some(parent) = target.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::remove_file(target).await.ok();
+    if let Err(e) = tokio::fs::hard_link(source, target).await {
+        info!(
+            "receiver: hardlink failed ({e}), copying to {}",
+            target.display()
+        );
+        tokio::fs::copy(source, target).await?;
+    }
+    Ok(())
+}
+
+/// Take a file from the sender's disk on this machine instead of over the
+/// wire, feeding it through the reassembler so it's still checked against
+/// the sender's checksum. Returns whether it was cloned copy-on-write.
+async fn receive_local(
+    source: &Path,
+    info: &FileInfo,
+    dest: &Path,
+    reassembler: &mut FileReassembler,
+    to_disk: bool,
+) -> AppResult<bool> {
+    let meta = tokio::fs::metadata(source).await?;
+    if !source.is_absolute() || !meta.is_file() || meta.len() != info.size {
+        return Err(AppError::Transfer(format!(
+            "local source for '{}' doesn't match the offer",
+            info.name
+        )));
+    }
+    if !to_disk {
+        let mut file = tokio::fs::File::open(source).await?;
+        reassembler.write_from(&mut file, info.size).await?;
+        return Ok(false);
+    }
+    let reflinked = local::copy_file(source, dest).await?;
+    let mut copied = tokio::fs::File::open(dest).await?;
+    reassembler.resume_from(&mut copied, info.size).await?;
+    Ok(reflinked)
+}
+
+/// The sender's next message, answering any keepalive `Ping`s before it.
+async fn recv_answering_pings(transport: &mut Transport) -> AppResult<PeerMessage> {
+    loop {
+        match transport.recv_peer_message().await? {
+            PeerMessage::Ping => transport.send_peer_message(&PeerMessage::Pong).await?,
+            msg => return Ok(msg),
+        }
+    }
+}
+
+/// The sender's next message, or `None` if `stall` passes without one.
+async fn recv_unless_stalled(
+    transport: &mut Transport,
+    stall: Option<Duration>,
+) -> AppResult<Option<PeerMessage>> {
+    let Some(limit) = stall else {
+        return transport.recv_peer_message().await.map(Some);
+    };
+    match tokio::time::timeout(limit, transport.recv_peer_message()).await {
+        Ok(msg) => msg.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Tell the sender why we're giving up, then hand back the error.
+async fn abort(transport: &mut Transport, err: AppError) -> AppError {
+    transport
+        .send_peer_message(&PeerMessage::Cancel {
+            reason: CancelReason::for_error(&err),
+            detail: err.to_string(),
+        })
+        .await
+        .ok();
+    err
+}
+
+/// A file stream failed with `e`. A sender that gave up drops them and
+/// says why on the transfer's own stream, so look there first; otherwise
+/// tell the sender why we're stopping.
+async fn stream_failed(
+    transport: &mut Transport,
+    progress_tx: &mpsc::UnboundedSender<ProgressEvent>,
+    e: AppError,
+) -> AppError {
+    let notice = tokio::time::timeout(Duration::from_secs(1), transport.recv_peer_message()).await;
+    if let Ok(Ok(PeerMessage::Cancel { reason, detail })) = notice {
+        warn!("receiver: sender cancelled: {reason}");
+        return peer_cancelled(progress_tx, reason, detail);
+    }
+    warn!("receiver: file stream failed: {e}");
+    abort(transport, e).await
+}
+
+/// Verify that `dir` accepts new files by creating and deleting a probe file.
+///
+/// If `dir` doesn't exist yet, its nearest existing ancestor is probed instead,
+/// since that's where the directory would be created.
+pub async fn check_save_dir(dir: &Path) -> AppResult<()> {
+    probe_save_dir(dir, |probe| tokio::fs::write(probe, b"")).await
+}
+
+/// [`check_save_dir`] with the probe write supplied by the caller, so tests
+/// can fail it whatever the user's permissions.
+async fn probe_save_dir<F, Fut>(dir: &Path, write_probe: F) -> AppResult<()>
+where
+    F: FnOnce(PathBuf) -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<()>>,
+{
+    let mut target = dir;
+    while !target.exists() {
+        target = target.parent().ok_or_else(|| {
+            AppError::Transfer(format!(
+                "save directory has no existing parent: {}",
+                dir.display()
+            ))
+        })?;
+    }
+
+    if !target.is_dir() {
+        return Err(AppError::Transfer(format!(
+            "save location is not a directory: {}",
+            target.display()
+        )));
+    }
+
+    let probe = target.join(format!(".relay-write-check-{}", uuid::Uuid::new_v4()));
+    write_probe(probe.clone()).await.map_err(|e| {
+        AppError::Transfer(format!(
+            "save directory is not writable: {}: {e}",
+            target.display()
+        ))
+    })?;
+    tokio::fs::remove_file(&probe).await.ok();
+
     Ok(())
 }
 
@@ -262,6 +1610,130 @@ pub fn sanitize_path(rel_path: &str) -> AppResult<PathBuf> {
     Ok(safe)
 }
 
+/// Check the target of a link saved at `rel_path`: it must be relative, and
+/// lead nowhere outside the save directory. `..` may only lead the target:
+/// after a name it would climb out of wherever that name goes, which may
+/// be another link.
+pub fn sanitize_link_target(rel_path: &str, target: &str) -> AppResult<()> {
+    let depth = sanitize_path(rel_path)?.components().count() - 1;
+    let escapes = || {
+        AppError::Transfer(format!(
+            "link leads outside the save directory: {rel_path} -> {target}"
+        ))
+    };
+    if target.is_empty() || target.contains('\0') {
+        return Err(AppError::Transfer(format!(
+            "invalid link target: {rel_path}"
+        )));
+    }
+    let mut climbed = 0;
+    let mut descended = false;
+    for component in Path::new(target).components() {
+        match component {
+            std::path::Component::ParentDir if !descended => climbed += 1,
+            std::path::Component::Normal(_) => descended = true,
+            std::path::Component::CurDir => {}
+            _ => return Err(escapes()),
+        }
+    }
+    if climbed > depth {
+        return Err(escapes());
+    }
+    Ok(())
+}
+
+/// Links in an offer must be safe to make, and nothing may be saved
+/// through one.
+fn check_links(files: &[FileInfo], rel_paths: &[PathBuf]) -> AppResult<()> {
+    for (file, link) in files.iter().zip(rel_paths) {
+        let Some(target) = &file.link_target else {
+            continue;
+        };
+        sanitize_link_target(file.target_path(), target)?;
+        if let Some(inside) = rel_paths
+            .iter()
+            .find(|&rel| rel != link && rel.starts_with(link))
+        {
+            return Err(AppError::Transfer(format!(
+                "'{}' would be saved through the link '{}'",
+                inside.display(),
+                link.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Nothing in an offer may be saved through a link already on disk, where
+/// each file, or copy of one, lands.
+fn check_saved_links(
+    base: &Path,
+    destinations: &HashMap<String, PathBuf>,
+    files: &[FileInfo],
+    rel_paths: &[PathBuf],
+) -> AppResult<()> {
+    for (file, rel) in files.iter().zip(rel_paths) {
+        check_no_linked_dirs(destinations::root(base, destinations, rel), rel)?;
+        for duplicate in &file.duplicates {
+            let rel = sanitize_path(duplicate)?;
+            check_no_linked_dirs(destinations::root(base, destinations, &rel), &rel)?;
+        }
+    }
+    Ok(())
+}
+
+/// Fail if a folder on the way from `root` to `rel` is a link. [`check_links`]
+/// only sees the links in one offer; one left by an earlier receive, or put
+/// there some other way, can lead anywhere once followed.
+pub(crate) fn check_no_linked_dirs(root: &Path, rel: &Path) -> AppResult<()> {
+    let Some(parent) = rel.parent() else {
+        return Ok(());
+    };
+    let mut dir = root.to_path_buf();
+    for component in parent.components() {
+        dir.push(component);
+        match std::fs::symlink_metadata(&dir) {
+            Ok(meta) if meta.is_symlink() => {
+                return Err(AppError::Transfer(format!(
+                    "'{}' would be saved through the link '{}'",
+                    rel.display(),
+                    dir.strip_prefix(root).unwrap_or(&dir).display()
+                )));
+            }
+            Ok(_) => {}
+            // Nothing further down exists yet either.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Make a link at `path`, replacing a file already there.
+#[cfg(unix)]
+async fn create_link(path: &Path, target: &str) -> AppResult<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    if tokio::fs::symlink_metadata(path)
+        .await
+        .is_ok_and(|meta| !meta.is_dir())
+    {
+        tokio::fs::remove_file(path).await?;
+    }
+    tokio::fs::symlink(target, path).await?;
+    Ok(())
+}
+
+/// Only Unix receivers advertise links, so none should arrive here.
+#[cfg(not(unix))]
+async fn create_link(path: &Path, _target: &str) -> AppResult<()> {
+    Err(AppError::Transfer(format!(
+        "can't make a link at {}: unsupported on this platform",
+        path.display()
+    )))
+}
+
 /// Sanitize a flat filename: remove path separators, reject traversal attacks.
 fn sanitize_filename(name: &str) -> String {
     let name = name
@@ -324,11 +1796,62 @@ mod tests {
         assert_eq!(p, PathBuf::from("foo/bar.txt"));
     }
 
+    #[test]
+    fn test_sanitize_link_target() {
+        assert!(sanitize_link_target("notes/latest.md", "2024/todo.md").is_ok());
+        assert!(sanitize_link_target("notes/2024/up", "../../readme.txt").is_ok());
+        assert!(sanitize_link_target("notes/here", "./").is_ok());
+
+        // Out of the save directory, or anywhere absolute.
+        assert!(sanitize_link_target("notes/up", "../../etc").is_err());
+        assert!(sanitize_link_target("top", "../x").is_err());
+        assert!(sanitize_link_target("notes/abs", "/etc/passwd").is_err());
+        // `..` after a name could climb out of another link.
+        assert!(sanitize_link_target("notes/a/l", "b/../../x").is_err());
+        assert!(sanitize_link_target("notes/l", "").is_err());
+        assert!(sanitize_link_target("../l", "x").is_err());
+    }
+
     #[test]
     fn test_sanitize_path_empty() {
         assert!(sanitize_path("").is_err());
     }
 
+    #[tokio::test]
+    async fn test_check_save_dir_writable() {
+        let temp = tempfile::tempdir().unwrap();
+        check_save_dir(temp.path()).await.unwrap();
+        // A missing directory is checked against its existing parent.
+        check_save_dir(&temp.path().join("new/nested"))
+            .await
+            .unwrap();
+        // The probe file must not be left behind.
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_check_save_dir_read_only() {
+        let temp = tempfile::tempdir().unwrap();
+        let err = probe_save_dir(temp.path(), |_| async {
+            Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+        })
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("not writable"), "got: {err}");
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_check_save_dir_rejects_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = temp.path().join("file.txt");
+        std::fs::write(&file, "x").unwrap();
+        assert!(check_save_dir(&file).await.is_err());
+        // Nor can a directory be created beneath it.
+        let err = check_save_dir(&file.join("sub")).await.unwrap_err();
+        assert!(err.to_string().contains("not a directory"), "got: {err}");
+    }
+
     #[test]
     fn test_sanitize_path_windows_separators() {
         let p = sanitize_path("docs\\readme.md").unwrap();
@@ -0,0 +1,128 @@
+// Resume sidecars — how much of a partially received file is safely on
+// disk, so the next receive of the same file can pick up from there.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+
+use crate::crypto::checksum::{ChecksumAlgorithm, StreamingChecksum};
+use crate::error::{AppError, AppResult};
+
+/// Contents of `<file>.relay-resume`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeState {
+    /// Full size of the file being received.
+    pub size: u64,
+    /// Bytes flushed to disk so far, all from the start of the file.
+    pub bytes_written: u64,
+}
+
+/// Sidecar for a partially received file: `<file>.relay-resume`.
+pub fn sidecar_path(file_path: &Path) -> PathBuf {
+    let mut name = file_path.as_os_str().to_owned();
+    name.push(".relay-resume");
+    PathBuf::from(name)
+}
+
+/// Record how much of `file_path` is on disk.
+pub async fn save(file_path: &Path, state: ResumeState) -> AppResult<()> {
+    let json = serde_json::to_vec(&state)
+        .map_err(|e| AppError::Serialization(format!("resume sidecar: {e}")))?;
+    let path = sidecar_path(file_path);
+    // Write-then-rename so a crash mid-save leaves the previous version.
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, json).await?;
+    tokio::fs::rename(&tmp, &path).await?;
+    Ok(())
+}
+
+/// Drop the sidecar once the file is complete (or not worth resuming).
+pub async fn remove(file_path: &Path) {
+    tokio::fs::remove_file(sidecar_path(file_path)).await.ok();
+}
+
+/// How many leading bytes of `file_path` an earlier receive left for a file
+/// of `size`; 0 means start over. A file longer than the sender's is treated
+/// as corrupt, and so is one that disagrees with its sidecar.
+///
+/// With the file's `expected` checksum from a pre-hashed offer, a partial
+/// that already holds every byte is checked against it up front rather than
+/// failing verification after the transfer.
+pub async fn resumable_prefix(
+    file_path: &Path,
+    size: u64,
+    expected: Option<(ChecksumAlgorithm, [u8; 32])>,
+) -> u64 {
+    let state = match tokio::fs::read(sidecar_path(file_path)).await {
+        Ok(json) => serde_json::from_slice::<ResumeState>(&json).ok(),
+        Err(_) => return 0,
+    };
+    let on_disk = tokio::fs::metadata(file_path).await.map(|m| m.len()).ok();
+    match (state, on_disk) {
+        (Some(state), Some(on_disk))
+            if state.size == size && on_disk <= size && state.bytes_written <= on_disk =>
+        {
+            match expected {
+                Some((algorithm, checksum)) if state.bytes_written == size => {
+                    if hash_file(file_path, algorithm).await.ok() == Some(checksum) {
+                        size
+                    } else {
+                        remove(file_path).await;
+                        0
+                    }
+                }
+                _ => state.bytes_written,
+            }
+        }
+        _ => {
+            remove(file_path).await;
+            0
+        }
+    }
+}
+
+pub(crate) async fn hash_file(
+    file_path: &Path,
+    algorithm: ChecksumAlgorithm,
+) -> AppResult<[u8; 32]> {
+    let mut file = tokio::fs::File::open(file_path).await?;
+    let mut checksum = StreamingChecksum::with_algorithm(algorithm);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(checksum.finalize());
+        }
+        checksum.update(&buf[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resumable_prefix_checks_disk() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("movie.mkv");
+        assert_eq!(resumable_prefix(&path, 1000, None).await, 0);
+
+        std::fs::write(&path, vec![1u8; 600]).unwrap();
+        let state = ResumeState {
+            size: 1000,
+            bytes_written: 512,
+        };
+        save(&path, state).await.unwrap();
+        assert_eq!(resumable_prefix(&path, 1000, None).await, 512);
+        // A different file under the same name starts over.
+        assert_eq!(resumable_prefix(&path, 2000, None).await, 0);
+        assert!(!sidecar_path(&path).exists());
+
+        // Longer than the sender's file: corrupt, start over.
+        save(&path, state).await.unwrap();
+        std::fs::write(&path, vec![1u8; 1500]).unwrap();
+        assert_eq!(resumable_prefix(&path, 1000, None).await, 0);
+    }
+}
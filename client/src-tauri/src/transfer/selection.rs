@@ -0,0 +1,24 @@
+// Selection — a receiver can accept only some of the offered files, picked
+// after seeing the offer.
+
+use std::sync::{Arc, Mutex};
+
+/// The offered files a receiver accepts, shared between a session and its
+/// pipeline like the destinations. Filled in before the offer is accepted;
+/// left unset, every file is.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    inner: Arc<Mutex<Option<Vec<u16>>>>,
+}
+
+impl Selection {
+    /// Accept only the offered files at `indices`.
+    pub fn set(&self, indices: Vec<u16>) {
+        *self.inner.lock().unwrap() = Some(indices);
+    }
+
+    /// The indices chosen, or `None` to accept everything.
+    pub(crate) fn snapshot(&self) -> Option<Vec<u16>> {
+        self.inner.lock().unwrap().clone()
+    }
+}
@@ -3,30 +3,374 @@
 // Phase 2: Via signaling server.
 // Phase 3: With relay fallback + folder support.
 
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use quinn::{Connection, SendStream};
+use ring::rand::SecureRandom;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::sync::mpsc;
+use tokio::task::{JoinError, JoinSet};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::AbortOnDropHandle;
 use tracing::{info, warn};
 
 use crate::crypto::aes_gcm::ChunkEncryptor;
+use crate::crypto::checksum::{ChecksumAlgorithm, StreamingChecksum};
 use crate::error::{AppError, AppResult};
+use crate::network::health::{RelayHealth, KEEPALIVE_INTERVAL, PING_INTERVAL, STALL_TIMEOUT};
 use crate::network::transport::Transport;
-use crate::protocol::chunker::FileChunker;
-use crate::protocol::messages::{FileInfo, PeerMessage};
-use crate::transfer::progress::{ProgressEvent, ProgressTracker};
+use crate::protocol::chunker::{default_chunk_size, FileChunker, FileSource, StreamSource};
+use crate::protocol::messages::{
+    write_message, BatchedChunk, BatchedFile, CancelReason, FileInfo, HaveFile, PeerMessage,
+    TransferFeatures, FEATURE_ARCHIVE, FEATURE_BATCH, FEATURE_BLAKE3, FEATURE_COMPRESSION,
+    FEATURE_EMPTY_DIRS, FEATURE_KEEPALIVE, FEATURE_LOCAL_COPY, FEATURE_PARALLEL_STREAMS,
+    FEATURE_PING, FEATURE_SYMLINKS, MIME_TEXT_PLAIN,
+};
+use crate::protocol::pieces::PieceHashConfig;
+use crate::transfer::archive;
+use crate::transfer::handshake;
+use crate::transfer::history::{HistoryEntry, HistoryLog};
+use crate::transfer::local::LocalProbe;
+#[cfg(feature = "metrics")]
+use crate::transfer::metrics::Metrics;
+use crate::transfer::progress::{peer_cancelled, ProgressEvent, ProgressTracker};
+use crate::transfer::resume;
+use crate::transfer::session::PauseToken;
+#[cfg(feature = "metrics")]
+use crate::transfer::session::TransferRole;
+use crate::transfer::streams;
+use crate::transfer::throttle::RateLimiter;
+use crate::transfer::usage::UsageStore;
+
+/// Largest file sent whole inside a `FileBatch`.
+pub const BATCH_FILE_MAX: u64 = 64 * 1024;
+/// A batch goes out once it holds this many files, or this many bytes.
+const BATCH_MAX_FILES: usize = 512;
+const BATCH_MAX_BYTES: u64 = 1024 * 1024;
+/// Chunks read ahead of the one being sent, unless `SendOptions` says
+/// otherwise: 2 MiB of buffered chunks over the relay, 8 MiB direct.
+pub const DEFAULT_SEND_WINDOW: usize = 8;
+/// How long the receiver has to answer the offer, unless `SendOptions`
+/// says otherwise.
+pub const ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Options and controls for the send pipeline.
+#[derive(Debug, Clone, Default)]
+pub struct SendOptions {
+    /// Pausing stops chunk transmission until resumed.
+    pub pause: PauseToken,
+    /// Send identical files once and let the receiver copy them into place.
+    /// Costs a full read of every file whose size collides with another.
+    pub dedupe: bool,
+    /// Send a BitTorrent-style piece-hash list after each file.
+    pub piece_hashes: Option<PieceHashConfig>,
+    /// Require the receiver to prove, per file, that it holds the exact bytes
+    /// sent: an HMAC keyed by a random nonce from the offer.
+    pub challenge: bool,
+    /// Hard cap on the whole send, however well it's progressing. Exceeding
+    /// it cancels with `AppError::ConnectionTimeout`.
+    pub max_total_duration: Option<Duration>,
+    /// Give up, as with `max_total_duration`, once a chunk can't be written
+    /// or the receiver hasn't replied for this long; each chunk starts the
+    /// wait afresh, so a slow but steady transfer carries on. `None` uses
+    /// [`STALL_TIMEOUT`].
+    pub stall_timeout: Option<Duration>,
+    /// Give up, as with `max_total_duration`, if the receiver hasn't
+    /// accepted or declined the offer this long after it went out. `None`
+    /// uses [`ACCEPT_TIMEOUT`].
+    pub accept_timeout: Option<Duration>,
+    /// Move each file on to a fresh key (HKDF of the previous one) after
+    /// this many chunks, limiting how much data any one key protects.
+    pub key_ratchet_every: Option<u32>,
+    /// Offer zstd-compressed chunks. Only used if the receiver agrees, and
+    /// only for chunks it actually shrinks.
+    pub compress: bool,
+    /// Checksum each file with this if the receiver supports it, SHA-256
+    /// otherwise.
+    pub checksum: ChecksumAlgorithm,
+    /// Tail mode: keep sending the (single) file as it grows, like
+    /// `tail -f`, until this is cancelled. The offer marks it streaming.
+    pub tail: Option<CancellationToken>,
+    /// Cap the upload rate, in bytes per second. `None` (or 0) sends as fast
+    /// as the connection allows.
+    pub max_bytes_per_sec: Option<u64>,
+    /// Empty folders to recreate on the receiver, as paths relative to the
+    /// save directory like `FileInfo::relative_path`. Left out for peers
+    /// that don't support them.
+    pub empty_dirs: Vec<String>,
+    /// Checksum every file before offering and put the checksums in the
+    /// offer, so a resuming receiver can check what it already has. Costs a
+    /// full read of everything up front; ignored in tail mode.
+    pub pre_hash: bool,
+    /// Send files of up to [`BATCH_FILE_MAX`] many to a message, cutting
+    /// per-message overhead and round trips for folders of tiny files.
+    /// Only used if the receiver supports it, and not alongside challenges,
+    /// piece hashes, key ratcheting or tail mode.
+    pub batch_small_files: bool,
+    /// If the receiver proves it's on this machine, let it copy the files
+    /// straight off disk — a copy-on-write clone where the filesystem can
+    /// — instead of streaming them. Not alongside challenges, piece hashes
+    /// or tail mode, which need the bytes to pass through the transfer.
+    pub local_copy: bool,
+    /// Chunks a reader task may read and encrypt ahead of the one being
+    /// sent, so disk, CPU and network work overlap. Holds up to this many
+    /// times the chunk size in memory. `None` uses [`DEFAULT_SEND_WINDOW`];
+    /// `Some(0)` reads each chunk only once the one before it is sent.
+    pub send_window: Option<usize>,
+    /// Bytes of the file per chunk, clamped to between `MIN_CHUNK_SIZE`
+    /// and `MAX_CHUNK_SIZE`. `None` picks by transport: `CHUNK_SIZE` over
+    /// the relay, where a lost message is resent whole, and
+    /// `DIRECT_CHUNK_SIZE` direct.
+    pub chunk_size: Option<usize>,
+    /// Over a direct connection, send up to this many files at once, each
+    /// on a QUIC stream of its own, so one that's slow to read or held up
+    /// on the network doesn't hold up the rest. 0 or 1 sends them one after
+    /// another. Only used if the receiver supports it, and not alongside
+    /// batching, local copies, challenges, piece hashes, key ratcheting or
+    /// tail mode; a rate cap is shared evenly between the streams.
+    pub parallel_streams: usize,
+    /// Send everything, empty folders included, as one tar archive that
+    /// the receiver unpacks as it arrives, sparing folders of many small
+    /// files a round trip each. Only used if the receiver supports it, and
+    /// not alongside local copies or tail mode, or for selections with
+    /// streams or links in them.
+    pub archive: bool,
+    /// How often to ping the receiver over an otherwise idle relay before
+    /// the transfer starts. `None` uses [`KEEPALIVE_INTERVAL`].
+    pub keepalive_interval: Option<Duration>,
+    /// Where to count the bytes sent. A send is refused once its monthly
+    /// quota is used up.
+    pub usage: Option<UsageStore>,
+    /// Where to record the outcome once the offer has gone out.
+    pub history: Option<HistoryLog>,
+    /// The transfer code, noted in the history record.
+    pub code: Option<String>,
+    /// Where to count this transfer.
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<Arc<Metrics>>,
+}
 
 /// Run the sender pipeline over an established transport (QUIC or relay).
 ///
-/// `files` — absolute paths to each file on disk (one per FileInfo entry).
+/// `files` — where to read each file from (one per FileInfo entry); paths on disk
+/// convert directly.
 /// `file_infos` — metadata including name, size, and optional relative_path for folders.
 pub async fn run_send(
-    files: Vec<PathBuf>,
+    files: Vec<impl Into<FileSource>>,
     file_infos: Vec<FileInfo>,
     transport: &mut Transport,
     encryption_key: [u8; 32],
     progress_tx: mpsc::UnboundedSender<ProgressEvent>,
     cancel: tokio_util::sync::CancellationToken,
+    options: SendOptions,
 ) -> AppResult<()> {
+    let files: Vec<FileSource> = files.into_iter().map(Into::into).collect();
+    let max_total_duration = options.max_total_duration;
+    let usage = options.usage.clone();
+    let history = options.history.clone();
+    let started = Instant::now();
+    #[cfg(feature = "metrics")]
+    let metrics = options.metrics.clone();
+    #[cfg(feature = "metrics")]
+    if let Some(metrics) = &metrics {
+        metrics.transfer_started(TransferRole::Sender, transport.is_relayed());
+    }
+    let mut record = None;
+    let send = send_files(
+        files,
+        file_infos,
+        encryption_key,
+        options,
+        SendContext {
+            transport: &mut *transport,
+            progress_tx,
+            cancel,
+            record: &mut record,
+        },
+    );
+    let result = match max_total_duration {
+        Some(limit) => match tokio::time::timeout(limit, send).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("sender: gave up after {limit:?}");
+                Err(timed_out(transport, format!("sender time limit of {limit:?} reached")).await)
+            }
+        },
+        None => send.await,
+    };
+
+    #[cfg(feature = "metrics")]
+    if let Some(metrics) = &metrics {
+        metrics.transfer_finished(TransferRole::Sender, started.elapsed(), &result);
+    }
+    if let Some(usage) = &usage {
+        if let Err(e) = usage.save().await {
+            warn!("sender: failed to record usage: {e}");
+        }
+    }
+    if let (Some(log), Some(mut entry)) = (history, record) {
+        entry.finish(&result, started.elapsed());
+        if let Err(e) = log.append(&entry).await {
+            warn!("sender: failed to record history: {e}");
+        }
+    }
+    result
+}
+
+/// What each stage of a send talks over, reports to and is stopped by.
+struct SendContext<'a> {
+    transport: &'a mut Transport,
+    progress_tx: mpsc::UnboundedSender<ProgressEvent>,
+    cancel: CancellationToken,
+    /// Filled in once the offer has gone out.
+    record: &'a mut Option<HistoryEntry>,
+}
+
+async fn send_files(
+    files: Vec<FileSource>,
+    file_infos: Vec<FileInfo>,
+    encryption_key: [u8; 32],
+    options: SendOptions,
+    ctx: SendContext<'_>,
+) -> AppResult<()> {
+    let SendContext {
+        transport,
+        progress_tx,
+        cancel,
+        record,
+    } = ctx;
+    let (mut files, mut file_infos) = if options.dedupe {
+        dedupe_files(files, file_infos).await?
+    } else {
+        (files, file_infos)
+    };
+    if options.tail.is_some() {
+        let [info] = file_infos.as_mut_slice() else {
+            return Err(AppError::Transfer(
+                "tail mode sends exactly one file".into(),
+            ));
+        };
+        info.streaming = true;
+        // Still changing, so the offer-time mtime would be stale.
+        info.modified = None;
+    }
+
+    let peer = handshake::exchange_hello(transport).await?;
+    // Until the receiver accepts, nothing else may cross the relay for a
+    // while; pings stop it looking dead. Only relay reads survive a ping
+    // interrupting them, so a direct connection is left to QUIC.
+    let keepalive_every = (transport.is_relayed() && peer.supports(FEATURE_KEEPALIVE))
+        .then(|| options.keepalive_interval.unwrap_or(KEEPALIVE_INTERVAL));
+    // A peer that can't make links would save them as empty files.
+    if !peer.supports(FEATURE_SYMLINKS) && file_infos.iter().any(|f| f.link_target.is_some()) {
+        let mut kept = Vec::with_capacity(files.len());
+        for (source, info) in files.into_iter().zip(std::mem::take(&mut file_infos)) {
+            if info.link_target.is_some() {
+                warn!(
+                    "sender: peer can't create links, leaving out '{}'",
+                    info.target_path()
+                );
+                progress_tx
+                    .send(ProgressEvent::FileSkipped {
+                        path: info.target_path().into(),
+                        reason: "receiver can't create links".into(),
+                    })
+                    .ok();
+                continue;
+            }
+            kept.push(source);
+            file_infos.push(info);
+        }
+        files = kept;
+    }
+    // Empty folders alone still make a transfer, which the receiver
+    // completes as soon as it has created them. Nothing at all doesn't.
+    if file_infos.is_empty()
+        && (options.empty_dirs.is_empty() || !peer.supports(FEATURE_EMPTY_DIRS))
+    {
+        warn!("sender: nothing to send");
+        transport
+            .send_peer_message(&PeerMessage::Cancel {
+                reason: CancelReason::Error,
+                detail: AppError::NothingToSend.to_string(),
+            })
+            .await
+            .ok();
+        return Err(AppError::NothingToSend);
+    }
+    if let Some(usage) = &options.usage {
+        if let Err(e) = usage.check_quota().await {
+            warn!("sender: {e}");
+            transport
+                .send_peer_message(&PeerMessage::Cancel {
+                    reason: CancelReason::for_error(&e),
+                    detail: e.to_string(),
+                })
+                .await
+                .ok();
+            return Err(e);
+        }
+    }
+    // One tar in place of the files, if each has fixed content to put in it.
+    let archive = options.archive
+        && peer.supports(FEATURE_ARCHIVE)
+        && options.tail.is_none()
+        && !file_infos.is_empty()
+        && files.iter().zip(&file_infos).all(|(source, info)| {
+            source.rereadable() && !info.streaming && info.link_target.is_none()
+        });
+    if archive {
+        info!("sender: archiving {} file(s) into one stream", files.len());
+        let (source, info) = archive::pack(files, file_infos, &options.empty_dirs);
+        files = vec![source];
+        file_infos = vec![info];
+    }
+    // Only offer extensions the peer said it understands.
+    let checksum = match options.checksum {
+        ChecksumAlgorithm::Blake3 if !peer.supports(FEATURE_BLAKE3) => ChecksumAlgorithm::default(),
+        checksum => checksum,
+    };
+    // Offer local copying along with proof that we share the machine.
+    let probe = if options.local_copy
+        && peer.supports(FEATURE_LOCAL_COPY)
+        && !archive
+        && !options.challenge
+        && options.piece_hashes.is_none()
+        && options.tail.is_none()
+    {
+        LocalProbe::create()
+            .await
+            .inspect_err(|e| warn!("sender: not offering local copy: {e}"))
+            .ok()
+    } else {
+        None
+    };
+    let offered = TransferFeatures {
+        compression: options.compress && peer.supports(FEATURE_COMPRESSION),
+        checksum,
+        local_copy: probe.is_some(),
+        archive,
+    };
+
+    if options.pre_hash && options.tail.is_none() {
+        progress_tx
+            .send(ProgressEvent::StateChanged {
+                state: "hashing".into(),
+            })
+            .ok();
+        let hashing = prehash_files(&files, &mut file_infos, checksum, &progress_tx, &cancel);
+        if let Err(e) = with_keepalive(transport, keepalive_every, hashing).await {
+            if matches!(e, AppError::Cancelled) {
+                return Err(cancelled_by_sender(transport).await);
+            }
+            return Err(e);
+        }
+    }
+
     info!("sender: starting transfer ({} files)", files.len());
     progress_tx
         .send(ProgressEvent::StateChanged {
@@ -35,62 +379,525 @@ pub async fn run_send(
         .ok();
 
     let total_bytes: u64 = file_infos.iter().map(|f| f.size).sum();
+    let challenge = if options.challenge {
+        Some(challenge_nonce()?)
+    } else {
+        None
+    };
+
+    if let Some(probe) = &probe {
+        transport
+            .send_peer_message(&PeerMessage::LocalProbe {
+                path: probe.path().into(),
+                token: probe.token(),
+            })
+            .await?;
+    }
+
+    *record = Some(
+        HistoryEntry::for_send(&file_infos)
+            .with_connection(options.code.clone(), transport.is_relayed()),
+    );
 
     // Send file offer
     transport
         .send_peer_message(&PeerMessage::FileOffer {
             files: file_infos.clone(),
+            piece_hashes: options.piece_hashes,
+            challenge,
+            features: offered,
         })
         .await?;
 
-    // Wait for accept/decline
-    let response = transport.recv_peer_message().await?;
-    match response {
-        PeerMessage::FileAccept => {
-            info!("sender: peer accepted transfer");
-        }
-        PeerMessage::FileDecline => {
-            warn!("sender: peer declined transfer");
-            return Err(AppError::PeerRejected);
+    // Wait for accept/decline. A resuming receiver first says how much of
+    // each file it already has, and a syncing one which files it has whole.
+    let mut resume_at = vec![0u64; files.len()];
+    let mut skipped = vec![false; files.len()];
+    // Files a partial accept left out.
+    let mut unselected = vec![false; files.len()];
+    let accept_timeout = options.accept_timeout.unwrap_or(ACCEPT_TIMEOUT);
+    let answer_by = tokio::time::sleep(accept_timeout);
+    tokio::pin!(answer_by);
+    let features = loop {
+        let reply = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => return Err(cancelled_by_sender(transport).await),
+            _ = &mut answer_by => {
+                warn!("sender: no answer to the offer after {accept_timeout:?}");
+                let detail = format!("offer not answered within {accept_timeout:?}");
+                return Err(timed_out(transport, detail).await);
+            }
+            reply = recv_keeping_alive(transport, keepalive_every) => reply?,
+        };
+        match reply {
+            PeerMessage::HaveFiles { entries } => {
+                let matching = matching_files(&files, &file_infos, &entries);
+                let file_indices = with_keepalive(transport, keepalive_every, matching).await?;
+                info!(
+                    "sender: receiver already has {} of {} file(s)",
+                    file_indices.len(),
+                    files.len()
+                );
+                for &file_index in &file_indices {
+                    skipped[file_index as usize] = true;
+                }
+                transport
+                    .send_peer_message(&PeerMessage::SkipFiles { file_indices })
+                    .await?;
+            }
+            PeerMessage::ResumeRequest {
+                file_index,
+                bytes_received,
+            } => {
+                let idx = file_index as usize;
+                match file_infos.get(idx) {
+                    Some(info) if bytes_received <= info.size => {
+                        info!("sender: resuming '{}' at byte {bytes_received}", info.name);
+                        resume_at[idx] = bytes_received;
+                    }
+                    _ => {
+                        return Err(AppError::Transfer(format!(
+                            "invalid resume request for file {file_index}"
+                        )));
+                    }
+                }
+            }
+            PeerMessage::FileAccept { features } => {
+                info!("sender: peer accepted transfer");
+                break agreed_features(offered, features);
+            }
+            PeerMessage::FileAcceptPartial {
+                accepted_indices,
+                features,
+            } => {
+                unselected.fill(true);
+                for file_index in accepted_indices {
+                    match unselected.get_mut(file_index as usize) {
+                        Some(declined) => *declined = false,
+                        None => {
+                            return Err(AppError::Transfer(format!(
+                                "invalid file index {file_index} in partial accept"
+                            )));
+                        }
+                    }
+                }
+                info!(
+                    "sender: peer accepted {} of {} file(s)",
+                    unselected.iter().filter(|&&declined| !declined).count(),
+                    files.len()
+                );
+                break agreed_features(offered, features);
+            }
+            PeerMessage::FileDecline => {
+                warn!("sender: peer declined transfer");
+                progress_tx
+                    .send(ProgressEvent::PeerCancelled {
+                        reason: CancelReason::Declined,
+                        detail: String::new(),
+                    })
+                    .ok();
+                return Err(AppError::PeerRejected);
+            }
+            PeerMessage::Cancel { reason, detail } => {
+                warn!("sender: peer cancelled before accepting: {reason}");
+                return Err(peer_cancelled(&progress_tx, reason, detail));
+            }
+            _ => {
+                return Err(AppError::Transfer("unexpected message from peer".into()));
+            }
         }
-        _ => {
-            return Err(AppError::Transfer("unexpected message from peer".into()));
+    };
+
+    // The receiver has checked the probe by now.
+    drop(probe);
+    if archive && !features.archive {
+        info!("sender: receiver will keep the archive as it is");
+    }
+
+    // An archive carries its own empty folders.
+    if !options.empty_dirs.is_empty() && !archive {
+        if peer.supports(FEATURE_EMPTY_DIRS) {
+            for relative_path in &options.empty_dirs {
+                transport
+                    .send_peer_message(&PeerMessage::CreateDir {
+                        relative_path: relative_path.clone(),
+                    })
+                    .await?;
+            }
+        } else {
+            warn!(
+                "sender: peer can't create empty folders, leaving out {}",
+                options.empty_dirs.len()
+            );
         }
     }
 
-    let mut tracker = ProgressTracker::new(total_bytes);
+    // TCP hides the relay's troubles, so time them with pings instead.
+    let mut health =
+        (transport.is_relayed() && peer.supports(FEATURE_PING)).then(RelayHealth::default);
+    let keepalive = peer.supports(FEATURE_PING);
+    let stall = options.stall_timeout.unwrap_or(STALL_TIMEOUT);
+    // Small files go out whole, many to a message, unless something needs a
+    // message of its own per file.
+    let mut batch = (options.batch_small_files
+        && peer.supports(FEATURE_BATCH)
+        && !features.local_copy
+        && challenge.is_none()
+        && options.piece_hashes.is_none()
+        && options.key_ratchet_every.is_none()
+        && options.tail.is_none())
+    .then(PendingBatch::default);
+
+    // Progress covers only what's actually sent this time, and the summary
+    // only what the receiver asked for.
+    let bytes_where = |flags: &[bool]| -> u64 {
+        file_infos
+            .iter()
+            .zip(flags)
+            .filter(|(_, &set)| set)
+            .map(|(info, _)| info.size)
+            .sum()
+    };
+    let total_bytes = total_bytes - bytes_where(&unselected);
+    let file_count = unselected.iter().filter(|&&declined| !declined).count() as u32;
+    let mut tracker =
+        ProgressTracker::new(total_bytes - bytes_where(&skipped) - resume_at.iter().sum::<u64>());
+    let mut limiter = options
+        .max_bytes_per_sec
+        .filter(|&rate| rate > 0)
+        .map(RateLimiter::new);
+    let chunk_size = options
+        .chunk_size
+        .unwrap_or_else(|| default_chunk_size(transport.is_relayed()));
+
+    // Files that went out side by side, each on a stream of its own.
+    let mut streamed = vec![false; files.len()];
+    let parallel = transport.connection().cloned().filter(|_| {
+        options.parallel_streams > 1
+            && peer.supports(FEATURE_PARALLEL_STREAMS)
+            && batch.is_none()
+            && !features.local_copy
+            && challenge.is_none()
+            && options.piece_hashes.is_none()
+            && options.key_ratchet_every.is_none()
+            && options.tail.is_none()
+    });
+    if let Some(conn) = parallel {
+        let file_indices: Vec<u16> = (0..files.len())
+            .filter(|&i| !unselected[i] && !skipped[i])
+            .map(|i| i as u16)
+            .collect();
+        let settings = StreamSettings {
+            key: encryption_key,
+            features,
+            chunk_size,
+            stall,
+            pause: options.pause.clone(),
+            bytes_per_sec: options
+                .max_bytes_per_sec
+                .filter(|&rate| rate > 0)
+                .map(|rate| (rate / options.parallel_streams as u64).max(1)),
+        };
+        send_in_parallel(
+            conn,
+            ParallelFiles {
+                files: &files,
+                file_infos: &file_infos,
+                file_indices: &file_indices,
+                resume_at: &resume_at,
+            },
+            settings,
+            &mut tracker,
+            &options,
+            SendContext {
+                transport: &mut *transport,
+                progress_tx: progress_tx.clone(),
+                cancel: cancel.clone(),
+                record: &mut *record,
+            },
+        )
+        .await?;
+        for file_index in file_indices {
+            streamed[file_index as usize] = true;
+        }
+    }
 
     // Transfer each file
-    for (file_index, path) in files.iter().enumerate() {
+    for (file_index, source) in files.iter().enumerate() {
+        if streamed[file_index] {
+            continue;
+        }
+        if unselected[file_index] {
+            info!(
+                "sender: skipping '{}', the receiver didn't select it",
+                file_infos[file_index].name
+            );
+            continue;
+        }
+        if skipped[file_index] {
+            info!(
+                "sender: skipping '{}', the receiver has it",
+                file_infos[file_index].name
+            );
+            continue;
+        }
+        // A fresh encryptor, so a fresh nonce prefix, for every file.
         let encryptor = ChunkEncryptor::new(&encryption_key)?;
-        let mut chunker = FileChunker::new(path, encryptor).await?;
+        let mut chunker =
+            FileChunker::from_source(source, file_index as u16, encryptor, chunk_size)
+                .await?
+            .with_checksum(features.checksum);
+        if let Some(config) = options.piece_hashes {
+            chunker = chunker.with_piece_hashes(config)?;
+        }
+        if let Some(nonce) = &challenge {
+            chunker = chunker.with_challenge(nonce);
+        }
+        if features.compression {
+            chunker = chunker.with_compression();
+        }
+        if let Some(stop) = &options.tail {
+            chunker = chunker.with_tail(stop.clone());
+        }
+        if resume_at[file_index] > 0 {
+            chunker.skip(resume_at[file_index]).await?;
+        }
         let file_name = &file_infos[file_index].name;
 
         info!("sender: sending file '{file_name}'");
 
-        // Send chunks
-        while let Some((data, nonce, chunk_index)) = chunker.next_chunk().await? {
+        if let Some(pending) = batch
+            .as_mut()
+            .filter(|_| {
+                resume_at[file_index] == 0
+                    && file_infos[file_index].size <= BATCH_FILE_MAX
+                    && !file_infos[file_index].streaming
+            })
+        {
+            wait_while_paused(transport, &options.pause, &mut health, keepalive, &cancel).await?;
             if cancel.is_cancelled() {
-                transport
-                    .send_peer_message(&PeerMessage::Cancel {
-                        reason: "cancelled by sender".into(),
-                    })
-                    .await
-                    .ok();
-                return Err(AppError::Cancelled);
+                return Err(cancelled_by_sender(transport).await);
+            }
+            let mut chunks = Vec::new();
+            while let Some((data, nonce, _, compressed, _)) = chunker.next_chunk().await? {
+                chunks.push(BatchedChunk {
+                    data,
+                    nonce,
+                    compressed,
+                });
+            }
+            let read = chunker.bytes_read();
+            let wire: u64 = chunks.iter().map(|c| c.data.len() as u64).sum();
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &options.metrics {
+                metrics.add_bytes(TransferRole::Sender, read);
+            }
+            if let Some(usage) = &options.usage {
+                usage.add(transport.is_relayed(), wire).await;
+            }
+            pending.file_bytes += read;
+            pending.wire_bytes += wire;
+            pending.files.push(BatchedFile {
+                file_index: file_index as u16,
+                chunks,
+                sha256: chunker.finalize(),
+            });
+            if let Some(limiter) = &mut limiter {
+                tokio::select! {
+                    _ = limiter.consume(wire) => {}
+                    _ = cancel.cancelled() => return Err(cancelled_by_sender(transport).await),
+                }
             }
 
-            let chunk_len = data.len() as u64;
+            tracker.update(read);
+            if let Some(entry) = record.as_mut() {
+                entry.bytes_received = tracker.bytes_transferred();
+            }
+            progress_tx
+                .send(ProgressEvent::file_progress(
+                    file_index as u16,
+                    read,
+                    file_infos[file_index].size,
+                ))
+                .ok();
+            progress_tx
+                .send(ProgressEvent::TransferProgress {
+                    bytes_transferred: tracker.bytes_transferred(),
+                    bytes_total: tracker.bytes_total(),
+                    speed_bps: tracker.speed_bps(),
+                    eta_seconds: tracker.eta_seconds(),
+                    current_file: file_name.clone(),
+                    percent: tracker.percent(),
+                })
+                .ok();
+
+            if pending.is_full() {
+                send_batch(
+                    transport,
+                    pending,
+                    &file_infos,
+                    &mut health,
+                    &progress_tx,
+                    stall,
+                )
+                .await?;
+            }
+            continue;
+        }
+        // Files go in order, so whatever's batched goes ahead of this one.
+        if let Some(pending) = batch.as_mut().filter(|b| !b.files.is_empty()) {
+            send_batch(
+                transport,
+                pending,
+                &file_infos,
+                &mut health,
+                &progress_tx,
+                stall,
+            )
+            .await?;
+        }
+
+        let local = if features.local_copy && resume_at[file_index] == 0 {
+            local_path(source).await
+        } else {
+            None
+        };
+        if let Some(path) = local {
+            wait_while_paused(transport, &options.pause, &mut health, keepalive, &cancel).await?;
+            if cancel.is_cancelled() {
+                return Err(cancelled_by_sender(transport).await);
+            }
             transport
-                .send_peer_message(&PeerMessage::FileChunk {
+                .send_peer_message(&PeerMessage::LocalSource {
                     file_index: file_index as u16,
-                    chunk_index,
-                    data,
-                    nonce,
+                    path: path.clone(),
                 })
                 .await?;
+            // Hashed while the receiver copies; a file that changes in
+            // between fails verification. Pings keep the receiver from
+            // taking a long hash for a stall.
+            let checksum = match file_infos[file_index].checksum {
+                Some(checksum) => checksum,
+                None => {
+                    let hash = resume::hash_file(Path::new(&path), features.checksum);
+                    with_keepalive(transport, keepalive.then_some(PING_INTERVAL), hash).await?
+                }
+            };
+
+            let size = file_infos[file_index].size;
+            tracker.update(size);
+            if let Some(entry) = record.as_mut() {
+                entry.bytes_received = tracker.bytes_transferred();
+            }
+            progress_tx
+                .send(ProgressEvent::file_progress(file_index as u16, size, size))
+                .ok();
+            progress_tx
+                .send(ProgressEvent::TransferProgress {
+                    bytes_transferred: tracker.bytes_transferred(),
+                    bytes_total: tracker.bytes_total(),
+                    speed_bps: tracker.speed_bps(),
+                    eta_seconds: tracker.eta_seconds(),
+                    current_file: file_name.clone(),
+                    percent: tracker.percent(),
+                })
+                .ok();
+
+            transport
+                .send_peer_message(&PeerMessage::FileComplete {
+                    file_index: file_index as u16,
+                    sha256: checksum,
+                })
+                .await?;
+            // However long the receiver's copy takes.
+            await_verified(transport, &mut health, &progress_tx, file_name, None).await?;
+            continue;
+        }
+
+        let mut file_bytes = chunker.bytes_read();
+        let ratchet_every = options.key_ratchet_every.filter(|&n| n > 0);
+        let mut feed = match options.send_window.unwrap_or(DEFAULT_SEND_WINDOW) {
+            0 => ChunkFeed::Serial {
+                chunker: Box::new(chunker),
+                ratchet_every,
+            },
+            window => ChunkFeed::pipelined(chunker, ratchet_every, window),
+        };
+
+        // Send chunks. A tailing chunker can wait on its file for as long as
+        // it's left running, so don't make a cancel wait for the next chunk.
+        loop {
+            let next = tokio::select! {
+                next = feed.next() => next?,
+                _ = cancel.cancelled() => return Err(cancelled_by_sender(transport).await),
+            };
+            let Some(ReadChunk {
+                data,
+                nonce,
+                chunk_index,
+                compressed,
+                plaintext_len,
+                ratcheted,
+            }) = next
+            else {
+                break;
+            };
+            wait_while_paused(transport, &options.pause, &mut health, keepalive, &cancel).await?;
+            if cancel.is_cancelled() {
+                return Err(cancelled_by_sender(transport).await);
+            }
+
+            let chunk_len = data.len() as u64;
+            // Progress counts file bytes, not what went over the wire.
+            let read = plaintext_len;
+            file_bytes += read;
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &options.metrics {
+                metrics.add_bytes(TransferRole::Sender, read);
+            }
+            if let Some(usage) = &options.usage {
+                usage.add(transport.is_relayed(), chunk_len).await;
+            }
+            let sent_at = Instant::now();
+            let chunk = PeerMessage::FileChunk {
+                file_index: file_index as u16,
+                chunk_index,
+                data,
+                nonce,
+                compressed,
+            };
+            send_unless_stalled(transport, &chunk, stall).await?;
+            if let Some(health) = &mut health {
+                health.record_send(read, chunk_len, sent_at.elapsed());
+                poll_relay_health(transport, health, &progress_tx).await?;
+            }
+            if let Some(limiter) = &mut limiter {
+                tokio::select! {
+                    _ = limiter.consume(chunk_len) => {}
+                    _ = cancel.cancelled() => return Err(cancelled_by_sender(transport).await),
+                }
+            }
 
-            tracker.update(chunk_len);
+            if ratcheted {
+                transport
+                    .send_peer_message(&PeerMessage::KeyRatchet {
+                        file_index: file_index as u16,
+                        chunk_index: chunk_index + 1,
+                    })
+                    .await?;
+            }
+
+            tracker.update(read);
+            if let Some(entry) = record.as_mut() {
+                entry.bytes_received = tracker.bytes_transferred();
+            }
+            progress_tx
+                .send(ProgressEvent::file_progress(
+                    file_index as u16,
+                    file_bytes,
+                    file_infos[file_index].size,
+                ))
+                .ok();
             progress_tx
                 .send(ProgressEvent::TransferProgress {
                     bytes_transferred: tracker.bytes_transferred(),
@@ -102,8 +909,39 @@ pub async fn run_send(
                 })
                 .ok();
         }
+        if file_bytes == 0 {
+            // No chunks, so nothing above said how far along it is.
+            progress_tx
+                .send(ProgressEvent::file_progress(
+                    file_index as u16,
+                    0,
+                    file_infos[file_index].size,
+                ))
+                .ok();
+        }
+
+        let mut chunker = feed.finish().await?;
+        if file_infos[file_index].streaming {
+            info!("sender: stream '{file_name}' stopped");
+            transport
+                .send_peer_message(&PeerMessage::StreamEnd {
+                    file_index: file_index as u16,
+                    size: chunker.bytes_read(),
+                })
+                .await?;
+        }
+
+        if let Some(hashes) = chunker.take_piece_hashes() {
+            transport
+                .send_peer_message(&PeerMessage::PieceHashes {
+                    file_index: file_index as u16,
+                    hashes,
+                })
+                .await?;
+        }
 
         // Send file complete with checksum
+        let expected_mac = chunker.take_challenge();
         let checksum = chunker.finalize();
         transport
             .send_peer_message(&PeerMessage::FileComplete {
@@ -112,30 +950,58 @@ pub async fn run_send(
             })
             .await?;
 
-        // Wait for verification
-        let verify = transport.recv_peer_message().await?;
-        match verify {
-            PeerMessage::FileVerified { .. } => {
-                info!("sender: file '{file_name}' verified by receiver");
-                progress_tx
-                    .send(ProgressEvent::FileCompleted {
-                        name: file_name.clone(),
-                    })
-                    .ok();
-            }
-            PeerMessage::Cancel { reason } => {
-                return Err(AppError::Transfer(format!("peer cancelled: {reason}")));
-            }
-            _ => {
-                return Err(AppError::Transfer("expected FileVerified message".into()));
+        if let Some(expected) = expected_mac {
+            match recv_reply(transport, &mut health, &progress_tx, Some(stall)).await? {
+                PeerMessage::ChallengeResponse { mac, .. } if expected.verify(&mac) => {
+                    info!("sender: receiver proved it holds '{file_name}'");
+                }
+                PeerMessage::ChallengeResponse { .. } => {
+                    let detail = format!("challenge response mismatch for '{file_name}'");
+                    transport
+                        .send_peer_message(&PeerMessage::Cancel {
+                            reason: CancelReason::ChecksumMismatch,
+                            detail: detail.clone(),
+                        })
+                        .await
+                        .ok();
+                    return Err(AppError::ChecksumMismatch(detail));
+                }
+                PeerMessage::Cancel { reason, detail } => {
+                    warn!("sender: receiver cancelled: {reason}");
+                    return Err(peer_cancelled(&progress_tx, reason, detail));
+                }
+                _ => {
+                    return Err(AppError::Transfer(
+                        "expected ChallengeResponse message".into(),
+                    ));
+                }
             }
         }
+
+        await_verified(transport, &mut health, &progress_tx, file_name, Some(stall)).await?;
+    }
+
+    if let Some(pending) = batch.as_mut().filter(|b| !b.files.is_empty()) {
+        send_batch(
+            transport,
+            pending,
+            &file_infos,
+            &mut health,
+            &progress_tx,
+            stall,
+        )
+        .await?;
+    }
+
+    if let Some(health) = &health {
+        progress_tx.send(health.stats()).ok();
     }
 
     // Send transfer complete
     transport
         .send_peer_message(&PeerMessage::TransferComplete)
         .await?;
+    transport.say_bye().await;
 
     // Finish the send side
     transport.finish_send().await?;
@@ -148,10 +1014,842 @@ pub async fn run_send(
             duration_seconds: tracker.elapsed_seconds(),
             average_speed: tracker.average_speed(),
             total_bytes,
-            file_count: files.len() as u32,
+            file_count,
+            connection_type: transport.connection_type().into(),
+            peak_speed_bps: tracker.peak_speed_bps(),
         })
         .ok();
 
     info!("sender: transfer complete");
     Ok(())
 }
+
+/// How the files sent side by side are read and paced.
+#[derive(Clone)]
+struct StreamSettings {
+    key: [u8; 32],
+    features: TransferFeatures,
+    chunk_size: usize,
+    stall: Duration,
+    pause: PauseToken,
+    /// Each stream's share of the rate cap.
+    bytes_per_sec: Option<u64>,
+}
+
+/// The part of an offer [`send_in_parallel`] sends.
+struct ParallelFiles<'a> {
+    files: &'a [FileSource],
+    file_infos: &'a [FileInfo],
+    /// The files that go out side by side.
+    file_indices: &'a [u16],
+    /// Where each file resumes, by index.
+    resume_at: &'a [u64],
+}
+
+/// Send the files at `file_indices` on streams of their own, up to
+/// `options.parallel_streams` at a time, then wait for the receiver to
+/// verify each. Progress counts every stream's chunks as they go out.
+async fn send_in_parallel(
+    conn: Connection,
+    parallel: ParallelFiles<'_>,
+    settings: StreamSettings,
+    tracker: &mut ProgressTracker,
+    options: &SendOptions,
+    ctx: SendContext<'_>,
+) -> AppResult<()> {
+    let ParallelFiles {
+        files,
+        file_infos,
+        file_indices,
+        resume_at,
+    } = parallel;
+    let SendContext {
+        transport,
+        progress_tx,
+        cancel,
+        record,
+    } = ctx;
+    transport
+        .send_peer_message(&PeerMessage::ParallelFiles {
+            file_indices: file_indices.to_vec(),
+        })
+        .await?;
+    let stall = settings.stall;
+    let width = options.parallel_streams.min(file_indices.len());
+    info!(
+        "sender: sending {} file(s) on up to {width} streams at once",
+        file_indices.len()
+    );
+
+    let queue: VecDeque<_> = file_indices
+        .iter()
+        .map(|&i| (i, files[i as usize].clone(), resume_at[i as usize]))
+        .collect();
+    let queue = Arc::new(Mutex::new(queue));
+    let (sent_tx, mut sent_rx) = mpsc::unbounded_channel();
+    let mut workers = JoinSet::new();
+    for _ in 0..width {
+        workers.spawn(stream_files(
+            conn.clone(),
+            queue.clone(),
+            settings.clone(),
+            sent_tx.clone(),
+        ));
+    }
+    drop(sent_tx);
+
+    let mut file_bytes = resume_at.to_vec();
+    loop {
+        let sent = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => return Err(cancelled_by_sender(transport).await),
+            Some(joined) = workers.join_next() => {
+                if let Err(e) = flatten(joined) {
+                    return Err(stream_failed(transport, &progress_tx, e, stall).await);
+                }
+                continue;
+            }
+            sent = sent_rx.recv() => sent,
+        };
+        // Every stream is done once none can report more.
+        let Some((file_index, read, wire)) = sent else {
+            break;
+        };
+        let idx = file_index as usize;
+        file_bytes[idx] += read;
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &options.metrics {
+            metrics.add_bytes(TransferRole::Sender, read);
+        }
+        if let Some(usage) = &options.usage {
+            usage.add(false, wire).await;
+        }
+
+        tracker.update(read);
+        if let Some(entry) = record.as_mut() {
+            entry.bytes_received = tracker.bytes_transferred();
+        }
+        progress_tx
+            .send(ProgressEvent::file_progress(
+                file_index,
+                file_bytes[idx],
+                file_infos[idx].size,
+            ))
+            .ok();
+        progress_tx
+            .send(ProgressEvent::TransferProgress {
+                bytes_transferred: tracker.bytes_transferred(),
+                bytes_total: tracker.bytes_total(),
+                speed_bps: tracker.speed_bps(),
+                eta_seconds: tracker.eta_seconds(),
+                current_file: file_infos[idx].name.clone(),
+                percent: tracker.percent(),
+            })
+            .ok();
+    }
+    while let Some(joined) = workers.join_next().await {
+        if let Err(e) = flatten(joined) {
+            return Err(stream_failed(transport, &progress_tx, e, stall).await);
+        }
+    }
+
+    // The receiver verified each as it completed; the replies waited here.
+    let mut unverified: HashSet<u16> = file_indices.iter().copied().collect();
+    while !unverified.is_empty() {
+        match recv_reply(transport, &mut None, &progress_tx, Some(stall)).await? {
+            PeerMessage::FileVerified { file_index } if unverified.remove(&file_index) => {
+                let file_name = &file_infos[file_index as usize].name;
+                info!("sender: file '{file_name}' verified by receiver");
+                progress_tx
+                    .send(ProgressEvent::FileCompleted {
+                        name: file_name.clone(),
+                    })
+                    .ok();
+            }
+            PeerMessage::Cancel { reason, detail } => {
+                warn!("sender: receiver cancelled: {reason}");
+                return Err(peer_cancelled(&progress_tx, reason, detail));
+            }
+            _ => return Err(AppError::Transfer("expected FileVerified message".into())),
+        }
+    }
+    Ok(())
+}
+
+/// Send files from `queue` one after another, each on a stream of its own,
+/// reporting every chunk sent as its file's index, file bytes and bytes on
+/// the wire.
+async fn stream_files(
+    conn: Connection,
+    queue: Arc<Mutex<VecDeque<(u16, FileSource, u64)>>>,
+    settings: StreamSettings,
+    sent: mpsc::UnboundedSender<(u16, u64, u64)>,
+) -> AppResult<()> {
+    let mut limiter = settings.bytes_per_sec.map(RateLimiter::new);
+    loop {
+        let Some((file_index, source, resume_at)) = queue.lock().unwrap().pop_front() else {
+            return Ok(());
+        };
+        let encryptor = ChunkEncryptor::new(&settings.key)?;
+        let mut chunker =
+            FileChunker::from_source(&source, file_index, encryptor, settings.chunk_size)
+                .await?
+                .with_checksum(settings.features.checksum);
+        if settings.features.compression {
+            chunker = chunker.with_compression();
+        }
+        if resume_at > 0 {
+            chunker.skip(resume_at).await?;
+        }
+
+        let mut stream = streams::open(&conn, file_index).await?;
+        while let Some((data, nonce, chunk_index, compressed, plaintext_len)) =
+            chunker.next_chunk().await?
+        {
+            settings.pause.wait_while_paused().await;
+            let wire = data.len() as u64;
+            let chunk = PeerMessage::FileChunk {
+                file_index,
+                chunk_index,
+                data,
+                nonce,
+                compressed,
+            };
+            write_unless_stalled(&mut stream, &chunk, settings.stall).await?;
+            if let Some(limiter) = &mut limiter {
+                limiter.consume(wire).await;
+            }
+            sent.send((file_index, plaintext_len, wire)).ok();
+            // A stream with room never waits, so give the others a turn.
+            tokio::task::yield_now().await;
+        }
+        if chunker.bytes_read() == 0 {
+            // An empty file: report it all the same.
+            sent.send((file_index, 0, 0)).ok();
+        }
+        let complete = PeerMessage::FileComplete {
+            file_index,
+            sha256: chunker.finalize(),
+        };
+        write_unless_stalled(&mut stream, &complete, settings.stall).await?;
+        stream
+            .finish()
+            .map_err(|e| AppError::Network(format!("failed to finish file stream: {e}")))?;
+    }
+}
+
+/// Write `msg` to a file stream, failing with `ConnectionTimeout` if it
+/// can't be written for `stall`.
+async fn write_unless_stalled(
+    stream: &mut SendStream,
+    msg: &PeerMessage,
+    stall: Duration,
+) -> AppResult<()> {
+    tokio::time::timeout(stall, write_message(stream, msg))
+        .await
+        .map_err(|_| AppError::ConnectionTimeout)?
+}
+
+/// A file stream task's outcome, panics included.
+fn flatten(joined: Result<AppResult<()>, JoinError>) -> AppResult<()> {
+    joined.map_err(|e| AppError::Transfer(format!("file stream failed: {e}")))?
+}
+
+/// A file stream failed with `e`. A receiver that gave up stops reading
+/// them and says why on the transfer's own stream, so look there first;
+/// otherwise tell the receiver why we're stopping.
+async fn stream_failed(
+    transport: &mut Transport,
+    progress_tx: &mpsc::UnboundedSender<ProgressEvent>,
+    e: AppError,
+    stall: Duration,
+) -> AppError {
+    if matches!(e, AppError::ConnectionTimeout) {
+        return stalled(transport, stall).await;
+    }
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+    // Verifications of files already done may come first.
+    while let Ok(Ok(msg)) = tokio::time::timeout_at(deadline, transport.recv_peer_message()).await {
+        if let PeerMessage::Cancel { reason, detail } = msg {
+            warn!("sender: receiver cancelled: {reason}");
+            return peer_cancelled(progress_tx, reason, detail);
+        }
+    }
+    warn!("sender: file stream failed: {e}");
+    transport
+        .send_peer_message(&PeerMessage::Cancel {
+            reason: CancelReason::for_error(&e),
+            detail: e.to_string(),
+        })
+        .await
+        .ok();
+    e
+}
+
+/// A chunk read and encrypted, ready to go out.
+struct ReadChunk {
+    data: Vec<u8>,
+    nonce: [u8; 12],
+    chunk_index: u32,
+    compressed: bool,
+    /// File bytes this chunk carries.
+    plaintext_len: u64,
+    /// The chunker moved on to a fresh key after this chunk.
+    ratcheted: bool,
+}
+
+/// Where the chunk loop takes its chunks from: the chunker directly, or a
+/// task reading up to a window of chunks ahead through a bounded channel.
+enum ChunkFeed {
+    Serial {
+        chunker: Box<FileChunker>,
+        ratchet_every: Option<u32>,
+    },
+    Pipelined {
+        chunks: mpsc::Receiver<AppResult<ReadChunk>>,
+        reader: AbortOnDropHandle<FileChunker>,
+    },
+}
+
+impl ChunkFeed {
+    fn pipelined(mut chunker: FileChunker, ratchet_every: Option<u32>, window: usize) -> Self {
+        let (tx, chunks) = mpsc::channel(window);
+        let reader = tokio::spawn(async move {
+            while let Some(next) = read_chunk(&mut chunker, ratchet_every).await.transpose() {
+                let failed = next.is_err();
+                if tx.send(next).await.is_err() || failed {
+                    break;
+                }
+            }
+            chunker
+        });
+        ChunkFeed::Pipelined {
+            chunks,
+            reader: AbortOnDropHandle::new(reader),
+        }
+    }
+
+    /// The next chunk, or `None` once the file is fully read.
+    async fn next(&mut self) -> AppResult<Option<ReadChunk>> {
+        match self {
+            ChunkFeed::Serial {
+                chunker,
+                ratchet_every,
+            } => read_chunk(chunker, *ratchet_every).await,
+            ChunkFeed::Pipelined { chunks, .. } => chunks.recv().await.transpose(),
+        }
+    }
+
+    /// The chunker back, for the checksum and whatever else it computed.
+    async fn finish(self) -> AppResult<FileChunker> {
+        match self {
+            ChunkFeed::Serial { chunker, .. } => Ok(*chunker),
+            ChunkFeed::Pipelined { reader, .. } => reader
+                .await
+                .map_err(|e| AppError::Transfer(format!("chunk reader failed: {e}"))),
+        }
+    }
+}
+
+/// Read the next chunk, ratcheting the key after it if it's due.
+async fn read_chunk(
+    chunker: &mut FileChunker,
+    ratchet_every: Option<u32>,
+) -> AppResult<Option<ReadChunk>> {
+    let Some((data, nonce, chunk_index, compressed, plaintext_len)) = chunker.next_chunk().await?
+    else {
+        return Ok(None);
+    };
+    let ratcheted = ratchet_every.is_some_and(|every| (chunk_index + 1) % every == 0);
+    if ratcheted {
+        chunker.ratchet_key()?;
+    }
+    Ok(Some(ReadChunk {
+        data,
+        nonce,
+        chunk_index,
+        compressed,
+        plaintext_len,
+        ratcheted,
+    }))
+}
+
+/// Wait for the receiver to verify `file_name`.
+async fn await_verified(
+    transport: &mut Transport,
+    health: &mut Option<RelayHealth>,
+    progress_tx: &mpsc::UnboundedSender<ProgressEvent>,
+    file_name: &str,
+    stall: Option<Duration>,
+) -> AppResult<()> {
+    match recv_reply(transport, health, progress_tx, stall).await? {
+        PeerMessage::FileVerified { .. } => {
+            info!("sender: file '{file_name}' verified by receiver");
+            progress_tx
+                .send(ProgressEvent::FileCompleted {
+                    name: file_name.into(),
+                })
+                .ok();
+            Ok(())
+        }
+        PeerMessage::Cancel { reason, detail } => {
+            warn!("sender: receiver cancelled: {reason}");
+            Err(peer_cancelled(progress_tx, reason, detail))
+        }
+        _ => Err(AppError::Transfer("expected FileVerified message".into())),
+    }
+}
+
+/// Where a receiver on this machine can copy `source` from: its absolute
+/// path, for a file on disk whose path can be sent.
+async fn local_path(source: &FileSource) -> Option<String> {
+    let FileSource::Path(path) = source else {
+        return None;
+    };
+    tokio::fs::canonicalize(path)
+        .await
+        .ok()?
+        .into_os_string()
+        .into_string()
+        .ok()
+}
+
+/// Hold the send while `pause` is set, or until cancelled. With `keepalive`,
+/// pings the receiver meanwhile so an idle relay doesn't drop the
+/// connection; the pongs are read once the transfer gets going again.
+async fn wait_while_paused(
+    transport: &mut Transport,
+    pause: &PauseToken,
+    health: &mut Option<RelayHealth>,
+    keepalive: bool,
+    cancel: &CancellationToken,
+) -> AppResult<()> {
+    if !pause.is_paused() {
+        return Ok(());
+    }
+    info!("sender: paused");
+    if let Some(health) = health {
+        health.forget_ping();
+    }
+    loop {
+        tokio::select! {
+            _ = pause.wait_while_paused() => {
+                info!("sender: resumed");
+                return Ok(());
+            }
+            _ = cancel.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(PING_INTERVAL), if keepalive => {
+                transport.send_peer_message(&PeerMessage::Ping).await?;
+            }
+        }
+    }
+}
+
+/// Run `work`, which mustn't use the transport, pinging the receiver every
+/// `interval` meanwhile if one is given.
+async fn with_keepalive<T>(
+    transport: &mut Transport,
+    interval: Option<Duration>,
+    work: impl Future<Output = AppResult<T>>,
+) -> AppResult<T> {
+    let Some(interval) = interval else {
+        return work.await;
+    };
+    tokio::pin!(work);
+    loop {
+        tokio::select! {
+            result = &mut work => return result,
+            _ = tokio::time::sleep(interval) => {
+                transport.send_peer_message(&PeerMessage::Ping).await?;
+            }
+        }
+    }
+}
+
+/// The receiver's next message other than a `Pong`, pinging it whenever
+/// `interval` passes without one. Only for the relay, whose reads survive
+/// being interrupted.
+async fn recv_keeping_alive(
+    transport: &mut Transport,
+    interval: Option<Duration>,
+) -> AppResult<PeerMessage> {
+    let Some(interval) = interval else {
+        return transport.recv_peer_message().await;
+    };
+    loop {
+        tokio::select! {
+            msg = transport.recv_peer_message() => match msg? {
+                PeerMessage::Pong => continue,
+                msg => return Ok(msg),
+            },
+            _ = tokio::time::sleep(interval) => {}
+        }
+        transport.send_peer_message(&PeerMessage::Ping).await?;
+    }
+}
+
+/// Small files read whole, waiting to go out together in one `FileBatch`.
+#[derive(Default)]
+struct PendingBatch {
+    files: Vec<BatchedFile>,
+    file_bytes: u64,
+    wire_bytes: u64,
+}
+
+impl PendingBatch {
+    fn is_full(&self) -> bool {
+        self.files.len() >= BATCH_MAX_FILES || self.wire_bytes >= BATCH_MAX_BYTES
+    }
+}
+
+/// Send everything batched so far, then wait for the receiver to verify
+/// each file in it.
+async fn send_batch(
+    transport: &mut Transport,
+    pending: &mut PendingBatch,
+    file_infos: &[FileInfo],
+    health: &mut Option<RelayHealth>,
+    progress_tx: &mpsc::UnboundedSender<ProgressEvent>,
+    stall: Duration,
+) -> AppResult<()> {
+    let PendingBatch {
+        files,
+        file_bytes,
+        wire_bytes,
+    } = std::mem::take(pending);
+    let indexes: Vec<u16> = files.iter().map(|f| f.file_index).collect();
+    let sent_at = Instant::now();
+    send_unless_stalled(transport, &PeerMessage::FileBatch { files }, stall).await?;
+    if let Some(health) = health.as_mut() {
+        health.record_send(file_bytes, wire_bytes, sent_at.elapsed());
+        poll_relay_health(transport, health, progress_tx).await?;
+    }
+
+    for file_index in indexes {
+        match recv_reply(transport, health, progress_tx, Some(stall)).await? {
+            PeerMessage::FileVerified {
+                file_index: verified,
+            } if verified == file_index => {
+                let name = &file_infos[file_index as usize].name;
+                info!("sender: file '{name}' verified by receiver");
+                progress_tx
+                    .send(ProgressEvent::FileCompleted { name: name.clone() })
+                    .ok();
+            }
+            PeerMessage::Cancel { reason, detail } => {
+                warn!("sender: receiver cancelled: {reason}");
+                return Err(peer_cancelled(progress_tx, reason, detail));
+            }
+            _ => {
+                return Err(AppError::Transfer("expected FileVerified message".into()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Take in whatever the receiver has sent mid-transfer without waiting,
+/// then ping it if one is due.
+async fn poll_relay_health(
+    transport: &mut Transport,
+    health: &mut RelayHealth,
+    progress_tx: &mpsc::UnboundedSender<ProgressEvent>,
+) -> AppResult<()> {
+    while let Some(msg) = transport.try_recv_peer_message() {
+        match msg? {
+            PeerMessage::Pong => {
+                health.pong_received();
+                progress_tx.send(health.stats()).ok();
+            }
+            PeerMessage::Cancel { reason, detail } => {
+                warn!("sender: receiver cancelled: {reason}");
+                return Err(peer_cancelled(progress_tx, reason, detail));
+            }
+            _ => {
+                return Err(AppError::Transfer(
+                    "unexpected message during transfer".into(),
+                ));
+            }
+        }
+    }
+    if health.ping_due() {
+        transport.send_peer_message(&PeerMessage::Ping).await?;
+        health.ping_sent();
+    }
+    Ok(())
+}
+
+/// The receiver's next reply, after any `Pong`s still on their way. Gives
+/// up on the receiver if `stall` passes without a message.
+async fn recv_reply(
+    transport: &mut Transport,
+    health: &mut Option<RelayHealth>,
+    progress_tx: &mpsc::UnboundedSender<ProgressEvent>,
+    stall: Option<Duration>,
+) -> AppResult<PeerMessage> {
+    loop {
+        let msg = match stall {
+            Some(limit) => match tokio::time::timeout(limit, transport.recv_peer_message()).await {
+                Ok(msg) => msg?,
+                Err(_) => return Err(stalled(transport, limit).await),
+            },
+            None => transport.recv_peer_message().await?,
+        };
+        match msg {
+            PeerMessage::Pong => {
+                if let Some(health) = health {
+                    health.pong_received();
+                    progress_tx.send(health.stats()).ok();
+                }
+            }
+            msg => return Ok(msg),
+        }
+    }
+}
+
+/// The features both sides use: only what was `offered`, with anything
+/// else the receiver `accepted` falling back to the default.
+fn agreed_features(offered: TransferFeatures, accepted: TransferFeatures) -> TransferFeatures {
+    TransferFeatures {
+        compression: offered.compression && accepted.compression,
+        checksum: if accepted.checksum == offered.checksum {
+            offered.checksum
+        } else {
+            ChecksumAlgorithm::default()
+        },
+        local_copy: offered.local_copy && accepted.local_copy,
+        archive: offered.archive && accepted.archive,
+    }
+}
+
+/// A fresh random nonce for the possession challenge.
+fn challenge_nonce() -> AppResult<[u8; 32]> {
+    let mut nonce = [0u8; 32];
+    ring::rand::SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| AppError::Crypto("failed to generate challenge nonce".into()))?;
+    Ok(nonce)
+}
+
+/// Collapse files with identical content into a single entry.
+///
+/// Only files whose size matches another file's are hashed, and never
+/// streams or links. Later copies are dropped and their target paths recorded in the
+/// first copy's `duplicates`.
+pub async fn dedupe_files(
+    files: Vec<FileSource>,
+    infos: Vec<FileInfo>,
+) -> AppResult<(Vec<FileSource>, Vec<FileInfo>)> {
+    let mut size_counts: HashMap<u64, usize> = HashMap::new();
+    for info in &infos {
+        *size_counts.entry(info.size).or_default() += 1;
+    }
+
+    let mut kept_files = Vec::with_capacity(files.len());
+    let mut kept_infos: Vec<FileInfo> = Vec::with_capacity(infos.len());
+    let mut seen: HashMap<(u64, [u8; 32]), usize> = HashMap::new();
+
+    for (source, info) in files.into_iter().zip(infos) {
+        if size_counts[&info.size] > 1
+            && source.rereadable()
+            && !info.streaming
+            && info.link_target.is_none()
+        {
+            let key = (info.size, hash_source(&source).await?);
+            if let Some(&idx) = seen.get(&key) {
+                let original = &mut kept_infos[idx];
+                let target = info.target_path();
+                if target != original.target_path()
+                    && !original.duplicates.iter().any(|d| d == target)
+                {
+                    info!("sender: '{target}' duplicates '{}'", original.target_path());
+                    original.duplicates.push(target.to_string());
+                }
+                continue;
+            }
+            seen.insert(key, kept_infos.len());
+        }
+        kept_files.push(source);
+        kept_infos.push(info);
+    }
+
+    Ok((kept_files, kept_infos))
+}
+
+/// Send `msg`, giving up on the receiver if it can't be written for `stall`.
+async fn send_unless_stalled(
+    transport: &mut Transport,
+    msg: &PeerMessage,
+    stall: Duration,
+) -> AppResult<()> {
+    match tokio::time::timeout(stall, transport.send_peer_message(msg)).await {
+        Ok(sent) => sent,
+        Err(_) => Err(stalled(transport, stall).await),
+    }
+}
+
+/// Give up on a receiver that has got nowhere for `limit`.
+async fn stalled(transport: &mut Transport, limit: Duration) -> AppError {
+    warn!("sender: no progress for {limit:?}");
+    timed_out(transport, format!("no progress for {limit:?}")).await
+}
+
+/// Tell the receiver the send timed out, then hand back the error. Best
+/// effort: a receiver that has stopped reading leaves the stream
+/// flow-controlled, so don't wait on it for long.
+async fn timed_out(transport: &mut Transport, detail: String) -> AppError {
+    let notice = PeerMessage::Cancel {
+        reason: CancelReason::Timeout,
+        detail,
+    };
+    tokio::time::timeout(Duration::from_secs(1), transport.send_peer_message(&notice))
+        .await
+        .ok();
+    AppError::ConnectionTimeout
+}
+
+/// Tell the receiver we're stopping, then hand back the error.
+async fn cancelled_by_sender(transport: &mut Transport) -> AppError {
+    transport
+        .send_peer_message(&PeerMessage::Cancel {
+            reason: CancelReason::UserCancelled,
+            detail: "cancelled by sender".into(),
+        })
+        .await
+        .ok();
+    transport.say_bye().await;
+    AppError::Cancelled
+}
+
+async fn hash_source(source: &FileSource) -> AppResult<[u8; 32]> {
+    let mut file = source.open().await?;
+    let mut checksum = StreamingChecksum::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        checksum.update(&buf[..n]);
+    }
+    Ok(checksum.finalize())
+}
+
+/// The offered files a `HaveFiles` entry matches by path, size and SHA-256.
+/// Only files whose path and size match are read.
+async fn matching_files(
+    files: &[FileSource],
+    file_infos: &[FileInfo],
+    entries: &[HaveFile],
+) -> AppResult<Vec<u16>> {
+    let mut matching = Vec::new();
+    for (file_index, (source, info)) in files.iter().zip(file_infos).enumerate() {
+        let Some(entry) = entries
+            .iter()
+            .find(|e| e.relative_path == info.target_path() && e.size == info.size)
+        else {
+            continue;
+        };
+        if info.streaming || !source.rereadable() {
+            continue;
+        }
+        if hash_source(source).await? == entry.sha256 {
+            matching.push(file_index as u16);
+        }
+    }
+    Ok(matching)
+}
+
+/// Fill in each offered file's checksum with `algorithm`, reporting
+/// `Hashing` progress as it reads. Streams, which can't be read twice, are
+/// left without.
+async fn prehash_files(
+    files: &[FileSource],
+    infos: &mut [FileInfo],
+    algorithm: ChecksumAlgorithm,
+    progress_tx: &mpsc::UnboundedSender<ProgressEvent>,
+    cancel: &tokio_util::sync::CancellationToken,
+) -> AppResult<()> {
+    let bytes_total = infos.iter().map(|f| f.size).sum();
+    let mut bytes_hashed = 0u64;
+    let mut buf = vec![0u8; 1024 * 1024];
+    for (source, info) in files.iter().zip(infos.iter_mut()) {
+        if !source.rereadable() {
+            continue;
+        }
+        let mut file = source.open().await?;
+        let mut checksum = StreamingChecksum::with_algorithm(algorithm);
+        let mut len = 0u64;
+        loop {
+            if cancel.is_cancelled() {
+                return Err(AppError::Cancelled);
+            }
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            checksum.update(&buf[..n]);
+            len += n as u64;
+            bytes_hashed += n as u64;
+            progress_tx
+                .send(ProgressEvent::Hashing {
+                    bytes_hashed,
+                    bytes_total,
+                    current_file: info.name.clone(),
+                })
+                .ok();
+        }
+        if len != info.size {
+            return Err(AppError::Transfer(format!(
+                "'{}' changed size while being hashed",
+                info.name
+            )));
+        }
+        info.checksum = Some(checksum.finalize());
+    }
+    Ok(())
+}
+
+/// Wrap a text snippet as a single in-memory file, hinted as plain text so
+/// the receiver can show it inline.
+pub fn text_file(text: String) -> (FileSource, FileInfo) {
+    let stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let info = FileInfo {
+        name: format!("clipboard-{stamp}.txt"),
+        size: text.len() as u64,
+        relative_path: None,
+        duplicates: Vec::new(),
+        mime_hint: Some(MIME_TEXT_PLAIN.into()),
+        streaming: false,
+        modified: None,
+        mode: None,
+        checksum: None,
+        link_target: None,
+    };
+    (FileSource::Memory(text.into_bytes()), info)
+}
+
+/// Wrap `reader`, e.g. stdin, as a single file called `name`. With no
+/// `size` it's offered as a stream, which ends wherever the reader does.
+pub fn stream_file(
+    name: String,
+    reader: impl AsyncRead + Send + Unpin + 'static,
+    size: Option<u64>,
+) -> (FileSource, FileInfo) {
+    let info = FileInfo {
+        name,
+        size: size.unwrap_or(0),
+        relative_path: None,
+        duplicates: Vec::new(),
+        mime_hint: None,
+        streaming: size.is_none(),
+        modified: None,
+        mode: None,
+        checksum: None,
+        link_target: None,
+    };
+    (FileSource::Stream(StreamSource::new(reader)), info)
+}
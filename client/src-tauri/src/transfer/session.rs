@@ -1,10 +1,17 @@
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::Arc;
 
-use serde::Serialize;
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Notify, RwLock};
 use tokio_util::sync::CancellationToken;
 
 use super::code::TransferCode;
+use super::destinations::Destinations;
+use super::partials::PartialFiles;
+use super::portable::{PortableState, ResumeTarget, SessionKey};
+use super::progress::ProgressEvent;
+use super::selection::Selection;
+use crate::error::{AppError, AppResult};
 
 /// A transfer session (either sending or receiving).
 pub struct TransferSession {
@@ -13,19 +20,64 @@ pub struct TransferSession {
     pub code: TransferCode,
     pub state: Arc<RwLock<TransferState>>,
     pub cancel_token: CancellationToken,
+    /// Child of `cancel_token` covering only peer discovery (signaling, key
+    /// and fingerprint exchange); cancelling it after the peers connected is a no-op.
+    pub discovery_token: CancellationToken,
+    pub pause_token: PauseToken,
+    /// Files a receive has left unfinished, for cleaning up after a cancel.
+    pub partial_files: PartialFiles,
+    /// Where a receive saves each top-level item, if not the save directory.
+    pub destinations: Destinations,
+    /// Which offered files a receive accepts, if not all of them.
+    pub selection: Selection,
+    /// The transfer key, once derived (or carried over from an import).
+    pub key: SessionKey,
+    /// What to pick up again if the session is exported; `None` for
+    /// transfers that can't be resumed, like a text snippet.
+    pub resume_target: Option<ResumeTarget>,
 }
 
 impl TransferSession {
     pub fn new(role: TransferRole, code: TransferCode) -> Self {
+        let cancel_token = CancellationToken::new();
         Self {
             id: uuid::Uuid::new_v4().to_string(),
             role,
             code,
             state: Arc::new(RwLock::new(TransferState::WaitingForPeer)),
-            cancel_token: CancellationToken::new(),
+            discovery_token: cancel_token.child_token(),
+            cancel_token,
+            pause_token: PauseToken::new(),
+            partial_files: PartialFiles::default(),
+            destinations: Destinations::default(),
+            selection: Selection::default(),
+            key: SessionKey::default(),
+            resume_target: None,
         }
     }
 
+    /// Use `key` instead of running SPAKE2, continuing an exported session.
+    pub fn with_key(mut self, key: [u8; 32]) -> Self {
+        self.key = SessionKey::imported(key);
+        self
+    }
+
+    /// Allow the session to be exported and resumed as `target`.
+    pub fn with_resume_target(mut self, target: ResumeTarget) -> Self {
+        self.resume_target = Some(target);
+        self
+    }
+
+    /// Everything needed to resume this session elsewhere: available once
+    /// the key exchange has finished, for sessions that can be resumed.
+    pub fn portable_state(&self) -> Option<PortableState> {
+        Some(PortableState {
+            code: self.code.to_code_string(),
+            key: self.key.get()?,
+            target: self.resume_target.clone()?,
+        })
+    }
+
     pub async fn set_state(&self, state: TransferState) {
         *self.state.write().await = state;
     }
@@ -34,12 +86,148 @@ impl TransferSession {
         self.state.read().await.clone()
     }
 
+    /// Follow the pipeline's progress events: `Transferring` as data moves.
+    /// A finished session stays as it ended, whatever events trail in.
+    pub async fn observe(&self, event: &ProgressEvent) {
+        if let ProgressEvent::TransferProgress {
+            bytes_transferred,
+            bytes_total,
+            speed_bps,
+            eta_seconds,
+            ..
+        } = *event
+        {
+            let mut state = self.state.write().await;
+            if !state.is_finished() {
+                *state = TransferState::Transferring {
+                    bytes_sent: bytes_transferred,
+                    bytes_total,
+                    speed_bps,
+                    eta_seconds,
+                };
+            }
+        }
+    }
+
+    /// Record how the pipeline ended.
+    pub async fn finish(&self, result: &AppResult<()>) {
+        let state = match result {
+            Ok(()) => TransferState::Completed,
+            Err(AppError::Cancelled | AppError::PeerCancelled { .. } | AppError::PeerRejected) => {
+                TransferState::Cancelled
+            }
+            Err(e) => TransferState::Failed {
+                reason: e.to_string(),
+            },
+        };
+        self.set_state(state).await;
+    }
+
     pub fn cancel(&self) {
         self.cancel_token.cancel();
     }
+
+    /// Cancel the transfer only if it is still discovering its peer.
+    pub fn cancel_discovery(&self) {
+        self.discovery_token.cancel();
+    }
+
+    /// Pause at the user's request. Returns true if the transfer was running.
+    pub fn pause(&self) -> bool {
+        self.pause_token.pause(PauseReason::User)
+    }
+
+    /// Lift the user's pause (other pause reasons still apply). Returns true
+    /// if the transfer is running again.
+    pub fn resume(&self) -> bool {
+        self.pause_token.resume(PauseReason::User)
+    }
+
+    /// Record whether the device is on a metered network.
+    /// Metered pauses the transfer; un-metered lifts that pause (other pause
+    /// reasons still apply). Returns true if the paused state changed.
+    pub fn set_network_metered(&self, metered: bool) -> bool {
+        if metered {
+            self.pause_token.pause(PauseReason::MeteredNetwork)
+        } else {
+            self.pause_token.resume(PauseReason::MeteredNetwork)
+        }
+    }
+}
+
+/// Why a transfer is paused. A transfer stays paused while any reason is active.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PauseReason {
+    User,
+    MeteredNetwork,
+}
+
+impl PauseReason {
+    fn bit(self) -> u8 {
+        match self {
+            PauseReason::User => 0b01,
+            PauseReason::MeteredNetwork => 0b10,
+        }
+    }
+}
+
+/// A cloneable pause flag shared between a session and its pipeline,
+/// checked alongside the cancellation token.
+#[derive(Debug, Clone, Default)]
+pub struct PauseToken {
+    inner: Arc<PauseInner>,
+}
+
+#[derive(Debug, Default)]
+struct PauseInner {
+    reasons: AtomicU8,
+    resumed: Notify,
+}
+
+impl PauseToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a pause reason. Returns true if the transfer was running before.
+    pub fn pause(&self, reason: PauseReason) -> bool {
+        let prev = self.inner.reasons.fetch_or(reason.bit(), Ordering::SeqCst);
+        prev == 0
+    }
+
+    /// Remove a pause reason. Returns true if this resumed the transfer.
+    pub fn resume(&self, reason: PauseReason) -> bool {
+        let prev = self
+            .inner
+            .reasons
+            .fetch_and(!reason.bit(), Ordering::SeqCst);
+        let resumed = prev != 0 && prev & !reason.bit() == 0;
+        if resumed {
+            self.inner.resumed.notify_waiters();
+        }
+        resumed
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.inner.reasons.load(Ordering::SeqCst) != 0
+    }
+
+    /// Wait until no pause reason is active. Returns immediately if not paused.
+    pub async fn wait_while_paused(&self) {
+        loop {
+            // Register for the wakeup before checking, so a resume between the
+            // check and the await isn't missed.
+            let resumed = self.inner.resumed.notified();
+            if !self.is_paused() {
+                return;
+            }
+            resumed.await;
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TransferRole {
     Sender,
     Receiver,
@@ -63,3 +251,113 @@ pub enum TransferState {
     },
     Cancelled,
 }
+
+impl TransferState {
+    /// Completed, failed or cancelled: nothing more will happen.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            TransferState::Completed | TransferState::Failed { .. } | TransferState::Cancelled
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn test_session() -> TransferSession {
+        TransferSession::new(TransferRole::Sender, TransferCode::generate())
+    }
+
+    #[tokio::test]
+    async fn test_metered_pauses_and_resumes() {
+        let session = test_session();
+        let pause = session.pause_token.clone();
+        assert!(!pause.is_paused());
+
+        assert!(session.set_network_metered(true));
+        assert!(pause.is_paused());
+
+        let waiter = tokio::spawn(async move { pause.wait_while_paused().await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished(), "must stay parked while metered");
+
+        assert!(session.set_network_metered(false));
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("waiter should wake on resume")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_state_follows_pipeline() {
+        let session = test_session();
+        assert!(matches!(
+            session.get_state().await,
+            TransferState::WaitingForPeer
+        ));
+        session.set_state(TransferState::Exchanging).await;
+        session.set_state(TransferState::Connecting).await;
+        assert!(matches!(
+            session.get_state().await,
+            TransferState::Connecting
+        ));
+
+        let progress = |bytes_transferred| ProgressEvent::TransferProgress {
+            bytes_transferred,
+            bytes_total: 1000,
+            speed_bps: 500,
+            eta_seconds: 1,
+            current_file: "a.bin".into(),
+            percent: bytes_transferred as f32 / 10.0,
+        };
+        session.observe(&progress(400)).await;
+        assert!(matches!(
+            session.get_state().await,
+            TransferState::Transferring {
+                bytes_sent: 400,
+                bytes_total: 1000,
+                ..
+            }
+        ));
+
+        session.finish(&Ok(())).await;
+        // Progress still queued for the frontend doesn't undo the ending.
+        session.observe(&progress(1000)).await;
+        assert!(matches!(
+            session.get_state().await,
+            TransferState::Completed
+        ));
+
+        let failed = test_session();
+        failed
+            .finish(&Err(AppError::Transfer("disk on fire".into())))
+            .await;
+        assert!(matches!(
+            failed.get_state().await,
+            TransferState::Failed { reason } if reason.contains("disk on fire")
+        ));
+        let cancelled = test_session();
+        cancelled.finish(&Err(AppError::PeerRejected)).await;
+        assert!(matches!(
+            cancelled.get_state().await,
+            TransferState::Cancelled
+        ));
+    }
+
+    #[test]
+    fn test_unmetered_keeps_user_pause() {
+        let session = test_session();
+        session.pause_token.pause(PauseReason::User);
+        assert!(!session.set_network_metered(true), "already paused");
+        assert!(
+            !session.set_network_metered(false),
+            "user pause still active"
+        );
+        assert!(session.pause_token.is_paused());
+        assert!(session.pause_token.resume(PauseReason::User));
+        assert!(!session.pause_token.is_paused());
+    }
+}
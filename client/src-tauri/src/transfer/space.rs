@@ -0,0 +1,201 @@
+// Free-space checks run before a receive is accepted.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use tracing::warn;
+
+use crate::error::{AppError, AppResult};
+use crate::protocol::messages::FileInfo;
+
+/// What a filesystem has left for an unprivileged writer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsSpace {
+    pub free_bytes: u64,
+    /// `None` where the filesystem has no fixed inode table (btrfs, ZFS, ...)
+    /// and so cannot run out of them independently of bytes.
+    pub free_inodes: Option<u64>,
+}
+
+/// The resource an offer would exhaust.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpaceResource {
+    Bytes,
+    Inodes,
+}
+
+impl fmt::Display for SpaceResource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SpaceResource::Bytes => "disk space",
+            SpaceResource::Inodes => "inodes",
+        })
+    }
+}
+
+/// Source of free-space figures; swapped out in tests.
+pub trait SpaceProbe: fmt::Debug + Send + Sync {
+    fn free_space(&self, dir: &Path) -> io::Result<FsSpace>;
+}
+
+/// Queries the real filesystem with `statvfs(3)`. Unsupported off Unix.
+#[derive(Debug, Default)]
+pub struct StatvfsProbe;
+
+impl SpaceProbe for StatvfsProbe {
+    #[cfg(unix)]
+    #[allow(clippy::unnecessary_cast)] // field widths differ between platforms
+    fn free_space(&self, dir: &Path) -> io::Result<FsSpace> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(dir.as_os_str().as_bytes())?;
+        // SAFETY: `statvfs` is plain old data and fully written on success.
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(FsSpace {
+            free_bytes: (stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64),
+            // Filesystems that allocate inodes dynamically report zero total.
+            free_inodes: (stat.f_files != 0).then_some(stat.f_favail as u64),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn free_space(&self, _dir: &Path) -> io::Result<FsSpace> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Bytes and inodes needed to write `files` under a save directory.
+///
+/// Counts one inode per file and per directory its relative path creates.
+/// Duplicates are hardlinked and need neither.
+pub fn required_space<'a>(files: impl IntoIterator<Item = &'a FileInfo>) -> (u64, u64) {
+    let mut bytes = 0u64;
+    let mut inodes = 0u64;
+    let mut dirs = HashSet::new();
+    for file in files {
+        bytes = bytes.saturating_add(file.size);
+        inodes += 1;
+        let mut parent = Path::new(file.target_path()).parent();
+        while let Some(dir) = parent.filter(|d| !d.as_os_str().is_empty()) {
+            if !dirs.insert(dir.to_path_buf()) {
+                break;
+            }
+            parent = dir.parent();
+        }
+    }
+    (bytes, inodes + dirs.len() as u64)
+}
+
+/// Fail with [`AppError::InsufficientSpace`] if `files` would not fit in `dir`.
+///
+/// A probe that can't answer (unsupported platform, odd mount) lets the
+/// transfer proceed; running out mid-write still aborts with `DiskFull`.
+pub fn check_space<'a>(
+    probe: &dyn SpaceProbe,
+    dir: &Path,
+    files: impl IntoIterator<Item = &'a FileInfo>,
+) -> AppResult<()> {
+    let Some(target) = dir.ancestors().find(|d| d.exists()) else {
+        return Ok(());
+    };
+    let space = match probe.free_space(target) {
+        Ok(space) => space,
+        Err(e) => {
+            warn!("free space check skipped for {}: {e}", target.display());
+            return Ok(());
+        }
+    };
+
+    let (bytes, inodes) = required_space(files);
+    if bytes > space.free_bytes {
+        return Err(AppError::InsufficientSpace {
+            resource: SpaceResource::Bytes,
+            needed: bytes,
+            available: space.free_bytes,
+        });
+    }
+    if let Some(free_inodes) = space.free_inodes.filter(|&free| inodes > free) {
+        return Err(AppError::InsufficientSpace {
+            resource: SpaceResource::Inodes,
+            needed: inodes,
+            available: free_inodes,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct FixedProbe(FsSpace);
+
+    impl SpaceProbe for FixedProbe {
+        fn free_space(&self, _dir: &Path) -> io::Result<FsSpace> {
+            Ok(self.0)
+        }
+    }
+
+    fn file(path: &str, size: u64) -> FileInfo {
+        FileInfo {
+            name: path.rsplit('/').next().unwrap().into(),
+            size,
+            relative_path: path.contains('/').then(|| path.into()),
+            duplicates: vec![],
+            mime_hint: None,
+            streaming: false,
+            modified: None,
+            mode: None,
+            checksum: None,
+            link_target: None,
+        }
+    }
+
+    #[test]
+    fn test_required_space_counts_directories_once() {
+        let files = [file("a/b/one", 10), file("a/b/two", 20), file("top", 5)];
+        // Three files plus the `a` and `a/b` directories.
+        assert_eq!(required_space(&files), (35, 5));
+    }
+
+    #[test]
+    fn test_check_space_reports_the_short_resource() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [file("a/one", 10), file("a/two", 10)];
+        let probe = |free_bytes, free_inodes| {
+            FixedProbe(FsSpace {
+                free_bytes,
+                free_inodes,
+            })
+        };
+
+        check_space(&probe(100, Some(3)), dir.path(), &files).unwrap();
+        check_space(&probe(100, None), dir.path(), &files).unwrap();
+
+        let err = check_space(&probe(100, Some(2)), dir.path(), &files).unwrap_err();
+        assert!(matches!(
+            err,
+            AppError::InsufficientSpace {
+                resource: SpaceResource::Inodes,
+                needed: 3,
+                available: 2,
+            }
+        ));
+        let err = check_space(&probe(19, Some(100)), dir.path(), &files).unwrap_err();
+        assert!(matches!(
+            err,
+            AppError::InsufficientSpace {
+                resource: SpaceResource::Bytes,
+                ..
+            }
+        ));
+    }
+}
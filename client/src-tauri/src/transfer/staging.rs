@@ -0,0 +1,161 @@
+// Staged receives — files land in a hidden directory inside the save
+// directory and are only moved into place once an inspector approves them.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use futures_util::future::BoxFuture;
+use tokio::sync::{oneshot, Mutex};
+
+use crate::error::AppResult;
+use crate::transfer::destinations;
+use crate::transfer::receiver::check_no_linked_dirs;
+
+/// Decides whether a staged receive is moved into the save directory.
+pub trait StagingInspector: fmt::Debug + Send + Sync {
+    /// Look over the received files. `files` are relative to `staging_dir`,
+    /// which is left untouched until this returns. `false` discards them.
+    fn inspect<'a>(&'a self, staging_dir: &'a Path, files: &'a [PathBuf]) -> BoxFuture<'a, bool>;
+}
+
+/// Waits for an explicit decision, e.g. from the `finalize_transfer` command.
+#[derive(Debug)]
+pub struct ManualFinalize {
+    decision: Mutex<Option<oneshot::Receiver<bool>>>,
+}
+
+impl ManualFinalize {
+    /// An inspector that finalizes once `true` arrives on `decision`; `false`
+    /// or a dropped sender discards the transfer.
+    pub fn new(decision: oneshot::Receiver<bool>) -> Self {
+        Self {
+            decision: Mutex::new(Some(decision)),
+        }
+    }
+}
+
+impl StagingInspector for ManualFinalize {
+    fn inspect<'a>(&'a self, _staging_dir: &'a Path, _files: &'a [PathBuf]) -> BoxFuture<'a, bool> {
+        Box::pin(async move {
+            match self.decision.lock().await.take() {
+                Some(decision) => decision.await.unwrap_or(false),
+                None => false,
+            }
+        })
+    }
+}
+
+/// A fresh staging directory under `save_dir`. Not created until the first
+/// file is written into it.
+pub fn staging_dir(save_dir: &Path) -> PathBuf {
+    save_dir.join(format!(".relay-staging-{}", uuid::Uuid::new_v4()))
+}
+
+/// Every file under `staging_dir`, relative to it, in a stable order.
+pub async fn staged_files(staging_dir: &Path) -> AppResult<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(rel_dir) = pending.pop() {
+        let mut entries = match tokio::fs::read_dir(staging_dir.join(&rel_dir)).await {
+            Ok(entries) => entries,
+            // Nothing was written to disk, e.g. an inline-only receive.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let rel = rel_dir.join(entry.file_name());
+            if entry.file_type().await?.is_dir() {
+                pending.push(rel);
+            } else {
+                files.push(rel);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Move `files` (relative to `staging_dir`) to the same places under
+/// `save_dir`, or under their item's entry in `destinations`, then remove
+/// the staging directory. Nothing is moved through a link on the way.
+pub async fn promote(
+    staging_dir: &Path,
+    save_dir: &Path,
+    destinations: &HashMap<String, PathBuf>,
+    files: &[PathBuf],
+) -> AppResult<()> {
+    for rel in files {
+        let root = destinations::root(save_dir, destinations, rel);
+        check_no_linked_dirs(root, rel)?;
+        let target = root.join(rel);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(staging_dir.join(rel), &target).await?;
+    }
+    discard(staging_dir).await;
+    Ok(())
+}
+
+/// Remove the staging directory and everything in it.
+pub async fn discard(staging_dir: &Path) {
+    tokio::fs::remove_dir_all(staging_dir).await.ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_promote_moves_tree_into_place() {
+        let temp = tempfile::tempdir().unwrap();
+        let staging = staging_dir(temp.path());
+        std::fs::create_dir_all(staging.join("photos/2024")).unwrap();
+        std::fs::write(staging.join("notes.txt"), b"a").unwrap();
+        std::fs::write(staging.join("photos/2024/img.jpg"), b"b").unwrap();
+
+        let files = staged_files(&staging).await.unwrap();
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("notes.txt"),
+                PathBuf::from("photos/2024/img.jpg")
+            ]
+        );
+
+        promote(&staging, temp.path(), &HashMap::new(), &files)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(temp.path().join("notes.txt")).unwrap(), b"a");
+        assert_eq!(
+            std::fs::read(temp.path().join("photos/2024/img.jpg")).unwrap(),
+            b"b"
+        );
+        assert!(!staging.exists());
+    }
+
+    #[tokio::test]
+    async fn test_promote_applies_destinations() {
+        let temp = tempfile::tempdir().unwrap();
+        let save_dir = temp.path().join("downloads");
+        let pictures = temp.path().join("pictures");
+        let staging = staging_dir(&save_dir);
+        std::fs::create_dir_all(staging.join("photos")).unwrap();
+        std::fs::write(staging.join("notes.txt"), b"a").unwrap();
+        std::fs::write(staging.join("photos/img.jpg"), b"b").unwrap();
+
+        let destinations = HashMap::from([("photos".to_string(), pictures.clone())]);
+        let files = staged_files(&staging).await.unwrap();
+        promote(&staging, &save_dir, &destinations, &files)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(save_dir.join("notes.txt")).unwrap(), b"a");
+        assert_eq!(
+            std::fs::read(pictures.join("photos/img.jpg")).unwrap(),
+            b"b"
+        );
+        assert!(!save_dir.join("photos").exists());
+        assert!(!staging.exists());
+    }
+}
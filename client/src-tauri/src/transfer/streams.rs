@@ -0,0 +1,142 @@
+// File streams — files sent side by side over a direct connection, each on
+// a QUIC stream of its own, so a file held up doesn't hold up the rest.
+//
+// The transfer's own stream carries `ParallelFiles` naming the files, then
+// nothing until they're done. Each file's stream opens with its index and
+// carries that file's chunks and `FileComplete`, the same messages it would
+// have had on the transfer's stream.
+
+use std::collections::HashSet;
+
+use quinn::{Connection, RecvStream, SendStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_util::task::AbortOnDropHandle;
+
+use crate::error::{AppError, AppResult};
+use crate::protocol::messages::{read_message, PeerMessage};
+
+/// Messages read off file streams that may wait for the receive loop.
+/// Further reads wait, and QUIC flow control holds the sender back.
+const INBOUND_BACKLOG: usize = 16;
+
+/// Open a stream for the file at `file_index`.
+pub(crate) async fn open(conn: &Connection, file_index: u16) -> AppResult<SendStream> {
+    let mut stream = conn
+        .open_uni()
+        .await
+        .map_err(|e| AppError::Network(format!("failed to open file stream: {e}")))?;
+    stream
+        .write_all(&file_index.to_be_bytes())
+        .await
+        .map_err(|e| AppError::Network(format!("failed to open file stream: {e}")))?;
+    Ok(stream)
+}
+
+/// The next file stream the peer opens, and the index of its file.
+async fn accept(conn: &Connection) -> AppResult<(u16, RecvStream)> {
+    let mut stream = conn
+        .accept_uni()
+        .await
+        .map_err(|e| AppError::Network(format!("failed to accept file stream: {e}")))?;
+    let mut index = [0u8; 2];
+    stream
+        .read_exact(&mut index)
+        .await
+        .map_err(|e| AppError::Network(format!("failed to read file stream header: {e}")))?;
+    Ok((u16::from_be_bytes(index), stream))
+}
+
+/// The messages of the files named in a `ParallelFiles`, merged from their
+/// streams in the order they arrive. Dropping it stops reading them all.
+pub(crate) struct Inbound {
+    messages: mpsc::Receiver<AppResult<PeerMessage>>,
+    /// Files whose `FileComplete` hasn't been handed out yet.
+    remaining: usize,
+    _acceptor: AbortOnDropHandle<()>,
+}
+
+impl Inbound {
+    /// Accept a stream for each of `file_indices` on `conn` and start
+    /// reading them.
+    pub(crate) fn accept(conn: Connection, file_indices: &[u16]) -> Self {
+        let (tx, messages) = mpsc::channel(INBOUND_BACKLOG);
+        let mut expected: HashSet<u16> = file_indices.iter().copied().collect();
+        let remaining = expected.len();
+        let acceptor = tokio::spawn(async move {
+            let mut readers = JoinSet::new();
+            while !expected.is_empty() {
+                let accepted = accept(&conn).await.and_then(|(file_index, stream)| {
+                    if expected.remove(&file_index) {
+                        Ok((file_index, stream))
+                    } else {
+                        Err(AppError::Transfer(format!(
+                            "unexpected stream for file {file_index}"
+                        )))
+                    }
+                });
+                match accepted {
+                    Ok((file_index, stream)) => {
+                        readers.spawn(read_file(file_index, stream, tx.clone()));
+                    }
+                    Err(e) => {
+                        tx.send(Err(e)).await.ok();
+                        return;
+                    }
+                }
+            }
+            while readers.join_next().await.is_some() {}
+        });
+        Self {
+            messages,
+            remaining,
+            _acceptor: AbortOnDropHandle::new(acceptor),
+        }
+    }
+
+    /// Whether every file's `FileComplete` has been handed out.
+    pub(crate) fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// The next message from any of the files. There's no stall timeout:
+    /// a paused sender sends nothing on the streams, and QUIC's idle
+    /// timeout notices one that's gone.
+    pub(crate) async fn recv(&mut self) -> AppResult<PeerMessage> {
+        let msg = self
+            .messages
+            .recv()
+            .await
+            .ok_or_else(|| AppError::Transfer("file streams ended early".into()))??;
+        if matches!(msg, PeerMessage::FileComplete { .. }) {
+            self.remaining -= 1;
+        }
+        Ok(msg)
+    }
+}
+
+/// Pass on the messages of the file at `file_index` up to its
+/// `FileComplete`, refusing any for another file.
+async fn read_file(
+    file_index: u16,
+    mut stream: RecvStream,
+    tx: mpsc::Sender<AppResult<PeerMessage>>,
+) {
+    loop {
+        let msg = read_message(&mut stream).await.and_then(|msg| match msg {
+            PeerMessage::FileChunk { file_index: of, .. }
+            | PeerMessage::FileComplete { file_index: of, .. }
+                if of == file_index =>
+            {
+                Ok(msg)
+            }
+            _ => Err(AppError::Transfer(format!(
+                "unexpected message on the stream for file {file_index}"
+            ))),
+        });
+        let last = !matches!(msg, Ok(PeerMessage::FileChunk { .. }));
+        if tx.send(msg).await.is_err() || last {
+            return;
+        }
+    }
+}
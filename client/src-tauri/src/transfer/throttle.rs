@@ -0,0 +1,38 @@
+// Upload throttling — a token bucket that paces the sender to a byte rate.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Paces writes to an average of `bytes_per_sec`, allowing bursts of up to
+/// one second's worth after an idle spell (e.g. a pause).
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    /// Bytes that may go out right now; negative when ahead of the rate.
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    /// A limiter for `bytes_per_sec`, which must be non-zero. Starts empty,
+    /// so the first second is paced like any other.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    /// Account for `bytes` just written, sleeping until that's within the rate.
+    pub async fn consume(&mut self, bytes: u64) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.bytes_per_sec;
+        self.tokens = (self.tokens + refill).min(self.bytes_per_sec) - bytes as f64;
+        self.last = now;
+        if self.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-self.tokens / self.bytes_per_sec)).await;
+        }
+    }
+}
@@ -0,0 +1,242 @@
+// Bandwidth usage — bytes moved per calendar month (UTC), with direct and
+// relayed transfers counted apart, kept across restarts. An optional
+// monthly quota stops new transfers once it's used up.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::error::{AppError, AppResult};
+
+/// Write the counters out after this many unsaved bytes (16 MiB), so a
+/// crash mid-transfer loses little of the count.
+pub const SAVE_EVERY: u64 = 16 * 1024 * 1024;
+
+/// Contents of the usage file, as `usage_stats` reports them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageStats {
+    /// The month being counted, as `YYYY-MM` (UTC).
+    pub period: String,
+    /// Bytes sent or received over direct QUIC connections this month.
+    pub direct_bytes: u64,
+    /// Bytes sent or received through the relay this month.
+    pub relay_bytes: u64,
+    /// Bytes allowed per month, direct and relayed together.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
+}
+
+impl UsageStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.direct_bytes + self.relay_bytes
+    }
+}
+
+#[derive(Debug)]
+struct Loaded {
+    stats: UsageStats,
+    unsaved: u64,
+}
+
+/// Persistent byte counters shared by every transfer, like the history log.
+/// Loaded on first use; counts roll over to zero when the month changes.
+#[derive(Debug, Clone)]
+pub struct UsageStore {
+    path: PathBuf,
+    state: Arc<Mutex<Option<Loaded>>>,
+}
+
+impl UsageStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            state: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// This month's usage and the quota.
+    pub async fn stats(&self) -> AppResult<UsageStats> {
+        let mut state = self.state.lock().await;
+        Ok(self.loaded(&mut state).await?.stats.clone())
+    }
+
+    /// Set or clear the monthly quota.
+    pub async fn set_quota(&self, quota: Option<u64>) -> AppResult<()> {
+        let mut state = self.state.lock().await;
+        let loaded = self.loaded(&mut state).await?;
+        loaded.stats.quota = quota;
+        let stats = loaded.stats.clone();
+        self.write(&stats).await?;
+        loaded.unsaved = 0;
+        Ok(())
+    }
+
+    /// Fails with `AppError::QuotaExceeded` once this month's usage has
+    /// reached the quota. A transfer already running is let finish.
+    pub async fn check_quota(&self) -> AppResult<()> {
+        let stats = self.stats().await?;
+        match stats.quota {
+            Some(quota) if stats.total_bytes() >= quota => Err(AppError::QuotaExceeded {
+                used: stats.total_bytes(),
+                quota,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Count `bytes` moved over a direct or relayed transport. Failing to
+    /// save is logged rather than interrupting the transfer.
+    pub async fn add(&self, relayed: bool, bytes: u64) {
+        let mut state = self.state.lock().await;
+        let loaded = match self.loaded(&mut state).await {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                warn!("usage: cannot load {}: {e}", self.path.display());
+                return;
+            }
+        };
+        if relayed {
+            loaded.stats.relay_bytes += bytes;
+        } else {
+            loaded.stats.direct_bytes += bytes;
+        }
+        loaded.unsaved += bytes;
+        if loaded.unsaved >= SAVE_EVERY {
+            let stats = loaded.stats.clone();
+            match self.write(&stats).await {
+                Ok(()) => loaded.unsaved = 0,
+                Err(e) => warn!("usage: failed to save: {e}"),
+            }
+        }
+    }
+
+    /// Write out anything counted since the last save.
+    pub async fn save(&self) -> AppResult<()> {
+        let mut state = self.state.lock().await;
+        let Some(loaded) = state.as_mut().filter(|l| l.unsaved > 0) else {
+            return Ok(());
+        };
+        let stats = loaded.stats.clone();
+        self.write(&stats).await?;
+        loaded.unsaved = 0;
+        Ok(())
+    }
+
+    /// The counters for the current month, read from disk the first time.
+    async fn loaded<'a>(&self, state: &'a mut Option<Loaded>) -> AppResult<&'a mut Loaded> {
+        if state.is_none() {
+            let stats = match tokio::fs::read(&self.path).await {
+                Ok(json) => serde_json::from_slice(&json).unwrap_or_else(|e| {
+                    warn!(
+                        "usage: starting over, unreadable {}: {e}",
+                        self.path.display()
+                    );
+                    UsageStats::default()
+                }),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => UsageStats::default(),
+                Err(e) => return Err(e.into()),
+            };
+            *state = Some(Loaded { stats, unsaved: 0 });
+        }
+        let loaded = state.as_mut().expect("loaded above");
+        let period = current_period();
+        if loaded.stats.period != period {
+            loaded.stats = UsageStats {
+                period,
+                quota: loaded.stats.quota,
+                ..UsageStats::default()
+            };
+        }
+        Ok(loaded)
+    }
+
+    async fn write(&self, stats: &UsageStats) -> AppResult<()> {
+        let json = serde_json::to_vec(stats)
+            .map_err(|e| AppError::Serialization(format!("usage: {e}")))?;
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write-then-rename so a crash mid-save leaves the previous version.
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, json).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+}
+
+fn current_period() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    period_at(now)
+}
+
+/// The `YYYY-MM` month (UTC) containing Unix time `secs`.
+fn period_at(secs: u64) -> String {
+    // Days to civil date, after Howard Hinnant's `civil_from_days`.
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_period_at() {
+        assert_eq!(period_at(0), "1970-01");
+        assert_eq!(period_at(951_782_400), "2000-02"); // 2000-02-29
+        assert_eq!(period_at(1_700_000_000), "2023-11");
+        assert_eq!(period_at(1_704_067_199), "2023-12"); // last second of 2023
+    }
+
+    #[tokio::test]
+    async fn test_new_month_starts_from_zero_and_keeps_quota() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("usage.json");
+        let old = UsageStats {
+            period: "2000-01".into(),
+            direct_bytes: 500,
+            relay_bytes: 700,
+            quota: Some(1000),
+        };
+        std::fs::write(&path, serde_json::to_vec(&old).unwrap()).unwrap();
+
+        let store = UsageStore::new(&path);
+        let stats = store.stats().await.unwrap();
+        assert_eq!(stats.period, current_period());
+        assert_eq!((stats.direct_bytes, stats.relay_bytes), (0, 0));
+        assert_eq!(stats.quota, Some(1000));
+        store.check_quota().await.unwrap();
+
+        store.add(true, 1000).await;
+        store.save().await.unwrap();
+        let reloaded = UsageStore::new(&path).stats().await.unwrap();
+        assert_eq!(reloaded.relay_bytes, 1000);
+        assert!(matches!(
+            store.check_quota().await,
+            Err(AppError::QuotaExceeded {
+                used: 1000,
+                quota: 1000
+            })
+        ));
+    }
+}
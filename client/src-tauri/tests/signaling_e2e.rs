@@ -7,17 +7,48 @@
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::process::{Child, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
+use relay_lib::crypto::aes_gcm::ChunkDecryptor;
+use relay_lib::crypto::checksum::{ChallengeMac, ChecksumAlgorithm};
+use relay_lib::crypto::keyschedule::{self, KeySchedule};
 use relay_lib::crypto::spake::KeyExchange;
-use relay_lib::network::quic::QuicEndpoint;
+use relay_lib::error::{AppError, AppResult};
+use relay_lib::network::connect::{self, ConnectionMode};
+use relay_lib::network::quic::{
+    AddressFamily, CertStore, CongestionControl, NetworkOptions, QuicEndpoint,
+};
 use relay_lib::network::relay::RelayStream;
-use relay_lib::network::signaling::SignalingClient;
+use relay_lib::network::signaling::{ReconnectPolicy, SignalingClient, DEFAULT_PEER_TIMEOUT};
+use relay_lib::network::tls::SignalingTls;
 use relay_lib::network::transport::Transport;
-use relay_lib::protocol::messages::FileInfo;
+use relay_lib::protocol::chunker::FileSource;
+use relay_lib::protocol::messages::{CancelReason, FileInfo, PeerMessage, PROTOCOL_VERSION};
+use relay_lib::protocol::pieces::{PieceHashAlgorithm, PieceHashConfig};
+use relay_lib::protocol::reassembler::FlushPolicy;
+use relay_lib::protocol::sink::{ChunkSink, SinkFactory, VerifyOnly};
 use relay_lib::transfer::code::TransferCode;
+use relay_lib::transfer::collision::CollisionPolicy;
+use relay_lib::transfer::destinations::Destinations;
+use relay_lib::transfer::handshake;
+use relay_lib::transfer::history::HistoryLog;
+use relay_lib::transfer::opener::{OnCompleteAction, Opener};
+use relay_lib::transfer::partials::PartialFiles;
+use relay_lib::transfer::portable::{self, ResumeStateDir, ResumeTarget};
 use relay_lib::transfer::progress::ProgressEvent;
+use relay_lib::transfer::receiver::ReceiveOptions;
+use relay_lib::transfer::resume::{self, ResumeState};
+use relay_lib::transfer::selection::Selection;
+use relay_lib::transfer::sender::SendOptions;
+use relay_lib::transfer::session::{PauseReason, TransferRole, TransferSession};
+use relay_lib::transfer::space::{FsSpace, SpaceProbe, SpaceResource};
+use relay_lib::transfer::staging::StagingInspector;
+use relay_lib::transfer::usage::UsageStore;
+use sha2::{Digest, Sha256};
 
+use futures_util::future::BoxFuture;
 use tokio::sync::{mpsc, oneshot};
 use tokio_util::sync::CancellationToken;
 
@@ -71,6 +102,12 @@ fn find_server_binary() -> Option<PathBuf> {
     None
 }
 
+/// Port for this test process's signaling server; one above it is also used
+/// when a test needs a second server.
+fn base_port() -> u16 {
+    10000 + (std::process::id() % 50000) as u16
+}
+
 /// Start the Go signaling server on a random port.
 struct TestServer {
     child: Child,
@@ -79,7 +116,11 @@ struct TestServer {
 
 impl TestServer {
     fn start(binary: &PathBuf) -> Self {
-        let port = 10000 + (std::process::id() % 50000) as u16;
+        Self::start_on(binary, base_port(), &[])
+    }
+
+    /// Start on a specific port with extra command-line flags.
+    fn start_on(binary: &PathBuf, port: u16, extra_args: &[&str]) -> Self {
         let addr = format!("127.0.0.1:{port}");
 
         let child = Command::new(binary)
@@ -87,6 +128,7 @@ impl TestServer {
             .arg(&addr)
             .arg("-session-ttl")
             .arg("30s")
+            .args(extra_args)
             .stdout(std::process::Stdio::piped())
             .stderr(std::process::Stdio::piped())
             .spawn()
@@ -131,12 +173,13 @@ async fn test_signaling_spake2_exchange() {
     let sender_task = tokio::spawn(async move {
         let mut client = SignalingClient::connect(&ws_url, &code_s).await.unwrap();
         client.register("sender", None).await.unwrap();
-        let _peer = client.wait_for_peer().await.unwrap();
+        let _peer = client.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
 
         let kx = KeyExchange::new(&code_s);
         let outbound = kx.outbound_message().to_vec();
         let peer_msg = client.exchange_spake2(&outbound).await.unwrap();
         let key = kx.finish(&peer_msg).unwrap();
+        client.confirm_key(&key).await.unwrap();
         client.disconnect().await.unwrap();
         key
     });
@@ -148,12 +191,13 @@ async fn test_signaling_spake2_exchange() {
     let receiver_task = tokio::spawn(async move {
         let mut client = SignalingClient::connect(&ws_url, &code_r).await.unwrap();
         client.register("receiver", None).await.unwrap();
-        let _peer = client.wait_for_peer().await.unwrap();
+        let _peer = client.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
 
         let kx = KeyExchange::new(&code_r);
         let outbound = kx.outbound_message().to_vec();
         let peer_msg = client.exchange_spake2(&outbound).await.unwrap();
         let key = kx.finish(&peer_msg).unwrap();
+        client.confirm_key(&key).await.unwrap();
         client.disconnect().await.unwrap();
         key
     });
@@ -162,6 +206,184 @@ async fn test_signaling_spake2_exchange() {
     assert_eq!(sender_key.unwrap(), receiver_key.unwrap());
 }
 
+/// Test: peers resuming with an imported key swap nonces over signaling and
+/// agree on keys that differ from the original session's and each other
+/// resume's.
+#[tokio::test]
+async fn test_resumed_sessions_derive_fresh_keys() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+
+    let server = TestServer::start(&binary);
+    let server = &server;
+    let imported = [0x42u8; 32];
+    let resume = move || async move {
+        let code = TransferCode::generate().to_code_string();
+        let join = |role: &'static str| {
+            let ws_url = server.ws_url().to_string();
+            let code = code.clone();
+            tokio::spawn(async move {
+                let mut client = SignalingClient::connect(&ws_url, &code).await.unwrap();
+                client.register(role, None).await.unwrap();
+                client.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+                let nonce = keyschedule::resume_nonce().unwrap();
+                let peer_nonce = client.exchange_resume_nonce(&nonce).await.unwrap();
+                client.disconnect().await.unwrap();
+                (nonce, peer_nonce)
+            })
+        };
+        let sender_task = join("sender");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (sender, receiver) = tokio::join!(sender_task, join("receiver"));
+        let ((sender_nonce, heard_from_receiver), (receiver_nonce, heard_from_sender)) =
+            (sender.unwrap(), receiver.unwrap());
+        assert_eq!(heard_from_receiver, receiver_nonce);
+        assert_eq!(heard_from_sender, sender_nonce);
+        KeySchedule::resumed(&imported, &sender_nonce, &receiver_nonce).unwrap()
+    };
+
+    let first = resume().await;
+    let second = resume().await;
+    assert_ne!(first.sender_to_receiver, second.sender_to_receiver);
+    assert_ne!(
+        first.sender_to_receiver,
+        KeySchedule::derive(&imported).unwrap().sender_to_receiver
+    );
+}
+
+/// Test: with the first signaling server down, both peers fall back to the
+/// next one in their list and find each other there.
+#[tokio::test]
+async fn test_signaling_falls_back_to_next_server() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+
+    let server = TestServer::start(&binary);
+    let code = TransferCode::generate().to_code_string();
+    // Nothing listens on port 1.
+    let servers = vec!["ws://127.0.0.1:1".to_string(), server.ws_url().to_string()];
+
+    let peer = |role: &'static str| {
+        let servers = servers.clone();
+        let code = code.clone();
+        tokio::spawn(async move {
+            let mut client = SignalingClient::connect_any(&servers, &code, SignalingTls::default())
+                .await
+                .expect("no server reachable");
+            client.register(role, None).await.unwrap();
+            client.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+            client.disconnect().await.unwrap();
+        })
+    };
+    let (sender, receiver) = tokio::join!(peer("sender"), peer("receiver"));
+    sender.unwrap();
+    receiver.unwrap();
+
+    let unreachable =
+        SignalingClient::connect_any(&servers[..1], &code, SignalingTls::default()).await;
+    assert!(
+        matches!(&unreachable, Err(AppError::WebSocket(e)) if e.contains("127.0.0.1:1")),
+        "{:?}",
+        unreachable.err()
+    );
+}
+
+/// Test: a second sender registering under a code another sender holds is
+/// told the code is in use, and the first keeps its registration.
+#[tokio::test]
+async fn test_occupied_code_reports_code_in_use() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+
+    let server = TestServer::start(&binary);
+    let code = TransferCode::generate().to_code_string();
+
+    let mut first = SignalingClient::connect(server.ws_url(), &code)
+        .await
+        .unwrap();
+    first.register("sender", None).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut second = SignalingClient::connect(server.ws_url(), &code)
+        .await
+        .unwrap();
+    second.register("sender", None).await.unwrap();
+    let taken = second.wait_for_peer(Duration::from_secs(5)).await;
+    assert!(
+        matches!(taken, Err(AppError::CodeInUse)),
+        "{:?}",
+        taken.err()
+    );
+
+    let mut receiver = SignalingClient::connect(server.ws_url(), &code)
+        .await
+        .unwrap();
+    receiver.register("receiver", None).await.unwrap();
+    first
+        .wait_for_peer(Duration::from_secs(5))
+        .await
+        .expect("first sender still registered");
+}
+
+/// Test: peers that entered different codes find out right after SPAKE2,
+/// with a clear error, instead of when the first chunk won't decrypt.
+#[tokio::test]
+async fn test_mismatched_codes_fail_key_confirmation() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+
+    let server = TestServer::start(&binary);
+    let code = TransferCode::generate().to_code_string();
+    let confirm = |role: &'static str, password: String| {
+        let ws_url = server.ws_url().to_string();
+        let code = code.clone();
+        async move {
+            let mut client = SignalingClient::connect(&ws_url, &code).await.unwrap();
+            client.register(role, None).await.unwrap();
+            client.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+            let kx = KeyExchange::new(&password);
+            let peer_msg = client.exchange_spake2(kx.outbound_message()).await.unwrap();
+            let key = kx.finish(&peer_msg).unwrap();
+            client.confirm_key(&key).await
+        }
+    };
+
+    let (sent, received) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(
+            confirm("sender", code.clone()),
+            confirm("receiver", format!("{code}-typo")),
+        )
+    })
+    .await
+    .expect("key confirmation hung");
+    for result in [sent, received] {
+        assert!(
+            matches!(&result, Err(AppError::CodeMismatch)),
+            "unexpected result: {result:?}"
+        );
+    }
+}
+
 /// Test: Basic QUIC connectivity between two endpoints.
 #[tokio::test]
 async fn test_quic_basic_connectivity() {
@@ -170,11 +392,17 @@ async fn test_quic_basic_connectivity() {
     let connect_addr: SocketAddr = format!("127.0.0.1:{}", server_addr.port())
         .parse()
         .unwrap();
+    let client_quic = QuicEndpoint::new(0).await.unwrap();
+    let (server_fp, client_fp) = (
+        server_quic.cert_fingerprint(),
+        client_quic.cert_fingerprint(),
+    );
 
     let server_handle = tokio::spawn(async move {
-        let conn = server_quic.accept_any().await.unwrap();
-        let (send, recv) = conn.open_bi().await.unwrap();
-        let mut transport = Transport::Direct { send, recv };
+        let conn = server_quic.accept_any(&client_fp).await.unwrap();
+        let mut transport = Transport::direct(&conn, TransferRole::Sender)
+            .await
+            .unwrap();
 
         use relay_lib::protocol::messages::{FileInfo, PeerMessage};
         transport
@@ -183,7 +411,17 @@ async fn test_quic_basic_connectivity() {
                     name: "test.txt".into(),
                     size: 100,
                     relative_path: None,
+                    duplicates: Vec::new(),
+                    mime_hint: None,
+                    streaming: false,
+                    modified: None,
+                    mode: None,
+                    checksum: None,
+                    link_target: None,
                 }],
+                piece_hashes: None,
+                challenge: None,
+                features: Default::default(),
             })
             .await
             .unwrap();
@@ -195,10 +433,10 @@ async fn test_quic_basic_connectivity() {
 
     tokio::time::sleep(Duration::from_millis(50)).await;
 
-    let client_quic = QuicEndpoint::new(0).await.unwrap();
-    let conn = client_quic.connect(connect_addr).await.unwrap();
-    let (send, recv) = conn.accept_bi().await.unwrap();
-    let mut transport = Transport::Direct { send, recv };
+    let conn = client_quic.connect(connect_addr, &server_fp).await.unwrap();
+    let mut transport = Transport::direct(&conn, TransferRole::Receiver)
+        .await
+        .unwrap();
 
     use relay_lib::protocol::messages::PeerMessage;
     let offer = transport.recv_peer_message().await.unwrap();
@@ -252,14 +490,14 @@ async fn test_full_file_transfer() {
             .register("sender", Some(register_addr))
             .await
             .unwrap();
-        let _peer = signaling.wait_for_peer().await.unwrap();
+        let _peer = signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
 
         let kx = KeyExchange::new(&code_s);
         let outbound = kx.outbound_message().to_vec();
         let peer_msg = signaling.exchange_spake2(&outbound).await.unwrap();
         let key = kx.finish(&peer_msg).unwrap();
 
-        let _peer_fp = signaling
+        let peer_fp = signaling
             .exchange_cert_fingerprint(&quic.cert_fingerprint(), &key)
             .await
             .unwrap();
@@ -268,15 +506,26 @@ async fn test_full_file_transfer() {
         tokio::time::sleep(Duration::from_millis(200)).await;
 
         // Accept QUIC connection and create transport
-        let conn = quic.accept_any().await.unwrap();
+        let conn = quic.accept_any(&peer_fp).await.unwrap();
         let (send, recv) = conn.open_bi().await.unwrap();
-        let mut transport = Transport::Direct { send, recv };
+        let mut transport = Transport::Direct {
+            send,
+            recv,
+            conn: None,
+        };
 
         let file_meta = tokio::fs::metadata(&send_file_clone).await.unwrap();
         let file_infos = vec![FileInfo {
             name: "test-file.txt".into(),
             size: file_meta.len(),
             relative_path: None,
+            duplicates: Vec::new(),
+            mime_hint: None,
+            streaming: false,
+            modified: None,
+            mode: None,
+            checksum: None,
+            link_target: None,
         }];
 
         let (progress_tx, _) = mpsc::unbounded_channel::<ProgressEvent>();
@@ -289,6 +538,7 @@ async fn test_full_file_transfer() {
             key,
             progress_tx,
             cancel,
+            SendOptions::default(),
         )
         .await
         .unwrap();
@@ -303,7 +553,7 @@ async fn test_full_file_transfer() {
     let receiver_handle = tokio::spawn(async move {
         let mut signaling = SignalingClient::connect(&ws_url_r, &code_r).await.unwrap();
         signaling.register("receiver", None).await.unwrap();
-        let peer_info = signaling.wait_for_peer().await.unwrap();
+        let peer_info = signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
 
         let kx = KeyExchange::new(&code_r);
         let outbound = kx.outbound_message().to_vec();
@@ -311,7 +561,7 @@ async fn test_full_file_transfer() {
         let key = kx.finish(&peer_msg).unwrap();
 
         let quic = QuicEndpoint::new(0).await.unwrap();
-        let _peer_fp = signaling
+        let peer_fp = signaling
             .exchange_cert_fingerprint(&quic.cert_fingerprint(), &key)
             .await
             .unwrap();
@@ -322,9 +572,13 @@ async fn test_full_file_transfer() {
                 .parse()
                 .unwrap();
 
-        let conn = quic.connect(sender_addr).await.unwrap();
+        let conn = quic.connect(sender_addr, &peer_fp).await.unwrap();
         let (send, recv) = conn.accept_bi().await.unwrap();
-        let mut transport = Transport::Direct { send, recv };
+        let mut transport = Transport::Direct {
+            send,
+            recv,
+            conn: None,
+        };
 
         let (progress_tx, _) = mpsc::unbounded_channel::<ProgressEvent>();
         let (accept_tx, accept_rx) = oneshot::channel::<bool>();
@@ -342,6 +596,7 @@ async fn test_full_file_transfer() {
             progress_tx,
             accept_rx,
             cancel,
+            ReceiveOptions::default(),
         )
         .await
         .unwrap();
@@ -392,7 +647,7 @@ async fn test_relay_fallback() {
     let sender_handle = tokio::spawn(async move {
         let mut signaling = SignalingClient::connect(&ws_url_s, &code_s).await.unwrap();
         signaling.register("sender", None).await.unwrap();
-        let _peer = signaling.wait_for_peer().await.unwrap();
+        let _peer = signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
 
         let kx = KeyExchange::new(&code_s);
         let outbound = kx.outbound_message().to_vec();
@@ -414,6 +669,13 @@ async fn test_relay_fallback() {
             name: "relay-test.txt".into(),
             size: file_meta.len(),
             relative_path: None,
+            duplicates: Vec::new(),
+            mime_hint: None,
+            streaming: false,
+            modified: None,
+            mode: None,
+            checksum: None,
+            link_target: None,
         }];
 
         let (progress_tx, _) = mpsc::unbounded_channel::<ProgressEvent>();
@@ -426,6 +688,7 @@ async fn test_relay_fallback() {
             key,
             progress_tx,
             cancel,
+            SendOptions::default(),
         )
         .await
         .unwrap();
@@ -440,7 +703,7 @@ async fn test_relay_fallback() {
     let receiver_handle = tokio::spawn(async move {
         let mut signaling = SignalingClient::connect(&ws_url_r, &code_r).await.unwrap();
         signaling.register("receiver", None).await.unwrap();
-        let _peer = signaling.wait_for_peer().await.unwrap();
+        let _peer = signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
 
         let kx = KeyExchange::new(&code_r);
         let outbound = kx.outbound_message().to_vec();
@@ -470,6 +733,7 @@ async fn test_relay_fallback() {
             progress_tx,
             accept_rx,
             cancel,
+            ReceiveOptions::default(),
         )
         .await
         .unwrap();
@@ -520,8 +784,12 @@ async fn test_folder_transfer() {
 
     // Expand the directory into files + infos
     let (files, file_infos) = {
-        use relay_lib::commands::send::expand_directory;
-        let expanded = expand_directory(&root, "my-project").await.unwrap();
+        use relay_lib::commands::send::{
+            expand_directory, HiddenFilter, SymlinkPolicy, DEFAULT_MAX_DEPTH,
+        };
+        let (expanded, _, _, _) = expand_directory(&root, "my-project", DEFAULT_MAX_DEPTH, SymlinkPolicy::Skip, &HiddenFilter::default())
+            .await
+            .unwrap();
 
         let mut paths = Vec::new();
         let mut infos = Vec::new();
@@ -532,6 +800,13 @@ async fn test_folder_transfer() {
                 name,
                 size: meta.len(),
                 relative_path: Some(rel),
+                duplicates: Vec::new(),
+                mime_hint: None,
+                streaming: false,
+                modified: None,
+                mode: None,
+                checksum: None,
+                link_target: None,
             });
             paths.push(path);
         }
@@ -556,14 +831,14 @@ async fn test_folder_transfer() {
             .register("sender", Some(register_addr))
             .await
             .unwrap();
-        let _peer = signaling.wait_for_peer().await.unwrap();
+        let _peer = signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
 
         let kx = KeyExchange::new(&code_s);
         let outbound = kx.outbound_message().to_vec();
         let peer_msg = signaling.exchange_spake2(&outbound).await.unwrap();
         let key = kx.finish(&peer_msg).unwrap();
 
-        let _peer_fp = signaling
+        let peer_fp = signaling
             .exchange_cert_fingerprint(&quic.cert_fingerprint(), &key)
             .await
             .unwrap();
@@ -571,9 +846,13 @@ async fn test_folder_transfer() {
 
         tokio::time::sleep(Duration::from_millis(200)).await;
 
-        let conn = quic.accept_any().await.unwrap();
+        let conn = quic.accept_any(&peer_fp).await.unwrap();
         let (send, recv) = conn.open_bi().await.unwrap();
-        let mut transport = Transport::Direct { send, recv };
+        let mut transport = Transport::Direct {
+            send,
+            recv,
+            conn: None,
+        };
 
         let (progress_tx, _) = mpsc::unbounded_channel::<ProgressEvent>();
         let cancel = CancellationToken::new();
@@ -585,6 +864,7 @@ async fn test_folder_transfer() {
             key,
             progress_tx,
             cancel,
+            SendOptions::default(),
         )
         .await
         .unwrap();
@@ -599,7 +879,7 @@ async fn test_folder_transfer() {
     let receiver_handle = tokio::spawn(async move {
         let mut signaling = SignalingClient::connect(&ws_url_r, &code_r).await.unwrap();
         signaling.register("receiver", None).await.unwrap();
-        let peer_info = signaling.wait_for_peer().await.unwrap();
+        let peer_info = signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
 
         let kx = KeyExchange::new(&code_r);
         let outbound = kx.outbound_message().to_vec();
@@ -607,7 +887,7 @@ async fn test_folder_transfer() {
         let key = kx.finish(&peer_msg).unwrap();
 
         let quic = QuicEndpoint::new(0).await.unwrap();
-        let _peer_fp = signaling
+        let peer_fp = signaling
             .exchange_cert_fingerprint(&quic.cert_fingerprint(), &key)
             .await
             .unwrap();
@@ -618,9 +898,13 @@ async fn test_folder_transfer() {
                 .parse()
                 .unwrap();
 
-        let conn = quic.connect(sender_addr).await.unwrap();
+        let conn = quic.connect(sender_addr, &peer_fp).await.unwrap();
         let (send, recv) = conn.accept_bi().await.unwrap();
-        let mut transport = Transport::Direct { send, recv };
+        let mut transport = Transport::Direct {
+            send,
+            recv,
+            conn: None,
+        };
 
         let (progress_tx, _) = mpsc::unbounded_channel::<ProgressEvent>();
         let (accept_tx, accept_rx) = oneshot::channel::<bool>();
@@ -638,6 +922,7 @@ async fn test_folder_transfer() {
             progress_tx,
             accept_rx,
             cancel,
+            ReceiveOptions::default(),
         )
         .await
         .unwrap();
@@ -664,3 +949,5247 @@ async fn test_folder_transfer() {
     assert_eq!(std::fs::read_to_string(&main_rs).unwrap(), "fn main() {}\n");
     assert_eq!(std::fs::read_to_string(&guide).unwrap(), "# Guide\nHello\n");
 }
+
+/// Test: the public address the sender advertised has gone stale (its NAT
+/// mapping changed after STUN) by the time the receiver dials; the receiver
+/// must still connect via the sender's other candidates, without the sender
+/// re-registering.
+#[tokio::test]
+async fn test_stale_public_candidate_still_connects() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+
+    let server = TestServer::start(&binary);
+    let code = TransferCode::generate().to_code_string();
+    let ws_url = server.ws_url().to_string();
+
+    // Packets to this socket go unanswered, like a lapsed NAT mapping.
+    let black_hole = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let stale = black_hole.local_addr().unwrap();
+
+    let code_s = code.clone();
+    let ws_url_s = ws_url.clone();
+    let sender_handle = tokio::spawn(async move {
+        let quic = QuicEndpoint::new(0).await.unwrap();
+        let port = quic.local_addr().unwrap().port();
+        let register_addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+
+        let mut signaling = SignalingClient::connect(&ws_url_s, &code_s)
+            .await
+            .unwrap()
+            .with_public_addr(Some(stale));
+        signaling
+            .register("sender", Some(register_addr))
+            .await
+            .unwrap();
+        let _peer = signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+
+        let kx = KeyExchange::new(&code_s);
+        let outbound = kx.outbound_message().to_vec();
+        let peer_msg = signaling.exchange_spake2(&outbound).await.unwrap();
+        let key = kx.finish(&peer_msg).unwrap();
+        let peer_fp = signaling
+            .exchange_cert_fingerprint(&quic.cert_fingerprint(), &key)
+            .await
+            .unwrap();
+        signaling.disconnect().await.unwrap();
+
+        let conn = quic.accept_any(&peer_fp).await.unwrap();
+        let (send, recv) = conn.open_bi().await.unwrap();
+        let mut transport = Transport::Direct {
+            send,
+            recv,
+            conn: None,
+        };
+        transport
+            .send_peer_message(&relay_lib::protocol::messages::PeerMessage::Ping)
+            .await
+            .unwrap();
+        let reply = transport.recv_peer_message().await.unwrap();
+        assert!(matches!(
+            reply,
+            relay_lib::protocol::messages::PeerMessage::Pong
+        ));
+        register_addr
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let code_r = code.clone();
+    let receiver_handle = tokio::spawn(async move {
+        let mut signaling = SignalingClient::connect(&ws_url, &code_r).await.unwrap();
+        signaling.register("receiver", None).await.unwrap();
+        let peer_info = signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+        let candidates = peer_info.candidate_addrs();
+        assert!(
+            candidates.contains(&stale),
+            "stale public address should be advertised: {candidates:?}"
+        );
+
+        let kx = KeyExchange::new(&code_r);
+        let outbound = kx.outbound_message().to_vec();
+        let peer_msg = signaling.exchange_spake2(&outbound).await.unwrap();
+        let key = kx.finish(&peer_msg).unwrap();
+
+        let quic = QuicEndpoint::new(0).await.unwrap();
+        let peer_fp = signaling
+            .exchange_cert_fingerprint(&quic.cert_fingerprint(), &key)
+            .await
+            .unwrap();
+        signaling.disconnect().await.unwrap();
+
+        let conn = tokio::time::timeout(
+            Duration::from_secs(5),
+            quic.connect_any(&candidates, &peer_fp),
+        )
+        .await
+        .expect("connecting past the stale candidate timed out")
+        .unwrap();
+        let (send, recv) = conn.accept_bi().await.unwrap();
+        let mut transport = Transport::Direct {
+            send,
+            recv,
+            conn: None,
+        };
+        let ping = transport.recv_peer_message().await.unwrap();
+        assert!(matches!(
+            ping,
+            relay_lib::protocol::messages::PeerMessage::Ping
+        ));
+        transport
+            .send_peer_message(&relay_lib::protocol::messages::PeerMessage::Pong)
+            .await
+            .unwrap();
+        transport.finish_send().await.unwrap();
+        // Keep the endpoint alive until the sender has read the reply.
+        let _ = tokio::time::timeout(Duration::from_secs(5), conn.closed()).await;
+        conn.remote_address()
+    });
+
+    let (sender_result, receiver_result) = tokio::join!(sender_handle, receiver_handle);
+    assert_eq!(sender_result.unwrap(), receiver_result.unwrap());
+    drop(black_hole);
+}
+
+/// Test: The receiver races every candidate the sender advertised; a dead
+/// first candidate doesn't stop it connecting directly on the second.
+#[tokio::test]
+async fn test_candidates_raced_until_one_connects() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+
+    let server = TestServer::start(&binary);
+    let code = TransferCode::generate().to_code_string();
+    let ws_url = server.ws_url().to_string();
+
+    let code_s = code.clone();
+    let ws_url_s = ws_url.clone();
+    let sender_handle = tokio::spawn(async move {
+        let quic = QuicEndpoint::new(0).await.unwrap();
+        let port = quic.local_addr().unwrap().port();
+        let register_addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+
+        let mut signaling = SignalingClient::connect(&ws_url_s, &code_s).await.unwrap();
+        signaling
+            .register("sender", Some(register_addr))
+            .await
+            .unwrap();
+        let _peer = signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+
+        let kx = KeyExchange::new(&code_s);
+        let outbound = kx.outbound_message().to_vec();
+        let peer_msg = signaling.exchange_spake2(&outbound).await.unwrap();
+        let key = kx.finish(&peer_msg).unwrap();
+        let peer_fp = signaling
+            .exchange_cert_fingerprint(&quic.cert_fingerprint(), &key)
+            .await
+            .unwrap();
+        signaling.disconnect().await.unwrap();
+
+        let conn = quic.accept_any(&peer_fp).await.unwrap();
+        let (send, recv) = conn.open_bi().await.unwrap();
+        let mut transport = Transport::Direct {
+            send,
+            recv,
+            conn: None,
+        };
+        transport
+            .send_peer_message(&relay_lib::protocol::messages::PeerMessage::Ping)
+            .await
+            .unwrap();
+        let reply = transport.recv_peer_message().await.unwrap();
+        assert!(matches!(
+            reply,
+            relay_lib::protocol::messages::PeerMessage::Pong
+        ));
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let receiver_handle = tokio::spawn(async move {
+        let mut signaling = SignalingClient::connect(&ws_url, &code).await.unwrap();
+        signaling.register("receiver", None).await.unwrap();
+        let peer_info = signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+        let reachable: SocketAddr = format!("127.0.0.1:{}", peer_info.local_port)
+            .parse()
+            .unwrap();
+        assert_eq!(peer_info.candidates, [reachable]);
+
+        let kx = KeyExchange::new(&code);
+        let outbound = kx.outbound_message().to_vec();
+        let peer_msg = signaling.exchange_spake2(&outbound).await.unwrap();
+        let key = kx.finish(&peer_msg).unwrap();
+
+        let quic = QuicEndpoint::new(0).await.unwrap();
+        let peer_fp = signaling
+            .exchange_cert_fingerprint(&quic.cert_fingerprint(), &key)
+            .await
+            .unwrap();
+        signaling.disconnect().await.unwrap();
+
+        // Packets to this socket go unanswered, like an unroutable interface.
+        let black_hole = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let candidates = [black_hole.local_addr().unwrap(), reachable];
+        let conn = tokio::time::timeout(
+            Duration::from_secs(5),
+            quic.connect_any(&candidates, &peer_fp),
+        )
+        .await
+        .expect("racing candidates timed out")
+        .unwrap();
+        assert_eq!(conn.remote_address(), reachable);
+
+        let (send, recv) = conn.accept_bi().await.unwrap();
+        let mut transport = Transport::Direct {
+            send,
+            recv,
+            conn: None,
+        };
+        let ping = transport.recv_peer_message().await.unwrap();
+        assert!(matches!(
+            ping,
+            relay_lib::protocol::messages::PeerMessage::Ping
+        ));
+        transport
+            .send_peer_message(&relay_lib::protocol::messages::PeerMessage::Pong)
+            .await
+            .unwrap();
+        transport.finish_send().await.unwrap();
+        let _ = tokio::time::timeout(Duration::from_secs(5), conn.closed()).await;
+    });
+
+    let (sender_result, receiver_result) = tokio::join!(sender_handle, receiver_handle);
+    sender_result.unwrap();
+    receiver_result.unwrap();
+}
+
+/// Pair a sender and receiver through signaling and SPAKE2, then let each
+/// settle its transport in its own mode — with a QUIC endpoint only if the
+/// mode tries direct, as the commands do. Each side returns whether its
+/// transport is relayed, after a Ping/Pong round trip over it.
+async fn connect_in_modes(
+    server: &TestServer,
+    sender_mode: ConnectionMode,
+    receiver_mode: ConnectionMode,
+) -> (AppResult<bool>, AppResult<bool>) {
+    let (sender, receiver) = connect_reporting(server, sender_mode, receiver_mode).await;
+    (sender.0, receiver.0)
+}
+
+/// [`connect_in_modes`], also returning each side's progress events.
+async fn connect_reporting(
+    server: &TestServer,
+    sender_mode: ConnectionMode,
+    receiver_mode: ConnectionMode,
+) -> (
+    (AppResult<bool>, Vec<ProgressEvent>),
+    (AppResult<bool>, Vec<ProgressEvent>),
+) {
+    connect_advertising(server, sender_mode, receiver_mode, false).await
+}
+
+/// [`connect_reporting`], with the receiver registering its QUIC address
+/// too if `receiver_advertises`, as the receive command does.
+async fn connect_advertising(
+    server: &TestServer,
+    sender_mode: ConnectionMode,
+    receiver_mode: ConnectionMode,
+    receiver_advertises: bool,
+) -> (
+    (AppResult<bool>, Vec<ProgressEvent>),
+    (AppResult<bool>, Vec<ProgressEvent>),
+) {
+    let code = TransferCode::generate();
+    let code_str = code.to_code_string();
+
+    fn drain(mut rx: mpsc::UnboundedReceiver<ProgressEvent>) -> Vec<ProgressEvent> {
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+    async fn endpoint(mode: ConnectionMode) -> Option<QuicEndpoint> {
+        if mode.tries_direct() {
+            Some(QuicEndpoint::new(0).await.unwrap())
+        } else {
+            None
+        }
+    }
+    async fn pair(signaling: &mut SignalingClient, code: &str) -> [u8; 32] {
+        let kx = KeyExchange::new(code);
+        let outbound = kx.outbound_message().to_vec();
+        let peer_msg = signaling.exchange_spake2(&outbound).await.unwrap();
+        kx.finish(&peer_msg).unwrap()
+    }
+
+    let ws_url = server.ws_url().to_string();
+    let (session_code, code_s) = (code.clone(), code_str.clone());
+    let sender = tokio::spawn(async move {
+        let session = TransferSession::new(TransferRole::Sender, session_code);
+        let (progress_tx, progress_rx) = mpsc::unbounded_channel();
+        let quic = endpoint(sender_mode).await;
+        let listen_addr = quic.as_ref().map(|q| q.local_addr().unwrap());
+
+        let mut signaling = SignalingClient::connect(&ws_url, &code_s).await.unwrap();
+        signaling.register("sender", listen_addr).await.unwrap();
+        signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+        let key = pair(&mut signaling, &code_s).await;
+
+        let connected = async {
+            let mut transport = connect::sender_transport(
+                signaling,
+                quic.as_ref(),
+                &key,
+                sender_mode,
+                &session,
+                &progress_tx,
+            )
+            .await?;
+            transport.send_peer_message(&PeerMessage::Ping).await?;
+            assert!(matches!(
+                transport.recv_peer_message().await?,
+                PeerMessage::Pong
+            ));
+            Ok(transport.is_relayed())
+        }
+        .await;
+        (connected, drain(progress_rx))
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let ws_url = server.ws_url().to_string();
+    let receiver = tokio::spawn(async move {
+        let session = TransferSession::new(TransferRole::Receiver, code);
+        let (progress_tx, progress_rx) = mpsc::unbounded_channel();
+
+        let quic = endpoint(receiver_mode).await;
+        let listen_addr = quic
+            .as_ref()
+            .filter(|_| receiver_advertises)
+            .map(|q| q.local_addr().unwrap());
+
+        let mut signaling = SignalingClient::connect(&ws_url, &code_str).await.unwrap();
+        signaling.register("receiver", listen_addr).await.unwrap();
+        let peer_info = signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+        let key = pair(&mut signaling, &code_str).await;
+
+        let connected = async {
+            let mut transport = connect::receiver_transport(
+                signaling,
+                quic.as_ref(),
+                peer_info,
+                &key,
+                receiver_mode,
+                &session,
+                &progress_tx,
+            )
+            .await?;
+            assert!(matches!(
+                transport.recv_peer_message().await?,
+                PeerMessage::Ping
+            ));
+            transport.send_peer_message(&PeerMessage::Pong).await?;
+            // Keep the endpoint up until the sender is done with it.
+            let _ =
+                tokio::time::timeout(Duration::from_secs(5), transport.recv_peer_message()).await;
+            Ok(transport.is_relayed())
+        }
+        .await;
+        (connected, drain(progress_rx))
+    });
+
+    let (sender, receiver) = tokio::join!(sender, receiver);
+    (sender.unwrap(), receiver.unwrap())
+}
+
+/// Test: the connecting steps are reported as they happen — a direct
+/// attempt that succeeds, and a fallback to the relay for a relay-only peer.
+#[tokio::test]
+async fn test_connecting_steps_reported() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+    let server = TestServer::start(&binary);
+    let steps = |events: &[ProgressEvent]| -> Vec<String> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                ProgressEvent::AttemptingDirect => Some("attempting".into()),
+                ProgressEvent::DirectFailed { reason } => Some(format!("failed: {reason}")),
+                ProgressEvent::FallingBackToRelay => Some("falling back".into()),
+                ProgressEvent::ConnectionTypeChanged { connection_type } => {
+                    Some(connection_type.clone())
+                }
+                _ => None,
+            })
+            .collect()
+    };
+
+    let (sender, receiver) =
+        connect_reporting(&server, ConnectionMode::Auto, ConnectionMode::Auto).await;
+    assert!(!sender.0.unwrap() && !receiver.0.unwrap());
+    assert_eq!(steps(&receiver.1), ["attempting", "direct"]);
+
+    let (sender, receiver) =
+        connect_reporting(&server, ConnectionMode::Auto, ConnectionMode::RelayOnly).await;
+    assert!(sender.0.unwrap() && receiver.0.unwrap());
+    assert_eq!(steps(&sender.1), ["falling back", "relay"]);
+    assert_eq!(steps(&receiver.1), ["relay"]);
+}
+
+#[tokio::test]
+async fn test_connection_modes() {
+    use ConnectionMode::{Auto, DirectOnly, RelayOnly};
+
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+    let server = TestServer::start(&binary);
+
+    // Loopback is reachable, so anything that tries direct gets it.
+    for (sender_mode, receiver_mode) in [(Auto, Auto), (DirectOnly, Auto), (Auto, DirectOnly)] {
+        let (sender, receiver) = connect_in_modes(&server, sender_mode, receiver_mode).await;
+        assert!(!sender.unwrap(), "{sender_mode:?}/{receiver_mode:?}");
+        assert!(!receiver.unwrap(), "{sender_mode:?}/{receiver_mode:?}");
+    }
+
+    // A relay-only side has no endpoint and skips the fingerprint exchange;
+    // an auto peer follows it onto the relay.
+    for (sender_mode, receiver_mode) in
+        [(RelayOnly, Auto), (Auto, RelayOnly), (RelayOnly, RelayOnly)]
+    {
+        let (sender, receiver) = connect_in_modes(&server, sender_mode, receiver_mode).await;
+        assert!(sender.unwrap(), "{sender_mode:?}/{receiver_mode:?}");
+        assert!(receiver.unwrap(), "{sender_mode:?}/{receiver_mode:?}");
+    }
+
+    // A direct-only side refuses the relay rather than silently using it,
+    // and its relay-only peer gives up instead of waiting for it.
+    for (sender_mode, receiver_mode) in [(DirectOnly, RelayOnly), (RelayOnly, DirectOnly)] {
+        let (sender, receiver) = connect_in_modes(&server, sender_mode, receiver_mode).await;
+        let (refusing, abandoned) = match sender_mode {
+            DirectOnly => (sender, receiver),
+            _ => (receiver, sender),
+        };
+        match refusing {
+            Err(AppError::Network(msg)) => assert!(msg.contains("relay is disabled"), "{msg}"),
+            other => panic!("expected a network error, got {:?}", other.map(|_| ())),
+        }
+        assert!(abandoned.is_err());
+    }
+}
+
+/// Outcome of one side of a direct transfer: its result and the progress events it emitted.
+type SideOutcome = (relay_lib::error::AppResult<()>, Vec<ProgressEvent>);
+
+/// Controls for each side of `run_direct_pair`.
+struct PairConfig {
+    send_cancel: CancellationToken,
+    send_options: SendOptions,
+    recv_cancel: CancellationToken,
+    recv_options: ReceiveOptions,
+    accept: bool,
+    /// Leave the offer unanswered, so the receiver waits until cancelled.
+    hold_accept: bool,
+    /// Transfer key shared by both sides.
+    key: [u8; 32],
+    /// Used for both endpoints, each with a certificate of its own.
+    network: NetworkOptions,
+    /// Which side dials the QUIC connection; the sender listens by default.
+    sender_dials: bool,
+    /// Both sides dial at once and settle on one connection.
+    both_dial: bool,
+}
+
+impl Default for PairConfig {
+    fn default() -> Self {
+        Self {
+            send_cancel: CancellationToken::new(),
+            send_options: SendOptions::default(),
+            recv_cancel: CancellationToken::new(),
+            recv_options: ReceiveOptions::default(),
+            accept: true,
+            hold_accept: false,
+            key: [0x42u8; 32],
+            network: NetworkOptions::default(),
+            sender_dials: false,
+            both_dial: false,
+        }
+    }
+}
+
+/// Run a sender and receiver against each other over loopback QUIC (no signaling).
+///
+/// Endpoints and connections stay alive until both pipelines have returned, so a
+/// final `Cancel` isn't lost to a connection close.
+async fn run_direct_pair(
+    files: Vec<impl Into<FileSource> + Send + 'static>,
+    infos: Vec<FileInfo>,
+    save_dir: PathBuf,
+    config: PairConfig,
+) -> (SideOutcome, SideOutcome) {
+    let PairConfig {
+        send_cancel,
+        send_options,
+        recv_cancel,
+        recv_options,
+        accept,
+        hold_accept,
+        key,
+        network,
+        sender_dials,
+        both_dial,
+    } = config;
+    let own_identity = || NetworkOptions {
+        identity: CertStore::default(),
+        ..network.clone()
+    };
+    let sender_quic = QuicEndpoint::with_options(0, &own_identity())
+        .await
+        .unwrap();
+    let receiver_quic = QuicEndpoint::with_options(0, &own_identity())
+        .await
+        .unwrap();
+    let loopback = |quic: &QuicEndpoint| -> SocketAddr {
+        let ip: std::net::IpAddr = match network.family {
+            AddressFamily::Ipv4 => std::net::Ipv4Addr::LOCALHOST.into(),
+            AddressFamily::DualStack | AddressFamily::Ipv6 => std::net::Ipv6Addr::LOCALHOST.into(),
+        };
+        SocketAddr::new(ip, quic.local_addr().unwrap().port())
+    };
+    let (sender_addr, receiver_addr) = (loopback(&sender_quic), loopback(&receiver_quic));
+    let (sender_fp, receiver_fp) = (
+        sender_quic.cert_fingerprint(),
+        receiver_quic.cert_fingerprint(),
+    );
+
+    let sender = tokio::spawn(async move {
+        let conn = if both_dial {
+            sender_quic
+                .connect_simultaneous(&[receiver_addr], &receiver_fp)
+                .await
+                .unwrap()
+        } else if sender_dials {
+            sender_quic
+                .connect(receiver_addr, &receiver_fp)
+                .await
+                .unwrap()
+        } else {
+            sender_quic.accept_any(&receiver_fp).await.unwrap()
+        };
+        let mut transport = Transport::direct(&conn, TransferRole::Sender)
+            .await
+            .unwrap();
+        let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+        let result = relay_lib::transfer::sender::run_send(
+            files,
+            infos,
+            &mut transport,
+            key,
+            progress_tx,
+            send_cancel,
+            send_options,
+        )
+        .await;
+        let mut events = Vec::new();
+        while let Ok(event) = progress_rx.try_recv() {
+            events.push(event);
+        }
+        ((result, events), transport, conn, sender_quic)
+    });
+
+    let receiver = tokio::spawn(async move {
+        let conn = if both_dial {
+            receiver_quic
+                .connect_simultaneous(&[sender_addr], &sender_fp)
+                .await
+                .unwrap()
+        } else if sender_dials {
+            receiver_quic.accept_any(&sender_fp).await.unwrap()
+        } else {
+            receiver_quic
+                .connect(sender_addr, &sender_fp)
+                .await
+                .unwrap()
+        };
+        let mut transport = Transport::direct(&conn, TransferRole::Receiver)
+            .await
+            .unwrap();
+        let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+        let (accept_tx, accept_rx) = oneshot::channel();
+        let _unanswered = if hold_accept {
+            Some(accept_tx)
+        } else {
+            accept_tx.send(accept).unwrap();
+            None
+        };
+        let result = relay_lib::transfer::receiver::run_receive(
+            save_dir,
+            &mut transport,
+            key,
+            progress_tx,
+            accept_rx,
+            recv_cancel,
+            recv_options,
+        )
+        .await;
+        let mut events = Vec::new();
+        while let Ok(event) = progress_rx.try_recv() {
+            events.push(event);
+        }
+        ((result, events), transport, conn, receiver_quic)
+    });
+
+    let (sent, received) = tokio::time::timeout(Duration::from_secs(10), async {
+        (sender.await.unwrap(), receiver.await.unwrap())
+    })
+    .await
+    .expect("direct transfer timed out");
+    (sent.0, received.0)
+}
+
+/// A loopback stand-in for the relay: two WebSockets joined by a TCP proxy
+/// that holds sender → receiver bytes back by `delay`, like a slow hop.
+async fn delayed_relay_pair(delay: Duration) -> (Transport, Transport) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::MaybeTlsStream;
+
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (client, _) = proxy.accept().await.unwrap();
+        let server = TcpStream::connect(upstream_addr).await.unwrap();
+        client.set_nodelay(true).unwrap();
+        server.set_nodelay(true).unwrap();
+        let (mut client_rd, mut client_wr) = client.into_split();
+        let (mut server_rd, mut server_wr) = server.into_split();
+        let (delayed_tx, mut delayed_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 64 * 1024];
+            while let Ok(n @ 1..) = client_rd.read(&mut buf).await {
+                let due = tokio::time::Instant::now() + delay;
+                if delayed_tx.send((due, buf[..n].to_vec())).is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(async move {
+            while let Some((due, bytes)) = delayed_rx.recv().await {
+                tokio::time::sleep_until(due).await;
+                if server_wr.write_all(&bytes).await.is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::io::copy(&mut server_rd, &mut client_wr).await.ok();
+    });
+
+    let (receiver_ws, sender_ws) = tokio::join!(
+        async {
+            let (tcp, _) = upstream.accept().await.unwrap();
+            tcp.set_nodelay(true).unwrap();
+            tokio_tungstenite::accept_async(MaybeTlsStream::Plain(tcp))
+                .await
+                .unwrap()
+        },
+        async {
+            let tcp = TcpStream::connect(proxy_addr).await.unwrap();
+            tcp.set_nodelay(true).unwrap();
+            let url = format!("ws://{proxy_addr}");
+            tokio_tungstenite::client_async(url, MaybeTlsStream::Plain(tcp))
+                .await
+                .unwrap()
+                .0
+        }
+    );
+    (
+        Transport::Relayed {
+            ws: RelayStream::new(sender_ws),
+        },
+        Transport::Relayed {
+            ws: RelayStream::new(receiver_ws),
+        },
+    )
+}
+
+/// A loopback relay whose sender → receiver hop stops dead for `stall` out
+/// of every `period`, through small socket buffers, so a sender's writes
+/// block for the length of a stall rather than filling a backlog.
+async fn stalling_relay_pair(period: Duration, stall: Duration) -> (Transport, Transport) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpSocket, TcpStream};
+    use tokio_tungstenite::MaybeTlsStream;
+
+    const BUFFER: u32 = 4096;
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let proxy = TcpSocket::new_v4().unwrap();
+    proxy.set_recv_buffer_size(BUFFER).unwrap();
+    proxy.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let proxy = proxy.listen(1).unwrap();
+    tokio::spawn(async move {
+        let (client, _) = proxy.accept().await.unwrap();
+        let server = TcpStream::connect(upstream_addr).await.unwrap();
+        let (mut client_rd, mut client_wr) = client.into_split();
+        let (mut server_rd, mut server_wr) = server.into_split();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; BUFFER as usize];
+            let started = tokio::time::Instant::now();
+            loop {
+                let into_period = started.elapsed().as_secs_f64() % period.as_secs_f64();
+                if into_period < stall.as_secs_f64() {
+                    let resume = Duration::from_secs_f64(stall.as_secs_f64() - into_period);
+                    tokio::time::sleep(resume).await;
+                }
+                let Ok(n @ 1..) = client_rd.read(&mut buf).await else {
+                    break;
+                };
+                if server_wr.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::io::copy(&mut server_rd, &mut client_wr).await.ok();
+    });
+
+    let (receiver_ws, sender_ws) = tokio::join!(
+        async {
+            let (tcp, _) = upstream.accept().await.unwrap();
+            tokio_tungstenite::accept_async(MaybeTlsStream::Plain(tcp))
+                .await
+                .unwrap()
+        },
+        async {
+            let socket = TcpSocket::new_v4().unwrap();
+            socket.set_send_buffer_size(BUFFER).unwrap();
+            let tcp = socket.connect(proxy_addr).await.unwrap();
+            let url = format!("ws://{proxy_addr}");
+            tokio_tungstenite::client_async(url, MaybeTlsStream::Plain(tcp))
+                .await
+                .unwrap()
+                .0
+        }
+    );
+    (
+        Transport::Relayed {
+            ws: RelayStream::new(sender_ws),
+        },
+        Transport::Relayed {
+            ws: RelayStream::new(receiver_ws),
+        },
+    )
+}
+
+/// A loopback relay whose sender → receiver hop passes the first `after`
+/// bytes, then freezes with the connection still open, like a sender whose
+/// process has hung. Small socket buffers make the sender's writes block
+/// soon after.
+async fn freezing_relay_pair(after: usize) -> (Transport, Transport) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpSocket, TcpStream};
+    use tokio_tungstenite::MaybeTlsStream;
+
+    const BUFFER: u32 = 4096;
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let proxy = TcpSocket::new_v4().unwrap();
+    proxy.set_recv_buffer_size(BUFFER).unwrap();
+    proxy.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let proxy = proxy.listen(1).unwrap();
+    tokio::spawn(async move {
+        let (client, _) = proxy.accept().await.unwrap();
+        let server = TcpStream::connect(upstream_addr).await.unwrap();
+        let (mut client_rd, mut client_wr) = client.into_split();
+        let (mut server_rd, mut server_wr) = server.into_split();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; BUFFER as usize];
+            let mut left = after;
+            while left > 0 {
+                let want = left.min(buf.len());
+                let Ok(n @ 1..) = client_rd.read(&mut buf[..want]).await else {
+                    return;
+                };
+                if server_wr.write_all(&buf[..n]).await.is_err() {
+                    return;
+                }
+                left -= n;
+            }
+            // Hold both ends open without reading or writing another byte.
+            std::future::pending::<()>().await;
+            drop((client_rd, server_wr));
+        });
+        tokio::io::copy(&mut server_rd, &mut client_wr).await.ok();
+    });
+
+    let (receiver_ws, sender_ws) = tokio::join!(
+        async {
+            let (tcp, _) = upstream.accept().await.unwrap();
+            tokio_tungstenite::accept_async(MaybeTlsStream::Plain(tcp))
+                .await
+                .unwrap()
+        },
+        async {
+            let socket = TcpSocket::new_v4().unwrap();
+            socket.set_send_buffer_size(BUFFER).unwrap();
+            let tcp = socket.connect(proxy_addr).await.unwrap();
+            let url = format!("ws://{proxy_addr}");
+            tokio_tungstenite::client_async(url, MaybeTlsStream::Plain(tcp))
+                .await
+                .unwrap()
+                .0
+        }
+    );
+    (
+        Transport::Relayed {
+            ws: RelayStream::new(sender_ws),
+        },
+        Transport::Relayed {
+            ws: RelayStream::new(receiver_ws),
+        },
+    )
+}
+
+/// A loopback relay that drops the connection once nothing has crossed it
+/// in either direction for `idle`, like a proxy reaping dead-looking
+/// WebSockets.
+async fn reaping_relay_pair(idle: Duration) -> (Transport, Transport) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::MaybeTlsStream;
+
+    async fn pipe(
+        mut rd: OwnedReadHalf,
+        mut wr: OwnedWriteHalf,
+        last_seen: Arc<std::sync::Mutex<tokio::time::Instant>>,
+    ) {
+        let mut buf = vec![0u8; 64 * 1024];
+        while let Ok(n @ 1..) = rd.read(&mut buf).await {
+            *last_seen.lock().unwrap() = tokio::time::Instant::now();
+            if wr.write_all(&buf[..n]).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (client, _) = proxy.accept().await.unwrap();
+        let server = TcpStream::connect(upstream_addr).await.unwrap();
+        let (client_rd, client_wr) = client.into_split();
+        let (server_rd, server_wr) = server.into_split();
+        let last_seen = Arc::new(std::sync::Mutex::new(tokio::time::Instant::now()));
+        let up = tokio::spawn(pipe(client_rd, server_wr, last_seen.clone()));
+        let down = tokio::spawn(pipe(server_rd, client_wr, last_seen.clone()));
+        while !(up.is_finished() && down.is_finished()) {
+            tokio::time::sleep(idle / 8).await;
+            if last_seen.lock().unwrap().elapsed() >= idle {
+                // Dropping the halves closes both connections.
+                up.abort();
+                down.abort();
+                break;
+            }
+        }
+    });
+
+    let (receiver_ws, sender_ws) = tokio::join!(
+        async {
+            let (tcp, _) = upstream.accept().await.unwrap();
+            tokio_tungstenite::accept_async(MaybeTlsStream::Plain(tcp))
+                .await
+                .unwrap()
+        },
+        async {
+            let tcp = TcpStream::connect(proxy_addr).await.unwrap();
+            let url = format!("ws://{proxy_addr}");
+            tokio_tungstenite::client_async(url, MaybeTlsStream::Plain(tcp))
+                .await
+                .unwrap()
+                .0
+        }
+    );
+    (
+        Transport::Relayed {
+            ws: RelayStream::new(sender_ws),
+        },
+        Transport::Relayed {
+            ws: RelayStream::new(receiver_ws),
+        },
+    )
+}
+
+/// A relay pair with the test in the middle, counting the messages the
+/// sender's end sends.
+async fn counting_relay_pair() -> (Transport, Transport, Arc<AtomicUsize>) {
+    let (sender, mut from_sender) = delayed_relay_pair(Duration::ZERO).await;
+    let (mut to_receiver, receiver) = delayed_relay_pair(Duration::ZERO).await;
+    let count = Arc::new(AtomicUsize::new(0));
+    let counted = count.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                msg = from_sender.recv_peer_message() => {
+                    let Ok(msg) = msg else { break };
+                    counted.fetch_add(1, Ordering::Relaxed);
+                    if to_receiver.send_peer_message(&msg).await.is_err() {
+                        break;
+                    }
+                }
+                msg = to_receiver.recv_peer_message() => {
+                    let Ok(msg) = msg else { break };
+                    if from_sender.send_peer_message(&msg).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    (sender, receiver, count)
+}
+
+/// Send `file` over a relay delayed by `delay`; the sender's last
+/// `ConnectionStats` as (rtt_ms, goodput_bps, stalls).
+async fn relayed_send_stats(file: &std::path::Path, delay: Duration) -> (Option<u32>, u64, u32) {
+    let (mut send_transport, mut recv_transport) = delayed_relay_pair(delay).await;
+    let save_dir = tempfile::tempdir().unwrap();
+    let key = [0x42u8; 32];
+    let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+    let (recv_progress_tx, _recv_progress_rx) = mpsc::unbounded_channel();
+    let (accept_tx, accept_rx) = oneshot::channel();
+    accept_tx.send(true).unwrap();
+    let (sent, received) = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::join!(
+            relay_lib::transfer::sender::run_send(
+                vec![file.to_path_buf()],
+                vec![flat_file_info(file)],
+                &mut send_transport,
+                key,
+                progress_tx,
+                CancellationToken::new(),
+                SendOptions::default(),
+            ),
+            relay_lib::transfer::receiver::run_receive(
+                save_dir.path().to_path_buf(),
+                &mut recv_transport,
+                key,
+                recv_progress_tx,
+                accept_rx,
+                CancellationToken::new(),
+                ReceiveOptions::default(),
+            )
+        )
+    })
+    .await
+    .expect("relayed transfer timed out");
+    sent.expect("send failed");
+    received.expect("receive failed");
+
+    let mut stats = None;
+    while let Ok(event) = progress_rx.try_recv() {
+        if let ProgressEvent::ConnectionStats {
+            rtt_ms,
+            goodput_bps,
+            stalls,
+            ..
+        } = event
+        {
+            stats = Some((rtt_ms, goodput_bps, stalls));
+        }
+    }
+    stats.expect("no connection stats reported")
+}
+
+/// Describe a single flat file for an offer.
+fn flat_file_info(path: &std::path::Path) -> FileInfo {
+    FileInfo {
+        name: path.file_name().unwrap().to_string_lossy().into(),
+        size: std::fs::metadata(path).unwrap().len(),
+        relative_path: None,
+        duplicates: Vec::new(),
+        mime_hint: None,
+        streaming: false,
+        modified: None,
+        mode: None,
+        checksum: None,
+        link_target: None,
+    }
+}
+
+/// Assert that `outcome` failed because the peer cancelled with `expected`,
+/// and that the matching progress event was emitted.
+fn assert_peer_cancelled(outcome: &SideOutcome, expected: CancelReason) {
+    match &outcome.0 {
+        Err(AppError::PeerCancelled { reason, .. }) => assert_eq!(*reason, expected),
+        other => panic!("expected PeerCancelled({expected:?}), got {other:?}"),
+    }
+    assert!(
+        outcome.1.iter().any(|e| matches!(
+            e,
+            ProgressEvent::PeerCancelled { reason, .. } if *reason == expected
+        )),
+        "missing PeerCancelled({expected:?}) event in {:?}",
+        outcome.1
+    );
+}
+
+/// Test: a peer that leaves, saying `Bye` or closing its end without a
+/// word, is reported as disconnected over a direct connection and the relay
+/// alike.
+#[tokio::test]
+async fn test_peer_departure_reported_on_both_transports() {
+    use relay_lib::protocol::messages::PeerMessage;
+
+    let disconnected = AppError::peer_disconnected().to_string();
+    let assert_departed = |result: AppResult<PeerMessage>, case: &str| match result {
+        Err(e) => assert_eq!(e.to_string(), disconnected, "{case}"),
+        Ok(msg) => panic!("{case}: expected the peer gone, got {msg:?}"),
+    };
+
+    for says_bye in [true, false] {
+        let case = if says_bye {
+            "direct, bye"
+        } else {
+            "direct, closed"
+        };
+        let sender_quic = QuicEndpoint::new(0).await.unwrap();
+        let receiver_quic = QuicEndpoint::new(0).await.unwrap();
+        let sender_addr: SocketAddr =
+            format!("127.0.0.1:{}", sender_quic.local_addr().unwrap().port())
+                .parse()
+                .unwrap();
+        let (sender_fp, receiver_fp) = (
+            sender_quic.cert_fingerprint(),
+            receiver_quic.cert_fingerprint(),
+        );
+        let (accepted, dialed) = tokio::join!(
+            sender_quic.accept_any(&receiver_fp),
+            receiver_quic.connect(sender_addr, &sender_fp)
+        );
+        let (sender_conn, receiver_conn) = (accepted.unwrap(), dialed.unwrap());
+        let mut sender = Transport::direct(&sender_conn, TransferRole::Sender)
+            .await
+            .unwrap();
+        sender.send_peer_message(&PeerMessage::Ping).await.unwrap();
+        let mut receiver = Transport::direct(&receiver_conn, TransferRole::Receiver)
+            .await
+            .unwrap();
+        assert!(matches!(
+            receiver.recv_peer_message().await,
+            Ok(PeerMessage::Ping)
+        ));
+
+        if says_bye {
+            sender.say_bye().await;
+        } else {
+            // The last handle gone closes the connection.
+            drop(sender);
+            drop(sender_conn);
+        }
+        assert_departed(receiver.recv_peer_message().await, case);
+    }
+
+    for says_bye in [true, false] {
+        let case = if says_bye {
+            "relay, bye"
+        } else {
+            "relay, closed"
+        };
+        let (mut sender, mut receiver) = delayed_relay_pair(Duration::ZERO).await;
+        sender.send_peer_message(&PeerMessage::Ping).await.unwrap();
+        assert!(matches!(
+            receiver.recv_peer_message().await,
+            Ok(PeerMessage::Ping)
+        ));
+
+        if says_bye {
+            sender.say_bye().await;
+        } else {
+            // As the relay server closes our end once the peer's is gone.
+            sender.finish_send().await.unwrap();
+        }
+        assert_departed(receiver.recv_peer_message().await, case);
+    }
+}
+
+/// Test: each cancellation cause reaches the peer as the matching `CancelReason`.
+#[tokio::test]
+async fn test_cancel_reasons_reach_peer() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("data.bin");
+    std::fs::write(&file, vec![7u8; 4096]).unwrap();
+
+    // Sender cancels mid-transfer.
+    let send_cancel = CancellationToken::new();
+    send_cancel.cancel();
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        temp.path().join("out-sender-cancel"),
+        PairConfig {
+            send_cancel,
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    assert!(matches!(sent.0, Err(AppError::Cancelled)), "{:?}", sent.0);
+    assert_peer_cancelled(&received, CancelReason::UserCancelled);
+
+    // Receiver cancels after accepting. The sender starts paused so the
+    // cancel lands before any data does.
+    let options = SendOptions::default();
+    options.pause.pause(PauseReason::User);
+    let pause = options.pause.clone();
+    let recv_cancel = CancellationToken::new();
+    let canceller = {
+        let recv_cancel = recv_cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            recv_cancel.cancel();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            pause.resume(PauseReason::User);
+        })
+    };
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        temp.path().join("out-receiver-cancel"),
+        PairConfig {
+            send_options: options,
+            recv_cancel,
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    canceller.await.unwrap();
+    assert!(
+        matches!(received.0, Err(AppError::Cancelled)),
+        "{:?}",
+        received.0
+    );
+    assert_peer_cancelled(&sent, CancelReason::UserCancelled);
+
+    // Receiver declines the offer.
+    let (sent, _) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        temp.path().join("out-declined"),
+        PairConfig {
+            accept: false,
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    assert!(
+        matches!(sent.0, Err(AppError::PeerRejected)),
+        "{:?}",
+        sent.0
+    );
+    assert!(sent.1.iter().any(|e| matches!(
+        e,
+        ProgressEvent::PeerCancelled {
+            reason: CancelReason::Declined,
+            ..
+        }
+    )));
+
+    // Receiver runs out of disk space: /dev/full fails every write with ENOSPC.
+    #[cfg(target_os = "linux")]
+    {
+        let save_dir = temp.path().join("out-disk-full");
+        std::fs::create_dir(&save_dir).unwrap();
+        std::os::unix::fs::symlink("/dev/full", save_dir.join("data.bin")).unwrap();
+        let (sent, received) = run_direct_pair(
+            vec![file.clone()],
+            vec![flat_file_info(&file)],
+            save_dir,
+            PairConfig::default(),
+        )
+        .await;
+        assert!(
+            matches!(received.0, Err(AppError::Io(_))),
+            "{:?}",
+            received.0
+        );
+        assert_peer_cancelled(&sent, CancelReason::DiskFull);
+    }
+}
+
+/// Test: with dedupe on, a file selected twice is sent once and lands at both paths.
+#[tokio::test]
+async fn test_dedupe_sends_duplicate_once() {
+    let temp = tempfile::tempdir().unwrap();
+    let src = temp.path().join("src");
+    std::fs::create_dir_all(src.join("copy")).unwrap();
+    let content = vec![0x5Au8; 200_000];
+    std::fs::write(src.join("photo.jpg"), &content).unwrap();
+    std::fs::write(src.join("copy/photo.jpg"), &content).unwrap();
+    // Same size, different content: must not be merged.
+    std::fs::write(src.join("other.jpg"), vec![0xA5u8; 200_000]).unwrap();
+
+    let files = vec![
+        src.join("photo.jpg"),
+        src.join("copy/photo.jpg"),
+        src.join("other.jpg"),
+    ];
+    let infos = vec![
+        FileInfo {
+            relative_path: Some("album/photo.jpg".into()),
+            ..flat_file_info(&files[0])
+        },
+        FileInfo {
+            relative_path: Some("album/copy/photo.jpg".into()),
+            ..flat_file_info(&files[1])
+        },
+        FileInfo {
+            relative_path: Some("album/other.jpg".into()),
+            ..flat_file_info(&files[2])
+        },
+    ];
+
+    let save_dir = temp.path().join("out");
+    let options = SendOptions {
+        dedupe: true,
+        ..SendOptions::default()
+    };
+    let (sent, received) = run_direct_pair(
+        files,
+        infos,
+        save_dir.clone(),
+        PairConfig {
+            send_options: options,
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    let offer = received
+        .1
+        .iter()
+        .find_map(|e| match e {
+            ProgressEvent::FileOffer { files, .. } => Some(files.clone()),
+            _ => None,
+        })
+        .expect("no offer event");
+    assert_eq!(offer.len(), 2, "duplicate should be folded into one entry");
+    assert_eq!(
+        offer[0].duplicates,
+        vec!["album/copy/photo.jpg".to_string()]
+    );
+
+    let total_sent = sent.1.iter().find_map(|e| match e {
+        ProgressEvent::TransferComplete { total_bytes, .. } => Some(*total_bytes),
+        _ => None,
+    });
+    assert_eq!(
+        total_sent,
+        Some(400_000),
+        "duplicate content sent more than once"
+    );
+
+    for rel in ["album/photo.jpg", "album/copy/photo.jpg"] {
+        assert_eq!(std::fs::read(save_dir.join(rel)).unwrap(), content, "{rel}");
+    }
+    assert_eq!(
+        std::fs::read(save_dir.join("album/other.jpg")).unwrap(),
+        vec![0xA5u8; 200_000]
+    );
+}
+
+/// Test: two top-level folders routed to their own destinations, with the
+/// unrouted file left in the save directory.
+#[tokio::test]
+async fn test_top_level_items_routed_to_destinations() {
+    let temp = tempfile::tempdir().unwrap();
+    let src = temp.path().join("src");
+    std::fs::create_dir_all(&src).unwrap();
+    let entries = [
+        ("photos/beach.jpg", b"jpeg".as_slice()),
+        ("docs/plan.md", b"# plan".as_slice()),
+        ("readme.txt", b"hello".as_slice()),
+    ];
+    let mut files = Vec::new();
+    let mut infos = Vec::new();
+    for (i, (rel, content)) in entries.iter().enumerate() {
+        let path = src.join(format!("{i}.bin"));
+        std::fs::write(&path, content).unwrap();
+        infos.push(FileInfo {
+            name: rel.rsplit('/').next().unwrap().into(),
+            relative_path: rel.contains('/').then(|| rel.to_string()),
+            ..flat_file_info(&path)
+        });
+        files.push(path);
+    }
+
+    let save_dir = temp.path().join("out");
+    let pictures = temp.path().join("Pictures");
+    let documents = temp.path().join("Documents");
+    let destinations = Destinations::default();
+    destinations.set("photos", &pictures).unwrap();
+    destinations.set("docs", &documents).unwrap();
+    let (sent, received) = run_direct_pair(
+        files,
+        infos,
+        save_dir.clone(),
+        PairConfig {
+            recv_options: ReceiveOptions {
+                destinations,
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    assert_eq!(
+        std::fs::read(pictures.join("photos/beach.jpg")).unwrap(),
+        b"jpeg"
+    );
+    assert_eq!(
+        std::fs::read(documents.join("docs/plan.md")).unwrap(),
+        b"# plan"
+    );
+    assert_eq!(
+        std::fs::read(save_dir.join("readme.txt")).unwrap(),
+        b"hello"
+    );
+    assert!(!save_dir.join("photos").exists());
+    assert!(!save_dir.join("docs").exists());
+}
+
+/// Test: an empty folder inside a sent tree is recreated on the receiver.
+#[tokio::test]
+async fn test_empty_directory_recreated() {
+    use relay_lib::commands::send::{
+        expand_directory, HiddenFilter, SymlinkPolicy, DEFAULT_MAX_DEPTH,
+    };
+
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("site");
+    std::fs::create_dir_all(root.join("assets")).unwrap();
+    std::fs::write(root.join("index.html"), b"<html></html>").unwrap();
+
+    let (expanded, _, empty_dirs, _) = expand_directory(&root, "site", DEFAULT_MAX_DEPTH, SymlinkPolicy::Skip, &HiddenFilter::default())
+        .await
+        .unwrap();
+    assert_eq!(empty_dirs, ["site/assets"]);
+    let (files, infos): (Vec<_>, Vec<_>) = expanded
+        .into_iter()
+        .map(|(path, rel)| {
+            let info = FileInfo {
+                relative_path: Some(rel),
+                ..flat_file_info(&path)
+            };
+            (path, info)
+        })
+        .unzip();
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        files,
+        infos,
+        save_dir.clone(),
+        PairConfig {
+            send_options: SendOptions {
+                empty_dirs,
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    assert!(save_dir.join("site/assets").is_dir());
+    assert_eq!(
+        std::fs::read(save_dir.join("site/index.html")).unwrap(),
+        b"<html></html>"
+    );
+}
+
+/// Test: links kept by a folder send are made again on the receiver,
+/// pointing where they did. Offers with links out of the save directory,
+/// or with files to be saved through a link, are refused.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_symlinks_preserved() {
+    use relay_lib::commands::send::{
+        expand_directory, HiddenFilter, SymlinkPolicy, DEFAULT_MAX_DEPTH,
+    };
+    use std::os::unix::fs::symlink;
+
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("notes");
+    std::fs::create_dir_all(root.join("2024")).unwrap();
+    let todo = root.join("2024/todo.md");
+    std::fs::write(&todo, b"- ship it").unwrap();
+    symlink("2024/todo.md", root.join("latest.md")).unwrap();
+    symlink("2024", root.join("current")).unwrap();
+
+    let (expanded, _, _, links) = expand_directory(
+        &root,
+        "notes",
+        DEFAULT_MAX_DEPTH,
+        SymlinkPolicy::PreserveAsLink,
+        &HiddenFilter::default(),
+    )
+    .await
+    .unwrap();
+    let mut files: Vec<FileSource> = Vec::new();
+    let mut infos = Vec::new();
+    for (path, rel) in expanded {
+        infos.push(FileInfo {
+            relative_path: Some(rel),
+            ..flat_file_info(&path)
+        });
+        files.push(path.into());
+    }
+    let link_info = |rel: &str, target: &str| FileInfo {
+        name: rel.rsplit('/').next().unwrap().into(),
+        size: 0,
+        relative_path: Some(rel.into()),
+        modified: None,
+        mode: None,
+        link_target: Some(target.into()),
+        ..flat_file_info(&todo)
+    };
+    for (rel, target) in &links {
+        infos.push(link_info(rel, target));
+        files.push(FileSource::Memory(Vec::new()));
+    }
+    assert_eq!(infos.len(), 3);
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) =
+        run_direct_pair(files, infos, save_dir.clone(), PairConfig::default()).await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+    let saved = save_dir.join("notes");
+    assert_eq!(
+        std::fs::read_link(saved.join("latest.md")).unwrap(),
+        std::path::Path::new("2024/todo.md")
+    );
+    assert_eq!(
+        std::fs::read(saved.join("latest.md")).unwrap(),
+        b"- ship it"
+    );
+    assert_eq!(
+        std::fs::read_link(saved.join("current")).unwrap(),
+        std::path::Path::new("2024")
+    );
+
+    for (infos, refusal) in [
+        (
+            vec![link_info("notes/escape", "../../etc")],
+            "outside the save directory",
+        ),
+        (
+            vec![
+                link_info("notes/current", "2024"),
+                FileInfo {
+                    relative_path: Some("notes/current/x.md".into()),
+                    ..flat_file_info(&todo)
+                },
+            ],
+            "through the link",
+        ),
+    ] {
+        let files: Vec<FileSource> = infos
+            .iter()
+            .map(|info| match info.link_target {
+                Some(_) => FileSource::Memory(Vec::new()),
+                None => todo.clone().into(),
+            })
+            .collect();
+        let save_dir = temp.path().join("refused");
+        let (sent, received) =
+            run_direct_pair(files, infos, save_dir.clone(), PairConfig::default()).await;
+        assert!(
+            matches!(&received.0, Err(AppError::Transfer(msg)) if msg.contains(refusal)),
+            "{:?}",
+            received.0
+        );
+        assert!(sent.0.is_err());
+        assert!(!save_dir.join("notes").exists());
+    }
+}
+
+/// Test: a link left by one receive isn't followed by the next. Each link
+/// below looks safe on its own, but together they would reach out of the
+/// save directory.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_links_from_earlier_receive_not_followed() {
+    let temp = tempfile::tempdir().unwrap();
+    let note = temp.path().join("note.txt");
+    std::fs::write(&note, b"hello").unwrap();
+    let save_dir = temp.path().join("nested/out");
+    let link_info = |rel: &str, target: &str| FileInfo {
+        name: rel.rsplit('/').next().unwrap().into(),
+        size: 0,
+        relative_path: Some(rel.into()),
+        modified: None,
+        mode: None,
+        link_target: Some(target.into()),
+        ..flat_file_info(&note)
+    };
+
+    let (sent, received) = run_direct_pair(
+        vec![FileSource::Memory(Vec::new())],
+        vec![link_info("a/b/l", "../..")],
+        save_dir.clone(),
+        PairConfig::default(),
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+    assert!(save_dir.join("a/b/l").is_symlink());
+
+    for (file, info) in [
+        (
+            FileSource::Memory(Vec::new()),
+            link_info("a/b/l/c/m", "../../../../x"),
+        ),
+        (
+            note.clone().into(),
+            FileInfo {
+                relative_path: Some("a/b/l/c/note.txt".into()),
+                ..flat_file_info(&note)
+            },
+        ),
+    ] {
+        let (sent, received) = run_direct_pair(
+            vec![file],
+            vec![info],
+            save_dir.clone(),
+            PairConfig::default(),
+        )
+        .await;
+        assert!(
+            matches!(&received.0, Err(AppError::Transfer(msg)) if msg.contains("through the link")),
+            "{:?}",
+            received.0
+        );
+        assert!(sent.0.is_err());
+        assert!(!save_dir.join("c").exists());
+        assert!(!temp.path().join("nested/x").exists());
+    }
+}
+
+/// Test: an empty folder goes over as a transfer of no files that completes
+/// once the folder exists; with nothing at all the sender refuses to start.
+#[tokio::test]
+async fn test_empty_folder_send() {
+    use relay_lib::commands::send::{
+        expand_directory, HiddenFilter, SymlinkPolicy, DEFAULT_MAX_DEPTH,
+    };
+
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("drafts");
+    std::fs::create_dir_all(&root).unwrap();
+    let (expanded, _, empty_dirs, _) = expand_directory(&root, "drafts", DEFAULT_MAX_DEPTH, SymlinkPolicy::Skip, &HiddenFilter::default())
+        .await
+        .unwrap();
+    assert!(expanded.is_empty());
+    assert_eq!(empty_dirs, ["drafts"]);
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        Vec::<PathBuf>::new(),
+        Vec::new(),
+        save_dir.clone(),
+        PairConfig {
+            send_options: SendOptions {
+                empty_dirs,
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+    assert!(save_dir.join("drafts").is_dir());
+    assert!(received.1.iter().any(|e| matches!(
+        e,
+        ProgressEvent::FileOffer { files, .. } if files.is_empty()
+    )));
+    assert!(received.1.iter().any(|e| matches!(
+        e,
+        ProgressEvent::TransferComplete {
+            total_bytes: 0,
+            file_count: 0,
+            ..
+        }
+    )));
+
+    // No files and no folders: refused, and the receiver is told why.
+    let (sent, received) = run_direct_pair(
+        Vec::<PathBuf>::new(),
+        Vec::new(),
+        temp.path().join("out-nothing"),
+        PairConfig::default(),
+    )
+    .await;
+    assert!(
+        matches!(sent.0, Err(AppError::NothingToSend)),
+        "{:?}",
+        sent.0
+    );
+    assert_peer_cancelled(&received, CancelReason::Error);
+}
+
+/// Test: each file reports its own progress, reaching 100% one file after
+/// another, and the aggregate stays the sum of the per-file counts.
+#[tokio::test]
+async fn test_per_file_progress_in_order() {
+    let temp = tempfile::tempdir().unwrap();
+    let small = temp.path().join("small.bin");
+    let large = temp.path().join("large.bin");
+    std::fs::write(&small, vec![1u8; 100_000]).unwrap();
+    std::fs::write(&large, vec![2u8; 700_000]).unwrap();
+
+    let (sent, received) = run_direct_pair(
+        vec![small.clone(), large.clone()],
+        vec![flat_file_info(&small), flat_file_info(&large)],
+        temp.path().join("out"),
+        PairConfig::default(),
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    for (side, events) in [("sender", &sent.1), ("receiver", &received.1)] {
+        let mut done = [0u64; 2];
+        let mut last_index = 0;
+        for event in events {
+            match event {
+                ProgressEvent::FileProgress {
+                    file_index,
+                    file_bytes,
+                    file_total,
+                    percent,
+                } => {
+                    let idx = *file_index as usize;
+                    assert!(idx >= last_index, "{side}: file {idx} after {last_index}");
+                    if idx > last_index {
+                        assert_eq!(done[last_index], [100_000, 700_000][last_index]);
+                    }
+                    last_index = idx;
+                    assert_eq!(*file_total, [100_000, 700_000][idx], "{side}");
+                    assert!(*percent <= 100.0, "{side}: {percent}%");
+                    done[idx] = *file_bytes;
+                }
+                ProgressEvent::TransferProgress {
+                    bytes_transferred, ..
+                } => {
+                    assert_eq!(*bytes_transferred, done.iter().sum::<u64>(), "{side}");
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(done, [100_000, 700_000], "{side}: files didn't finish");
+        let last_percent = events.iter().rev().find_map(|e| match e {
+            ProgressEvent::FileProgress { percent, .. } => Some(*percent),
+            _ => None,
+        });
+        assert_eq!(last_percent, Some(100.0), "{side}");
+    }
+}
+
+/// Test: with compression shrinking the chunks on the wire, both sides
+/// still count file bytes, finishing at exactly the files' total size.
+#[tokio::test]
+async fn test_progress_counts_plaintext_bytes() {
+    let temp = tempfile::tempdir().unwrap();
+    let text = temp.path().join("log.txt");
+    let empty = temp.path().join("empty.txt");
+    std::fs::write(&text, "the same line again\n".repeat(50_000)).unwrap();
+    std::fs::write(&empty, b"").unwrap();
+    let infos = vec![flat_file_info(&text), flat_file_info(&empty)];
+    let total: u64 = infos.iter().map(|info| info.size).sum();
+
+    let (sent, received) = run_direct_pair(
+        vec![text.clone(), empty.clone()],
+        infos,
+        temp.path().join("out"),
+        PairConfig {
+            send_options: SendOptions {
+                compress: true,
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    for (side, events) in [("sender", &sent.1), ("receiver", &received.1)] {
+        let last = events.iter().rev().find_map(|e| match e {
+            ProgressEvent::TransferProgress {
+                bytes_transferred,
+                bytes_total,
+                ..
+            } => Some((*bytes_transferred, *bytes_total)),
+            _ => None,
+        });
+        assert_eq!(last, Some((total, total)), "{side}");
+    }
+}
+
+/// Test: zero-byte files, on their own and inside a folder, arrive as empty
+/// files that verify against the empty file's SHA-256, and report complete.
+#[tokio::test]
+async fn test_zero_byte_files_arrive_and_verify() {
+    use relay_lib::commands::send::{
+        expand_directory, HiddenFilter, SymlinkPolicy, DEFAULT_MAX_DEPTH,
+    };
+
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    let temp = tempfile::tempdir().unwrap();
+    let flat = temp.path().join("placeholder.txt");
+    std::fs::write(&flat, b"").unwrap();
+    let root = temp.path().join("project");
+    std::fs::create_dir_all(root.join("src")).unwrap();
+    std::fs::write(root.join("src/__init__.py"), b"").unwrap();
+    std::fs::write(root.join("README.md"), b"# project\n").unwrap();
+
+    let (expanded, _, _, _) =
+        expand_directory(&root, "project", DEFAULT_MAX_DEPTH, SymlinkPolicy::Skip, &HiddenFilter::default())
+            .await
+            .unwrap();
+    let (mut files, mut infos): (Vec<_>, Vec<_>) = expanded
+        .into_iter()
+        .map(|(path, rel)| {
+            let info = FileInfo {
+                relative_path: Some(rel),
+                ..flat_file_info(&path)
+            };
+            (path, info)
+        })
+        .unzip();
+    files.push(flat.clone());
+    infos.push(flat_file_info(&flat));
+    let total: u64 = infos.iter().map(|info| info.size).sum();
+    let empties: Vec<u16> = infos
+        .iter()
+        .enumerate()
+        .filter(|(_, info)| info.size == 0)
+        .map(|(i, _)| i as u16)
+        .collect();
+    assert_eq!(empties.len(), 2);
+
+    let out = temp.path().join("out");
+    let (sent, received) =
+        run_direct_pair(files.clone(), infos, out.clone(), PairConfig::default()).await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    assert_eq!(format!("{:x}", Sha256::digest(b"")), EMPTY_SHA256);
+    for rel in ["placeholder.txt", "project/src/__init__.py"] {
+        let copy = out.join(rel);
+        assert!(copy.is_file(), "{rel} missing");
+        assert_eq!(std::fs::metadata(&copy).unwrap().len(), 0, "{rel}");
+        assert_eq!(
+            format!("{:x}", Sha256::digest(std::fs::read(&copy).unwrap())),
+            EMPTY_SHA256
+        );
+    }
+    assert_eq!(
+        std::fs::read(out.join("project/README.md")).unwrap(),
+        b"# project\n"
+    );
+
+    for (side, events) in [("sender", &sent.1), ("receiver", &received.1)] {
+        let completed = events
+            .iter()
+            .filter(|e| matches!(e, ProgressEvent::FileCompleted { .. }))
+            .count();
+        assert_eq!(completed, files.len(), "{side}");
+        // Each empty file shows as done, not stuck at nothing of nothing.
+        for &empty in &empties {
+            let done = events.iter().any(|e| {
+                matches!(e, ProgressEvent::FileProgress { file_index, file_bytes: 0, file_total: 0, percent }
+                    if *file_index == empty && *percent == 100.0)
+            });
+            assert!(done, "{side}: no progress for empty file {empty}");
+        }
+        let last = events.iter().rev().find_map(|e| match e {
+            ProgressEvent::TransferProgress {
+                bytes_transferred,
+                bytes_total,
+                percent,
+                ..
+            } => Some((*bytes_transferred, *bytes_total, *percent)),
+            _ => None,
+        });
+        assert_eq!(last, Some((total, total, 100.0)), "{side}");
+    }
+}
+
+/// Test: the completion event on both sides names the connection used and a
+/// peak speed no lower than the average.
+#[tokio::test]
+async fn test_completion_reports_connection_and_peak_speed() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("video.bin");
+    let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        temp.path().join("out"),
+        PairConfig::default(),
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    for (side, events) in [("sender", &sent.1), ("receiver", &received.1)] {
+        let complete = events.iter().find_map(|e| match e {
+            ProgressEvent::TransferComplete {
+                average_speed,
+                connection_type,
+                peak_speed_bps,
+                ..
+            } => Some((*average_speed, connection_type.clone(), *peak_speed_bps)),
+            _ => None,
+        });
+        let (average_speed, connection_type, peak_speed_bps) =
+            complete.unwrap_or_else(|| panic!("{side}: no TransferComplete"));
+        assert_eq!(connection_type, "direct", "{side}");
+        assert!(peak_speed_bps > 0, "{side}");
+        assert!(peak_speed_bps >= average_speed, "{side}");
+    }
+}
+
+/// Test: with parallel streams, files go out side by side over a direct
+/// connection and all arrive with the checksums they were sent with.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_parallel_streams_deliver_every_file() {
+    let temp = tempfile::tempdir().unwrap();
+    let mut files = Vec::new();
+    for (i, size) in [1_500_000usize, 1_200_000, 1_600_000, 100_000, 0]
+        .into_iter()
+        .enumerate()
+    {
+        let path = temp.path().join(format!("part-{i}.bin"));
+        let data: Vec<u8> = (0..size).map(|b| ((b * 7 + i) % 253) as u8).collect();
+        std::fs::write(&path, data).unwrap();
+        files.push(path);
+    }
+    let infos: Vec<FileInfo> = files.iter().map(|f| flat_file_info(f)).collect();
+    let total: u64 = infos.iter().map(|info| info.size).sum();
+    let out = temp.path().join("out");
+
+    let (sent, received) = run_direct_pair(
+        files.clone(),
+        infos,
+        out.clone(),
+        PairConfig {
+            send_options: SendOptions {
+                parallel_streams: 3,
+                // Paced, so every stream gets its turn.
+                max_bytes_per_sec: Some(6_000_000),
+                chunk_size: Some(256 * 1024),
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    for file in &files {
+        let original = Sha256::digest(std::fs::read(file).unwrap());
+        let copy = Sha256::digest(std::fs::read(out.join(file.file_name().unwrap())).unwrap());
+        assert_eq!(original, copy, "{}", file.display());
+    }
+    for (side, events) in [("sender", &sent.1), ("receiver", &received.1)] {
+        let completed = events
+            .iter()
+            .filter(|e| matches!(e, ProgressEvent::FileCompleted { .. }))
+            .count();
+        assert_eq!(completed, files.len(), "{side}");
+        let last = events.iter().rev().find_map(|e| match e {
+            ProgressEvent::TransferProgress {
+                bytes_transferred, ..
+            } => Some(*bytes_transferred),
+            _ => None,
+        });
+        assert_eq!(last, Some(total), "{side}");
+    }
+    // Side by side, not one after another: some file makes progress after
+    // a later one has.
+    let order: Vec<u16> = sent
+        .1
+        .iter()
+        .filter_map(|e| match e {
+            ProgressEvent::FileProgress { file_index, .. } => Some(*file_index),
+            _ => None,
+        })
+        .collect();
+    assert!(order.windows(2).any(|w| w[1] < w[0]), "{order:?}");
+}
+
+/// Test: 5000 tiny files arrive intact either way, but batched they take a
+/// small fraction of the messages.
+#[tokio::test]
+async fn test_batched_small_files_cut_message_count() {
+    let temp = tempfile::tempdir().unwrap();
+    let mut files = Vec::new();
+    let mut infos = Vec::new();
+    for i in 0..5000 {
+        let rel = format!("notes/{:02}/note-{i}.txt", i % 50);
+        let path = temp.path().join(&rel);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        // Every hundredth one is empty.
+        let content = if i % 100 == 0 {
+            String::new()
+        } else {
+            format!("note {i}\n")
+        };
+        std::fs::write(&path, content).unwrap();
+        let mut info = flat_file_info(&path);
+        info.relative_path = Some(rel);
+        files.push(path);
+        infos.push(info);
+    }
+
+    let mut counts = Vec::new();
+    for batch_small_files in [false, true] {
+        let (mut send_transport, mut recv_transport, count) = counting_relay_pair().await;
+        let save_dir = tempfile::tempdir().unwrap();
+        let key = [0x42u8; 32];
+        let (progress_tx, _progress_rx) = mpsc::unbounded_channel();
+        let (recv_progress_tx, _recv_progress_rx) = mpsc::unbounded_channel();
+        let (accept_tx, accept_rx) = oneshot::channel();
+        accept_tx.send(true).unwrap();
+        let (sent, received) = tokio::time::timeout(Duration::from_secs(120), async {
+            tokio::join!(
+                relay_lib::transfer::sender::run_send(
+                    files.clone(),
+                    infos.clone(),
+                    &mut send_transport,
+                    key,
+                    progress_tx,
+                    CancellationToken::new(),
+                    SendOptions {
+                        batch_small_files,
+                        ..SendOptions::default()
+                    },
+                ),
+                relay_lib::transfer::receiver::run_receive(
+                    save_dir.path().to_path_buf(),
+                    &mut recv_transport,
+                    key,
+                    recv_progress_tx,
+                    accept_rx,
+                    CancellationToken::new(),
+                    ReceiveOptions::default(),
+                )
+            )
+        })
+        .await
+        .expect("transfer timed out");
+        sent.expect("send failed");
+        received.expect("receive failed");
+
+        for (file, info) in files.iter().zip(&infos) {
+            let saved = save_dir.path().join(info.relative_path.as_ref().unwrap());
+            assert_eq!(std::fs::read(saved).unwrap(), std::fs::read(file).unwrap());
+        }
+        counts.push(count.load(Ordering::Relaxed));
+    }
+
+    let [plain, batched] = counts[..] else {
+        unreachable!()
+    };
+    assert!(plain > 9_000, "{plain} messages unbatched");
+    assert!(
+        batched * 100 < plain,
+        "{batched} messages batched vs {plain}"
+    );
+}
+
+/// Test: an archive-mode send of a folder of many small files arrives as
+/// the same tree, empty folders included, in a handful of messages. A
+/// receiver that can't unpack as it goes keeps the tar instead.
+#[tokio::test]
+async fn test_archive_mode_unpacks_folder() {
+    use relay_lib::commands::send::{
+        expand_directory, HiddenFilter, SymlinkPolicy, DEFAULT_MAX_DEPTH,
+    };
+
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("notes");
+    std::fs::create_dir_all(root.join("drafts")).unwrap();
+    for i in 0..3000 {
+        let path = root.join(format!("{:02}/note-{i}.txt", i % 40));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let content = if i % 100 == 0 {
+            String::new()
+        } else {
+            format!("note {i}\n").repeat(i % 7 + 1)
+        };
+        std::fs::write(&path, content).unwrap();
+    }
+    let long = root.join("a-folder-name-long-enough".repeat(5)).join("deep.txt");
+    std::fs::create_dir_all(long.parent().unwrap()).unwrap();
+    std::fs::write(&long, "deep").unwrap();
+
+    let (expanded, _, empty_dirs, _) = expand_directory(
+        &root,
+        "notes",
+        DEFAULT_MAX_DEPTH,
+        SymlinkPolicy::Skip,
+        &HiddenFilter::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(empty_dirs, ["notes/drafts"]);
+    let (files, infos): (Vec<_>, Vec<_>) = expanded
+        .into_iter()
+        .map(|(path, rel)| {
+            let info = FileInfo {
+                relative_path: Some(rel),
+                ..flat_file_info(&path)
+            };
+            (path, info)
+        })
+        .unzip();
+
+    let (mut send_transport, mut recv_transport, count) = counting_relay_pair().await;
+    let save_dir = temp.path().join("out");
+    let key = [0x42u8; 32];
+    let (progress_tx, _progress_rx) = mpsc::unbounded_channel();
+    let (recv_progress_tx, _recv_progress_rx) = mpsc::unbounded_channel();
+    let (accept_tx, accept_rx) = oneshot::channel();
+    accept_tx.send(true).unwrap();
+    let (sent, received) = tokio::time::timeout(Duration::from_secs(60), async {
+        tokio::join!(
+            relay_lib::transfer::sender::run_send(
+                files.clone(),
+                infos.clone(),
+                &mut send_transport,
+                key,
+                progress_tx,
+                CancellationToken::new(),
+                SendOptions {
+                    archive: true,
+                    empty_dirs: empty_dirs.clone(),
+                    ..SendOptions::default()
+                },
+            ),
+            relay_lib::transfer::receiver::run_receive(
+                save_dir.clone(),
+                &mut recv_transport,
+                key,
+                recv_progress_tx,
+                accept_rx,
+                CancellationToken::new(),
+                ReceiveOptions::default(),
+            )
+        )
+    })
+    .await
+    .expect("transfer timed out");
+    sent.expect("send failed");
+    received.expect("receive failed");
+
+    for (file, info) in files.iter().zip(&infos) {
+        let saved = save_dir.join(info.relative_path.as_ref().unwrap());
+        assert_eq!(std::fs::read(saved).unwrap(), std::fs::read(file).unwrap());
+    }
+    assert!(save_dir.join("notes/drafts").is_dir());
+    assert!(!save_dir.join("notes.tar").exists());
+    let messages = count.load(Ordering::Relaxed);
+    assert!(messages < 100, "{messages} messages for an archive");
+
+    // Renaming on collision needs each file's name up front, so the tar is
+    // kept whole.
+    let kept_dir = temp.path().join("kept");
+    let (sent, received) = run_direct_pair(
+        files,
+        infos,
+        kept_dir.clone(),
+        PairConfig {
+            send_options: SendOptions {
+                archive: true,
+                ..SendOptions::default()
+            },
+            recv_options: ReceiveOptions {
+                on_collision: CollisionPolicy::Rename,
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+    let entries: Vec<_> = std::fs::read_dir(&kept_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(entries, ["notes.tar"]);
+}
+
+/// Test: a slow relay shows up in the sender's connection stats as a long
+/// round trip, stalls and lower goodput.
+#[tokio::test]
+async fn test_relay_health_reflects_slow_relay() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("video.mp4");
+    std::fs::write(&file, vec![0x3Cu8; 1024 * 1024]).unwrap();
+
+    let (fast_rtt, fast_goodput, _) = relayed_send_stats(&file, Duration::ZERO).await;
+    let (slow_rtt, slow_goodput, slow_stalls) =
+        relayed_send_stats(&file, Duration::from_millis(300)).await;
+
+    let slow_rtt = slow_rtt.expect("no round trip measured over the slow relay");
+    assert!(slow_rtt >= 300, "slow relay rtt {slow_rtt}ms");
+    assert!(
+        fast_rtt.unwrap_or(0) < slow_rtt,
+        "fast relay rtt {fast_rtt:?}"
+    );
+    assert!(slow_stalls >= 1, "no stalls on the slow relay");
+    assert!(
+        slow_goodput < fast_goodput,
+        "goodput {slow_goodput} on the slow relay vs {fast_goodput}"
+    );
+}
+
+/// Test: a completed receive is recorded in the history log with its checksum.
+#[tokio::test]
+async fn test_receive_history_recorded() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("report.pdf");
+    std::fs::write(&file, b"quarterly numbers").unwrap();
+
+    let log = HistoryLog::new(temp.path().join("history.jsonl"));
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        save_dir.clone(),
+        PairConfig {
+            recv_options: ReceiveOptions {
+                history: Some(log.clone()),
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    let entries = log.recent(10).await.unwrap();
+    assert_eq!(entries.len(), 1);
+    let entry = &entries[0];
+    assert!(entry.success);
+    assert!(entry.finished_at > 0);
+    assert_eq!(entry.save_dir, save_dir.to_string_lossy());
+    assert_eq!(entry.total_bytes, 17);
+    assert_eq!(entry.bytes_received, 17);
+    assert_eq!(entry.files.len(), 1);
+    assert_eq!(entry.files[0].name, "report.pdf");
+    let expected: String = Sha256::digest(b"quarterly numbers")
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    assert_eq!(entry.files[0].sha256.as_deref(), Some(expected.as_str()));
+    assert_eq!(entry.role, TransferRole::Receiver);
+    assert_eq!(entry.connection_type.as_deref(), Some("direct"));
+}
+
+/// Test: sender and receiver recording into one log at the same time each
+/// add an entry for their side.
+#[tokio::test]
+async fn test_send_and_receive_history_share_log() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("invoice.pdf");
+    std::fs::write(&file, b"amount due: 42").unwrap();
+
+    let log = HistoryLog::new(temp.path().join("history.jsonl"));
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        temp.path().join("out"),
+        PairConfig {
+            send_options: SendOptions {
+                history: Some(log.clone()),
+                code: Some("3-amber-falcon".into()),
+                ..SendOptions::default()
+            },
+            recv_options: ReceiveOptions {
+                history: Some(log.clone()),
+                code: Some("3-amber-falcon".into()),
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    let entries = log.recent(10).await.unwrap();
+    let mut roles: Vec<TransferRole> = entries.iter().map(|e| e.role).collect();
+    roles.sort_by_key(|role| *role == TransferRole::Receiver);
+    assert_eq!(roles, [TransferRole::Sender, TransferRole::Receiver]);
+    for entry in &entries {
+        assert!(entry.success, "{entry:?}");
+        assert_eq!(entry.code.as_deref(), Some("3-amber-falcon"));
+        assert_eq!(entry.files[0].name, "invoice.pdf");
+        assert_eq!(entry.bytes_received, 14);
+        assert!(entry.duration_seconds > 0.0);
+    }
+}
+
+/// Test: usage adds up across transfers on both sides and persists; once
+/// the sender's monthly quota is used up, its next send is refused.
+#[tokio::test]
+async fn test_usage_accumulates_and_quota_refuses() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("backup.tar");
+    std::fs::write(&file, vec![0x5au8; 100_000]).unwrap();
+
+    let send_usage = UsageStore::new(temp.path().join("send-usage.json"));
+    send_usage.set_quota(Some(150_000)).await.unwrap();
+    let recv_usage = UsageStore::new(temp.path().join("recv-usage.json"));
+    let config = || PairConfig {
+        send_options: SendOptions {
+            usage: Some(send_usage.clone()),
+            ..SendOptions::default()
+        },
+        recv_options: ReceiveOptions {
+            usage: Some(recv_usage.clone()),
+            ..ReceiveOptions::default()
+        },
+        ..PairConfig::default()
+    };
+
+    for round in 0..2 {
+        let (sent, received) = run_direct_pair(
+            vec![file.clone()],
+            vec![flat_file_info(&file)],
+            temp.path().join(format!("out-{round}")),
+            config(),
+        )
+        .await;
+        sent.0.expect("send failed");
+        received.0.expect("receive failed");
+    }
+
+    // Saved when each transfer ended, and the same on both sides.
+    let sent_stats = UsageStore::new(send_usage.path()).stats().await.unwrap();
+    let received_stats = UsageStore::new(recv_usage.path()).stats().await.unwrap();
+    assert!(sent_stats.direct_bytes >= 200_000, "{sent_stats:?}");
+    assert_eq!(sent_stats.relay_bytes, 0);
+    assert_eq!(sent_stats.quota, Some(150_000));
+    assert_eq!(received_stats.direct_bytes, sent_stats.direct_bytes);
+    assert_eq!(received_stats.quota, None);
+
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        temp.path().join("out-2"),
+        config(),
+    )
+    .await;
+    match sent.0 {
+        Err(AppError::QuotaExceeded { used, quota }) => {
+            assert_eq!(used, sent_stats.direct_bytes);
+            assert_eq!(quota, 150_000);
+        }
+        other => panic!("expected QuotaExceeded, got {other:?}"),
+    }
+    assert_peer_cancelled(&received, CancelReason::Error);
+    assert!(!temp.path().join("out-2/backup.tar").exists());
+}
+
+/// Test: a text snippet round-trips in memory and surfaces as `TextReceived`.
+#[tokio::test]
+async fn test_send_text_inline() {
+    let temp = tempfile::tempdir().unwrap();
+    let snippet = "https://example.com/shared?ref=relay — café ☕".to_string();
+    let (source, info) = relay_lib::transfer::sender::text_file(snippet.clone());
+    assert_eq!(info.mime_hint.as_deref(), Some("text/plain"));
+
+    let save_dir = temp.path().join("out");
+    std::fs::create_dir(&save_dir).unwrap();
+    let (sent, received) = run_direct_pair(
+        vec![source],
+        vec![info],
+        save_dir.clone(),
+        PairConfig {
+            recv_options: ReceiveOptions {
+                inline_text: true,
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    let text = received.1.iter().find_map(|e| match e {
+        ProgressEvent::TextReceived { text, .. } => Some(text.clone()),
+        _ => None,
+    });
+    assert_eq!(text.as_deref(), Some(snippet.as_str()));
+    assert_eq!(
+        std::fs::read_dir(&save_dir).unwrap().count(),
+        0,
+        "inline text should not be saved"
+    );
+}
+
+/// Test: readers arrive intact, one of known size and one, like a pipe,
+/// of unknown length that ends wherever the reader does.
+#[tokio::test]
+async fn test_send_from_readers() {
+    let temp = tempfile::tempdir().unwrap();
+    let piped: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+    let sized = b"name,score\nada,97\n".to_vec();
+    let (pipe_source, pipe_info) = relay_lib::transfer::sender::stream_file(
+        "stdin.bin".into(),
+        std::io::Cursor::new(piped.clone()),
+        None,
+    );
+    let (sized_source, sized_info) = relay_lib::transfer::sender::stream_file(
+        "scores.csv".into(),
+        std::io::Cursor::new(sized.clone()),
+        Some(sized.len() as u64),
+    );
+    assert!(pipe_info.streaming && !sized_info.streaming);
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![pipe_source, sized_source],
+        vec![pipe_info, sized_info],
+        save_dir.clone(),
+        PairConfig {
+            // Neither reader can be hashed ahead of sending.
+            send_options: SendOptions {
+                pre_hash: true,
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    assert_eq!(std::fs::read(save_dir.join("stdin.bin")).unwrap(), piped);
+    assert_eq!(std::fs::read(save_dir.join("scores.csv")).unwrap(), sized);
+}
+
+/// Test: each collision policy, for a flat file and one inside a folder,
+/// against existing files of the same name.
+#[tokio::test]
+async fn test_collision_policies() {
+    let temp = tempfile::tempdir().unwrap();
+    let flat = temp.path().join("report.pdf");
+    std::fs::write(&flat, b"new report").unwrap();
+    let nested = temp.path().join("nested.pdf");
+    std::fs::write(&nested, b"new nested report").unwrap();
+    let infos = || {
+        vec![
+            flat_file_info(&flat),
+            FileInfo {
+                name: "report.pdf".into(),
+                relative_path: Some("q3/report.pdf".into()),
+                ..flat_file_info(&nested)
+            },
+        ]
+    };
+
+    for policy in [
+        CollisionPolicy::Overwrite,
+        CollisionPolicy::Rename,
+        CollisionPolicy::Skip,
+        CollisionPolicy::Fail,
+    ] {
+        let save_dir = temp.path().join(format!("{policy:?}"));
+        std::fs::create_dir_all(save_dir.join("q3")).unwrap();
+        std::fs::write(save_dir.join("report.pdf"), b"old").unwrap();
+        std::fs::write(save_dir.join("q3/report.pdf"), b"old nested").unwrap();
+
+        let (sent, received) = run_direct_pair(
+            vec![flat.clone(), nested.clone()],
+            infos(),
+            save_dir.clone(),
+            PairConfig {
+                recv_options: ReceiveOptions {
+                    on_collision: policy,
+                    ..ReceiveOptions::default()
+                },
+                ..PairConfig::default()
+            },
+        )
+        .await;
+        let read = |rel: &str| std::fs::read(save_dir.join(rel)).ok();
+
+        if policy == CollisionPolicy::Fail {
+            assert!(
+                matches!(received.0, Err(AppError::Transfer(_))),
+                "{:?}",
+                received.0
+            );
+            assert!(sent.0.is_err());
+        } else {
+            sent.0.expect("send failed");
+            received.0.expect("receive failed");
+        }
+        match policy {
+            CollisionPolicy::Overwrite => {
+                assert_eq!(read("report.pdf").unwrap(), b"new report");
+                assert_eq!(read("q3/report.pdf").unwrap(), b"new nested report");
+            }
+            CollisionPolicy::Rename => {
+                assert_eq!(read("report.pdf").unwrap(), b"old");
+                assert_eq!(read("report (1).pdf").unwrap(), b"new report");
+                assert_eq!(read("q3/report.pdf").unwrap(), b"old nested");
+                assert_eq!(read("q3/report (1).pdf").unwrap(), b"new nested report");
+            }
+            CollisionPolicy::Skip | CollisionPolicy::Fail => {
+                assert_eq!(read("report.pdf").unwrap(), b"old");
+                assert_eq!(read("q3/report.pdf").unwrap(), b"old nested");
+                assert_eq!(std::fs::read_dir(&save_dir).unwrap().count(), 2);
+                assert_eq!(std::fs::read_dir(save_dir.join("q3")).unwrap().count(), 1);
+            }
+        }
+        if policy == CollisionPolicy::Skip {
+            let skipped = received
+                .1
+                .iter()
+                .filter(|e| matches!(e, ProgressEvent::FileSkipped { .. }))
+                .count();
+            assert_eq!(skipped, 2);
+        }
+    }
+}
+
+/// Test: an offer past the receiver's file count or byte limit is declined
+/// before anything is written; one within both goes through.
+#[tokio::test]
+async fn test_offer_over_limits_declined() {
+    let temp = tempfile::tempdir().unwrap();
+    let a = temp.path().join("a.log");
+    let b = temp.path().join("b.log");
+    std::fs::write(&a, vec![b'a'; 600]).unwrap();
+    std::fs::write(&b, vec![b'b'; 600]).unwrap();
+
+    for (max_total_bytes, max_file_count, accepted) in [
+        (Some(1000), None, false),
+        (None, Some(1), false),
+        (Some(1200), Some(2), true),
+    ] {
+        let save_dir = temp
+            .path()
+            .join(format!("out-{max_total_bytes:?}-{max_file_count:?}"));
+        let (sent, received) = run_direct_pair(
+            vec![a.clone(), b.clone()],
+            vec![flat_file_info(&a), flat_file_info(&b)],
+            save_dir.clone(),
+            PairConfig {
+                recv_options: ReceiveOptions {
+                    max_total_bytes,
+                    max_file_count,
+                    ..ReceiveOptions::default()
+                },
+                ..PairConfig::default()
+            },
+        )
+        .await;
+        if accepted {
+            sent.0.expect("send failed");
+            received.0.expect("receive failed");
+            continue;
+        }
+        assert!(
+            matches!(&received.0, Err(AppError::Transfer(detail)) if detail.contains("limit")),
+            "{:?}",
+            received.0
+        );
+        assert!(
+            matches!(sent.0, Err(AppError::PeerRejected)),
+            "{:?}",
+            sent.0
+        );
+        assert!(!save_dir.join("a.log").exists());
+    }
+}
+
+/// Test: a file that turns out longer than its offered size is refused
+/// rather than written past it.
+#[tokio::test]
+async fn test_file_larger_than_offered_aborts() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("growing.csv");
+    std::fs::write(&file, vec![b'x'; 300_000]).unwrap();
+    let info = FileInfo {
+        size: 1000,
+        ..flat_file_info(&file)
+    };
+
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![info],
+        temp.path().join("out"),
+        PairConfig::default(),
+    )
+    .await;
+    assert!(
+        matches!(&received.0, Err(AppError::Transfer(detail)) if detail.contains("larger than")),
+        "{:?}",
+        received.0
+    );
+    assert!(sent.0.is_err());
+}
+
+/// Test: a receiver accepting one of three offered files gets only that
+/// one, and the sender sends nothing for the others.
+#[tokio::test]
+async fn test_partial_accept_receives_only_selected() {
+    let temp = tempfile::tempdir().unwrap();
+    let paths: Vec<PathBuf> = ["a.txt", "b.txt", "c.txt"]
+        .iter()
+        .map(|name| {
+            let path = temp.path().join(name);
+            std::fs::write(&path, format!("contents of {name}").repeat(1000)).unwrap();
+            path
+        })
+        .collect();
+    let infos: Vec<FileInfo> = paths.iter().map(|p| flat_file_info(p)).collect();
+    let selected_size = infos[1].size;
+    let save_dir = temp.path().join("out");
+
+    let selection = Selection::default();
+    selection.set(vec![1]);
+    let (sent, received) = run_direct_pair(
+        paths.clone(),
+        infos,
+        save_dir.clone(),
+        PairConfig {
+            recv_options: ReceiveOptions {
+                selection,
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    assert_eq!(
+        std::fs::read(save_dir.join("b.txt")).unwrap(),
+        std::fs::read(&paths[1]).unwrap()
+    );
+    assert!(!save_dir.join("a.txt").exists());
+    assert!(!save_dir.join("c.txt").exists());
+    for events in [&sent.1, &received.1] {
+        let complete = events.iter().find_map(|e| match e {
+            ProgressEvent::TransferComplete {
+                total_bytes,
+                file_count,
+                ..
+            } => Some((*total_bytes, *file_count)),
+            _ => None,
+        });
+        assert_eq!(complete, Some((selected_size, 1)));
+    }
+}
+
+/// Test: piece hashes travel with the file and land in a `.pieces` sidecar
+/// matching an independent per-piece SHA-256.
+#[tokio::test]
+async fn test_piece_hashes_sidecar() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("disk.img");
+    // Spans several transfer chunks and ends on a partial piece.
+    let data: Vec<u8> = (0..700_000u32).map(|i| (i % 253) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+
+    let config = PieceHashConfig {
+        piece_size: 64 * 1024,
+        algorithm: PieceHashAlgorithm::Sha256,
+    };
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        save_dir.clone(),
+        PairConfig {
+            send_options: SendOptions {
+                piece_hashes: Some(config),
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    let sidecar = std::fs::read(save_dir.join("disk.img.pieces")).unwrap();
+    let reference: Vec<u8> = data
+        .chunks(64 * 1024)
+        .flat_map(|piece| Sha256::digest(piece).to_vec())
+        .collect();
+    assert_eq!(sidecar, reference);
+}
+
+/// Test: with key ratcheting every two chunks, a file spanning several
+/// ratchets still arrives intact.
+#[tokio::test]
+async fn test_key_ratchet_roundtrip() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("ratchet.bin");
+    // Seven 256 KiB chunks, the last one partial: three ratchets.
+    let data: Vec<u8> = (0..1_700_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        save_dir.clone(),
+        PairConfig {
+            send_options: SendOptions {
+                key_ratchet_every: Some(2),
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    assert_eq!(std::fs::read(save_dir.join("ratchet.bin")).unwrap(), data);
+}
+
+/// Test: a compressible file sent with compression on arrives intact.
+#[tokio::test]
+async fn test_compressed_roundtrip() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("build.log");
+    let line = b"INFO compiling relay_lib (lib) in 0.42s\n";
+    let data: Vec<u8> = line.iter().copied().cycle().take(1024 * 1024).collect();
+    std::fs::write(&file, &data).unwrap();
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        save_dir.clone(),
+        PairConfig {
+            send_options: SendOptions {
+                compress: true,
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    assert_eq!(std::fs::read(save_dir.join("build.log")).unwrap(), data);
+}
+
+/// Test: a bandwidth cap paces the sender: 2 MB at 1 MB/s takes about two
+/// seconds.
+#[tokio::test]
+async fn test_bandwidth_cap_paces_sender() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("upload.bin");
+    let data: Vec<u8> = (0..2 * 1024 * 1024u32).map(|i| (i % 241) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+
+    let save_dir = temp.path().join("out");
+    let started = std::time::Instant::now();
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        save_dir.clone(),
+        PairConfig {
+            send_options: SendOptions {
+                max_bytes_per_sec: Some(1024 * 1024),
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    let elapsed = started.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(1900),
+        "2 MB at 1 MB/s finished in {elapsed:?}"
+    );
+    assert_eq!(std::fs::read(save_dir.join("upload.bin")).unwrap(), data);
+}
+
+/// Test: a transfer checksummed with BLAKE3 verifies and arrives intact.
+#[tokio::test]
+async fn test_blake3_roundtrip() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("disk.img");
+    let data: Vec<u8> = (0..700_000u32).map(|i| (i * 7 % 253) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        save_dir.clone(),
+        PairConfig {
+            send_options: SendOptions {
+                checksum: ChecksumAlgorithm::Blake3,
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    assert_eq!(std::fs::read(save_dir.join("disk.img")).unwrap(), data);
+}
+
+/// Test: the received file keeps the sender's modification time.
+#[tokio::test]
+async fn test_modification_time_preserved() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("Makefile");
+    std::fs::write(&file, b"all:\n\tcargo build\n").unwrap();
+    let mtime = 1_600_000_000;
+    filetime::set_file_mtime(&file, filetime::FileTime::from_unix_time(mtime, 0)).unwrap();
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![FileInfo {
+            modified: Some(mtime as u64),
+            ..flat_file_info(&file)
+        }],
+        save_dir.clone(),
+        PairConfig::default(),
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    let meta = std::fs::metadata(save_dir.join("Makefile")).unwrap();
+    let received_mtime = filetime::FileTime::from_last_modification_time(&meta);
+    assert!(
+        (received_mtime.unix_seconds() - mtime).abs() <= 1,
+        "received mtime {} differs from {mtime}",
+        received_mtime.unix_seconds()
+    );
+}
+
+/// Test: a script's executable bit survives the transfer.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_executable_bit_preserved() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("build.sh");
+    std::fs::write(&file, b"#!/bin/sh\ncargo build\n").unwrap();
+    std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![FileInfo {
+            mode: Some(0o755),
+            checksum: None,
+            ..flat_file_info(&file)
+        }],
+        save_dir.clone(),
+        PairConfig::default(),
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    let mode = std::fs::metadata(save_dir.join("build.sh"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o100, 0o100, "owner can't execute: {mode:o}");
+}
+
+/// Test: in tail mode, bytes appended to the file mid-transfer are sent too,
+/// until the sender stops the stream.
+#[tokio::test]
+async fn test_tail_mode_sends_appended_bytes() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("app.log");
+    let first: Vec<u8> = b"boot ok\n".iter().copied().cycle().take(300_000).collect();
+    std::fs::write(&file, &first).unwrap();
+
+    let stop = CancellationToken::new();
+    let appended = b"request served\n".repeat(20_000);
+    let writer = {
+        let (file, appended, stop) = (file.clone(), appended.clone(), stop.clone());
+        tokio::spawn(async move {
+            // Long after the first 300 KB have gone out.
+            tokio::time::sleep(Duration::from_millis(800)).await;
+            let mut log = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&file)
+                .unwrap();
+            std::io::Write::write_all(&mut log, &appended).unwrap();
+            drop(log);
+            tokio::time::sleep(Duration::from_millis(800)).await;
+            stop.cancel();
+        })
+    };
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        save_dir.clone(),
+        PairConfig {
+            send_options: SendOptions {
+                tail: Some(stop),
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    writer.await.unwrap();
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    let offer = received
+        .1
+        .iter()
+        .find_map(|e| match e {
+            ProgressEvent::FileOffer { files, .. } => Some(files[0].clone()),
+            _ => None,
+        })
+        .expect("no offer event");
+    assert!(offer.streaming);
+    assert_eq!(offer.size, first.len() as u64);
+    assert_eq!(
+        std::fs::read(save_dir.join("app.log")).unwrap(),
+        [first, appended].concat()
+    );
+}
+
+/// Test: when both peers dial each other at once, they settle on a single
+/// connection and the transfer goes through.
+#[tokio::test]
+async fn test_simultaneous_connect_transfers() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("both.bin");
+    std::fs::write(&file, vec![0x3Cu8; 400_000]).unwrap();
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        save_dir.clone(),
+        PairConfig {
+            both_dial: true,
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+    assert_eq!(
+        std::fs::read(save_dir.join("both.bin")).unwrap(),
+        std::fs::read(&file).unwrap()
+    );
+}
+
+/// Test: a receiver that registers its address is dialed by the sender as
+/// well, and both still end up on one direct connection.
+#[tokio::test]
+async fn test_both_advertising_peers_connect_directly() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+    let server = TestServer::start(&binary);
+    let (sender, receiver) =
+        connect_advertising(&server, ConnectionMode::Auto, ConnectionMode::Auto, true).await;
+    assert!(!sender.0.expect("sender failed to connect"));
+    assert!(!receiver.0.expect("receiver failed to connect"));
+}
+
+/// Test: cancelling while waiting for the peer returns promptly and frees the
+/// code on the server, so a new sender can take it.
+#[tokio::test]
+async fn test_cancel_during_wait_for_peer() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+
+    let server = TestServer::start(&binary);
+    let code = TransferCode::generate().to_code_string();
+
+    let cancel = CancellationToken::new();
+    let mut client = SignalingClient::connect(server.ws_url(), &code)
+        .await
+        .unwrap()
+        .with_cancel(cancel.clone());
+    client.register("sender", None).await.unwrap();
+
+    let canceller = cancel.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        canceller.cancel();
+    });
+
+    let started = std::time::Instant::now();
+    let result = tokio::time::timeout(Duration::from_secs(2), client.wait_for_peer(DEFAULT_PEER_TIMEOUT))
+        .await
+        .expect("wait_for_peer ignored cancellation");
+    assert!(matches!(result, Err(AppError::Cancelled)), "got {result:?}");
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    // The sender slot was released, so a fresh pair can use the same code.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut sender = SignalingClient::connect(server.ws_url(), &code)
+        .await
+        .unwrap();
+    sender.register("sender", None).await.unwrap();
+    let mut receiver = SignalingClient::connect(server.ws_url(), &code)
+        .await
+        .unwrap();
+    receiver.register("receiver", None).await.unwrap();
+
+    let (sender_peer, receiver_peer) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(
+            sender.wait_for_peer(DEFAULT_PEER_TIMEOUT),
+            receiver.wait_for_peer(DEFAULT_PEER_TIMEOUT)
+        )
+    })
+    .await
+    .expect("peers never paired");
+    sender_peer.expect("sender never saw receiver");
+    receiver_peer.expect("receiver never saw sender");
+}
+
+/// Test: transfers complete with a non-default congestion controller and a
+/// raised initial window.
+#[tokio::test]
+async fn test_custom_congestion_control() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("small.bin");
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 241) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+
+    for congestion in [CongestionControl::Bbr, CongestionControl::NewReno] {
+        let save_dir = temp.path().join(format!("out-{congestion:?}"));
+        let (sent, received) = run_direct_pair(
+            vec![file.clone()],
+            vec![flat_file_info(&file)],
+            save_dir.clone(),
+            PairConfig {
+                network: NetworkOptions {
+                    congestion,
+                    initial_window: Some(256 * 1024),
+                    ..NetworkOptions::default()
+                },
+                ..PairConfig::default()
+            },
+        )
+        .await;
+        sent.0.expect("send failed");
+        received.0.expect("receive failed");
+        assert_eq!(std::fs::read(save_dir.join("small.bin")).unwrap(), data);
+    }
+}
+
+/// Test: a direct transfer completes between two IPv6-only endpoints on `::1`.
+#[tokio::test]
+async fn test_direct_transfer_over_ipv6() {
+    if std::net::UdpSocket::bind("[::1]:0").is_err() {
+        eprintln!("SKIP: no IPv6 loopback");
+        return;
+    }
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("v6.bin");
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        save_dir.clone(),
+        PairConfig {
+            network: NetworkOptions {
+                family: AddressFamily::Ipv6,
+                ..NetworkOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+    assert_eq!(std::fs::read(save_dir.join("v6.bin")).unwrap(), data);
+}
+
+/// Test: with a challenge, an honest receiver passes and a receiver whose
+/// bytes differ from what was sent fails the sender's check.
+#[tokio::test]
+async fn test_challenge_detects_tampered_receiver() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("contract.pdf");
+    let data: Vec<u8> = (0..600_000u32).map(|i| (i % 239) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+    let challenge_options = || SendOptions {
+        challenge: true,
+        ..SendOptions::default()
+    };
+
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        temp.path().join("honest"),
+        PairConfig {
+            send_options: challenge_options(),
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("honest send failed");
+    received.0.expect("honest receive failed");
+
+    // A receiver that flips one byte of what it "wrote" but otherwise follows
+    // the protocol, acknowledging the file as verified.
+    let key = [0x42u8; 32];
+    let server_quic = QuicEndpoint::new(0).await.unwrap();
+    let connect_addr: SocketAddr =
+        format!("127.0.0.1:{}", server_quic.local_addr().unwrap().port())
+            .parse()
+            .unwrap();
+    let infos = vec![flat_file_info(&file)];
+    let client_quic = QuicEndpoint::new(0).await.unwrap();
+    let (server_fp, client_fp) = (
+        server_quic.cert_fingerprint(),
+        client_quic.cert_fingerprint(),
+    );
+    let sender = tokio::spawn(async move {
+        let conn = server_quic.accept_any(&client_fp).await.unwrap();
+        let (send, recv) = conn.open_bi().await.unwrap();
+        let mut transport = Transport::Direct {
+            send,
+            recv,
+            conn: None,
+        };
+        let (progress_tx, _progress_rx) = mpsc::unbounded_channel();
+        let result = relay_lib::transfer::sender::run_send(
+            vec![file],
+            infos,
+            &mut transport,
+            key,
+            progress_tx,
+            CancellationToken::new(),
+            challenge_options(),
+        )
+        .await;
+        (result, transport, conn, server_quic)
+    });
+
+    let conn = client_quic.connect(connect_addr, &server_fp).await.unwrap();
+    let (send, recv) = conn.accept_bi().await.unwrap();
+    let mut transport = Transport::Direct {
+        send,
+        recv,
+        conn: None,
+    };
+    handshake::exchange_hello(&mut transport).await.unwrap();
+    let nonce = match transport.recv_peer_message().await.unwrap() {
+        PeerMessage::FileOffer { challenge, .. } => challenge.expect("offer carries no challenge"),
+        other => panic!("expected FileOffer, got {other:?}"),
+    };
+    transport
+        .send_peer_message(&PeerMessage::FileAccept {
+            features: Default::default(),
+        })
+        .await
+        .unwrap();
+
+    let decryptor = ChunkDecryptor::new(&key).unwrap();
+    let mut mac = ChallengeMac::new(&nonce);
+    let mut tampered = false;
+    loop {
+        match transport.recv_peer_message().await.unwrap() {
+            PeerMessage::FileChunk {
+                file_index,
+                chunk_index,
+                data,
+                nonce,
+                compressed,
+            } => {
+                let mut plaintext = decryptor
+                    .decrypt_chunk(&data, &nonce, file_index, chunk_index, compressed)
+                    .unwrap();
+                if !tampered {
+                    plaintext[0] ^= 0xFF;
+                    tampered = true;
+                }
+                mac.update(&plaintext);
+            }
+            PeerMessage::FileComplete { file_index, .. } => {
+                transport
+                    .send_peer_message(&PeerMessage::ChallengeResponse {
+                        file_index,
+                        mac: mac.finalize(),
+                    })
+                    .await
+                    .unwrap();
+                transport
+                    .send_peer_message(&PeerMessage::FileVerified { file_index })
+                    .await
+                    .unwrap();
+                break;
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    match transport.recv_peer_message().await.unwrap() {
+        PeerMessage::Cancel { reason, .. } => assert_eq!(reason, CancelReason::ChecksumMismatch),
+        other => panic!("expected Cancel, got {other:?}"),
+    }
+    let (result, ..) = tokio::time::timeout(Duration::from_secs(10), sender)
+        .await
+        .expect("sender hung")
+        .unwrap();
+    assert!(
+        matches!(result, Err(AppError::ChecksumMismatch(_))),
+        "got {result:?}"
+    );
+}
+
+/// Test: a sender on a newer protocol version is turned away with a clean
+/// Cancel and a clear error instead of either side hanging.
+#[tokio::test]
+async fn test_protocol_version_mismatch_aborts() {
+    let temp = tempfile::tempdir().unwrap();
+    let save_dir = temp.path().join("received");
+    let server_quic = QuicEndpoint::new(0).await.unwrap();
+    let connect_addr: SocketAddr =
+        format!("127.0.0.1:{}", server_quic.local_addr().unwrap().port())
+            .parse()
+            .unwrap();
+    let client_quic = QuicEndpoint::new(0).await.unwrap();
+    let (server_fp, client_fp) = (
+        server_quic.cert_fingerprint(),
+        client_quic.cert_fingerprint(),
+    );
+    let receiver = tokio::spawn(async move {
+        let conn = client_quic.connect(connect_addr, &server_fp).await.unwrap();
+        let (send, recv) = conn.accept_bi().await.unwrap();
+        let mut transport = Transport::Direct {
+            send,
+            recv,
+            conn: None,
+        };
+        let (progress_tx, _progress_rx) = mpsc::unbounded_channel();
+        let (_accept_tx, accept_rx) = oneshot::channel();
+        let result = relay_lib::transfer::receiver::run_receive(
+            save_dir,
+            &mut transport,
+            [0x42u8; 32],
+            progress_tx,
+            accept_rx,
+            CancellationToken::new(),
+            ReceiveOptions::default(),
+        )
+        .await;
+        (result, transport, conn, client_quic)
+    });
+
+    // A sender from the future.
+    let conn = server_quic.accept_any(&client_fp).await.unwrap();
+    let (send, recv) = conn.open_bi().await.unwrap();
+    let mut transport = Transport::Direct {
+        send,
+        recv,
+        conn: None,
+    };
+    transport
+        .send_peer_message(&PeerMessage::Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            features: Vec::new(),
+        })
+        .await
+        .unwrap();
+    match transport.recv_peer_message().await.unwrap() {
+        PeerMessage::Hello {
+            protocol_version, ..
+        } => assert_eq!(protocol_version, PROTOCOL_VERSION),
+        other => panic!("expected Hello, got {other:?}"),
+    }
+    match transport.recv_peer_message().await.unwrap() {
+        PeerMessage::Cancel { reason, .. } => {
+            assert_eq!(reason, CancelReason::IncompatibleVersion)
+        }
+        other => panic!("expected Cancel, got {other:?}"),
+    }
+
+    let (result, ..) = tokio::time::timeout(Duration::from_secs(10), receiver)
+        .await
+        .expect("receiver hung")
+        .unwrap();
+    let expected = format!(
+        "peer uses protocol v{}, we use v{PROTOCOL_VERSION}",
+        PROTOCOL_VERSION + 1
+    );
+    assert!(
+        matches!(&result, Err(AppError::Transfer(msg)) if *msg == expected),
+        "got {result:?}"
+    );
+}
+
+/// Test: the sender opens the data stream whichever side dials the QUIC
+/// connection, so both setups transfer the same way.
+#[tokio::test]
+async fn test_stream_direction_follows_role() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("data.bin");
+    std::fs::write(&file, vec![3u8; 100_000]).unwrap();
+
+    for sender_dials in [false, true] {
+        let save_dir = temp.path().join(format!("out-{sender_dials}"));
+        let (sent, received) = run_direct_pair(
+            vec![file.clone()],
+            vec![flat_file_info(&file)],
+            save_dir.clone(),
+            PairConfig {
+                sender_dials,
+                ..PairConfig::default()
+            },
+        )
+        .await;
+        sent.0.expect("send failed");
+        received.0.expect("receive failed");
+        assert_eq!(
+            std::fs::read(save_dir.join("data.bin")).unwrap().len(),
+            100_000
+        );
+    }
+}
+
+/// Reports plenty of bytes but only a handful of inodes.
+#[derive(Debug)]
+struct FewInodesProbe;
+
+impl SpaceProbe for FewInodesProbe {
+    fn free_space(&self, _dir: &std::path::Path) -> std::io::Result<FsSpace> {
+        Ok(FsSpace {
+            free_bytes: u64::MAX,
+            free_inodes: Some(3),
+        })
+    }
+}
+
+/// Test: an offer with more files than free inodes is declined before the
+/// user is asked, and both sides learn which resource ran out.
+#[tokio::test]
+async fn test_inode_limited_receive_declined() {
+    let temp = tempfile::tempdir().unwrap();
+    let mut files = Vec::new();
+    let mut infos = Vec::new();
+    for i in 0..5 {
+        let path = temp.path().join(format!("small-{i}.txt"));
+        std::fs::write(&path, b"tiny").unwrap();
+        infos.push(flat_file_info(&path));
+        files.push(path);
+    }
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        files,
+        infos,
+        save_dir.clone(),
+        PairConfig {
+            recv_options: ReceiveOptions {
+                space_probe: Some(Arc::new(FewInodesProbe)),
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+
+    assert!(
+        matches!(
+            received.0,
+            Err(AppError::InsufficientSpace {
+                resource: SpaceResource::Inodes,
+                needed: 5,
+                available: 3,
+            })
+        ),
+        "{:?}",
+        received.0
+    );
+    assert!(
+        !received
+            .1
+            .iter()
+            .any(|e| matches!(e, ProgressEvent::FileOffer { .. })),
+        "user should not be asked about an offer that can't fit"
+    );
+    assert_peer_cancelled(&sent, CancelReason::DiskFull);
+    match &sent.0 {
+        Err(AppError::PeerCancelled { detail, .. }) => assert!(detail.contains("inodes")),
+        other => panic!("expected PeerCancelled, got {other:?}"),
+    }
+    assert!(!save_dir.exists());
+}
+
+/// Test: a sender whose code outlives its TTL with no peer gives up with
+/// `SessionExpired` and releases the code on the server.
+#[tokio::test]
+async fn test_code_expires_without_peer() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+
+    let server = TestServer::start(&binary);
+    let code = TransferCode::generate().to_code_string();
+
+    let mut sender = SignalingClient::connect(server.ws_url(), &code)
+        .await
+        .unwrap();
+    sender.register("sender", None).await.unwrap();
+
+    let started = std::time::Instant::now();
+    let deadline = tokio::time::Instant::now() + Duration::from_millis(300);
+    let result = sender.wait_for_peer_until(deadline).await;
+    assert!(
+        matches!(result, Err(AppError::SessionExpired)),
+        "got {result:?}"
+    );
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // A receiver arriving after expiry finds no sender waiting on the code.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut receiver = SignalingClient::connect(server.ws_url(), &code)
+        .await
+        .unwrap();
+    receiver.register("receiver", None).await.unwrap();
+    let joined = tokio::time::timeout(
+        Duration::from_millis(500),
+        receiver.wait_for_peer(DEFAULT_PEER_TIMEOUT),
+    )
+    .await;
+    assert!(joined.is_err(), "receiver paired with an expired sender");
+}
+
+/// Test: a receiver whose sender never shows up gives up after its timeout
+/// with `ConnectionTimeout`, and leaves the server.
+#[tokio::test]
+async fn test_wait_for_peer_times_out() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+
+    let server = TestServer::start(&binary);
+    let code = TransferCode::generate().to_code_string();
+
+    let mut receiver = SignalingClient::connect(server.ws_url(), &code)
+        .await
+        .unwrap();
+    receiver.register("receiver", None).await.unwrap();
+
+    let started = std::time::Instant::now();
+    let result = receiver.wait_for_peer(Duration::from_millis(300)).await;
+    assert!(
+        matches!(result, Err(AppError::ConnectionTimeout)),
+        "got {result:?}"
+    );
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // The connection is closed, so a sender arriving now waits alone.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut sender = SignalingClient::connect(server.ws_url(), &code)
+        .await
+        .unwrap();
+    sender.register("sender", None).await.unwrap();
+    let joined = sender.wait_for_peer(Duration::from_millis(500)).await;
+    assert!(
+        matches!(joined, Err(AppError::ConnectionTimeout)),
+        "sender paired with a receiver that gave up: {joined:?}"
+    );
+}
+
+/// Test: a sender and receiver registered on two federated servers pair up,
+/// exchange keys, and transfer a file over the relay bridged between them.
+#[tokio::test]
+async fn test_federated_servers_relay() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+
+    let (port_a, port_b) = (base_port(), base_port() + 1);
+    let peer_a = format!("ws://127.0.0.1:{port_a}");
+    let peer_b = format!("ws://127.0.0.1:{port_b}");
+    let federate = |peer: &str| -> Vec<String> {
+        vec![
+            "-federation-peers".into(),
+            peer.into(),
+            "-federation-secret".into(),
+            "e2e-secret".into(),
+        ]
+    };
+    let args_a = federate(&peer_b);
+    let args_b = federate(&peer_a);
+    let server_a = TestServer::start_on(
+        &binary,
+        port_a,
+        &args_a.iter().map(String::as_str).collect::<Vec<_>>(),
+    );
+    let server_b = TestServer::start_on(
+        &binary,
+        port_b,
+        &args_b.iter().map(String::as_str).collect::<Vec<_>>(),
+    );
+    let code = TransferCode::generate().to_code_string();
+
+    let temp = tempfile::tempdir().unwrap();
+    let send_file = temp.path().join("federated.txt");
+    let test_data = "bridged between two relay servers\n".repeat(100);
+    std::fs::write(&send_file, &test_data).unwrap();
+    let save_dir = temp.path().join("out");
+
+    // Join the session on `url` and hand back a relayed transport and the key.
+    async fn join_relayed(url: String, code: String, role: &str) -> (Transport, [u8; 32]) {
+        let mut signaling = SignalingClient::connect(&url, &code).await.unwrap();
+        signaling.register(role, None).await.unwrap();
+        signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+
+        let kx = KeyExchange::new(&code);
+        let outbound = kx.outbound_message().to_vec();
+        let peer_msg = signaling.exchange_spake2(&outbound).await.unwrap();
+        let key = kx.finish(&peer_msg).unwrap();
+
+        signaling.request_relay().await.unwrap();
+        let transport = Transport::Relayed {
+            ws: RelayStream::new(signaling.into_ws()),
+        };
+        (transport, key)
+    }
+
+    let sender = {
+        let (url, code, info) = (
+            server_a.ws_url().to_string(),
+            code.clone(),
+            flat_file_info(&send_file),
+        );
+        let send_file = send_file.clone();
+        tokio::spawn(async move {
+            let (mut transport, key) = join_relayed(url, code, "sender").await;
+            let (progress_tx, _) = mpsc::unbounded_channel();
+            relay_lib::transfer::sender::run_send(
+                vec![send_file],
+                vec![info],
+                &mut transport,
+                key,
+                progress_tx,
+                CancellationToken::new(),
+                SendOptions::default(),
+            )
+            .await
+        })
+    };
+
+    // The sender must be waiting on A before the receiver looks it up from B.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let receiver = {
+        let (url, code, save_dir) = (
+            server_b.ws_url().to_string(),
+            code.clone(),
+            save_dir.clone(),
+        );
+        tokio::spawn(async move {
+            let (mut transport, key) = join_relayed(url, code, "receiver").await;
+            let (progress_tx, _) = mpsc::unbounded_channel();
+            let (accept_tx, accept_rx) = oneshot::channel();
+            accept_tx.send(true).unwrap();
+            relay_lib::transfer::receiver::run_receive(
+                save_dir,
+                &mut transport,
+                key,
+                progress_tx,
+                accept_rx,
+                CancellationToken::new(),
+                ReceiveOptions::default(),
+            )
+            .await
+        })
+    };
+
+    let (sent, received) = tokio::time::timeout(Duration::from_secs(15), async {
+        (sender.await.unwrap(), receiver.await.unwrap())
+    })
+    .await
+    .expect("federated transfer timed out");
+    sent.expect("send failed");
+    received.expect("receive failed");
+    assert_eq!(
+        std::fs::read_to_string(save_dir.join("federated.txt")).unwrap(),
+        test_data
+    );
+}
+
+/// TCP proxy in front of the signaling server whose open connections can be
+/// cut on demand, to simulate a dropped WebSocket. New connections still work.
+struct CuttableProxy {
+    url: String,
+    cut: std::sync::Arc<std::sync::Mutex<CancellationToken>>,
+}
+
+impl CuttableProxy {
+    async fn start(upstream: &str) -> Self {
+        let upstream = upstream.trim_start_matches("ws://").to_string();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let cut = std::sync::Arc::new(std::sync::Mutex::new(CancellationToken::new()));
+        let current = cut.clone();
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let upstream = upstream.clone();
+                let token = current.lock().unwrap().clone();
+                tokio::spawn(async move {
+                    let mut server = tokio::net::TcpStream::connect(&upstream).await.unwrap();
+                    tokio::select! {
+                        _ = tokio::io::copy_bidirectional(&mut client, &mut server) => {}
+                        _ = token.cancelled() => {}
+                    }
+                });
+            }
+        });
+        Self { url, cut }
+    }
+
+    /// Drop every connection currently open through the proxy.
+    fn cut(&self) {
+        let mut token = self.cut.lock().unwrap();
+        token.cancel();
+        *token = CancellationToken::new();
+    }
+}
+
+/// Test: a signaling connection lost during the fingerprint exchange is
+/// re-established, and both sides still get each other's fingerprint.
+#[tokio::test]
+async fn test_signaling_reconnects_during_fingerprint_exchange() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+
+    let server = TestServer::start(&binary);
+    let proxy = CuttableProxy::start(server.ws_url()).await;
+    let code = TransferCode::generate().to_code_string();
+    let policy = ReconnectPolicy {
+        max_attempts: 2,
+        delay: Duration::from_millis(200),
+        ..ReconnectPolicy::default()
+    };
+
+    // Join, run SPAKE2, then exchange fingerprints once `go` fires.
+    async fn handshake(
+        url: String,
+        code: String,
+        role: &'static str,
+        policy: ReconnectPolicy,
+        fingerprint: [u8; 32],
+        keyed: oneshot::Sender<()>,
+        go: oneshot::Receiver<()>,
+    ) -> [u8; 32] {
+        let mut signaling = SignalingClient::connect(&url, &code)
+            .await
+            .unwrap()
+            .with_reconnect(policy);
+        signaling.register(role, None).await.unwrap();
+        signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+        let kx = KeyExchange::new(&code);
+        let outbound = kx.outbound_message().to_vec();
+        let peer_msg = signaling.exchange_spake2(&outbound).await.unwrap();
+        let key = kx.finish(&peer_msg).unwrap();
+        keyed.send(()).unwrap();
+        go.await.unwrap();
+        signaling
+            .exchange_cert_fingerprint(&fingerprint, &key)
+            .await
+            .unwrap()
+    }
+
+    let (sender_keyed, sender_keyed_rx) = oneshot::channel();
+    let (sender_go_tx, sender_go) = oneshot::channel();
+    let sender = tokio::spawn(handshake(
+        server.ws_url().to_string(),
+        code.clone(),
+        "sender",
+        policy,
+        [0xAA; 32],
+        sender_keyed,
+        sender_go,
+    ));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let (receiver_keyed, receiver_keyed_rx) = oneshot::channel();
+    let (receiver_go_tx, receiver_go) = oneshot::channel();
+    let receiver = tokio::spawn(handshake(
+        proxy.url.clone(),
+        code.clone(),
+        "receiver",
+        policy,
+        [0xBB; 32],
+        receiver_keyed,
+        receiver_go,
+    ));
+
+    sender_keyed_rx.await.unwrap();
+    receiver_keyed_rx.await.unwrap();
+    // The sender sends its fingerprint, then the receiver's link drops
+    // before it sends its own (and likely before the sender's arrives).
+    sender_go_tx.send(()).unwrap();
+    proxy.cut();
+    receiver_go_tx.send(()).unwrap();
+
+    let (sender_got, receiver_got) = tokio::time::timeout(Duration::from_secs(10), async {
+        (sender.await.unwrap(), receiver.await.unwrap())
+    })
+    .await
+    .expect("handshake did not recover");
+    assert_eq!(sender_got, [0xBB; 32]);
+    assert_eq!(receiver_got, [0xAA; 32]);
+}
+
+/// Test: the server goes away between connect and register; the client
+/// reconnects to its replacement, registers there, and pairs as usual.
+#[tokio::test]
+async fn test_signaling_reconnects_after_server_restart() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+
+    let server = TestServer::start(&binary);
+    let url = server.ws_url().to_string();
+    let code = TransferCode::generate().to_code_string();
+    let policy = ReconnectPolicy {
+        max_attempts: 4,
+        delay: Duration::from_millis(100),
+        ..ReconnectPolicy::default()
+    };
+
+    let mut sender = SignalingClient::connect(&url, &code)
+        .await
+        .unwrap()
+        .with_reconnect(policy);
+    drop(server);
+    let _server = TestServer::start(&binary);
+    sender.register("sender", None).await.unwrap();
+
+    let receiver = tokio::spawn({
+        let (url, code) = (url.clone(), code.clone());
+        async move {
+            let mut signaling = SignalingClient::connect(&url, &code).await.unwrap();
+            signaling.register("receiver", None).await.unwrap();
+            signaling.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+            let kx = KeyExchange::new(&code);
+            let peer_msg = signaling
+                .exchange_spake2(kx.outbound_message())
+                .await
+                .unwrap();
+            kx.finish(&peer_msg).unwrap()
+        }
+    });
+
+    let sender_key = tokio::time::timeout(Duration::from_secs(10), async {
+        sender.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+        let kx = KeyExchange::new(&code);
+        let peer_msg = sender.exchange_spake2(kx.outbound_message()).await.unwrap();
+        kx.finish(&peer_msg).unwrap()
+    })
+    .await
+    .expect("sender did not recover");
+    let receiver_key = receiver.await.unwrap();
+    assert_eq!(sender_key, receiver_key);
+}
+
+/// Collects every received file in memory, keyed by its would-be path.
+#[derive(Debug, Default)]
+struct MemorySinks {
+    files: Arc<std::sync::Mutex<std::collections::HashMap<PathBuf, Vec<u8>>>>,
+}
+
+struct MemorySink {
+    path: PathBuf,
+    files: Arc<std::sync::Mutex<std::collections::HashMap<PathBuf, Vec<u8>>>>,
+}
+
+impl ChunkSink for MemorySink {
+    fn write<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, std::io::Result<()>> {
+        self.files
+            .lock()
+            .unwrap()
+            .entry(self.path.clone())
+            .or_default()
+            .extend_from_slice(data);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl SinkFactory for MemorySinks {
+    fn open<'a>(
+        &'a self,
+        path: &'a std::path::Path,
+        _file: &'a FileInfo,
+    ) -> BoxFuture<'a, std::io::Result<Box<dyn ChunkSink>>> {
+        let sink = MemorySink {
+            path: path.to_path_buf(),
+            files: self.files.clone(),
+        };
+        Box::pin(async { Ok(Box::new(sink) as Box<dyn ChunkSink>) })
+    }
+}
+
+/// Test: a custom sink factory receives the decrypted bytes instead of the
+/// filesystem, and the content still passes checksum verification.
+#[tokio::test]
+async fn test_receive_into_custom_sink() {
+    let temp = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..600_000u32).map(|i| (i * 31 % 251) as u8).collect();
+    let path = temp.path().join("virtual.bin");
+    std::fs::write(&path, &data).unwrap();
+    let info = flat_file_info(&path);
+
+    let sinks = Arc::new(MemorySinks::default());
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![path],
+        vec![info],
+        save_dir.clone(),
+        PairConfig {
+            recv_options: ReceiveOptions {
+                sink_factory: Some(sinks.clone()),
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    let files = sinks.files.lock().unwrap();
+    let got = files
+        .get(&save_dir.join("virtual.bin"))
+        .expect("sink was not opened for the file");
+    assert_eq!(got.len(), data.len());
+    assert_eq!(Sha256::digest(got), Sha256::digest(&data));
+    assert!(received
+        .1
+        .iter()
+        .any(|e| matches!(e, ProgressEvent::FileCompleted { .. })));
+    assert!(!save_dir.exists(), "nothing should be written to disk");
+}
+
+/// Test: a verify-only receive decrypts and checks every file, but leaves
+/// nothing behind, not even the empty folders the sender asked for.
+#[tokio::test]
+async fn test_verify_only_receive_writes_nothing() {
+    let temp = tempfile::tempdir().unwrap();
+    let big = temp.path().join("big.bin");
+    let small = temp.path().join("small.txt");
+    std::fs::write(
+        &big,
+        (0..900_000u32).map(|i| (i % 241) as u8).collect::<Vec<_>>(),
+    )
+    .unwrap();
+    std::fs::write(&small, b"checked, then dropped").unwrap();
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![big.clone(), small.clone()],
+        vec![flat_file_info(&big), flat_file_info(&small)],
+        save_dir.clone(),
+        PairConfig {
+            send_options: SendOptions {
+                empty_dirs: vec!["empty".into()],
+                ..SendOptions::default()
+            },
+            recv_options: ReceiveOptions {
+                sink_factory: Some(Arc::new(VerifyOnly)),
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    let verified = received
+        .1
+        .iter()
+        .filter(|e| matches!(e, ProgressEvent::FileCompleted { .. }))
+        .count();
+    assert_eq!(verified, 2);
+    assert!(!save_dir.exists(), "nothing should be written to disk");
+}
+
+/// Rejects every staged receive, noting what it was shown.
+#[derive(Debug, Default)]
+struct RejectStaged {
+    seen: std::sync::Mutex<Vec<PathBuf>>,
+}
+
+impl StagingInspector for RejectStaged {
+    fn inspect<'a>(
+        &'a self,
+        staging_dir: &'a std::path::Path,
+        files: &'a [PathBuf],
+    ) -> BoxFuture<'a, bool> {
+        let mut seen = self.seen.lock().unwrap();
+        seen.extend(files.iter().map(|f| staging_dir.join(f)));
+        assert!(seen.iter().all(|f| f.exists()), "staged files missing");
+        Box::pin(async { false })
+    }
+}
+
+/// Test: a staged receive rejected at inspection is discarded, leaving the
+/// save directory empty.
+#[tokio::test]
+async fn test_staged_receive_rejected_at_inspection() {
+    let temp = tempfile::tempdir().unwrap();
+    let path = temp.path().join("suspect.exe");
+    std::fs::write(&path, vec![0x4Du8; 300_000]).unwrap();
+    let info = flat_file_info(&path);
+
+    let inspector = Arc::new(RejectStaged::default());
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![path],
+        vec![info],
+        save_dir.clone(),
+        PairConfig {
+            recv_options: ReceiveOptions {
+                inspector: Some(inspector.clone()),
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    let err = received.0.expect_err("rejected receive should fail");
+    assert!(
+        matches!(&err, AppError::Transfer(msg) if msg.contains("rejected at inspection")),
+        "unexpected error: {err}"
+    );
+
+    let seen = inspector.seen.lock().unwrap();
+    assert_eq!(seen.len(), 1);
+    assert!(seen[0].ends_with("suspect.exe"));
+    assert!(received.1.iter().any(
+        |e| matches!(e, ProgressEvent::StagingReady { files, .. } if files == &["suspect.exe"])
+    ));
+    let leftovers = std::fs::read_dir(&save_dir)
+        .map(|entries| entries.count())
+        .unwrap_or(0);
+    assert_eq!(leftovers, 0, "save directory should stay empty");
+}
+
+/// Approves every staged receive.
+#[derive(Debug)]
+struct ApproveStaged;
+
+impl StagingInspector for ApproveStaged {
+    fn inspect<'a>(
+        &'a self,
+        _staging_dir: &'a std::path::Path,
+        _files: &'a [PathBuf],
+    ) -> BoxFuture<'a, bool> {
+        Box::pin(async { true })
+    }
+}
+
+/// Notes each file it's asked to open.
+#[derive(Debug, Default)]
+struct RecordingOpener(std::sync::Mutex<Vec<PathBuf>>);
+
+impl Opener for RecordingOpener {
+    fn reveal(&self, _path: &std::path::Path) -> AppResult<()> {
+        Ok(())
+    }
+
+    fn open(&self, path: &std::path::Path) -> AppResult<()> {
+        self.0.lock().unwrap().push(path.to_path_buf());
+        Ok(())
+    }
+}
+
+/// Test: a staged receive with a destination for its one file moves it
+/// there once approved, and then opens it from there.
+#[tokio::test]
+async fn test_staged_receive_promoted_to_destination_and_opened() {
+    let temp = tempfile::tempdir().unwrap();
+    let path = temp.path().join("report.pdf");
+    std::fs::write(&path, b"%PDF-1.7").unwrap();
+    let info = flat_file_info(&path);
+
+    let save_dir = temp.path().join("out");
+    let documents = temp.path().join("Documents");
+    let destinations = Destinations::default();
+    destinations.set("report.pdf", &documents).unwrap();
+    let opener = Arc::new(RecordingOpener::default());
+    let (sent, received) = run_direct_pair(
+        vec![path],
+        vec![info],
+        save_dir.clone(),
+        PairConfig {
+            recv_options: ReceiveOptions {
+                inspector: Some(Arc::new(ApproveStaged)),
+                destinations,
+                on_complete: OnCompleteAction::Open,
+                opener: Some(opener.clone()),
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    let saved = documents.join("report.pdf");
+    assert_eq!(std::fs::read(&saved).unwrap(), b"%PDF-1.7");
+    assert!(!save_dir.join("report.pdf").exists());
+    assert_eq!(
+        *opener.0.lock().unwrap(),
+        vec![saved.canonicalize().unwrap()]
+    );
+}
+
+/// Test: an oversized SPAKE2 message from the peer is rejected with a crypto
+/// error before it ever reaches `KeyExchange::finish`.
+#[tokio::test]
+async fn test_oversized_spake2_rejected() {
+    let binary = match find_server_binary() {
+        Some(b) => b,
+        None => {
+            eprintln!("SKIP: Go signaling server binary not found");
+            return;
+        }
+    };
+
+    let server = TestServer::start(&binary);
+    let code = TransferCode::generate().to_code_string();
+
+    let ws_url = server.ws_url().to_string();
+    let code_s = code.clone();
+    let hostile = tokio::spawn(async move {
+        let mut client = SignalingClient::connect(&ws_url, &code_s).await.unwrap();
+        client.register("sender", None).await.unwrap();
+        let _peer = client.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+        // Far larger than any SPAKE2 message; the reply never comes.
+        let _ = tokio::time::timeout(
+            Duration::from_secs(2),
+            client.exchange_spake2(&[0x5A; 4096]),
+        )
+        .await;
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = SignalingClient::connect(server.ws_url(), &code)
+        .await
+        .unwrap();
+    client.register("receiver", None).await.unwrap();
+    let _peer = client.wait_for_peer(DEFAULT_PEER_TIMEOUT).await.unwrap();
+    let kx = KeyExchange::new(&code);
+    let result = client.exchange_spake2(kx.outbound_message()).await;
+    match result {
+        Err(AppError::Crypto(msg)) => assert!(msg.contains("too large"), "{msg}"),
+        other => panic!("expected Crypto error, got {other:?}"),
+    }
+    hostile.abort();
+}
+
+/// Test: a relayed send paused mid-transfer makes no progress while paused,
+/// keeps the connection alive, and completes once resumed.
+#[tokio::test]
+async fn test_pause_mid_transfer_then_resume() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("call-recording.wav");
+    let data: Vec<u8> = (0..2_000_000u32).map(|i| (i % 229) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+    let save_dir = tempfile::tempdir().unwrap();
+
+    let (mut send_transport, mut recv_transport) = delayed_relay_pair(Duration::ZERO).await;
+    let options = SendOptions {
+        max_bytes_per_sec: Some(1_000_000),
+        ..SendOptions::default()
+    };
+    let pause = options.pause.clone();
+    let key = [0x42u8; 32];
+    let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+    let (recv_progress_tx, _recv_progress_rx) = mpsc::unbounded_channel();
+    let (accept_tx, accept_rx) = oneshot::channel();
+    accept_tx.send(true).unwrap();
+
+    // Pause as soon as data flows, then watch progress for a while.
+    let controller = async {
+        let mut sent = 0;
+        while let Some(event) = progress_rx.recv().await {
+            if let ProgressEvent::TransferProgress {
+                bytes_transferred, ..
+            } = event
+            {
+                sent = bytes_transferred;
+                break;
+            }
+        }
+        pause.pause(PauseReason::User);
+        let window = tokio::time::Instant::now() + Duration::from_millis(2000);
+        // Whatever chunk was already under way may still land, once the rate
+        // limit lets it.
+        let mut settled = None;
+        loop {
+            tokio::select! {
+                event = progress_rx.recv() => {
+                    if let Some(ProgressEvent::TransferProgress { bytes_transferred, .. }) = event {
+                        sent = bytes_transferred;
+                    }
+                }
+                _ = tokio::time::sleep(Duration::from_millis(600)), if settled.is_none() => {
+                    settled = Some(sent);
+                }
+                _ = tokio::time::sleep_until(window) => break,
+            }
+        }
+        assert_eq!(Some(sent), settled, "progress while paused");
+        assert!(sent < data.len() as u64);
+        pause.resume(PauseReason::User);
+        while progress_rx.recv().await.is_some() {}
+    };
+
+    let (sent, received, ()) = tokio::time::timeout(Duration::from_secs(15), async {
+        tokio::join!(
+            relay_lib::transfer::sender::run_send(
+                vec![file.clone()],
+                vec![flat_file_info(&file)],
+                &mut send_transport,
+                key,
+                progress_tx,
+                CancellationToken::new(),
+                options.clone(),
+            ),
+            relay_lib::transfer::receiver::run_receive(
+                save_dir.path().to_path_buf(),
+                &mut recv_transport,
+                key,
+                recv_progress_tx,
+                accept_rx,
+                CancellationToken::new(),
+                ReceiveOptions::default(),
+            ),
+            controller
+        )
+    })
+    .await
+    .expect("paused transfer never finished");
+    sent.expect("send failed");
+    received.expect("receive failed");
+    assert_eq!(
+        std::fs::read(save_dir.path().join("call-recording.wav")).unwrap(),
+        data
+    );
+}
+
+/// Test: while the receiver takes its time over the offer, the sender's
+/// keepalive pings stop a relay that reaps idle connections from dropping
+/// the transfer; without them it does drop.
+#[tokio::test]
+async fn test_keepalive_holds_idle_relay_open() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("minutes.txt");
+    let data = b"Minutes of the meeting, awaiting approval.\n".repeat(100);
+    std::fs::write(&file, &data).unwrap();
+    let reap_after = Duration::from_millis(400);
+
+    for (interval, survives) in [(reap_after / 4, true), (Duration::from_secs(60), false)] {
+        let save_dir = tempfile::tempdir().unwrap();
+        let (mut send_transport, mut recv_transport) = reaping_relay_pair(reap_after).await;
+        let options = SendOptions {
+            keepalive_interval: Some(interval),
+            ..SendOptions::default()
+        };
+        let key = [0x42u8; 32];
+        let (progress_tx, _progress_rx) = mpsc::unbounded_channel();
+        let (recv_progress_tx, _recv_progress_rx) = mpsc::unbounded_channel();
+        let (accept_tx, accept_rx) = oneshot::channel();
+        // The user reads the offer for three reaping periods.
+        tokio::spawn(async move {
+            tokio::time::sleep(reap_after * 3).await;
+            accept_tx.send(true).ok();
+        });
+
+        let (sent, received) = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::join!(
+                relay_lib::transfer::sender::run_send(
+                    vec![file.clone()],
+                    vec![flat_file_info(&file)],
+                    &mut send_transport,
+                    key,
+                    progress_tx,
+                    CancellationToken::new(),
+                    options,
+                ),
+                relay_lib::transfer::receiver::run_receive(
+                    save_dir.path().to_path_buf(),
+                    &mut recv_transport,
+                    key,
+                    recv_progress_tx,
+                    accept_rx,
+                    CancellationToken::new(),
+                    ReceiveOptions::default(),
+                ),
+            )
+        })
+        .await
+        .expect("transfer hung");
+        if survives {
+            sent.expect("send failed");
+            received.expect("receive failed");
+            assert_eq!(
+                std::fs::read(save_dir.path().join("minutes.txt")).unwrap(),
+                data
+            );
+        } else {
+            assert!(sent.is_err(), "idle relay should have been reaped");
+        }
+    }
+}
+
+/// Test: a transfer throttled far below what the file needs hits each side's
+/// total time cap, and the receiver removes the partial file.
+#[tokio::test]
+async fn test_max_total_duration_aborts_slow_transfer() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("big.bin");
+    std::fs::write(&file, vec![0xA5u8; 8 * 1024 * 1024]).unwrap();
+
+    // Let the receiver read for a moment every 100ms.
+    let options = ReceiveOptions {
+        max_total_duration: Some(Duration::from_millis(400)),
+        ..ReceiveOptions::default()
+    };
+    let pause = options.pause.clone();
+    let done = CancellationToken::new();
+    let throttle = {
+        let done = done.clone();
+        tokio::spawn(async move {
+            while !done.is_cancelled() {
+                pause.pause(PauseReason::User);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                pause.resume(PauseReason::User);
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+        })
+    };
+
+    let save_dir = temp.path().join("out");
+    let started = std::time::Instant::now();
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        save_dir.clone(),
+        PairConfig {
+            recv_options: options,
+            // The sender never reads mid-file, so it needs a cap of its own
+            // to stop pushing into a receiver that has given up.
+            send_options: SendOptions {
+                max_total_duration: Some(Duration::from_secs(1)),
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    done.cancel();
+    throttle.await.unwrap();
+
+    assert!(
+        matches!(received.0, Err(AppError::ConnectionTimeout)),
+        "{:?}",
+        received.0
+    );
+    assert!(
+        matches!(sent.0, Err(AppError::ConnectionTimeout)),
+        "{:?}",
+        sent.0
+    );
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert!(
+        !save_dir.join("big.bin").exists(),
+        "partial file should be removed"
+    );
+}
+
+/// Test: when the sender's side of the connection freezes mid-file, both
+/// sides give up after their stall timeout rather than waiting forever, and
+/// the receiver removes the partial file.
+#[tokio::test]
+async fn test_stalled_transfer_times_out() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("backup.tar");
+    std::fs::write(&file, vec![0x5Au8; 8 * 1024 * 1024]).unwrap();
+    let save_dir = tempfile::tempdir().unwrap();
+
+    // Enough for the hello, the offer and the first few chunks.
+    let (mut send_transport, mut recv_transport) = freezing_relay_pair(1024 * 1024).await;
+    let stall = Some(Duration::from_millis(500));
+    let key = [0x42u8; 32];
+    let (progress_tx, _progress_rx) = mpsc::unbounded_channel();
+    let (recv_progress_tx, mut recv_progress_rx) = mpsc::unbounded_channel();
+    let (accept_tx, accept_rx) = oneshot::channel();
+    accept_tx.send(true).unwrap();
+
+    let started = std::time::Instant::now();
+    let (sent, received) = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::join!(
+            relay_lib::transfer::sender::run_send(
+                vec![file.clone()],
+                vec![flat_file_info(&file)],
+                &mut send_transport,
+                key,
+                progress_tx,
+                CancellationToken::new(),
+                SendOptions {
+                    stall_timeout: stall,
+                    ..SendOptions::default()
+                },
+            ),
+            relay_lib::transfer::receiver::run_receive(
+                save_dir.path().to_path_buf(),
+                &mut recv_transport,
+                key,
+                recv_progress_tx,
+                accept_rx,
+                CancellationToken::new(),
+                ReceiveOptions {
+                    stall_timeout: stall,
+                    ..ReceiveOptions::default()
+                },
+            ),
+        )
+    })
+    .await
+    .expect("stalled transfer hung");
+
+    assert!(
+        matches!(received, Err(AppError::ConnectionTimeout)),
+        "{received:?}"
+    );
+    assert!(sent.is_err(), "sender should have given up");
+    assert!(started.elapsed() < Duration::from_secs(5));
+    let mut got_data = false;
+    while let Ok(event) = recv_progress_rx.try_recv() {
+        got_data |= matches!(event, ProgressEvent::TransferProgress { bytes_transferred, .. } if bytes_transferred > 0);
+    }
+    assert!(got_data, "the stall should come mid-file");
+    assert!(
+        !save_dir.path().join("backup.tar").exists(),
+        "partial file should be removed"
+    );
+}
+
+/// Test: cancelling a receive that is still waiting on the user to accept
+/// stops it, and the cleanup that follows waits for it and leaves nothing.
+#[tokio::test]
+async fn test_cancel_and_cleanup_during_accept_wait() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("offer.bin");
+    std::fs::write(&file, vec![5u8; 300_000]).unwrap();
+    let save_dir = temp.path().join("out");
+    std::fs::create_dir_all(&save_dir).unwrap();
+
+    let partials = PartialFiles::default();
+    let recv_cancel = CancellationToken::new();
+    let cleanup = tokio::spawn({
+        let (partials, recv_cancel) = (partials.clone(), recv_cancel.clone());
+        async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            recv_cancel.cancel();
+            partials.clean_up().await
+        }
+    });
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        save_dir.clone(),
+        PairConfig {
+            recv_cancel,
+            recv_options: ReceiveOptions {
+                partials,
+                inspector: Some(Arc::new(RejectStaged::default())),
+                ..ReceiveOptions::default()
+            },
+            hold_accept: true,
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    assert!(
+        matches!(received.0, Err(AppError::Cancelled)),
+        "{:?}",
+        received.0
+    );
+    assert!(
+        matches!(sent.0, Err(AppError::PeerRejected)),
+        "{:?}",
+        sent.0
+    );
+    let removed = tokio::time::timeout(Duration::from_secs(5), cleanup)
+        .await
+        .expect("cleanup hung")
+        .unwrap();
+    assert_eq!(
+        removed, 0,
+        "nothing is written before the offer is accepted"
+    );
+    assert_eq!(std::fs::read_dir(&save_dir).unwrap().count(), 0);
+}
+
+/// Test: a sender cancelling while its offer is still unanswered tells the
+/// receiver, whose prompt ends at once instead of waiting on a gone peer.
+#[tokio::test]
+async fn test_sender_cancel_during_accept_wait() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("offer.bin");
+    std::fs::write(&file, vec![5u8; 300_000]).unwrap();
+
+    let send_cancel = CancellationToken::new();
+    tokio::spawn({
+        let send_cancel = send_cancel.clone();
+        async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            send_cancel.cancel();
+        }
+    });
+    let (sent, received) = tokio::time::timeout(
+        Duration::from_secs(10),
+        run_direct_pair(
+            vec![file.clone()],
+            vec![flat_file_info(&file)],
+            temp.path().join("out"),
+            PairConfig {
+                send_cancel,
+                hold_accept: true,
+                ..PairConfig::default()
+            },
+        ),
+    )
+    .await
+    .expect("a side hung");
+    assert!(matches!(sent.0, Err(AppError::Cancelled)), "{:?}", sent.0);
+    assert!(
+        matches!(
+            received.0,
+            Err(AppError::PeerCancelled {
+                reason: CancelReason::UserCancelled,
+                ..
+            })
+        ),
+        "{:?}",
+        received.0
+    );
+}
+
+/// Test: a sender whose offer goes unanswered gives up after its accept
+/// timeout and tells the receiver, instead of holding the connection.
+#[tokio::test]
+async fn test_unanswered_offer_times_out() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("offer.bin");
+    std::fs::write(&file, vec![5u8; 300_000]).unwrap();
+
+    let (sent, received) = tokio::time::timeout(
+        Duration::from_secs(10),
+        run_direct_pair(
+            vec![file.clone()],
+            vec![flat_file_info(&file)],
+            temp.path().join("out"),
+            PairConfig {
+                send_options: SendOptions {
+                    accept_timeout: Some(Duration::from_millis(300)),
+                    ..SendOptions::default()
+                },
+                hold_accept: true,
+                ..PairConfig::default()
+            },
+        ),
+    )
+    .await
+    .expect("a side hung");
+    assert!(
+        matches!(sent.0, Err(AppError::ConnectionTimeout)),
+        "{:?}",
+        sent.0
+    );
+    assert!(
+        matches!(
+            received.0,
+            Err(AppError::PeerCancelled {
+                reason: CancelReason::Timeout,
+                ..
+            })
+        ),
+        "{:?}",
+        received.0
+    );
+}
+
+/// Test: a receive interrupted halfway resumes from its sidecar, and the
+/// second attempt only sends the bytes the receiver didn't have.
+#[tokio::test]
+async fn test_resume_sends_only_remaining_bytes() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("large.bin");
+    let data: Vec<u8> = (0..4_000_000u32).map(|i| (i % 241) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+    let save_dir = temp.path().join("out");
+    let sidecar = resume::sidecar_path(&save_dir.join("large.bin"));
+    let resume_options = || ReceiveOptions {
+        resume: true,
+        // Record progress after every chunk.
+        flush_policy: FlushPolicy {
+            every_bytes: Some(1),
+            every: None,
+        },
+        ..ReceiveOptions::default()
+    };
+
+    // First attempt: let the receiver read in short bursts and drop the
+    // connection once about half the file is on disk.
+    let options = resume_options();
+    let pause = options.pause.clone();
+    let (send_cancel, recv_cancel) = (CancellationToken::new(), CancellationToken::new());
+    let interrupt = {
+        let (send_cancel, recv_cancel) = (send_cancel.clone(), recv_cancel.clone());
+        let sidecar = sidecar.clone();
+        tokio::spawn(async move {
+            loop {
+                pause.resume(PauseReason::User);
+                tokio::time::sleep(Duration::from_millis(2)).await;
+                pause.pause(PauseReason::User);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                let state = std::fs::read(&sidecar)
+                    .ok()
+                    .and_then(|json| serde_json::from_slice::<ResumeState>(&json).ok());
+                if state.is_some_and(|s| s.bytes_written >= 2_000_000) {
+                    break;
+                }
+            }
+            recv_cancel.cancel();
+            send_cancel.cancel();
+            pause.resume(PauseReason::User);
+        })
+    };
+    let (_, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        save_dir.clone(),
+        PairConfig {
+            send_cancel,
+            recv_cancel,
+            recv_options: options,
+            // The sender may be stuck pushing into the stopped receiver.
+            send_options: SendOptions {
+                max_total_duration: Some(Duration::from_secs(2)),
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    interrupt.await.unwrap();
+    assert!(
+        matches!(received.0, Err(AppError::Cancelled)),
+        "{:?}",
+        received.0
+    );
+
+    let state: ResumeState = serde_json::from_slice(&std::fs::read(&sidecar).unwrap()).unwrap();
+    assert!(state.bytes_written > 0 && state.bytes_written < data.len() as u64);
+
+    // Second attempt picks up where the first left off.
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        save_dir.clone(),
+        PairConfig {
+            recv_options: resume_options(),
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("resumed send failed");
+    received.0.expect("resumed receive failed");
+
+    let resent = received
+        .1
+        .iter()
+        .filter_map(|e| match e {
+            ProgressEvent::TransferProgress {
+                bytes_transferred, ..
+            } => Some(*bytes_transferred),
+            _ => None,
+        })
+        .max()
+        .unwrap();
+    assert_eq!(resent, data.len() as u64 - state.bytes_written);
+    assert_eq!(std::fs::read(save_dir.join("large.bin")).unwrap(), data);
+    assert!(!sidecar.exists(), "sidecar should be removed once complete");
+}
+
+/// Test: a pre-hashed offer carries each file's checksum, and a resuming
+/// receiver checks a complete-looking partial against it: kept if it
+/// matches, received afresh if not.
+#[tokio::test]
+async fn test_pre_hashed_offer_checks_complete_partials() {
+    let temp = tempfile::tempdir().unwrap();
+    let files: Vec<PathBuf> = ["a.bin", "b.bin"]
+        .iter()
+        .map(|name| temp.path().join(name))
+        .collect();
+    let contents: Vec<Vec<u8>> = vec![
+        (0..300_000u32).map(|i| (i % 251) as u8).collect(),
+        (0..200_000u32).map(|i| (i % 239) as u8).collect(),
+    ];
+    for (file, data) in files.iter().zip(&contents) {
+        std::fs::write(file, data).unwrap();
+    }
+    let infos = || files.iter().map(|f| flat_file_info(f)).collect::<Vec<_>>();
+    let pre_hash = || SendOptions {
+        pre_hash: true,
+        ..SendOptions::default()
+    };
+
+    // The offer itself, seen by a receiver that then declines.
+    let (mut send_transport, mut recv_transport) = delayed_relay_pair(Duration::ZERO).await;
+    let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+    let sender = {
+        let (files, infos) = (files.clone(), infos());
+        tokio::spawn(async move {
+            relay_lib::transfer::sender::run_send(
+                files,
+                infos,
+                &mut send_transport,
+                [0x42u8; 32],
+                progress_tx,
+                CancellationToken::new(),
+                pre_hash(),
+            )
+            .await
+        })
+    };
+    handshake::exchange_hello(&mut recv_transport)
+        .await
+        .unwrap();
+    let offered = match recv_transport.recv_peer_message().await.unwrap() {
+        PeerMessage::FileOffer { files, .. } => files,
+        other => panic!("expected FileOffer, got {other:?}"),
+    };
+    let expected: Vec<[u8; 32]> = contents.iter().map(|d| Sha256::digest(d).into()).collect();
+    assert_eq!(
+        offered.iter().map(|f| f.checksum).collect::<Vec<_>>(),
+        expected.iter().copied().map(Some).collect::<Vec<_>>()
+    );
+    recv_transport
+        .send_peer_message(&PeerMessage::FileDecline)
+        .await
+        .unwrap();
+    assert!(matches!(sender.await.unwrap(), Err(AppError::PeerRejected)));
+    let mut hashed = 0;
+    while let Ok(event) = progress_rx.try_recv() {
+        if let ProgressEvent::Hashing { bytes_hashed, .. } = event {
+            hashed = bytes_hashed;
+        }
+    }
+    assert_eq!(hashed, 500_000);
+
+    // Both files fully on disk from an earlier receive; b.bin's got corrupted.
+    let save_dir = temp.path().join("out");
+    std::fs::create_dir_all(&save_dir).unwrap();
+    for (file, data) in files.iter().zip(&contents) {
+        let saved = save_dir.join(file.file_name().unwrap());
+        std::fs::write(&saved, data).unwrap();
+        let state = ResumeState {
+            size: data.len() as u64,
+            bytes_written: data.len() as u64,
+        };
+        resume::save(&saved, state).await.unwrap();
+    }
+    let mut corrupt = contents[1].clone();
+    corrupt[1000] ^= 0xFF;
+    std::fs::write(save_dir.join("b.bin"), &corrupt).unwrap();
+
+    let (sent, received) = run_direct_pair(
+        files.clone(),
+        infos(),
+        save_dir.clone(),
+        PairConfig {
+            send_options: pre_hash(),
+            recv_options: ReceiveOptions {
+                resume: true,
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("pre-hashed send failed");
+    received.0.expect("pre-hashed receive failed");
+
+    // Only the corrupted file was sent again.
+    let resent = received
+        .1
+        .iter()
+        .filter_map(|e| match e {
+            ProgressEvent::TransferProgress {
+                bytes_transferred, ..
+            } => Some(*bytes_transferred),
+            _ => None,
+        })
+        .max()
+        .unwrap();
+    assert_eq!(resent, contents[1].len() as u64);
+    for (file, data) in files.iter().zip(&contents) {
+        assert_eq!(
+            &std::fs::read(save_dir.join(file.file_name().unwrap())).unwrap(),
+            data
+        );
+    }
+}
+
+/// Test: both sides export an interrupted transfer's state under a
+/// passphrase, import it as new sessions, and finish the transfer with the
+/// original key, sending only what the receiver was missing.
+#[tokio::test]
+async fn test_exported_resume_state_completes_transfer() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("archive.tar");
+    let data: Vec<u8> = (0..1_500_000u32).map(|i| (i % 233) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+    let save_dir = temp.path().join("out");
+    std::fs::create_dir_all(&save_dir).unwrap();
+
+    // The first session got a key from SPAKE2 and half the file across.
+    let code = "7-guitar-palace";
+    let (sender_kx, receiver_kx) = (KeyExchange::new(code), KeyExchange::new(code));
+    let sender_msg = sender_kx.outbound_message().to_vec();
+    let key = sender_kx.finish(receiver_kx.outbound_message()).unwrap();
+    assert_eq!(receiver_kx.finish(&sender_msg).unwrap(), key);
+    let have = 512 * 1024;
+    let partial = save_dir.join("archive.tar");
+    std::fs::write(&partial, &data[..have]).unwrap();
+    resume::save(
+        &partial,
+        ResumeState {
+            size: data.len() as u64,
+            bytes_written: have as u64,
+        },
+    )
+    .await
+    .unwrap();
+
+    let states = ResumeStateDir::new(temp.path().join("states"));
+    let export = |target: ResumeTarget| {
+        let states = &states;
+        async move {
+            let state = portable::PortableState {
+                code: code.into(),
+                key,
+                target,
+            };
+            let id = uuid::Uuid::new_v4().to_string();
+            states
+                .export(&id, &state, "moving to the café")
+                .await
+                .unwrap()
+        }
+    };
+    let send_file = export(ResumeTarget::Send {
+        paths: vec![file.clone()],
+    })
+    .await;
+    let recv_file = export(ResumeTarget::Receive {
+        save_dir: save_dir.clone(),
+    })
+    .await;
+
+    // New sessions on the new network.
+    let sending = portable::import(&send_file, "moving to the café")
+        .await
+        .unwrap();
+    let receiving = portable::import(&recv_file, "moving to the café")
+        .await
+        .unwrap();
+    assert_eq!(
+        (sending.code.as_str(), receiving.code.as_str()),
+        (code, code)
+    );
+    let ResumeTarget::Send { paths } = sending.target else {
+        panic!("expected a send target");
+    };
+    let ResumeTarget::Receive { save_dir } = receiving.target else {
+        panic!("expected a receive target");
+    };
+    assert_eq!(sending.key, receiving.key);
+
+    let infos = paths.iter().map(|p| flat_file_info(p)).collect();
+    let (sent, received) = run_direct_pair(
+        paths,
+        infos,
+        save_dir.clone(),
+        PairConfig {
+            key: receiving.key,
+            recv_options: ReceiveOptions {
+                resume: true,
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("resumed send failed");
+    received.0.expect("resumed receive failed");
+
+    let resent = received
+        .1
+        .iter()
+        .filter_map(|e| match e {
+            ProgressEvent::TransferProgress {
+                bytes_transferred, ..
+            } => Some(*bytes_transferred),
+            _ => None,
+        })
+        .max()
+        .unwrap();
+    assert_eq!(resent, (data.len() - have) as u64);
+    assert_eq!(std::fs::read(&partial).unwrap(), data);
+}
+
+/// Test: metrics count each side's transfers, outcomes and bytes across a
+/// successful and a declined transfer.
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn test_metrics_count_transfers() {
+    use relay_lib::transfer::metrics::Metrics;
+
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("counted.bin");
+    std::fs::write(&file, vec![7u8; 300_000]).unwrap();
+
+    let metrics = Arc::new(Metrics::default());
+    let config = |accept| PairConfig {
+        send_options: SendOptions {
+            metrics: Some(metrics.clone()),
+            ..SendOptions::default()
+        },
+        recv_options: ReceiveOptions {
+            metrics: Some(metrics.clone()),
+            ..ReceiveOptions::default()
+        },
+        accept,
+        ..PairConfig::default()
+    };
+
+    let (sent, received) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        temp.path().join("out-1"),
+        config(true),
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+    let (sent, _) = run_direct_pair(
+        vec![file.clone()],
+        vec![flat_file_info(&file)],
+        temp.path().join("out-2"),
+        config(false),
+    )
+    .await;
+    assert!(sent.0.is_err());
+
+    let text = metrics.render();
+    for line in [
+        "relay_transfers_started_total{role=\"send\"} 2",
+        "relay_transfers_started_total{role=\"receive\"} 2",
+        "relay_transfers_completed_total{role=\"send\"} 1",
+        "relay_transfers_failed_total{role=\"send\"} 1",
+        "relay_transfer_bytes_total{role=\"send\"} 300000",
+        "relay_transfer_bytes_total{role=\"receive\"} 300000",
+        "relay_transfers_by_transport_total{transport=\"direct\"} 4",
+        "relay_transfers_relayed_ratio 0",
+    ] {
+        assert!(
+            text.lines().any(|l| l == line),
+            "missing {line:?} in\n{text}"
+        );
+    }
+}
+
+/// Test: with both ends on one machine the receiver copies each file off the
+/// sender's disk instead of receiving chunks, and still verifies it.
+#[tokio::test]
+async fn test_same_host_copies_locally() {
+    let temp = tempfile::tempdir().unwrap();
+    let big = temp.path().join("disk.img");
+    let small = temp.path().join("readme.txt");
+    let big_content: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&big, &big_content).unwrap();
+    std::fs::write(&small, b"same machine").unwrap();
+
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        vec![big.clone(), small.clone()],
+        vec![flat_file_info(&big), flat_file_info(&small)],
+        save_dir.clone(),
+        PairConfig {
+            send_options: SendOptions {
+                local_copy: true,
+                ..SendOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    assert_eq!(
+        std::fs::read(save_dir.join("disk.img")).unwrap(),
+        big_content
+    );
+    assert_eq!(
+        std::fs::read(save_dir.join("readme.txt")).unwrap(),
+        b"same machine"
+    );
+    let copied: Vec<&str> = received
+        .1
+        .iter()
+        .filter_map(|event| match event {
+            ProgressEvent::LocalCopy { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(copied, ["disk.img", "readme.txt"]);
+}
+
+/// Test: over a link that keeps stalling, reading chunks ahead keeps a slow
+/// source flowing through each stall where the serial path stops reading,
+/// so the pipelined send finishes well ahead; both arrive intact.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_send_window_beats_serial() {
+    use std::io::Write;
+
+    const SIZE: usize = 4 * 1024 * 1024;
+    const RATE: u64 = 4 * 1024 * 1024;
+    let temp = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..SIZE as u32).map(|i| (i % 253) as u8).collect();
+
+    let mut timings = Vec::new();
+    for send_window in [Some(0), None] {
+        // A pipe fed at `RATE` stands in for a slow disk: it falls behind
+        // whenever the sender stops reading from it.
+        let source = temp.path().join(format!("archive-{send_window:?}.bin"));
+        let fifo = std::ffi::CString::new(source.to_str().unwrap()).unwrap();
+        // SAFETY: `fifo` is a valid NUL-terminated path.
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        let feeder = {
+            let (source, data) = (source.clone(), data.clone());
+            std::thread::spawn(move || {
+                let mut pipe = std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(source)
+                    .unwrap();
+                for piece in data.chunks(64 * 1024) {
+                    std::thread::sleep(Duration::from_secs_f64(piece.len() as f64 / RATE as f64));
+                    pipe.write_all(piece).unwrap();
+                }
+            })
+        };
+        let info = FileInfo {
+            name: "archive.bin".into(),
+            size: SIZE as u64,
+            ..flat_file_info(temp.path())
+        };
+
+        let (mut send_transport, mut recv_transport) =
+            stalling_relay_pair(Duration::from_millis(300), Duration::from_millis(150)).await;
+        let save_dir = temp.path().join(format!("out-{send_window:?}"));
+        let (progress_tx, _progress_rx) = mpsc::unbounded_channel();
+        let (recv_progress_tx, _recv_progress_rx) = mpsc::unbounded_channel();
+        let (accept_tx, accept_rx) = oneshot::channel();
+        accept_tx.send(true).unwrap();
+        let started = std::time::Instant::now();
+        let (sent, received) = tokio::time::timeout(Duration::from_secs(60), async {
+            tokio::join!(
+                relay_lib::transfer::sender::run_send(
+                    vec![source.clone()],
+                    vec![info],
+                    &mut send_transport,
+                    [0x42u8; 32],
+                    progress_tx,
+                    CancellationToken::new(),
+                    SendOptions {
+                        send_window,
+                        ..SendOptions::default()
+                    },
+                ),
+                relay_lib::transfer::receiver::run_receive(
+                    save_dir.clone(),
+                    &mut recv_transport,
+                    [0x42u8; 32],
+                    recv_progress_tx,
+                    accept_rx,
+                    CancellationToken::new(),
+                    ReceiveOptions::default(),
+                )
+            )
+        })
+        .await
+        .expect("transfer timed out");
+        sent.expect("send failed");
+        received.expect("receive failed");
+        timings.push(started.elapsed());
+        feeder.join().unwrap();
+        assert!(std::fs::read(save_dir.join("archive.bin")).unwrap() == data);
+    }
+
+    // About 1.3s against 1.9s on loopback.
+    let [serial, pipelined] = timings[..] else {
+        unreachable!()
+    };
+    assert!(
+        pipelined.as_secs_f64() * 1.2 < serial.as_secs_f64(),
+        "serial {serial:?}, pipelined {pipelined:?}"
+    );
+}
+
+/// Test: a send preview lists exactly what the transfer then delivers, and
+/// names a path it can't find.
+#[tokio::test]
+async fn test_preview_matches_transfer() {
+    use relay_lib::commands::send::preview_send;
+
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("project");
+    std::fs::create_dir_all(root.join("src/nested/deeper")).unwrap();
+    std::fs::create_dir_all(root.join("empty")).unwrap();
+    std::fs::write(root.join("README.md"), "# Project\n").unwrap();
+    std::fs::write(root.join("src/main.rs"), "fn main() {}\n").unwrap();
+    std::fs::write(root.join("src/nested/data.bin"), vec![3u8; 70_000]).unwrap();
+    std::fs::write(root.join("src/nested/deeper/notes.txt"), "deep").unwrap();
+    std::fs::write(root.join(".DS_Store"), "junk").unwrap();
+    let single = temp.path().join("loose.txt");
+    std::fs::write(&single, "loose file").unwrap();
+
+    let inputs = vec![
+        root.to_string_lossy().to_string(),
+        single.to_string_lossy().to_string(),
+    ];
+    let preview = preview_send(inputs, None, None, None).await.unwrap();
+    assert_eq!(preview.file_count, 5);
+    assert_eq!(preview.files.len(), 5);
+    assert_eq!(preview.total_bytes, 10 + 13 + 70_000 + 4 + 10);
+    assert_eq!(preview.empty_dirs, ["project/empty"]);
+
+    let paths: Vec<PathBuf> = preview
+        .files
+        .iter()
+        .map(|info| match &info.relative_path {
+            Some(relative) => temp.path().join(relative),
+            None => single.clone(),
+        })
+        .collect();
+    let save_dir = temp.path().join("out");
+    let (sent, received) = run_direct_pair(
+        paths,
+        preview.files.clone(),
+        save_dir.clone(),
+        PairConfig::default(),
+    )
+    .await;
+    sent.0.unwrap();
+    received.0.unwrap();
+
+    let (mut count, mut bytes) = (0, 0);
+    let mut dirs = vec![save_dir];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let entry = entry.unwrap();
+            let meta = entry.metadata().unwrap();
+            if meta.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                count += 1;
+                bytes += meta.len();
+            }
+        }
+    }
+    assert_eq!((count, bytes), (preview.file_count, preview.total_bytes));
+
+    let missing = temp.path().join("missing.txt");
+    let err = preview_send(vec![missing.to_string_lossy().to_string()], None, None, None)
+        .await
+        .unwrap_err();
+    assert!(err.contains(&*missing.to_string_lossy()), "{err}");
+}
+
+/// Test: a receiver syncing into a folder that already holds some of the
+/// offered files is sent only the missing or changed ones.
+#[tokio::test]
+async fn test_skip_existing_sends_only_missing() {
+    let temp = tempfile::tempdir().unwrap();
+    let kept = temp.path().join("kept.bin");
+    let stale = temp.path().join("stale.bin");
+    let missing = temp.path().join("missing.bin");
+    std::fs::write(&kept, vec![1u8; 40_000]).unwrap();
+    std::fs::write(&stale, vec![2u8; 30_000]).unwrap();
+    std::fs::write(&missing, vec![3u8; 20_000]).unwrap();
+
+    let save_dir = temp.path().join("out");
+    std::fs::create_dir_all(&save_dir).unwrap();
+    std::fs::copy(&kept, save_dir.join("kept.bin")).unwrap();
+    // Same size, different content: sent again in full.
+    std::fs::write(save_dir.join("stale.bin"), vec![9u8; 30_000]).unwrap();
+
+    let files = vec![kept.clone(), stale.clone(), missing.clone()];
+    let infos = files.iter().map(|f| flat_file_info(f)).collect();
+    let (sent, received) = run_direct_pair(
+        files,
+        infos,
+        save_dir.clone(),
+        PairConfig {
+            recv_options: ReceiveOptions {
+                skip_existing: true,
+                ..ReceiveOptions::default()
+            },
+            ..PairConfig::default()
+        },
+    )
+    .await;
+    sent.0.expect("send failed");
+    received.0.expect("receive failed");
+
+    let sent_total = sent
+        .1
+        .iter()
+        .find_map(|e| match e {
+            ProgressEvent::TransferProgress { bytes_total, .. } => Some(*bytes_total),
+            _ => None,
+        })
+        .expect("no sender progress");
+    assert_eq!(sent_total, 50_000);
+    let skipped: Vec<&str> = received
+        .1
+        .iter()
+        .filter_map(|e| match e {
+            ProgressEvent::FileSkipped { path, .. } => Some(path.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(skipped, ["kept.bin"]);
+
+    for file in [&kept, &stale, &missing] {
+        let saved = save_dir.join(file.file_name().unwrap());
+        assert_eq!(std::fs::read(saved).unwrap(), std::fs::read(file).unwrap());
+    }
+}
+
+/// Test: without a chunk size set, direct transfers use larger chunks than
+/// relayed ones, and an explicit size overrides either.
+#[tokio::test]
+async fn test_chunk_size_follows_transport() {
+    let temp = tempfile::tempdir().unwrap();
+    let file = temp.path().join("video.mp4");
+    std::fs::write(&file, vec![4u8; 3 * 1024 * 1024]).unwrap();
+    let first_chunk = |events: &[ProgressEvent]| {
+        events.iter().find_map(|e| match e {
+            ProgressEvent::FileProgress { file_bytes, .. } => Some(*file_bytes),
+            _ => None,
+        })
+    };
+
+    for (chunk_size, expected) in [(None, 1024 * 1024), (Some(512 * 1024), 512 * 1024)] {
+        let (sent, received) = run_direct_pair(
+            vec![file.clone()],
+            vec![flat_file_info(&file)],
+            temp.path().join(format!("direct-{expected}")),
+            PairConfig {
+                send_options: SendOptions {
+                    chunk_size,
+                    ..SendOptions::default()
+                },
+                ..PairConfig::default()
+            },
+        )
+        .await;
+        received.0.expect("receive failed");
+        sent.0.expect("send failed");
+        assert_eq!(first_chunk(&sent.1), Some(expected), "direct");
+    }
+
+    let (mut send_transport, mut recv_transport) = delayed_relay_pair(Duration::ZERO).await;
+    let key = [0x42u8; 32];
+    let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+    let (recv_progress_tx, _recv_progress_rx) = mpsc::unbounded_channel();
+    let (accept_tx, accept_rx) = oneshot::channel();
+    accept_tx.send(true).unwrap();
+    let (sent, received) = tokio::join!(
+        relay_lib::transfer::sender::run_send(
+            vec![file.clone()],
+            vec![flat_file_info(&file)],
+            &mut send_transport,
+            key,
+            progress_tx,
+            CancellationToken::new(),
+            SendOptions::default(),
+        ),
+        relay_lib::transfer::receiver::run_receive(
+            temp.path().join("relayed"),
+            &mut recv_transport,
+            key,
+            recv_progress_tx,
+            accept_rx,
+            CancellationToken::new(),
+            ReceiveOptions::default(),
+        )
+    );
+    sent.expect("send failed");
+    received.expect("receive failed");
+    let mut events = Vec::new();
+    while let Ok(event) = progress_rx.try_recv() {
+        events.push(event);
+    }
+    assert_eq!(first_chunk(&events), Some(256 * 1024), "relayed");
+    assert_eq!(
+        std::fs::read(temp.path().join("relayed/video.mp4")).unwrap(),
+        std::fs::read(&file).unwrap()
+    );
+}
@@ -1,6 +1,10 @@
 import { createSignal, onMount, onCleanup, Switch, Match } from "solid-js";
 import { transfer, setTransfer, resetTransfer } from "./stores/transfer";
-import { onTransferProgress, type ProgressEvent } from "./lib/tauri-bridge";
+import {
+  onTransferProgress,
+  type CancelReason,
+  type ProgressEvent,
+} from "./lib/tauri-bridge";
 import SendView from "./components/SendView";
 import ReceiveView from "./components/ReceiveView";
 import TransferProgress from "./components/TransferProgress";
@@ -9,6 +13,15 @@ import ConnectionStatus from "./components/ConnectionStatus";
 import Settings from "./components/Settings";
 import "./styles/app.css";
 
+const peerCancelledMessage: Record<CancelReason, string> = {
+  user_cancelled: "The other side cancelled the transfer.",
+  declined: "The receiver declined the transfer.",
+  disk_full: "The receiver ran out of disk space.",
+  timeout: "The other side stopped responding.",
+  checksum_mismatch: "A file was corrupted in transit.",
+  error: "The other side hit an error and stopped the transfer.",
+};
+
 export default function App() {
   let unlisten: (() => void) | undefined;
   const [showSettings, setShowSettings] = createSignal(false);
@@ -59,6 +72,10 @@ export default function App() {
         setTransfer("phase", "error");
         setTransfer("error", event.message);
         break;
+      case "peerCancelled":
+        setTransfer("phase", "error");
+        setTransfer("error", peerCancelledMessage[event.reason]);
+        break;
       case "stateChanged":
         if (event.state === "connecting") {
           setTransfer("phase", "connecting");
@@ -13,11 +13,9 @@ export default function ReceiveView() {
     setTransfer("phase", "connecting");
     try {
       const dir = saveDir() || settings.defaultSaveDir || "/tmp/relay-received";
-      const sessionId = await startReceive(
-        code,
-        dir,
-        settings.signalServerUrl || undefined
-      );
+      const sessionId = await startReceive(code, dir, {
+        signalServerUrl: settings.signalServerUrl || undefined,
+      });
       setTransfer("sessionId", sessionId);
     } catch (e) {
       setTransfer("phase", "error");
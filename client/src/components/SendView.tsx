@@ -32,10 +32,9 @@ export default function SendView() {
   async function handleSend() {
     if (transfer.selectedFiles.length === 0) return;
     try {
-      const result = await startSend(
-        transfer.selectedFiles,
-        settings.signalServerUrl || undefined
-      );
+      const result = await startSend(transfer.selectedFiles, {
+        signalServerUrl: settings.signalServerUrl || undefined,
+      });
       setTransfer("code", result.code);
       setTransfer("sessionId", result.session_id);
       setTransfer("senderPort", result.port);
@@ -11,6 +11,10 @@ export interface FileOfferInfo {
   name: string;
   size: number;
   relativePath?: string;
+  duplicates?: string[];
+  mime_hint?: string;
+  /** Still growing on the sender's side; `size` is only where it started. */
+  streaming?: boolean;
 }
 
 export interface TransferProgress {
@@ -23,12 +27,41 @@ export interface TransferProgress {
   percent: number;
 }
 
+/** One file's progress; `file_bytes` includes any resumed prefix. */
+export interface FileProgressEvent {
+  type: "fileProgress";
+  file_index: number;
+  file_bytes: number;
+  file_total: number;
+  percent: number;
+}
+
+/** The sender checksumming its files before offering them. */
+export interface HashingEvent {
+  type: "hashing";
+  bytes_hashed: number;
+  bytes_total: number;
+  current_file: string;
+}
+
+/** Relay-path health, reported by the sender of a relayed transfer. */
+export interface ConnectionStatsEvent {
+  type: "connectionStats";
+  rtt_ms: number | null;
+  goodput_bps: number;
+  wire_bps: number;
+  stalls: number;
+  stalls_per_minute: number;
+}
+
 export interface TransferCompleteEvent {
   type: "transferComplete";
   duration_seconds: number;
   average_speed: number;
   total_bytes: number;
   file_count: number;
+  connection_type: "direct" | "relay";
+  peak_speed_bps: number;
 }
 
 export interface FileOfferEvent {
@@ -42,6 +75,12 @@ export interface FileCompletedEvent {
   name: string;
 }
 
+export interface LocalCopyEvent {
+  type: "localCopy";
+  name: string;
+  reflinked: boolean;
+}
+
 export interface ErrorEvent {
   type: "error";
   message: string;
@@ -57,48 +96,444 @@ export interface ConnectionTypeChangedEvent {
   connection_type: "direct" | "relay";
 }
 
+/** Connecting: a direct QUIC connection is being tried. */
+export interface AttemptingDirectEvent {
+  type: "attemptingDirect";
+}
+
+export interface DirectFailedEvent {
+  type: "directFailed";
+  reason: string;
+}
+
+export interface FallingBackToRelayEvent {
+  type: "fallingBackToRelay";
+}
+
+export interface PausedEvent {
+  type: "paused";
+  reason: "user" | "meteredNetwork";
+}
+
+export interface ResumedEvent {
+  type: "resumed";
+}
+
+export interface ExpiredEvent {
+  type: "expired";
+}
+
+/** Another sender holds the code; start the send again for a fresh one. */
+export interface CodeInUseEvent {
+  type: "codeInUse";
+}
+
+export type CancelReason =
+  | "user_cancelled"
+  | "declined"
+  | "disk_full"
+  | "timeout"
+  | "checksum_mismatch"
+  | "incompatible_version"
+  | "error";
+
+export interface PeerCancelledEvent {
+  type: "peerCancelled";
+  reason: CancelReason;
+  detail: string;
+}
+
+export interface TextReceivedEvent {
+  type: "textReceived";
+  name: string;
+  text: string;
+}
+
+export interface FileSkippedEvent {
+  type: "fileSkipped";
+  path: string;
+  reason: string;
+}
+
+export interface StagingReadyEvent {
+  type: "stagingReady";
+  staging_dir: string;
+  files: string[];
+}
+
 export type ProgressEvent =
   | TransferProgress
+  | FileProgressEvent
   | TransferCompleteEvent
   | FileOfferEvent
   | FileCompletedEvent
+  | LocalCopyEvent
+  | HashingEvent
   | ErrorEvent
   | StateChangedEvent
-  | ConnectionTypeChangedEvent;
+  | ConnectionTypeChangedEvent
+  | AttemptingDirectEvent
+  | DirectFailedEvent
+  | FallingBackToRelayEvent
+  | ConnectionStatsEvent
+  | PausedEvent
+  | ResumedEvent
+  | ExpiredEvent
+  | CodeInUseEvent
+  | PeerCancelledEvent
+  | TextReceivedEvent
+  | FileSkippedEvent
+  | StagingReadyEvent;
+
+export interface PieceHashConfig {
+  piece_size: number;
+  algorithm: "sha1" | "sha256";
+}
+
+/** QUIC congestion tuning; see `NetworkOptions` in quic.rs before raising the window. */
+export interface NetworkOptions {
+  congestion?: "cubic" | "new_reno" | "bbr";
+  initial_window?: number;
+  /** Bytes in flight cap; raise for fast links with long round trips. */
+  max_window?: number;
+  /** Close a connection after this long without hearing from the peer; 0 never does. */
+  idle_timeout_ms?: number;
+  /** Ping a quiet connection this often; 0 sends no pings. */
+  keep_alive_ms?: number;
+  /** `host:port` of a STUN server to learn the sender's public address from. */
+  stun_server?: string;
+  /** IP versions to listen and dial on; `dual_stack` takes both. */
+  family?: "ipv4" | "dual_stack" | "ipv6";
+}
+
+/** What to do with received files once a receive succeeds. `open` opens a
+ * single file; several files are revealed in their folder instead. */
+export type OnCompleteAction = "none" | "reveal" | "open";
+
+/** What a receive does about a file already where a received one would be saved. */
+export type CollisionPolicy = "overwrite" | "rename" | "skip" | "fail";
+
+/** Which transports a transfer may use. `auto` tries a direct connection
+ * and falls back to the relay. */
+export type ConnectionMode = "auto" | "direct_only" | "relay_only";
+
+/** What folder expansion does with symbolic links inside a folder. */
+export type SymlinkPolicy = "skip" | "followFiles" | "preserveAsLink";
+
+/** Which entries inside folders are left out. Selected paths are always
+ * sent. */
+export interface HiddenFilter {
+  /** Send dotfiles and dot-folders too. Default false. */
+  includeHidden?: boolean;
+  /** Names always left out. Defaults to `.DS_Store`, `.git`, `Thumbs.db`
+   * and `__MACOSX`. */
+  ignore?: string[];
+}
+
+/** What `startSend` can be asked besides the paths; anything left out keeps
+ * its default. */
+export interface StartSendOptions {
+  signalServerUrl?: string;
+  /** Fallbacks tried in order after `signalServerUrl`; give the receiver the
+   * same servers in the same order. */
+  signalServerUrls?: string[];
+  dedupe?: boolean;
+  pieceHashes?: PieceHashConfig;
+  challenge?: boolean;
+  network?: NetworkOptions;
+  maxDepth?: number;
+  codeTtlSecs?: number;
+  maxBytesPerSec?: number;
+  /** Hex certificate fingerprints of the only receivers served. */
+  peerAllowlist?: string[];
+  preHash?: boolean;
+  batchSmallFiles?: boolean;
+  peerTimeoutSecs?: number;
+  localCopy?: boolean;
+  connectionMode?: ConnectionMode;
+  symlinks?: SymlinkPolicy;
+  acceptTimeoutSecs?: number;
+  parallelStreams?: number;
+  hidden?: HiddenFilter;
+  archive?: boolean;
+}
 
 export async function startSend(
   filePaths: string[],
-  signalServerUrl?: string
+  options?: StartSendOptions
 ): Promise<SendStarted> {
-  return invoke<SendStarted>("start_send", {
+  return invoke<SendStarted>("start_send", { filePaths, options });
+}
+
+/** One file a send would transfer, as `previewSend` lists it. */
+export interface PreviewFile {
+  name: string;
+  size: number;
+  relative_path?: string;
+  /** Unix seconds. */
+  modified?: number;
+  /** Set for a link sent as one: what it points at. */
+  link_target?: string;
+}
+
+export interface SendPreview {
+  files: PreviewFile[];
+  total_bytes: number;
+  file_count: number;
+  /** Folders left out for being nested deeper than `maxDepth`. */
+  skipped: string[];
+  empty_dirs: string[];
+}
+
+/** List what `startSend` would transfer, without connecting. */
+export async function previewSend(
+  filePaths: string[],
+  maxDepth?: number,
+  symlinks?: SymlinkPolicy,
+  hidden?: HiddenFilter
+): Promise<SendPreview> {
+  return invoke<SendPreview>("preview_send", {
     filePaths,
-    signalServerUrl,
+    maxDepth,
+    symlinks,
+    hidden,
   });
 }
 
+export async function sendText(
+  text: string,
+  signalServerUrl?: string
+): Promise<SendStarted> {
+  return invoke<SendStarted>("send_text", { text, signalServerUrl });
+}
+
+/** What `startReceive` can be asked besides the code and save directory;
+ * anything left out keeps its default. */
+export interface StartReceiveOptions {
+  signalServerUrl?: string;
+  /** Fallbacks tried in order after `signalServerUrl`, as for the sender. */
+  signalServerUrls?: string[];
+  flushIntervalMs?: number;
+  inlineText?: boolean;
+  network?: NetworkOptions;
+  inspectBeforeFinalize?: boolean;
+  onComplete?: OnCompleteAction;
+  resume?: boolean;
+  peerTimeoutSecs?: number;
+  connectionMode?: ConnectionMode;
+  skipExisting?: boolean;
+  onCollision?: CollisionPolicy;
+  maxTotalBytes?: number;
+  maxFileCount?: number;
+  /** Check the files and throw them away instead of saving them. */
+  verifyOnly?: boolean;
+}
+
 export async function startReceive(
   code: string,
   saveDir: string,
+  options?: StartReceiveOptions
+): Promise<string> {
+  return invoke<string>("start_receive", { code, saveDir, options });
+}
+
+/** Export a transfer's resume state, sealed under `passphrase`. Resolves to the file's path. */
+export async function exportResumeState(
+  sessionId: string,
+  passphrase: string
+): Promise<string> {
+  return invoke<string>("export_resume_state", { sessionId, passphrase });
+}
+
+/** Resume an exported transfer. Both peers import their own state. Resolves to the new session id. */
+export async function importResumeState(
+  file: string,
+  passphrase: string,
   signalServerUrl?: string
 ): Promise<string> {
-  return invoke<string>("start_receive", {
-    code,
-    saveDir,
+  return invoke<string>("import_resume_state", {
+    file,
+    passphrase,
     signalServerUrl,
   });
 }
 
+/**
+ * Accept or decline an offer. `destinations` maps top-level offered items
+ * (a file's or folder's name) to the directory to save each in;
+ * `acceptedIndices` accepts only the offered files at those indices.
+ */
 export async function acceptTransfer(
+  sessionId: string,
+  accept: boolean,
+  destinations?: Record<string, string>,
+  acceptedIndices?: number[]
+): Promise<void> {
+  return invoke("accept_transfer", {
+    sessionId,
+    accept,
+    destinations,
+    acceptedIndices,
+  });
+}
+
+/** Move a staged receive into place (`accept`) or discard it. */
+export async function finalizeTransfer(
   sessionId: string,
   accept: boolean
 ): Promise<void> {
-  return invoke("accept_transfer", { sessionId, accept });
+  return invoke("finalize_transfer", { sessionId, accept });
+}
+
+export interface HistoryFile {
+  name: string;
+  size: number;
+  relative_path?: string;
+  sha256?: string;
+  blake3?: string;
+}
+
+export interface HistoryEntry {
+  finished_at: number;
+  role: "Sender" | "Receiver";
+  code?: string;
+  connection_type?: "direct" | "relay";
+  duration_seconds: number;
+  /** Empty for sends. */
+  save_dir?: string;
+  files: HistoryFile[];
+  total_bytes: number;
+  bytes_received: number;
+  success: boolean;
+  error?: string;
+}
+
+export async function transferHistory(limit?: number): Promise<HistoryEntry[]> {
+  return invoke<HistoryEntry[]>("transfer_history", { limit });
+}
+
+export async function clearTransferHistory(): Promise<void> {
+  return invoke("clear_transfer_history");
+}
+
+/** Bytes moved this month (UTC), as `usageStats` reports them. */
+export interface UsageStats {
+  /** `YYYY-MM` */
+  period: string;
+  direct_bytes: number;
+  relay_bytes: number;
+  quota?: number;
+}
+
+export async function usageStats(): Promise<UsageStats> {
+  return invoke<UsageStats>("usage_stats");
+}
+
+/** Set the monthly quota in bytes, or clear it with `null`. */
+export async function setUsageQuota(quotaBytes: number | null): Promise<void> {
+  return invoke("set_usage_quota", { quotaBytes });
+}
+
+export interface PartialFileReport {
+  path: string;
+  size: number;
+  bytes_on_disk: number;
+  status: "complete" | "partial" | "corrupt";
+}
+
+export async function verifyPartial(
+  sessionToken: string
+): Promise<PartialFileReport[]> {
+  return invoke<PartialFileReport[]>("verify_partial", { sessionToken });
+}
+
+export async function checkSaveDir(path: string): Promise<void> {
+  return invoke("check_save_dir", { path });
 }
 
 export async function cancelTransfer(sessionId: string): Promise<void> {
   return invoke("cancel_transfer", { sessionId });
 }
 
+export async function cancelDiscovery(sessionId: string): Promise<void> {
+  return invoke("cancel_discovery", { sessionId });
+}
+
+/** Cancel a receive and remove its partial files. Resolves to how many were removed. */
+export async function cancelAndCleanup(sessionId: string): Promise<number> {
+  return invoke<number>("cancel_and_cleanup", { sessionId });
+}
+
+/** A session's phase, as `getSessionState` reports it. */
+export type TransferState =
+  | { phase: "waitingForPeer" }
+  | { phase: "exchanging" }
+  | { phase: "connecting" }
+  | {
+      phase: "transferring";
+      bytes_sent: number;
+      bytes_total: number;
+      speed_bps: number;
+      eta_seconds: number;
+    }
+  | { phase: "completed" }
+  | { phase: "failed"; reason: string }
+  | { phase: "cancelled" };
+
+export async function getSessionState(
+  sessionId: string
+): Promise<TransferState> {
+  return invoke<TransferState>("get_session_state", { sessionId });
+}
+
+export async function pauseTransfer(sessionId: string): Promise<void> {
+  return invoke("pause_transfer", { sessionId });
+}
+
+export async function resumeTransfer(sessionId: string): Promise<void> {
+  return invoke("resume_transfer", { sessionId });
+}
+
+export async function setNetworkMetered(
+  sessionId: string,
+  metered: boolean
+): Promise<void> {
+  return invoke("set_network_metered", { sessionId, metered });
+}
+
+export async function localFingerprint(): Promise<string> {
+  return invoke<string>("local_fingerprint");
+}
+
+/** A network interface, as `listNetworkInterfaces` reports it. */
+export interface InterfaceInfo {
+  name: string;
+  ips: string[];
+  loopback: boolean;
+  up: boolean;
+}
+
+export async function listNetworkInterfaces(
+  includeLinkLocal?: boolean
+): Promise<InterfaceInfo[]> {
+  return invoke<InterfaceInfo[]>("list_network_interfaces", { includeLinkLocal });
+}
+
+/**
+ * How a `wss://` signaling server's certificate is checked. By default
+ * against the system's root certificates.
+ */
+export interface SignalingTls {
+  /** PEM certificates of CAs to trust besides the system roots. */
+  extraRootsPem?: string[];
+  /** SHA-256 of the server's certificate, as hex; accepted alone when set. */
+  pinnedFingerprint?: string;
+}
+
+export async function setSignalingTls(config: SignalingTls): Promise<void> {
+  return invoke("set_signaling_tls", { config });
+}
+
 export function onTransferProgress(
   handler: (event: ProgressEvent) => void
 ): Promise<UnlistenFn> {
@@ -0,0 +1,187 @@
+package main
+
+import (
+	"encoding/json"
+	"log"
+	"net/http"
+	"net/url"
+	"strings"
+	"time"
+
+	"github.com/gorilla/websocket"
+)
+
+// federationHeader carries the shared secret on server-to-server requests.
+const federationHeader = "X-Relay-Federation"
+
+// Federation links this server with peer servers so that a sender and a
+// receiver registered on different servers can still pair up.
+//
+// Whichever peer registers second looks its code up on each federated
+// server. If the other role is waiting there, the new connection is bridged
+// to that server frame-for-frame, so signaling and relay run there exactly
+// as if both peers were local. Bridged connections are never federated
+// again, which keeps lookups from looping between servers.
+type Federation struct {
+	Peers  []string // base WebSocket URLs, e.g. ws://relay-b.example:8080
+	Secret string
+	client *http.Client
+}
+
+// NewFederation creates a Federation over the given peer servers.
+func NewFederation(peers []string, secret string) *Federation {
+	return &Federation{
+		Peers:  peers,
+		Secret: secret,
+		client: &http.Client{Timeout: 3 * time.Second},
+	}
+}
+
+// authorized reports whether r was made by a federated server.
+func (f *Federation) authorized(r *http.Request) bool {
+	return f != nil && f.Secret != "" && r.Header.Get(federationHeader) == f.Secret
+}
+
+// federationLookup reports which roles are registered locally for a code.
+type federationLookup struct {
+	Sender   bool `json:"sender"`
+	Receiver bool `json:"receiver"`
+}
+
+// FederationLookupHandler handles GET /federation/{code} for federated servers.
+func (s *Server) FederationLookupHandler(w http.ResponseWriter, r *http.Request) {
+	if !s.federation.authorized(r) {
+		http.Error(w, "forbidden", http.StatusForbidden)
+		return
+	}
+
+	code := r.PathValue("code")
+	s.mu.RLock()
+	sess := s.sessions[code]
+	s.mu.RUnlock()
+
+	var found federationLookup
+	if sess != nil {
+		sess.mu.Lock()
+		found.Sender = sess.Sender != nil
+		found.Receiver = sess.Receiver != nil
+		sess.mu.Unlock()
+	}
+
+	w.Header().Set("Content-Type", "application/json")
+	_ = json.NewEncoder(w).Encode(found)
+}
+
+// federationTarget returns the federated server to bridge a new local peer
+// to, or "" to handle it here. A session already waiting locally wins.
+func (s *Server) federationTarget(code string, role string) string {
+	if s.federation == nil || len(s.federation.Peers) == 0 {
+		return ""
+	}
+
+	s.mu.RLock()
+	sess := s.sessions[code]
+	s.mu.RUnlock()
+	if sess != nil {
+		sess.mu.Lock()
+		waiting := sess.Sender != nil || sess.Receiver != nil
+		sess.mu.Unlock()
+		if waiting {
+			return ""
+		}
+	}
+
+	return s.federation.findRemote(code, role)
+}
+
+// findRemote returns the first federated server where the role opposite to
+// role is waiting on code, or "".
+func (f *Federation) findRemote(code string, role string) string {
+	for _, peer := range f.Peers {
+		// ws:// → http://, wss:// → https://
+		lookupURL := "http" + strings.TrimPrefix(peer, "ws") + "/federation/" + url.PathEscape(code)
+		req, err := http.NewRequest(http.MethodGet, lookupURL, nil)
+		if err != nil {
+			log.Printf("federation: bad peer URL %s: %v", peer, err)
+			continue
+		}
+		req.Header.Set(federationHeader, f.Secret)
+
+		resp, err := f.client.Do(req)
+		if err != nil {
+			log.Printf("federation: lookup on %s failed: %v", peer, err)
+			continue
+		}
+		var found federationLookup
+		err = json.NewDecoder(resp.Body).Decode(&found)
+		resp.Body.Close()
+		if err != nil || resp.StatusCode != http.StatusOK {
+			log.Printf("federation: lookup on %s failed: status %d", peer, resp.StatusCode)
+			continue
+		}
+
+		if (role == "sender" && found.Receiver) || (role == "receiver" && found.Sender) {
+			return peer
+		}
+	}
+	return ""
+}
+
+// bridge registers conn's peer on the federated server and copies frames in
+// both directions until either side closes. The register message is passed
+// on with the client's public IP, which the remote server would otherwise
+// see as ours.
+func (f *Federation) bridge(conn *websocket.Conn, peer string, code string, reg SignalMessage) {
+	defer conn.Close()
+
+	header := http.Header{}
+	header.Set(federationHeader, f.Secret)
+	remote, _, err := websocket.DefaultDialer.Dial(peer+"/ws/"+url.PathEscape(code), header)
+	if err != nil {
+		log.Printf("federation: dial %s failed: %v", peer, err)
+		sendErrorConn(conn, "FEDERATION_FAILED", "could not reach the peer's server")
+		return
+	}
+	defer remote.Close()
+
+	info := peerInfoFromConn(conn)
+	if reg.PeerInfo != nil {
+		info.LocalIP = reg.PeerInfo.LocalIP
+		info.LocalPort = reg.PeerInfo.LocalPort
+		info.Candidates = reg.PeerInfo.Candidates
+	}
+	reg.PeerInfo = info
+	if err := remote.WriteJSON(reg); err != nil {
+		log.Printf("federation: register on %s failed: %v", peer, err)
+		sendErrorConn(conn, "FEDERATION_FAILED", "could not reach the peer's server")
+		return
+	}
+
+	// Relay traffic flows through the bridge too.
+	conn.SetReadLimit(16 * 1024 * 1024)
+	remote.SetReadLimit(16 * 1024 * 1024)
+
+	done := make(chan struct{}, 2)
+	go pipeFrames(remote, conn, done)
+	go pipeFrames(conn, remote, done)
+	<-done
+}
+
+// pipeFrames copies messages from src to dst. It is the only writer to dst,
+// and passes a close on when src ends.
+func pipeFrames(dst *websocket.Conn, src *websocket.Conn, done chan<- struct{}) {
+	defer func() { done <- struct{}{} }()
+	for {
+		msgType, data, err := src.ReadMessage()
+		if err != nil {
+			_ = dst.WriteMessage(
+				websocket.CloseMessage,
+				websocket.FormatCloseMessage(websocket.CloseNormalClosure, ""),
+			)
+			return
+		}
+		if err := dst.WriteMessage(msgType, data); err != nil {
+			return
+		}
+	}
+}
@@ -0,0 +1,82 @@
+package main
+
+import (
+	"encoding/json"
+	"net/http"
+	"net/http/httptest"
+	"strings"
+	"testing"
+	"time"
+)
+
+// newFederatedPair starts two test servers federated with each other.
+func newFederatedPair(t *testing.T) (*httptest.Server, *httptest.Server) {
+	t.Helper()
+	start := func() (*Server, *httptest.Server) {
+		srv := NewServer(100, 10*time.Minute, 10*1024*1024)
+		mux := http.NewServeMux()
+		mux.HandleFunc("GET /ws/{code}", srv.WebSocketHandler)
+		mux.HandleFunc("GET /federation/{code}", srv.FederationLookupHandler)
+		return srv, httptest.NewServer(mux)
+	}
+	srvA, tsA := start()
+	srvB, tsB := start()
+	wsURL := func(ts *httptest.Server) string { return "ws" + strings.TrimPrefix(ts.URL, "http") }
+	srvA.SetFederation(NewFederation([]string{wsURL(tsB)}, "test-secret"))
+	srvB.SetFederation(NewFederation([]string{wsURL(tsA)}, "test-secret"))
+	return tsA, tsB
+}
+
+func TestFederatedPeersPair(t *testing.T) {
+	tsA, tsB := newFederatedPair(t)
+	defer tsA.Close()
+	defer tsB.Close()
+
+	sender := dialWS(t, tsA, "federated-test")
+	defer sender.Close()
+	if err := register(sender, "sender"); err != nil {
+		t.Fatalf("sender register failed: %v", err)
+	}
+	time.Sleep(50 * time.Millisecond)
+
+	receiver := dialWS(t, tsB, "federated-test")
+	defer receiver.Close()
+	if err := register(receiver, "receiver"); err != nil {
+		t.Fatalf("receiver register failed: %v", err)
+	}
+
+	senderMsg := readMsg(t, sender)
+	if senderMsg.Type != "peer_joined" {
+		t.Fatalf("sender expected peer_joined, got %s", senderMsg.Type)
+	}
+	if senderMsg.PeerInfo == nil || senderMsg.PeerInfo.PublicIP != "127.0.0.1" {
+		t.Errorf("sender should see the receiver's own address, got %+v", senderMsg.PeerInfo)
+	}
+	if msg := readMsg(t, receiver); msg.Type != "peer_joined" {
+		t.Fatalf("receiver expected peer_joined, got %s", msg.Type)
+	}
+
+	// Signaling crosses the bridge in both directions.
+	payload := json.RawMessage(`{"key":"across"}`)
+	if err := receiver.WriteJSON(SignalMessage{Type: "spake2", Payload: payload}); err != nil {
+		t.Fatalf("send spake2 failed: %v", err)
+	}
+	if msg := readMsg(t, sender); msg.Type != "spake2" || string(msg.Payload) != string(payload) {
+		t.Errorf("sender expected forwarded spake2, got %+v", msg)
+	}
+}
+
+func TestFederationLookupRequiresSecret(t *testing.T) {
+	tsA, tsB := newFederatedPair(t)
+	defer tsA.Close()
+	defer tsB.Close()
+
+	resp, err := http.Get(tsA.URL + "/federation/some-code")
+	if err != nil {
+		t.Fatalf("lookup failed: %v", err)
+	}
+	resp.Body.Close()
+	if resp.StatusCode != http.StatusForbidden {
+		t.Errorf("expected 403 without secret, got %d", resp.StatusCode)
+	}
+}
@@ -25,6 +25,10 @@ type PeerInfo struct {
 	PublicPort int    `json:"public_port"`
 	LocalIP    string `json:"local_ip,omitempty"`
 	LocalPort  int    `json:"local_port,omitempty"`
+
+	// Candidates lists every "ip:port" the peer may be reachable on, for
+	// the other side to try in parallel. Passed through untouched.
+	Candidates []string `json:"candidates,omitempty"`
 }
 
 var upgrader = websocket.Upgrader{
@@ -41,6 +45,10 @@ func (s *Server) WebSocketHandler(w http.ResponseWriter, r *http.Request) {
 		return
 	}
 
+	// Connections bridged from a federated server carry the client's real
+	// public IP in their register message.
+	federated := s.federation.authorized(r)
+
 	conn, err := upgrader.Upgrade(w, r, nil)
 	if err != nil {
 		log.Printf("upgrade error: %v", err)
@@ -61,6 +69,14 @@ func (s *Server) WebSocketHandler(w http.ResponseWriter, r *http.Request) {
 		return
 	}
 
+	if !federated {
+		if remote := s.federationTarget(code, reg.Role); remote != "" {
+			log.Printf("federation: bridging %s for session %s to %s", reg.Role, code, remote)
+			s.federation.bridge(conn, remote, code, reg)
+			return
+		}
+	}
+
 	sess, err := s.GetOrCreateSession(code, reg.Role)
 	if err != nil {
 		sendErrorConn(conn, "CODE_IN_USE", err.Error())
@@ -69,10 +85,11 @@ func (s *Server) WebSocketHandler(w http.ResponseWriter, r *http.Request) {
 	}
 
 	peer := &Peer{
-		Conn: conn,
-		Role: reg.Role,
-		Info: reg.PeerInfo,
-		Done: make(chan struct{}),
+		Conn:      conn,
+		Role:      reg.Role,
+		Info:      reg.PeerInfo,
+		Federated: federated,
+		Done:      make(chan struct{}),
 	}
 
 	// Attach peer to session.
@@ -167,21 +184,31 @@ func (s *Server) notifyPeersJoined(sess *Session) {
 }
 
 // buildPeerInfo merges the peer's registered info (local_ip, local_port)
-// with the detected public IP from the WebSocket connection.
+// with the detected public IP from the WebSocket connection, or the one a
+// federated server reported for a bridged peer. A client that learned its
+// QUIC socket's public address over STUN registers it, and that wins: the
+// WebSocket's address needn't match the QUIC socket's mapping.
 func buildPeerInfo(p *Peer) *PeerInfo {
 	detected := peerInfoFromConn(p.Conn)
-
 	if p.Info == nil {
 		return detected
 	}
 
 	// Use detected public IP, but keep the registered local info
-	return &PeerInfo{
+	info := &PeerInfo{
 		PublicIP:   detected.PublicIP,
 		PublicPort: p.Info.LocalPort, // QUIC port, not WebSocket port
 		LocalIP:    p.Info.LocalIP,
 		LocalPort:  p.Info.LocalPort,
+		Candidates: p.Info.Candidates,
+	}
+	if p.Info.PublicIP != "" && (p.Federated || p.Info.PublicPort > 0) {
+		info.PublicIP = p.Info.PublicIP
+		if p.Info.PublicPort > 0 {
+			info.PublicPort = p.Info.PublicPort
+		}
 	}
+	return info
 }
 
 func (s *Server) forwardLoop(sess *Session, peer *Peer, code string) {
@@ -246,7 +273,7 @@ func (s *Server) forwardLoop(sess *Session, peer *Peer, code string) {
 			// Exit the forwardLoop so the relay can take over this connection.
 			return
 
-		case "spake2", "cert_fingerprint":
+		case "spake2", "key_confirm", "resume_nonce", "cert_fingerprint":
 			sess.mu.Lock()
 			other := sess.OtherPeer(peer)
 			sess.mu.Unlock()
@@ -257,6 +284,21 @@ func (s *Server) forwardLoop(sess *Session, peer *Peer, code string) {
 				}
 			}
 
+		case "address_update":
+			// The peer's QUIC address changed after register (rebind or
+			// port mapping). Record it and pass the merged info along.
+			sess.mu.Lock()
+			peer.Info = msg.PeerInfo
+			other := sess.OtherPeer(peer)
+			sess.mu.Unlock()
+			if other != nil {
+				update := SignalMessage{Type: "address_update", PeerInfo: buildPeerInfo(peer)}
+				if err := other.WriteJSON(update); err != nil {
+					log.Printf("forward error on session %s: %v", code, err)
+					return
+				}
+			}
+
 		case "relay_request":
 			sess.mu.Lock()
 			if peer.Role == "sender" {
@@ -84,6 +84,38 @@ func TestWebSocketHandshake(t *testing.T) {
 	}
 }
 
+func TestRegisteredPublicAddressForwarded(t *testing.T) {
+	_, ts := newTestServer(t, 100, 10*time.Minute)
+	defer ts.Close()
+
+	sender := dialWS(t, ts, "stun-test")
+	defer sender.Close()
+	receiver := dialWS(t, ts, "stun-test")
+	defer receiver.Close()
+
+	// As a client that asked STUN registers.
+	info := &PeerInfo{
+		PublicIP:   "203.0.113.7",
+		PublicPort: 41000,
+		LocalIP:    "192.168.1.5",
+		LocalPort:  5000,
+		Candidates: []string{"192.168.1.5:5000", "10.0.0.5:5000", "203.0.113.7:41000"},
+	}
+	if err := sender.WriteJSON(SignalMessage{Type: "register", Role: "sender", PeerInfo: info}); err != nil {
+		t.Fatalf("sender register failed: %v", err)
+	}
+	register(receiver, "receiver")
+	readMsg(t, sender)
+
+	msg := readMsg(t, receiver)
+	if msg.PeerInfo == nil || msg.PeerInfo.PublicIP != "203.0.113.7" || msg.PeerInfo.PublicPort != 41000 {
+		t.Errorf("receiver should see the sender's STUN address, got %+v", msg.PeerInfo)
+	}
+	if msg.PeerInfo == nil || len(msg.PeerInfo.Candidates) != 3 || msg.PeerInfo.Candidates[1] != "10.0.0.5:5000" {
+		t.Errorf("receiver should see all of the sender's candidates, got %+v", msg.PeerInfo)
+	}
+}
+
 func TestSPAKE2Forwarding(t *testing.T) {
 	_, ts := newTestServer(t, 100, 10*time.Minute)
 	defer ts.Close()
@@ -121,6 +153,92 @@ func TestSPAKE2Forwarding(t *testing.T) {
 	}
 }
 
+func TestKeyConfirmForwarding(t *testing.T) {
+	_, ts := newTestServer(t, 100, 10*time.Minute)
+	defer ts.Close()
+
+	sender := dialWS(t, ts, "confirm-test")
+	defer sender.Close()
+	receiver := dialWS(t, ts, "confirm-test")
+	defer receiver.Close()
+
+	register(sender, "sender")
+	register(receiver, "receiver")
+	readMsg(t, sender)
+	readMsg(t, receiver)
+
+	if err := receiver.WriteJSON(SignalMessage{Type: "key_confirm", Message: "bWFj"}); err != nil {
+		t.Fatalf("send key_confirm failed: %v", err)
+	}
+	msg := readMsg(t, sender)
+	if msg.Type != "key_confirm" || msg.Message != "bWFj" {
+		t.Errorf("expected key_confirm carrying the MAC, got %+v", msg)
+	}
+}
+
+func TestResumeNonceForwarding(t *testing.T) {
+	_, ts := newTestServer(t, 100, 10*time.Minute)
+	defer ts.Close()
+
+	sender := dialWS(t, ts, "resume-test")
+	defer sender.Close()
+	receiver := dialWS(t, ts, "resume-test")
+	defer receiver.Close()
+
+	register(sender, "sender")
+	register(receiver, "receiver")
+	readMsg(t, sender)
+	readMsg(t, receiver)
+
+	if err := sender.WriteJSON(SignalMessage{Type: "resume_nonce", Message: "bm9uY2U="}); err != nil {
+		t.Fatalf("send resume_nonce failed: %v", err)
+	}
+	msg := readMsg(t, receiver)
+	if msg.Type != "resume_nonce" || msg.Message != "bm9uY2U=" {
+		t.Errorf("expected resume_nonce carrying the nonce, got %+v", msg)
+	}
+}
+
+func TestAddressUpdateForwarding(t *testing.T) {
+	_, ts := newTestServer(t, 100, 10*time.Minute)
+	defer ts.Close()
+
+	sender := dialWS(t, ts, "address-test")
+	defer sender.Close()
+	receiver := dialWS(t, ts, "address-test")
+	defer receiver.Close()
+
+	sender.WriteJSON(SignalMessage{
+		Type:     "register",
+		Role:     "sender",
+		PeerInfo: &PeerInfo{LocalIP: "10.0.0.5", LocalPort: 4000},
+	})
+	register(receiver, "receiver")
+
+	// Drain peer_joined messages.
+	readMsg(t, sender)
+	readMsg(t, receiver)
+
+	update := SignalMessage{
+		Type:     "address_update",
+		PeerInfo: &PeerInfo{LocalIP: "10.0.0.5", LocalPort: 5000},
+	}
+	if err := sender.WriteJSON(update); err != nil {
+		t.Fatalf("send address_update failed: %v", err)
+	}
+
+	msg := readMsg(t, receiver)
+	if msg.Type != "address_update" {
+		t.Fatalf("expected address_update, got %s", msg.Type)
+	}
+	if msg.PeerInfo == nil || msg.PeerInfo.LocalPort != 5000 {
+		t.Errorf("expected updated local_port 5000, got %+v", msg.PeerInfo)
+	}
+	if msg.PeerInfo.PublicIP == "" {
+		t.Error("address_update should carry the detected public IP")
+	}
+}
+
 func TestDuplicateCode(t *testing.T) {
 	_, ts := newTestServer(t, 100, 10*time.Minute)
 	defer ts.Close()
@@ -4,6 +4,7 @@ import (
 	"flag"
 	"log"
 	"net/http"
+	"strings"
 	"time"
 )
 
@@ -12,15 +13,29 @@ func main() {
 	maxSessions := flag.Int("max-sessions", 1000, "maximum concurrent sessions")
 	sessionTTL := flag.Duration("session-ttl", 10*time.Minute, "session time-to-live")
 	relayRateLimit := flag.Int64("relay-rate-limit", 10*1024*1024, "relay rate limit in bytes/sec (default 10 MB/s)")
+	federationPeers := flag.String("federation-peers", "", "comma-separated WebSocket base URLs of federated servers")
+	federationSecret := flag.String("federation-secret", "", "shared secret federated servers present to each other")
 	flag.Parse()
 
 	srv := NewServer(*maxSessions, *sessionTTL, *relayRateLimit)
+	if *federationPeers != "" {
+		if *federationSecret == "" {
+			log.Fatalf("-federation-peers requires -federation-secret")
+		}
+		peers := strings.Split(*federationPeers, ",")
+		for i := range peers {
+			peers[i] = strings.TrimRight(strings.TrimSpace(peers[i]), "/")
+		}
+		srv.SetFederation(NewFederation(peers, *federationSecret))
+		log.Printf("federation: linked with %s", strings.Join(peers, ", "))
+	}
 
 	go srv.CleanupLoop(60 * time.Second)
 
 	mux := http.NewServeMux()
 	mux.HandleFunc("GET /health", srv.HealthHandler)
 	mux.HandleFunc("GET /ws/{code}", srv.WebSocketHandler)
+	mux.HandleFunc("GET /federation/{code}", srv.FederationLookupHandler)
 
 	log.Printf("Relay signaling server starting on %s (max-sessions=%d, session-ttl=%s, relay-rate-limit=%d B/s)",
 		*addr, *maxSessions, *sessionTTL, *relayRateLimit)
@@ -14,6 +14,7 @@ type Server struct {
 	maxSessions  int
 	sessionTTL   time.Duration
 	relayLimiter *RateLimiter
+	federation   *Federation // nil when not federated
 }
 
 // NewServer creates a Server with the given capacity, TTL, and relay rate limit.
@@ -62,6 +63,11 @@ func (s *Server) GetOrCreateSession(code string, role string) (*Session, error)
 	return sess, nil
 }
 
+// SetFederation links this server with other servers; see Federation.
+func (s *Server) SetFederation(f *Federation) {
+	s.federation = f
+}
+
 // RemoveSession deletes a session by code.
 func (s *Server) RemoveSession(code string) {
 	s.mu.Lock()
@@ -23,11 +23,12 @@ type Session struct {
 
 // Peer represents one side of a signaling session.
 type Peer struct {
-	Conn    *websocket.Conn
-	Role    string
-	Info    *PeerInfo // from register message (local_ip, local_port)
-	Done    chan struct{}
-	writeMu sync.Mutex
+	Conn      *websocket.Conn
+	Role      string
+	Info      *PeerInfo // from register message (local_ip, local_port)
+	Federated bool      // bridged from a federated server
+	Done      chan struct{}
+	writeMu   sync.Mutex
 }
 
 // WriteJSON sends a JSON message to the peer, safe for concurrent use.